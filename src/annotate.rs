@@ -0,0 +1,240 @@
+use bson::{Bson, Document};
+use byteorder::{ByteOrder, LittleEndian};
+use prometheus::{CounterVec};
+use tracing::{debug};
+
+use crate::mongodb::{HEADER_LENGTH, MSG_FLAG_CHECKSUM_PRESENT};
+
+// Commands that get the client address in their comment. These take a `comment` on
+// all the server versions that have a generic command comment (4.4+), the handshake,
+// authentication and admin commands are left alone.
+const ANNOTATED_COMMANDS: [&str; 10] = [
+    "find", "aggregate", "count", "distinct", "getMore",
+    "insert", "update", "delete", "findAndModify", "findandmodify"];
+
+// Field added to the document comments, and the prefix in the string comments
+const CLIENT_FIELD: &str = "client";
+
+lazy_static! {
+    static ref CLIENT_ANNOTATIONS_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_client_annotations_total",
+            "Number of client requests annotated with the client address, by outcome",
+            &["outcome"]).unwrap();
+}
+
+// Return the OP_MSG request with the client address added to the command comment, so
+// that it shows up in the server's slow query log and profiler. Messages that are not
+// annotated commands, or that can't be annotated, give None and should be forwarded
+// as they are.
+//
+// With no comment the command gets `comment: "client:<addr>"`. A string comment gets
+// ` client:<addr>` appended, a document comment gets a `client` field unless it already
+// has one. Comments of other types are left as they are.
+pub fn annotate_client(message: &[u8], client: &str) -> Option<Vec<u8>> {
+    if message.len() < HEADER_LENGTH || LittleEndian::read_u32(&message[12..16]) != 2013 {
+        return None;
+    }
+
+    let new_body = annotate_op_msg(&message[HEADER_LENGTH..], client)?;
+
+    let mut buf = Vec::with_capacity(HEADER_LENGTH + new_body.len());
+    buf.extend_from_slice(&message[..HEADER_LENGTH]);
+    buf.extend(new_body);
+    let message_length = buf.len() as u32;
+    LittleEndian::write_u32(&mut buf[..4], message_length);
+
+    CLIENT_ANNOTATIONS_TOTAL.with_label_values(&["annotated"]).inc();
+    Some(buf)
+}
+
+// The command document is the kind 0 section, the document sequences (eg. the documents
+// of an insert) are copied as they are. The checksum would no longer match, so it is
+// dropped.
+fn annotate_op_msg(body: &[u8], client: &str) -> Option<Vec<u8>> {
+    if body.len() < 5 {
+        return None;
+    }
+
+    let mut flag_bits = LittleEndian::read_u32(&body[..4]);
+    let mut sections = &body[4..];
+    if flag_bits & MSG_FLAG_CHECKSUM_PRESENT != 0 {
+        sections = &sections[..sections.len().checked_sub(4)?];
+        flag_bits &= !MSG_FLAG_CHECKSUM_PRESENT;
+    }
+
+    let mut buf = Vec::with_capacity(body.len() + 32);
+    buf.extend_from_slice(&flag_bits.to_le_bytes());
+
+    let mut annotated = false;
+    while !sections.is_empty() {
+        let size = LittleEndian::read_u32(sections.get(1..5)?) as usize;
+        let section = sections.get(..1 + size)?;
+        match section[0] {
+            0 if !annotated => {
+                buf.push(0);
+                buf.extend(annotate_document(&section[1..], client)?);
+                annotated = true;
+            },
+            1 => buf.extend_from_slice(section),
+            _ => return None,
+        }
+        sections = &sections[1 + size..];
+    }
+
+    if annotated { Some(buf) } else { None }
+}
+
+// Annotate the serialized command document, which must take up all of `buf`
+fn annotate_document(buf: &[u8], client: &str) -> Option<Vec<u8>> {
+    let mut doc = Document::from_reader(&mut &buf[..]).ok()?;
+
+    let command = doc.keys().next()?;
+    if !ANNOTATED_COMMANDS.contains(&command.as_str()) {
+        return None;
+    }
+
+    if !add_client(&mut doc, client) {
+        CLIENT_ANNOTATIONS_TOTAL.with_label_values(&["skipped"]).inc();
+        return None;
+    }
+
+    let mut new_buf = Vec::with_capacity(buf.len() + 32);
+    match doc.to_writer(&mut new_buf) {
+        Ok(_) => Some(new_buf),
+        Err(e) => {
+            debug!("Failed to serialize the annotated command: {}", e);
+            None
+        },
+    }
+}
+
+// Add the client address to the comment of the command. Returns false if the
+// existing comment is left as it is.
+fn add_client(doc: &mut Document, client: &str) -> bool {
+    match doc.get_mut("comment") {
+        None => {
+            doc.insert("comment", format!("{}:{}", CLIENT_FIELD, client));
+            true
+        },
+        Some(Bson::String(comment)) => {
+            comment.push_str(&format!(" {}:{}", CLIENT_FIELD, client));
+            true
+        },
+        Some(Bson::Document(comment)) if !comment.contains_key(CLIENT_FIELD) => {
+            comment.insert(CLIENT_FIELD, client);
+            true
+        },
+        Some(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use crate::mongodb::MongoMessage;
+
+    fn op_msg(flag_bits: u32, doc: &Document, sequence: Option<&[Document]>) -> Vec<u8> {
+        let mut body = flag_bits.to_le_bytes().to_vec();
+        body.push(0);
+        doc.to_writer(&mut body).unwrap();
+
+        if let Some(docs) = sequence {
+            let mut section = Vec::new();
+            section.extend_from_slice(b"documents\0");
+            for d in docs {
+                d.to_writer(&mut section).unwrap();
+            }
+            body.push(1);
+            body.extend_from_slice(&((section.len() + 4) as u32).to_le_bytes());
+            body.extend(section);
+        }
+
+        if flag_bits & MSG_FLAG_CHECKSUM_PRESENT != 0 {
+            body.extend_from_slice(&[1, 2, 3, 4]);
+        }
+
+        let mut buf = vec![0; HEADER_LENGTH];
+        LittleEndian::write_u32(&mut buf[..4], (HEADER_LENGTH + body.len()) as u32);
+        LittleEndian::write_u32(&mut buf[4..8], 7);
+        LittleEndian::write_u32(&mut buf[12..16], 2013);
+        buf.extend(body);
+        buf
+    }
+
+    fn command(buf: &[u8]) -> Document {
+        Document::from_reader(&mut &buf[HEADER_LENGTH+5..]).unwrap()
+    }
+
+    #[test]
+    fn test_annotate_comment() {
+        let find = doc! { "find": "kittens", "filter": {}, "$db": "test" };
+        let buf = annotate_client(&op_msg(0, &find, None), "10.0.0.1").unwrap();
+        assert_eq!("client:10.0.0.1", command(&buf).get_str("comment").unwrap());
+
+        let find = doc! { "find": "kittens", "comment": "report", "$db": "test" };
+        let buf = annotate_client(&op_msg(0, &find, None), "10.0.0.1").unwrap();
+        assert_eq!("report client:10.0.0.1", command(&buf).get_str("comment").unwrap());
+
+        let find = doc! { "find": "kittens", "comment": { "job": "report" }, "$db": "test" };
+        let buf = annotate_client(&op_msg(0, &find, None), "10.0.0.1").unwrap();
+        assert_eq!(&doc! { "job": "report", "client": "10.0.0.1" }, command(&buf).get_document("comment").unwrap());
+
+        // The command name stays first
+        assert_eq!(Some("find"), command(&buf).keys().next().map(String::as_str));
+
+        let find = doc! { "find": "kittens", "comment": 42, "$db": "test" };
+        assert!(annotate_client(&op_msg(0, &find, None), "10.0.0.1").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_annotate_framing() {
+        let insert = doc! { "insert": "kittens", "ordered": true, "$db": "test" };
+        let docs = [doc! { "name": "Tom" }, doc! { "name": "Felix" }];
+        let message = op_msg(MSG_FLAG_CHECKSUM_PRESENT, &insert, Some(&docs));
+        let buf = annotate_client(&message, "10.0.0.1").unwrap();
+
+        // Header is kept apart from the length, the checksum is gone and the
+        // document sequence follows the annotated command
+        assert_eq!(buf.len() as u32, LittleEndian::read_u32(&buf[..4]));
+        assert_eq!(7, LittleEndian::read_u32(&buf[4..8]));
+        assert_eq!(0, LittleEndian::read_u32(&buf[HEADER_LENGTH..HEADER_LENGTH+4]));
+        assert_eq!(buf.len(), message.len() - 4 + "comment".len() + "client:10.0.0.1".len() + 7);
+
+        let mut insert_bytes = Vec::new();
+        insert.to_writer(&mut insert_bytes).unwrap();
+        let sequence = &message[HEADER_LENGTH + 5 + insert_bytes.len()..message.len() - 4];
+        assert!(buf.ends_with(sequence));
+
+        let (hdr, msg) = MongoMessage::from_buffer(&buf).await.unwrap();
+        assert_eq!(buf.len(), hdr.message_length);
+        assert!(matches!(msg, MongoMessage::Msg(_)));
+    }
+
+    #[test]
+    fn test_other_messages_are_not_annotated() {
+        let hello = doc! { "hello": 1, "$db": "admin" };
+        assert!(annotate_client(&op_msg(0, &hello, None), "10.0.0.1").is_none());
+
+        let sasl = doc! { "saslStart": 1, "mechanism": "SCRAM-SHA-256", "$db": "admin" };
+        assert!(annotate_client(&op_msg(0, &sasl, None), "10.0.0.1").is_none());
+
+        // Unknown section kind
+        let find = doc! { "find": "kittens", "$db": "test" };
+        let mut buf = op_msg(0, &find, None);
+        buf.extend_from_slice(&[2, 5, 0, 0, 0]);
+        LittleEndian::write_u32(&mut buf[..4], buf.len() as u32);
+        assert!(annotate_client(&buf, "10.0.0.1").is_none());
+
+        // Truncated document sequence
+        let mut buf = op_msg(0, &find, None);
+        buf.extend_from_slice(&[1, 50, 0, 0, 0]);
+        LittleEndian::write_u32(&mut buf[..4], buf.len() as u32);
+        assert!(annotate_client(&buf, "10.0.0.1").is_none());
+
+        let mut query = vec![0; HEADER_LENGTH];
+        LittleEndian::write_u32(&mut query[12..16], 2004);
+        assert!(annotate_client(&query, "10.0.0.1").is_none());
+    }
+}
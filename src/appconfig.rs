@@ -1,22 +1,205 @@
+use std::collections::HashSet;
 use std::sync::{Arc,Mutex};
+use std::time::Duration;
+use std::str::FromStr;
+use std::fmt::{self, Debug};
 
 use crate::jaeger_tracing::{Tracer};
-use crate::tracker::{CursorTraceMapper};
+use crate::tracker::{CursorTraceMapper, DocumentsPerResponse};
+use crate::policy::{RequestPolicy};
+use crate::mongodb::{MAX_MESSAGE_SIZE};
+use crate::clientlabel::{ClientLabel};
+use crate::chaos::{InjectLatency};
+use crate::statsd::{StatsdClient};
+use crate::connlimit::{ActiveConnections, ClientConnections};
+use crate::hellorewrite::{HelloRewriter};
+use crate::breaker::{CircuitBreakers};
+use crate::slowquery::{SlowQueryFeed};
+use crate::upstreampool::{UpstreamPool};
+use crate::upstreamproxy::{UpstreamProxy};
+use crate::sni::{SniRoutes};
+use crate::sockopt::{NoDelay};
+use crate::commentlabel::{CommentLabels};
+use crate::config::{self, BUFFER_SIZE, CONNECT_TIMEOUT, LOG_SAMPLE_RATE, NODELAY, PARSE_TIMEOUT, TRACKER_OVERFLOW, TRACKING_MODE};
+
+// What to do when the tracker can't keep up with the proxied bytes
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum TrackerOverflow {
+    // Wait for the tracker to catch up before proxying more bytes
+    Block,
+    // Drop the oldest bytes queued for the tracker to make room for the new ones. The
+    // tracker then has to resync to the next message.
+    DropOldest,
+    // Stop tracking the connection
+    Disable,
+}
+
+impl FromStr for TrackerOverflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(TrackerOverflow::Block),
+            "drop-oldest" => Ok(TrackerOverflow::DropOldest),
+            "disable" => Ok(TrackerOverflow::Disable),
+            _ => Err("expecting one of block, drop-oldest or disable".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for TrackerOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackerOverflow::Block => write!(f, "block"),
+            TrackerOverflow::DropOldest => write!(f, "drop-oldest"),
+            TrackerOverflow::Disable => write!(f, "disable"),
+        }
+    }
+}
+
+// Whether the tracker has to see every proxied message
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum TrackingMode {
+    // Follow the --tracker-overflow policy when the tracker falls behind
+    BestEffort,
+    // Always wait for the tracker, slowing down the proxying if needed
+    Reliable,
+}
+
+impl FromStr for TrackingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "best-effort" => Ok(TrackingMode::BestEffort),
+            "reliable" => Ok(TrackingMode::Reliable),
+            _ => Err("expecting best-effort or reliable".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for TrackingMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackingMode::BestEffort => write!(f, "best-effort"),
+            TrackingMode::Reliable => write!(f, "reliable"),
+        }
+    }
+}
 
 #[derive(Clone,Debug)]
 pub struct AppConfig {
     pub tracer: Option<Tracer>,
+    // Name the command spans "<op> <collection>" instead of just "<op>"
+    pub namespace_span_names: bool,
+    pub statsd: Option<StatsdClient>,
     pub trace_mapper: Arc<Mutex<CursorTraceMapper>>,
     pub log_mongo_messages: bool,
+    // Log only every Nth message when logging is enabled
+    pub log_sample_rate: u64,
+    pub connect_timeout: Duration,
+    pub connect_time_by_ip: bool,
+    // Collect the response latencies for /stats and /top-commands
+    pub command_stats: bool,
+    pub parse_timeout: Duration,
+    // Time a single message is allowed to take to parse, not counting I/O waits
+    pub parse_budget: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+    pub nodelay: NoDelay,
+    pub policy: Arc<RequestPolicy>,
+    pub max_message_size: usize,
+    // Read buffer of the proxying and the capacity of the tracker channels in buffers
+    pub buffer_size: usize,
+    pub tracker_channel_size: usize,
+    pub client_label: ClientLabel,
+    pub anonymize_client_logs: bool,
+    pub tracker_overflow: TrackerOverflow,
+    pub tracking_mode: TrackingMode,
+    pub inject_latency: Option<InjectLatency>,
+    pub fault_drop_prob: f64,
+    pub track_document_shape: bool,
+    pub track_collection_scans: bool,
+    pub track_query_selectivity: bool,
+    pub observe_server_status: bool,
+    pub profile_locking: bool,
+    pub documents_per_response: Option<DocumentsPerResponse>,
+    // Lowercase names of the commands left out of the per-request metrics
+    pub exclude_commands: HashSet<String>,
+    pub comment_labels: Option<CommentLabels>,
+    pub client_connections: Option<ClientConnections>,
+    pub active_connections: Option<ActiveConnections>,
+    // Recycle the client connections that have been open this long
+    pub max_connection_lifetime: Option<Duration>,
+    pub rewrite_hello: Option<HelloRewriter>,
+    // Add the client address to the comments of the forwarded commands
+    pub annotate_client: bool,
+    pub circuit_breakers: Option<CircuitBreakers>,
+    pub upstream_pool: Option<UpstreamPool>,
+    // Forward proxy that the server connections go through
+    pub upstream_proxy: Option<UpstreamProxy>,
+    // Pass TLS through, choosing the upstream by the server name of the ClientHello
+    pub sni_routes: Option<SniRoutes>,
+    pub slow_queries: Option<SlowQueryFeed>,
+    pub strict_handshake: bool,
+    // The --proxy spec the connections came in through, the `proxy` label of the metrics
+    pub proxy_label: String,
 }
 
 impl AppConfig {
 
+    // Create the config with default settings, the rest of the fields are
+    // set from the command line options.
     pub fn new(tracer: Option<Tracer>, log_mongo_messages: bool) -> Self {
+        let buffer_size = default_value(BUFFER_SIZE);
         AppConfig {
             tracer,
+            namespace_span_names: false,
+            statsd: None,
             trace_mapper: Arc::new(Mutex::new(CursorTraceMapper::new())),
             log_mongo_messages,
+            log_sample_rate: default_value(LOG_SAMPLE_RATE),
+            connect_timeout: Duration::from_secs(default_value(CONNECT_TIMEOUT)),
+            connect_time_by_ip: false,
+            command_stats: false,
+            parse_timeout: Duration::from_secs(default_value(PARSE_TIMEOUT)),
+            parse_budget: None,
+            tcp_keepalive: None,
+            nodelay: default_value(NODELAY),
+            policy: Arc::new(RequestPolicy::default()),
+            max_message_size: MAX_MESSAGE_SIZE,
+            buffer_size,
+            tracker_channel_size: config::default_tracker_channel_size(buffer_size),
+            client_label: ClientLabel::default(),
+            anonymize_client_logs: false,
+            tracker_overflow: default_value(TRACKER_OVERFLOW),
+            tracking_mode: default_value(TRACKING_MODE),
+            inject_latency: None,
+            fault_drop_prob: 0.0,
+            track_document_shape: false,
+            track_collection_scans: false,
+            track_query_selectivity: false,
+            observe_server_status: false,
+            profile_locking: false,
+            documents_per_response: None,
+            exclude_commands: HashSet::new(),
+            comment_labels: None,
+            client_connections: None,
+            active_connections: None,
+            max_connection_lifetime: None,
+            rewrite_hello: None,
+            annotate_client: false,
+            circuit_breakers: None,
+            upstream_pool: None,
+            upstream_proxy: None,
+            sni_routes: None,
+            slow_queries: None,
+            strict_handshake: false,
+            proxy_label: String::new(),
         }
     }
 }
+
+// Parse the default of a command line option, which is kept as a string for clap
+fn default_value<T: FromStr>(value: &str) -> T where T::Err: Debug {
+    value.parse().unwrap()
+}
@@ -0,0 +1,214 @@
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use bson::{doc, Bson, Document};
+use byteorder::{LittleEndian, ReadBytesExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::mongodb::{self, MsgHeader, HEADER_LENGTH, MAX_MESSAGE_SIZE, MSG_FLAG_MORE_TO_COME};
+
+// Has to be set for --blackhole, so that it's not enabled by accident
+pub const ALLOW_BLACKHOLE_ENV: &str = "MONGOPROXY_ALLOW_BLACKHOLE";
+
+// Wire version 17 is MongoDb 6.0, new enough that the drivers use OP_MSG
+const MAX_WIRE_VERSION: i32 = 17;
+
+const MAX_BSON_OBJECT_SIZE: i32 = 16 * 1024 * 1024;
+
+static NEXT_CONNECTION_ID: AtomicI32 = AtomicI32::new(1);
+
+// A stand-in for the MongoDb server, for testing the metrics and tracing without one.
+// It answers the handshake like a standalone server and every other command with an
+// empty success: queries find nothing and writes change nothing. It listens on a
+// loopback port and the proxy forwards to it like to any upstream, so the trackers
+// see both the requests and the responses.
+pub async fn start() -> io::Result<SocketAddr> {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    info!("Blackhole server listening on {}", addr);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream).await {
+                            debug!("Blackhole connection failed: {}", e);
+                        }
+                    });
+                },
+                Err(e) => warn!("Blackhole accept failed: {}", e),
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn serve(mut stream: TcpStream) -> io::Result<()> {
+    let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    while let Some((hdr, body)) = read_request(&mut stream).await? {
+        let reply = match hdr.op_code {
+            2013 => {
+                let (flag_bits, command) = parse_op_msg(&body)?;
+                if flag_bits & MSG_FLAG_MORE_TO_COME != 0 {
+                    continue;
+                }
+                mongodb::command_reply(hdr.request_id, &reply_document(&command, connection_id))?
+            },
+            2004 => {
+                let command = parse_op_query(&body)?;
+                mongodb::op_reply(hdr.request_id, &reply_document(&command, connection_id))?
+            },
+            op_code => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported op code {}", op_code)));
+            },
+        };
+        stream.write_all(&reply).await?;
+    }
+    Ok(())
+}
+
+// The header and the rest of the message, None at the end of the stream
+async fn read_request<R>(rdr: &mut R) -> io::Result<Option<(MsgHeader, Vec<u8>)>>
+    where R: AsyncRead + Unpin
+{
+    let mut header = [0; HEADER_LENGTH];
+    let len = rdr.read(&mut header).await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    rdr.read_exact(&mut header[len..]).await?;
+
+    let hdr = MsgHeader::from_bytes(&header);
+    if hdr.message_length < HEADER_LENGTH || hdr.message_length > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid message length"));
+    }
+    let mut body = vec![0; hdr.message_length - HEADER_LENGTH];
+    rdr.read_exact(&mut body).await?;
+    Ok(Some((hdr, body)))
+}
+
+// The flag bits and the command document of an OP_MSG, skipping the document sequences
+fn parse_op_msg(body: &[u8]) -> io::Result<(u32, Document)> {
+    let mut rdr = body;
+    let flag_bits = rdr.read_u32::<LittleEndian>()?;
+    loop {
+        match rdr.read_u8()? {
+            0 => return Ok((flag_bits, read_document(&mut rdr)?)),
+            1 => {
+                let size = rdr.read_u32::<LittleEndian>()? as usize;
+                let skip = size.checked_sub(4).filter(|&skip| skip <= rdr.len())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid document sequence"))?;
+                rdr = &rdr[skip..];
+            },
+            kind => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown section kind {}", kind))),
+        }
+    }
+}
+
+// The query document of an OP_QUERY
+fn parse_op_query(body: &[u8]) -> io::Result<Document> {
+    let mut rdr = body;
+    rdr.read_u32::<LittleEndian>()?;       // flags
+    let name_len = rdr.iter().position(|&b| b == 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unterminated collection name"))?;
+    rdr = &rdr[name_len + 1..];
+    rdr.read_u32::<LittleEndian>()?;       // number to skip
+    rdr.read_u32::<LittleEndian>()?;       // number to return
+    read_document(&mut rdr)
+}
+
+fn read_document(rdr: &mut impl Read) -> io::Result<Document> {
+    Document::from_reader(rdr).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+// The response to a command, an empty success the way a server with no data would reply
+fn reply_document(command: &Document, connection_id: i32) -> Document {
+    let name = command.keys().next().map(String::as_str).unwrap_or("");
+    let db = command.get_str("$db").unwrap_or("admin");
+    let namespace = match command.get(name) {
+        Some(Bson::String(collection)) => format!("{}.{}", db, collection),
+        _ => format!("{}.$cmd.{}", db, name),
+    };
+
+    match name {
+        "hello" | "isMaster" | "ismaster" => doc! {
+            "helloOk": true,
+            "ismaster": true,
+            "isWritablePrimary": true,
+            "maxBsonObjectSize": MAX_BSON_OBJECT_SIZE,
+            "maxMessageSizeBytes": MAX_MESSAGE_SIZE as i32,
+            "maxWriteBatchSize": 100_000,
+            "logicalSessionTimeoutMinutes": 30,
+            "connectionId": connection_id,
+            "minWireVersion": 0,
+            "maxWireVersion": MAX_WIRE_VERSION,
+            "readOnly": false,
+            "ok": 1.0,
+        },
+        "find" | "aggregate" | "listCollections" | "listIndexes" => doc! {
+            "cursor": { "id": 0_i64, "ns": namespace, "firstBatch": [] },
+            "ok": 1.0,
+        },
+        "getMore" => doc! {
+            "cursor": { "id": 0_i64, "ns": namespace, "nextBatch": [] },
+            "ok": 1.0,
+        },
+        "insert" | "delete" | "count" => doc! { "n": 0, "ok": 1.0 },
+        "update" => doc! { "n": 0, "nModified": 0, "ok": 1.0 },
+        "listDatabases" => doc! { "databases": [], "totalSize": 0, "ok": 1.0 },
+        _ => doc! { "ok": 1.0 },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mongodb::MongoMessage;
+
+    async fn roundtrip(stream: &mut TcpStream, request: &[u8]) -> MongoMessage {
+        stream.write_all(request).await.unwrap();
+        let (hdr, body) = read_request(stream).await.unwrap().unwrap();
+        let mut message = Vec::new();
+        hdr.write(&mut message).unwrap();
+        message.extend_from_slice(&body);
+        MongoMessage::from_buffer(&message).await.unwrap().1
+    }
+
+    #[tokio::test]
+    async fn test_blackhole() {
+        let addr = start().await.unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+
+        let hello = mongodb::command_reply(0, &doc! { "hello": 1, "$db": "admin" }).unwrap();
+        match roundtrip(&mut stream, &hello).await {
+            MongoMessage::Msg(m) => assert_eq!(Some(MAX_WIRE_VERSION), m.documents[0].get_i32("max_wire_version")),
+            other => panic!("expecting OP_MSG, got {}", other),
+        }
+
+        let find = mongodb::command_reply(0, &doc! { "find": "kittens", "filter": {}, "$db": "test" }).unwrap();
+        match roundtrip(&mut stream, &find).await {
+            MongoMessage::Msg(m) => {
+                assert_eq!(Some(0), m.documents[0].get_i64("cursor_id"));
+                assert_eq!(Some(0), m.documents[0].get_i32("docs_returned"));
+            },
+            other => panic!("expecting OP_MSG, got {}", other),
+        }
+    }
+
+    #[test]
+    fn test_reply_document() {
+        let reply = reply_document(&doc! { "find": "kittens", "$db": "test" }, 1);
+        assert_eq!("test.kittens", reply.get_document("cursor").unwrap().get_str("ns").unwrap());
+
+        let reply = reply_document(&doc! { "aggregate": 1, "pipeline": [], "$db": "test" }, 1);
+        assert_eq!("test.$cmd.aggregate", reply.get_document("cursor").unwrap().get_str("ns").unwrap());
+
+        let reply = reply_document(&doc! { "ping": 1, "$db": "admin" }, 1);
+        assert_eq!(doc! { "ok": 1.0 }, reply);
+    }
+}
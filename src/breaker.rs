@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::sync::{Arc,Mutex};
+use std::time::{Duration,Instant};
+
+use prometheus::{GaugeVec};
+use tracing::{info, warn};
+
+lazy_static! {
+    static ref CIRCUIT_BREAKER_STATE: GaugeVec =
+        register_gauge_vec!(
+            "mongoproxy_circuit_breaker_state",
+            "Upstream circuit breaker state: 0=closed, 1=open, 2=half-open",
+            &["server_addr"]).unwrap();
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum BreakerState {
+    // Connecting as usual
+    Closed,
+    // Too many failures, new connections are not attempted until the cooldown is over
+    Open,
+    // Cooldown is over, a single trial connection decides whether to close or open again
+    HalfOpen,
+}
+
+impl BreakerState {
+    fn gauge_value(self) -> f64 {
+        match self {
+            BreakerState::Closed => 0.0,
+            BreakerState::Open => 1.0,
+            BreakerState::HalfOpen => 2.0,
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug)]
+pub struct BreakerConfig {
+    // Consecutive connect failures that open the breaker
+    pub failure_threshold: u32,
+    // The failures need to happen within this window
+    pub window: Duration,
+    // How long the breaker stays open before trying again
+    pub cooldown: Duration,
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: BreakerState,
+    failures: u32,
+    first_failure: Instant,
+    opened_at: Instant,
+    // A half-open breaker lets through one connect attempt at a time
+    trial_in_progress: bool,
+}
+
+// Circuit breakers for the upstream servers, shared by all the connections.
+// Breakers are keyed by the upstream address as given on the command line.
+#[derive(Clone,Debug)]
+pub struct CircuitBreakers {
+    config: BreakerConfig,
+    breakers: Arc<Mutex<HashMap<String, Breaker>>>,
+}
+
+impl CircuitBreakers {
+
+    pub fn new(config: BreakerConfig) -> Self {
+        CircuitBreakers {
+            config,
+            breakers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Is connecting to the upstream allowed right now
+    pub fn allow(&self, upstream: &str) -> bool {
+        self.allow_at(upstream, Instant::now())
+    }
+
+    pub fn record_success(&self, upstream: &str) {
+        let mut breakers = self.breakers.lock().unwrap();
+        if let Some(breaker) = breakers.remove(upstream) {
+            if breaker.state != BreakerState::Closed {
+                info!("Circuit breaker for {} closed", upstream);
+            }
+        }
+        set_state_gauge(upstream, BreakerState::Closed);
+    }
+
+    pub fn record_failure(&self, upstream: &str) {
+        self.record_failure_at(upstream, Instant::now())
+    }
+
+    pub fn state(&self, upstream: &str) -> BreakerState {
+        self.breakers.lock().unwrap()
+            .get(upstream)
+            .map(|b| b.state)
+            .unwrap_or(BreakerState::Closed)
+    }
+
+    fn allow_at(&self, upstream: &str, now: Instant) -> bool {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = match breakers.get_mut(upstream) {
+            Some(breaker) => breaker,
+            None => return true,
+        };
+
+        match breaker.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => {
+                if now.duration_since(breaker.opened_at) < self.config.cooldown {
+                    return false;
+                }
+                breaker.state = BreakerState::HalfOpen;
+                breaker.trial_in_progress = true;
+                set_state_gauge(upstream, BreakerState::HalfOpen);
+                true
+            },
+            BreakerState::HalfOpen => {
+                if breaker.trial_in_progress {
+                    return false;
+                }
+                breaker.trial_in_progress = true;
+                true
+            },
+        }
+    }
+
+    fn record_failure_at(&self, upstream: &str, now: Instant) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(upstream.to_string()).or_insert(Breaker {
+            state: BreakerState::Closed,
+            failures: 0,
+            first_failure: now,
+            opened_at: now,
+            trial_in_progress: false,
+        });
+
+        match breaker.state {
+            BreakerState::Closed => {
+                if breaker.failures == 0 || now.duration_since(breaker.first_failure) > self.config.window {
+                    breaker.failures = 0;
+                    breaker.first_failure = now;
+                }
+                breaker.failures += 1;
+                if breaker.failures < self.config.failure_threshold {
+                    return;
+                }
+                warn!("Circuit breaker for {} opened after {} connect failures", upstream, breaker.failures);
+            },
+            BreakerState::HalfOpen => {
+                warn!("Circuit breaker for {} opened again, the trial connection failed", upstream);
+            },
+            // Connects that were started before the breaker opened
+            BreakerState::Open => return,
+        }
+
+        breaker.state = BreakerState::Open;
+        breaker.opened_at = now;
+        breaker.trial_in_progress = false;
+        set_state_gauge(upstream, BreakerState::Open);
+    }
+}
+
+fn set_state_gauge(upstream: &str, state: BreakerState) {
+    CIRCUIT_BREAKER_STATE.with_label_values(&[upstream]).set(state.gauge_value());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breakers() -> CircuitBreakers {
+        CircuitBreakers::new(BreakerConfig {
+            failure_threshold: 3,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+        })
+    }
+
+    #[test]
+    fn test_breaker_opens_and_recovers() {
+        let breakers = breakers();
+        let start = Instant::now();
+
+        for i in 0..3 {
+            assert!(breakers.allow_at("mongo:27017", start));
+            breakers.record_failure_at("mongo:27017", start + Duration::from_secs(i));
+        }
+        assert_eq!(BreakerState::Open, breakers.state("mongo:27017"));
+        assert!(!breakers.allow_at("mongo:27017", start + Duration::from_secs(10)));
+        assert!(breakers.allow_at("other:27017", start + Duration::from_secs(10)));
+
+        // After the cooldown only one trial connection gets through
+        let later = start + Duration::from_secs(40);
+        assert!(breakers.allow_at("mongo:27017", later));
+        assert_eq!(BreakerState::HalfOpen, breakers.state("mongo:27017"));
+        assert!(!breakers.allow_at("mongo:27017", later));
+
+        breakers.record_success("mongo:27017");
+        assert_eq!(BreakerState::Closed, breakers.state("mongo:27017"));
+        assert!(breakers.allow_at("mongo:27017", later));
+    }
+
+    #[test]
+    fn test_failed_trial_reopens() {
+        let breakers = breakers();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            breakers.record_failure_at("mongo:27017", start);
+        }
+        let later = start + Duration::from_secs(31);
+        assert!(breakers.allow_at("mongo:27017", later));
+        breakers.record_failure_at("mongo:27017", later);
+
+        assert_eq!(BreakerState::Open, breakers.state("mongo:27017"));
+        assert!(!breakers.allow_at("mongo:27017", later + Duration::from_secs(29)));
+        assert!(breakers.allow_at("mongo:27017", later + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_failures_outside_the_window() {
+        let breakers = breakers();
+        let start = Instant::now();
+
+        breakers.record_failure_at("mongo:27017", start);
+        breakers.record_failure_at("mongo:27017", start + Duration::from_secs(5));
+        breakers.record_failure_at("mongo:27017", start + Duration::from_secs(11));
+        assert_eq!(BreakerState::Closed, breakers.state("mongo:27017"));
+
+        // A success resets the count
+        breakers.record_success("mongo:27017");
+        breakers.record_failure_at("mongo:27017", start + Duration::from_secs(12));
+        breakers.record_failure_at("mongo:27017", start + Duration::from_secs(13));
+        assert_eq!(BreakerState::Closed, breakers.state("mongo:27017"));
+    }
+}
@@ -0,0 +1,67 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Runs a future and turns a panic while polling it into an error with the panic
+// message, so that the task polling it can carry on. Nothing of the future is used
+// after a panic, it is only dropped.
+pub struct CatchPanic<F> {
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> CatchPanic<F> {
+    pub fn new(inner: F) -> Self {
+        CatchPanic { inner: Box::pin(inner) }
+    }
+}
+
+impl<F: Future> Future for CatchPanic<F> {
+    type Output = Result<F::Output, String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let inner = &mut self.inner;
+        match panic::catch_unwind(AssertUnwindSafe(|| inner.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(panic_message(payload.as_ref()))),
+        }
+    }
+}
+
+// Call the function, returning the panic message if it panics
+pub fn catch_panic<F: FnOnce()>(f: F) -> Result<(), String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| panic_message(payload.as_ref()))
+}
+
+// The message of a panic, for panics with a string payload
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_catch_panic_future() {
+        assert_eq!(Ok(1), CatchPanic::new(async { 1 }).await);
+
+        let v: Vec<u32> = Vec::new();
+        let result = CatchPanic::new(async move { v[1] }).await;
+        assert!(result.unwrap_err().contains("index out of bounds"));
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        assert_eq!(Ok(()), catch_panic(|| {}));
+        assert_eq!(Err("tracker bug".to_string()), catch_panic(|| panic!("tracker bug")));
+    }
+}
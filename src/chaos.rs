@@ -0,0 +1,205 @@
+use std::collections::hash_map::RandomState;
+use std::future;
+use std::hash::{BuildHasher,Hasher};
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+use prometheus::{Counter};
+use tokio::time;
+
+// Range of the random delay before closing a connection with an injected fault
+const FAULT_DELAY_MIN_MS: u64 = 100;
+const FAULT_DELAY_MAX_MS: u64 = 10_000;
+
+lazy_static! {
+    static ref INJECTED_DELAYS_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_injected_delays_total",
+            "Number of server responses delayed by latency injection"
+            ).unwrap();
+
+    static ref INJECTED_FAULTS_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_injected_faults_total",
+            "Number of connections closed by fault injection"
+            ).unwrap();
+}
+
+// Artificial latency added to the server responses. This is a testing feature for
+// seeing how the applications cope with a slow database.
+#[derive(Clone,Copy,Debug)]
+pub struct InjectLatency {
+    pub latency: Duration,
+    // Up to this much is added on top of `latency`, picked randomly for each response
+    pub jitter: Duration,
+}
+
+// Delays server responses as they are being proxied. The bytes are only held back,
+// never changed.
+pub struct LatencyInjector {
+    config: InjectLatency,
+    boundaries: MessageBoundaries,
+}
+
+impl LatencyInjector {
+
+    pub fn new(config: InjectLatency) -> Self {
+        LatencyInjector {
+            config,
+            boundaries: MessageBoundaries::default(),
+        }
+    }
+
+    // Wait before forwarding the chunk if a new message starts in it. Each message is
+    // delayed once, regardless of how many chunks it spans.
+    pub async fn delay(&mut self, chunk: &[u8]) {
+        if self.boundaries.feed(chunk) == 0 {
+            return;
+        }
+
+        let mut delay = self.config.latency;
+        let jitter_ms = self.config.jitter.as_millis() as u64;
+        if jitter_ms > 0 {
+            delay += Duration::from_millis(random_u64() % (jitter_ms + 1));
+        }
+
+        INJECTED_DELAYS_TOTAL.inc();
+        time::delay_for(delay).await;
+    }
+}
+
+// Decide whether to inject a fault into a connection. With probability `drop_prob`
+// returns a random short delay after which the connection should be closed.
+pub fn pick_fault_delay(drop_prob: f64) -> Option<Duration> {
+    if drop_prob <= 0.0 || random_fraction() >= drop_prob {
+        return None;
+    }
+    let delay_ms = FAULT_DELAY_MIN_MS + random_u64() % (FAULT_DELAY_MAX_MS - FAULT_DELAY_MIN_MS);
+    Some(Duration::from_millis(delay_ms))
+}
+
+// Completes when it's time for the injected fault. Without a fault never completes.
+pub async fn injected_fault(delay: Option<Duration>) {
+    match delay {
+        Some(delay) => {
+            time::delay_for(delay).await;
+            INJECTED_FAULTS_TOTAL.inc();
+        },
+        None => future::pending().await,
+    }
+}
+
+// Random numbers from the std hasher keys, good enough for chaos
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Random number in [0, 1)
+fn random_fraction() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Follows the message boundaries in a stream of MongoDb messages by their length
+// prefix. The messages are not validated, it's just counting bytes.
+#[derive(Default)]
+struct MessageBoundaries {
+    // Bytes left in the current message
+    remaining: usize,
+    // The message length, as it may be split between chunks
+    length_bytes: [u8; 4],
+    length_read: usize,
+}
+
+impl MessageBoundaries {
+
+    // Return the number of messages that start in this chunk
+    fn feed(&mut self, chunk: &[u8]) -> usize {
+        let mut started = 0;
+        let mut pos = 0;
+
+        while pos < chunk.len() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(chunk.len() - pos);
+                self.remaining -= n;
+                pos += n;
+                continue;
+            }
+
+            if self.length_read == 0 {
+                started += 1;
+            }
+
+            let n = (4 - self.length_read).min(chunk.len() - pos);
+            self.length_bytes[self.length_read..self.length_read+n].copy_from_slice(&chunk[pos..pos+n]);
+            self.length_read += n;
+            pos += n;
+
+            if self.length_read == 4 {
+                let message_length = LittleEndian::read_u32(&self.length_bytes) as usize;
+                self.remaining = message_length.saturating_sub(4);
+                self.length_read = 0;
+            }
+        }
+
+        started
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        LittleEndian::write_u32(&mut buf[..4], len as u32);
+        buf
+    }
+
+    #[test]
+    fn test_message_boundaries() {
+        let mut boundaries = MessageBoundaries::default();
+
+        // Two messages in one chunk
+        let mut buf = message(20);
+        buf.extend(message(30));
+        assert_eq!(2, boundaries.feed(&buf));
+
+        // One message split in three chunks, the length split as well
+        let buf = message(100);
+        assert_eq!(1, boundaries.feed(&buf[..2]));
+        assert_eq!(0, boundaries.feed(&buf[2..50]));
+        assert_eq!(0, boundaries.feed(&buf[50..]));
+
+        // End of one message and the start of the next
+        let mut buf = message(40);
+        buf.extend(message(40));
+        assert_eq!(1, boundaries.feed(&buf[..30]));
+        assert_eq!(1, boundaries.feed(&buf[30..]));
+    }
+
+    #[test]
+    fn test_pick_fault_delay() {
+        assert!(pick_fault_delay(0.0).is_none());
+
+        let delay = pick_fault_delay(1.0).unwrap();
+        assert!(delay >= Duration::from_millis(FAULT_DELAY_MIN_MS));
+        assert!(delay < Duration::from_millis(FAULT_DELAY_MAX_MS));
+    }
+
+    #[tokio::test]
+    async fn test_delay() {
+        let mut injector = LatencyInjector::new(InjectLatency {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(10),
+        });
+
+        let buf = message(100);
+        let started = std::time::Instant::now();
+        injector.delay(&buf[..50]).await;
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        let started = std::time::Instant::now();
+        injector.delay(&buf[50..]).await;
+        assert!(started.elapsed() < Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+// Bounded queue of the proxied chunks for a tracker. It works like a tokio mpsc channel,
+// but the sender can also make room by evicting the oldest queued chunk, which the mpsc
+// sender can't do.
+pub fn chunk_queue<T>(capacity: usize) -> (ChunkSender<T>, ChunkReceiver<T>) {
+    assert!(capacity > 0, "chunk queue capacity must be at least 1");
+    let shared = Arc::new(Mutex::new(Shared {
+        queue: VecDeque::with_capacity(capacity),
+        capacity,
+        senders: 1,
+        receiver_closed: false,
+        recv_waker: None,
+        send_wakers: Vec::new(),
+    }));
+    (ChunkSender { shared: shared.clone() }, ChunkReceiver { shared })
+}
+
+#[derive(Debug,PartialEq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "no available capacity"),
+            TrySendError::Closed(_) => write!(f, "channel closed"),
+        }
+    }
+}
+
+// The receiver has gone away, the item is handed back
+#[derive(Debug,PartialEq)]
+pub struct Closed<T>(pub T);
+
+impl<T> fmt::Display for Closed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+struct Shared<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver_closed: bool,
+    recv_waker: Option<Waker>,
+    // Senders waiting for room in the queue
+    send_wakers: Vec<Waker>,
+}
+
+impl<T> Shared<T> {
+    fn push(&mut self, item: T) {
+        self.queue.push_back(item);
+        if let Some(waker) = self.recv_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_senders(&mut self) {
+        for waker in self.send_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+pub struct ChunkSender<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> ChunkSender<T> {
+
+    // Queue the item if there is room
+    pub fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.receiver_closed {
+            return Err(TrySendError::Closed(item));
+        }
+        if shared.queue.len() >= shared.capacity {
+            return Err(TrySendError::Full(item));
+        }
+        shared.push(item);
+        Ok(())
+    }
+
+    // Queue the item, evicting the oldest queued item if the queue is full. Returns the
+    // evicted item.
+    pub fn send_evicting(&self, item: T) -> Result<Option<T>, Closed<T>> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.receiver_closed {
+            return Err(Closed(item));
+        }
+        let evicted = if shared.queue.len() >= shared.capacity {
+            shared.queue.pop_front()
+        } else {
+            None
+        };
+        shared.push(item);
+        Ok(evicted)
+    }
+
+    // Queue the item, waiting for room if the queue is full
+    pub fn send(&self, item: T) -> SendFuture<'_, T> {
+        SendFuture { sender: self, item: Some(item) }
+    }
+}
+
+impl<T> Clone for ChunkSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        ChunkSender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for ChunkSender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.senders -= 1;
+        if shared.senders == 0 {
+            if let Some(waker) = shared.recv_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+pub struct SendFuture<'a, T> {
+    sender: &'a ChunkSender<T>,
+    item: Option<T>,
+}
+
+impl<'a, T: Unpin> Future for SendFuture<'a, T> {
+    type Output = Result<(), Closed<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let item = self.item.take().expect("polled after completion");
+        // Check for room and register the waker under the same lock, so that the
+        // receiver can't take an item in between and miss waking us up
+        let mut shared = self.sender.shared.lock().unwrap();
+        if shared.receiver_closed {
+            return Poll::Ready(Err(Closed(item)));
+        }
+        if shared.queue.len() < shared.capacity {
+            shared.push(item);
+            return Poll::Ready(Ok(()));
+        }
+        shared.send_wakers.push(cx.waker().clone());
+        drop(shared);
+        self.item = Some(item);
+        Poll::Pending
+    }
+}
+
+pub struct ChunkReceiver<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> ChunkReceiver<T> {
+
+    // The next item, or None once the queue is empty and all the senders are gone
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(item) = shared.queue.pop_front() {
+            shared.wake_senders();
+            return Poll::Ready(Some(item));
+        }
+        if shared.senders == 0 {
+            return Poll::Ready(None);
+        }
+        shared.recv_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        let mut shared = self.shared.lock().unwrap();
+        let item = shared.queue.pop_front();
+        if item.is_some() {
+            shared.wake_senders();
+        }
+        item
+    }
+
+    // Stop accepting new items, the ones already queued can still be received
+    pub fn close(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.receiver_closed = true;
+        shared.wake_senders();
+    }
+}
+
+impl<T> Drop for ChunkReceiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time;
+
+    async fn recv<T>(rx: &mut ChunkReceiver<T>) -> Option<T> {
+        RecvFuture { rx }.await
+    }
+
+    struct RecvFuture<'a, T> {
+        rx: &'a mut ChunkReceiver<T>,
+    }
+
+    impl<'a, T> Future for RecvFuture<'a, T> {
+        type Output = Option<T>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+            self.rx.poll_recv(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_evicting() {
+        let (tx, mut rx) = chunk_queue(2);
+        assert_eq!(Ok(None), tx.send_evicting(1));
+        assert_eq!(Ok(None), tx.send_evicting(2));
+        assert_eq!(Err(TrySendError::Full(3)), tx.try_send(3));
+
+        // The oldest one makes room for the newest
+        assert_eq!(Ok(Some(1)), tx.send_evicting(3));
+        drop(tx);
+        assert_eq!(Some(2), recv(&mut rx).await);
+        assert_eq!(Some(3), recv(&mut rx).await);
+        assert_eq!(None, recv(&mut rx).await);
+    }
+
+    #[tokio::test]
+    async fn test_send_waits_for_room() {
+        let (tx, mut rx) = chunk_queue(1);
+        tx.send(1).await.unwrap();
+        assert!(time::timeout(Duration::from_millis(50), tx.send(2)).await.is_err());
+
+        let sending = tokio::spawn(async move {
+            tx.send(3).await.unwrap();
+        });
+        assert_eq!(Some(1), recv(&mut rx).await);
+        sending.await.unwrap();
+        assert_eq!(Some(3), recv(&mut rx).await);
+        assert_eq!(None, recv(&mut rx).await);
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let (tx, mut rx) = chunk_queue(2);
+        let other = tx.clone();
+        tx.send(1).await.unwrap();
+
+        // The receiver waits for all the senders
+        drop(tx);
+        assert_eq!(Some(1), recv(&mut rx).await);
+        assert!(time::timeout(Duration::from_millis(50), recv(&mut rx)).await.is_err());
+
+        rx.close();
+        assert_eq!(Err(Closed(2)), other.send(2).await);
+        assert_eq!(Err(TrySendError::Closed(3)), other.try_send(3));
+        assert_eq!(Err(Closed(4)), other.send_evicting(4));
+    }
+}
@@ -0,0 +1,413 @@
+use std::ffi::OsString;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand, crate_version};
+
+use crate::blackhole::{ALLOW_BLACKHOLE_ENV};
+use crate::config::{ACCEPT_LOOPS, ADMIN_BIND, ADMIN_PORT, BREAKER_COOLDOWN, BREAKER_WINDOW, BUFFER_SIZE,
+    CONNECT_TIMEOUT, DOCUMENTS_PER_RESPONSE_BUCKETS, ENABLE_JAEGER_ENV, HEALTH_CHECK_INTERVAL, JAEGER_ADDR,
+    JAEGER_ADDR_ENV, LIMIT_MODE, LOG_LEVEL, LOG_MONGO_MESSAGES_ENV, LOG_SAMPLE_RATE, NODELAY,
+    OTLP_METRICS_INTERVAL, PARSE_TIMEOUT, SERVICE_NAME, SERVICE_NAME_ENV, STATSD_PREFIX, TRACKER_OVERFLOW,
+    TRACKER_QUEUE_BYTES, TRACKING_MODE};
+use crate::mongodb::{MAX_MESSAGE_SIZE};
+
+// Parse the command line options of the proxy. The App is built and used in the same
+// expression, as the help texts with the defaults are temporaries that it borrows.
+// The first item of `args` is the program name.
+pub fn get_matches_from_safe<I, T>(args: I) -> clap::Result<ArgMatches<'static>>
+    where I: IntoIterator<Item = T>, T: Into<OsString> + Clone
+{
+    App::new("mongoproxy")
+        .version(crate_version!())
+        .about("Proxies MongoDb requests to obtain metrics")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(SubCommand::with_name("parse")
+            .about("Parses a raw capture of MongoDb messages and prints them, without proxying")
+            .arg(Arg::with_name("file")
+                .long("file")
+                .value_name("CAPTURE")
+                .help("File with the captured bytes of one direction of a connection")
+                .takes_value(true)
+                .required(true)))
+        .arg(Arg::with_name("proxy")
+            .long("proxy")
+            .value_name("[bind-addr:]local-port[:remote-host:remote-port[,...]]")
+            .help("Port the proxy listens on (sidecar) and optionally\na target hostport (for static proxy). Additional\ncomma separated hostports are failover targets.\nCan be repeated to run several proxies")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .required_unless("listen")
+            .conflicts_with_all(&["listen", "upstream"]))
+        .arg(Arg::with_name("listen")
+            .long("listen")
+            .value_name("[bind-addr:]port")
+            .help("Address the proxy listens on, an alternative to --proxy")
+            .takes_value(true))
+        .arg(Arg::with_name("upstream")
+            .long("upstream")
+            .value_name("host:port[,...]")
+            .help("Server to proxy to with --listen, additional comma separated\nhostports are failover targets. Default is the original destination")
+            .takes_value(true)
+            .requires("listen"))
+        .arg(Arg::with_name("srv_host")
+            .long("srv-host")
+            .value_name("_mongodb._tcp.HOST")
+            .help("Look up the server address from this SRV record at startup")
+            .takes_value(true))
+        .arg(Arg::with_name("log_mongo_messages")
+            .long("log-mongo-messages")
+            .help(&format!("Log the contents of MongoDb messages (adds full BSON parsing).\nEnvironment: {}=true", LOG_MONGO_MESSAGES_ENV))
+            .takes_value(false)
+            .required(false))
+        .arg(Arg::with_name("log_level")
+            .long("log-level")
+            .value_name("LEVEL")
+            .help(&format!("Log level when RUST_LOG is not set. Default {}", LOG_LEVEL))
+            .possible_values(&["error", "warn", "info", "debug", "trace"])
+            .takes_value(true))
+        .arg(Arg::with_name("log_sample_rate")
+            .long("log-sample-rate")
+            .value_name("N")
+            .help(&format!("Only log 1 in every N messages with --log-mongo-messages. Default {}", LOG_SAMPLE_RATE))
+            .takes_value(true))
+        .arg(Arg::with_name("enable_jaeger")
+            .long("enable-jaeger")
+            .help(&format!("Enable distributed tracing with Jaeger. Environment: {}=true", ENABLE_JAEGER_ENV))
+            .takes_value(false)
+            .required(false))
+        .arg(Arg::with_name("namespace_span_names")
+            .long("namespace-span-names")
+            .help("Name the command spans after the command and collection, eg. \"find products\"")
+            .takes_value(false))
+        .arg(Arg::with_name("jaeger_addr")
+            .long("jaeger-addr")
+            .value_name("Jaeger agent host:port")
+            .help(&format!("Jaeger agent hostport to send traces to (compact thrift protocol).\nEnvironment: {}. Default {}", JAEGER_ADDR_ENV, JAEGER_ADDR))
+            .takes_value(true)
+            .required(false))
+        .arg(Arg::with_name("service_name")
+            .long("service-name")
+            .value_name("SERVICE_NAME")
+            .help(&format!("Service name that will be used in Jaeger traces and metric labels.\nEnvironment: {}. Default {}", SERVICE_NAME_ENV, SERVICE_NAME))
+            .takes_value(true))
+        .arg(Arg::with_name("statsd_addr")
+            .long("statsd-addr")
+            .value_name("host:port")
+            .help("Also send the key metrics to this StatsD agent")
+            .takes_value(true))
+        .arg(Arg::with_name("statsd_prefix")
+            .long("statsd-prefix")
+            .value_name("PREFIX")
+            .help(&format!("Prefix for the StatsD metric names. Default {}", STATSD_PREFIX))
+            .takes_value(true)
+            .requires("statsd_addr"))
+        .arg(Arg::with_name("otlp_metrics_endpoint")
+            .long("otlp-metrics-endpoint")
+            .value_name("http://host:port")
+            .help("Also export the metrics to this OTLP/gRPC collector")
+            .takes_value(true))
+        .arg(Arg::with_name("otlp_metrics_interval")
+            .long("otlp-metrics-interval")
+            .value_name("SECONDS")
+            .help(&format!("How often to export the metrics to the OTLP collector. Default {}", OTLP_METRICS_INTERVAL))
+            .takes_value(true)
+            .requires("otlp_metrics_endpoint"))
+        .arg(Arg::with_name("connect_timeout")
+            .long("connect-timeout")
+            .value_name("SECONDS")
+            .help(&format!("Timeout for connecting to the server. Default {}", CONNECT_TIMEOUT))
+            .takes_value(true))
+        .arg(Arg::with_name("wait_for_upstream")
+            .long("wait-for-upstream")
+            .value_name("SECONDS")
+            .help("At startup wait for the static upstream to accept connections before accepting\nclients, exit with an error if it doesn't within this time")
+            .takes_value(true))
+        .arg(Arg::with_name("strict_handshake")
+            .long("strict-handshake")
+            .help("Close connections that don't start with a hello/isMaster handshake")
+            .takes_value(false))
+        .arg(Arg::with_name("slow_query_ms")
+            .long("slow-query-ms")
+            .value_name("MILLISECONDS")
+            .help("Stream the commands slower than this from /slow-queries/stream on the admin port")
+            .takes_value(true))
+        .arg(Arg::with_name("blackhole")
+            .long("blackhole")
+            .help(&format!("Testing mode: answer the clients with a built-in stand-in server instead of\nMongoDb. Needs {}=true", ALLOW_BLACKHOLE_ENV))
+            .takes_value(false))
+        .arg(Arg::with_name("enable_metrics_reset")
+            .long("enable-metrics-reset")
+            .help("DESTRUCTIVE, for testing and benchmarks only: zero the counters and histograms\non POST /admin/reset-metrics. Needs --admin-auth")
+            .takes_value(false)
+            .requires("admin_auth"))
+        .arg(Arg::with_name("circuit_breaker_failures")
+            .long("circuit-breaker-failures")
+            .value_name("N")
+            .help("Stop connecting to an upstream for a while after N consecutive connect failures")
+            .takes_value(true))
+        .arg(Arg::with_name("circuit_breaker_window")
+            .long("circuit-breaker-window")
+            .value_name("SECONDS")
+            .help(&format!("The failures must happen within this many seconds. Default {}", BREAKER_WINDOW))
+            .takes_value(true)
+            .requires("circuit_breaker_failures"))
+        .arg(Arg::with_name("circuit_breaker_cooldown")
+            .long("circuit-breaker-cooldown")
+            .value_name("SECONDS")
+            .help(&format!("How long to wait before trying the upstream again. Default {}", BREAKER_COOLDOWN))
+            .takes_value(true)
+            .requires("circuit_breaker_failures"))
+        .arg(Arg::with_name("tcp_keepalive")
+            .long("tcp-keepalive")
+            .value_name("SECONDS")
+            .help("Enable TCP keepalive on client and server connections with this idle time")
+            .takes_value(true))
+        .arg(Arg::with_name("nodelay")
+            .long("nodelay")
+            .value_name("client,server|client|server|none")
+            .help(&format!("Which sides of the connections get TCP_NODELAY. Nagle can help bulk transfers\nbut adds latency to small messages. Default {}", NODELAY))
+            .takes_value(true))
+        .arg(Arg::with_name("max_message_size")
+            .long("max-message-size")
+            .value_name("BYTES")
+            .help(&format!("Stop tracking connections that send messages larger than this. Default {}", MAX_MESSAGE_SIZE))
+            .takes_value(true))
+        .arg(Arg::with_name("parse_timeout")
+            .long("parse-timeout")
+            .value_name("SECONDS")
+            .help(&format!("Stop tracking connections where a message is incomplete for this long. Default {}", PARSE_TIMEOUT))
+            .takes_value(true))
+        .arg(Arg::with_name("parse_budget_ms")
+            .long("parse-budget-ms")
+            .value_name("MILLISECONDS")
+            .help("Skip the messages that take longer than this to parse, not counting\nthe time spent waiting for the bytes. Default is no limit")
+            .takes_value(true))
+        .arg(Arg::with_name("buffer_size")
+            .long("buffer-size")
+            .value_name("BYTES")
+            .help(&format!("Size of the reads when proxying the bytes. Default {}", BUFFER_SIZE))
+            .takes_value(true))
+        .arg(Arg::with_name("tracker_channel_size")
+            .long("tracker-channel-size")
+            .value_name("N")
+            .help(&format!("How many reads can be queued for the tracker. Default is {} bytes worth of\n--buffer-size reads", TRACKER_QUEUE_BYTES))
+            .takes_value(true))
+        .arg(Arg::with_name("tracker_overflow")
+            .long("tracker-overflow")
+            .value_name("block|drop-oldest|disable")
+            .help(&format!("What to do when the tracker falls behind the proxied bytes. Default {}", TRACKER_OVERFLOW))
+            .takes_value(true))
+        .arg(Arg::with_name("tracking_mode")
+            .long("tracking-mode")
+            .value_name("best-effort|reliable")
+            .help(&format!("With reliable the proxy always waits for the tracker to catch up, whatever\n--tracker-overflow says, so that no message goes untracked. Default {}", TRACKING_MODE))
+            .takes_value(true))
+        .arg(Arg::with_name("reject_legacy_opcodes")
+            .long("reject-legacy-opcodes")
+            .help("Close client connections that use OP_QUERY (except for the handshake),\nOP_INSERT, OP_UPDATE or OP_DELETE")
+            .takes_value(false)
+            .required(false))
+        .arg(Arg::with_name("deny_commands")
+            .long("deny-commands")
+            .value_name("cmd1,cmd2,...")
+            .help("Close client connections that send any of these commands")
+            .takes_value(true)
+            .conflicts_with("allow_commands"))
+        .arg(Arg::with_name("allow_commands")
+            .long("allow-commands")
+            .value_name("cmd1,cmd2,...")
+            .help("Close client connections that send commands other than these\n(the hello/isMaster handshake and authentication are always allowed)")
+            .takes_value(true))
+        .arg(Arg::with_name("anonymize_clients")
+            .long("anonymize-clients")
+            .help("Replace the client IP address in metric labels with a salted hash")
+            .takes_value(false)
+            .conflicts_with("no_client_labels"))
+        .arg(Arg::with_name("no_client_labels")
+            .long("no-client-labels")
+            .help("Use the same client label for all clients")
+            .takes_value(false))
+        .arg(Arg::with_name("client_hash_salt")
+            .long("client-hash-salt")
+            .value_name("SALT")
+            .help("Salt for hashing the client addresses. Default is random on every start")
+            .takes_value(true)
+            .requires("anonymize_clients"))
+        .arg(Arg::with_name("anonymize_client_logs")
+            .long("anonymize-client-logs")
+            .help("Also use the hashed client address in logs")
+            .takes_value(false)
+            .requires("anonymize_clients"))
+        .arg(Arg::with_name("worker_threads")
+            .long("worker-threads")
+            .value_name("N")
+            .help("Number of worker threads for proxying. Default is the number of cores")
+            .takes_value(true))
+        .arg(Arg::with_name("accept_loops")
+            .long("accept-loops")
+            .value_name("N")
+            .help(&format!("Number of SO_REUSEPORT listeners to accept connections on (Linux only). Default {}", ACCEPT_LOOPS))
+            .takes_value(true))
+        .arg(Arg::with_name("pool_upstream")
+            .long("pool-upstream")
+            .help("Reuse the server connections of unauthenticated clients that disconnect")
+            .takes_value(false))
+        .arg(Arg::with_name("upstream_proxy")
+            .long("upstream-proxy")
+            .value_name("socks5://host:port|http://host:port")
+            .help("Connect to the servers through this SOCKS5 or HTTP CONNECT forward proxy")
+            .takes_value(true))
+        .arg(Arg::with_name("sni_route")
+            .long("sni-route")
+            .value_name("server.name=host:port[,...]")
+            .help("Pass TLS connections through without decrypting, choosing the upstream by the\nserver name in the ClientHello. Can be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("max_connections")
+            .long("max-connections")
+            .value_name("N")
+            .help("Close new connections when N connections are already open")
+            .takes_value(true))
+        .arg(Arg::with_name("shed_idle")
+            .long("shed-idle")
+            .help("At --max-connections close the connection idle the longest instead of the new one")
+            .takes_value(false)
+            .requires("max_connections"))
+        .arg(Arg::with_name("limit_mode")
+            .long("limit-mode")
+            .value_name("reject|pause")
+            .help(&format!("What to do with new connections at --max-connections. With pause the\nconnections wait in the listen backlog until one closes. Default {}", LIMIT_MODE))
+            .takes_value(true)
+            .requires("max_connections")
+            .conflicts_with("shed_idle"))
+        .arg(Arg::with_name("max_connections_per_client")
+            .long("max-connections-per-client")
+            .value_name("N")
+            .help("Close new connections from clients that already have N connections open")
+            .takes_value(true))
+        .arg(Arg::with_name("max_connection_lifetime")
+            .long("max-connection-lifetime")
+            .value_name("SECONDS")
+            .help("Close the client connections that have been open this long, once they are\nbetween messages, so that the drivers reconnect")
+            .takes_value(true))
+        .arg(Arg::with_name("rewrite_hello")
+            .long("rewrite-hello")
+            .value_name("ADDR|member=ADDR,...")
+            .help("Rewrite the replica set member addresses in hello responses to the proxy address,\nso that the clients keep connecting through the proxy")
+            .takes_value(true))
+        .arg(Arg::with_name("annotate_client")
+            .long("annotate-client")
+            .help("Add the client address to the comment of the CRUD commands forwarded to the server,\nso that it shows up in the slow query log")
+            .takes_value(false))
+        .arg(Arg::with_name("inject_latency_ms")
+            .long("inject-latency-ms")
+            .value_name("MILLISECONDS")
+            .help("Testing: delay every server response by this much")
+            .takes_value(true))
+        .arg(Arg::with_name("inject_jitter_ms")
+            .long("inject-jitter-ms")
+            .value_name("MILLISECONDS")
+            .help("Testing: add up to this much random delay on top of --inject-latency-ms")
+            .takes_value(true)
+            .requires("inject_latency_ms"))
+        .arg(Arg::with_name("fault_drop_prob")
+            .long("fault-drop-prob")
+            .value_name("P")
+            .help("Testing: close connections with probability P (0.0-1.0) after a random short interval")
+            .takes_value(true))
+        .arg(Arg::with_name("track_document_shape")
+            .long("track-document-shape")
+            .help("Record the nesting depth and field count of the commands.\nOnly has an effect with message logging or tracing enabled")
+            .takes_value(false))
+        .arg(Arg::with_name("track_collection_scans")
+            .long("track-collection-scans")
+            .help("Count the explain responses with a COLLSCAN in the winning plan")
+            .takes_value(false))
+        .arg(Arg::with_name("track_query_selectivity")
+            .long("track-query-selectivity")
+            .help("Count the documents examined and returned by the explain responses\nwith executionStats")
+            .takes_value(false))
+        .arg(Arg::with_name("observe_server_status")
+            .long("observe-server-status")
+            .help("Record the connection and operation counts from the serverStatus responses\nthat the clients run")
+            .takes_value(false))
+        .arg(Arg::with_name("documents_per_response")
+            .long("documents-per-response")
+            .help("Record a histogram of the number of documents in each query response")
+            .takes_value(false))
+        .arg(Arg::with_name("documents_per_response_buckets")
+            .long("documents-per-response-buckets")
+            .value_name("b1,b2,...")
+            .help(&format!("Buckets of the --documents-per-response histogram. Default {}", DOCUMENTS_PER_RESPONSE_BUCKETS))
+            .requires("documents_per_response")
+            .takes_value(true))
+        .arg(Arg::with_name("profile_locking")
+            .long("profile-locking")
+            .help("Record how long the trackers hold the connection's tracker lock")
+            .takes_value(false))
+        .arg(Arg::with_name("exclude_commands")
+            .long("exclude-commands")
+            .value_name("cmd1,cmd2,...")
+            .help("Leave these commands out of the per-request metrics, eg. the\nhello,isMaster,ping heartbeats. They are still proxied and traced")
+            .takes_value(true))
+        .arg(Arg::with_name("use_comment_label")
+            .long("use-comment-label")
+            .help("Label the per-request metrics with the $comment of the command,\nif it's short and there are not too many distinct ones")
+            .takes_value(false))
+        .arg(Arg::with_name("command_stats")
+            .long("command-stats")
+            .help("Collect the response latencies per command for /stats and /top-commands")
+            .takes_value(false))
+        .arg(Arg::with_name("connect_time_by_ip")
+            .long("connect-time-by-ip")
+            .help("Label the server connect time with the resolved server IP address")
+            .takes_value(false))
+        .arg(Arg::with_name("admin_port")
+            .long("admin-port")
+            .value_name("ADMIN_PORT")
+            .help(&format!("Port the admin endpoints listens on (metrics and health). Default {}", ADMIN_PORT))
+            .takes_value(true))
+        .arg(Arg::with_name("admin_auth")
+            .long("admin-auth")
+            .value_name("user:password")
+            .help("Require HTTP Basic authentication on the admin endpoints")
+            .takes_value(true))
+        .arg(Arg::with_name("admin_auth_exempt_livez")
+            .long("admin-auth-exempt-livez")
+            .help("Do not require authentication on the /livez endpoint")
+            .takes_value(false)
+            .requires("admin_auth"))
+        .arg(Arg::with_name("k8s_pod_labels")
+            .long("k8s-pod-labels")
+            .help("Add pod, namespace and node labels to the metrics from the POD_NAME,\nPOD_NAMESPACE and NODE_NAME environment variables")
+            .takes_value(false))
+        .arg(Arg::with_name("health_check_interval")
+            .long("health-check-interval")
+            .value_name("SECONDS")
+            .help(&format!("How long to cache the /readyz upstream check result. Default {}", HEALTH_CHECK_INTERVAL))
+            .takes_value(true))
+        .arg(Arg::with_name("admin_bind")
+            .long("admin-bind")
+            .value_name("ADMIN_BIND")
+            .help(&format!("Address the admin endpoints bind to. Default {}", ADMIN_BIND))
+            .takes_value(true))
+        .arg(Arg::with_name("print_config")
+            .long("print-config")
+            .help("Print the resolved configuration as JSON and exit")
+            .takes_value(false))
+        .args(&profiling_args())
+        .get_matches_from_safe(args)
+}
+
+#[cfg(feature = "profiling")]
+fn profiling_args() -> Vec<Arg<'static, 'static>> {
+    vec![Arg::with_name("enable_profiling")
+        .long("enable-profiling")
+        .help("Serve CPU profiles from /debug/profile?seconds=N on the admin port")
+        .takes_value(false)]
+}
+
+#[cfg(not(feature = "profiling"))]
+fn profiling_args() -> Vec<Arg<'static, 'static>> {
+    Vec::new()
+}
@@ -0,0 +1,86 @@
+use std::collections::hash_map::{DefaultHasher,RandomState};
+use std::hash::{BuildHasher,Hash,Hasher};
+
+// How many hex digits of the hash to keep
+const HASH_LABEL_LEN: usize = 12;
+
+// Label value for all clients when per client labels are disabled
+pub const AGGREGATE_LABEL: &str = "aggregate";
+
+// How the client address is turned into the `client` metric label
+#[derive(Clone,Debug)]
+pub enum ClientLabel {
+    // The client IP address as is
+    Address,
+    // Truncated salted hash of the client IP address. Distinct clients still get
+    // distinct labels, but the address itself is not exposed.
+    Hashed { salt: String },
+    // The same label for all clients
+    Aggregate,
+}
+
+impl Default for ClientLabel {
+    fn default() -> Self {
+        ClientLabel::Address
+    }
+}
+
+impl ClientLabel {
+
+    // Hash the client addresses with the given salt. Without a salt a random one is
+    // picked, so the labels are only stable for the lifetime of the process. Use the
+    // same salt on all proxies to be able to correlate clients across them.
+    pub fn hashed(salt: Option<&str>) -> Self {
+        let salt = match salt {
+            Some(salt) => salt.to_string(),
+            None => format!("{:016x}", RandomState::new().build_hasher().finish()),
+        };
+        ClientLabel::Hashed { salt }
+    }
+
+    pub fn format(&self, client_addr: &str) -> String {
+        match self {
+            ClientLabel::Address => client_addr.to_string(),
+            ClientLabel::Hashed { salt } => {
+                let mut hasher = DefaultHasher::new();
+                salt.hash(&mut hasher);
+                client_addr.hash(&mut hasher);
+                let mut label = format!("{:016x}", hasher.finish());
+                label.truncate(HASH_LABEL_LEN);
+                label
+            },
+            ClientLabel::Aggregate => AGGREGATE_LABEL.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_label() {
+        assert_eq!("10.0.0.1", ClientLabel::default().format("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_hashed_label() {
+        let label = ClientLabel::hashed(Some("pepper"));
+        let hashed = label.format("10.0.0.1");
+
+        assert_eq!(HASH_LABEL_LEN, hashed.len());
+        assert!(!hashed.contains("10.0.0.1"));
+        assert_eq!(hashed, label.format("10.0.0.1"));
+        assert_ne!(hashed, label.format("10.0.0.2"));
+
+        // Same salt gives the same labels, a different one doesn't
+        assert_eq!(hashed, ClientLabel::hashed(Some("pepper")).format("10.0.0.1"));
+        assert_ne!(hashed, ClientLabel::hashed(Some("salt")).format("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_aggregate_label() {
+        assert_eq!(AGGREGATE_LABEL, ClientLabel::Aggregate.format("10.0.0.1"));
+        assert_eq!(AGGREGATE_LABEL, ClientLabel::Aggregate.format("10.0.0.2"));
+    }
+}
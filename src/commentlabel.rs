@@ -0,0 +1,78 @@
+use std::collections::HashSet;
+use std::sync::{Arc,Mutex};
+
+use crate::jaeger_tracing::TRACE_ID_PREFIX;
+
+// Longest comment that is used as a label value
+pub const MAX_COMMENT_LEN: usize = 64;
+
+// Number of distinct comments that get a label, the rest are left unlabeled
+pub const MAX_COMMENT_VALUES: usize = 100;
+
+// Turns the `$comment` of the commands into `comment` label values. Comments are meant
+// for humans and can be anything, so only short printable strings are used and only
+// up to a fixed number of distinct values. Once that many have been seen, new comments
+// are not labeled but the ones seen before still are.
+#[derive(Clone,Debug)]
+pub struct CommentLabels {
+    max_len: usize,
+    max_values: usize,
+    seen: Arc<Mutex<HashSet<String>>>,
+}
+
+impl CommentLabels {
+
+    pub fn new(max_len: usize, max_values: usize) -> Self {
+        CommentLabels {
+            max_len,
+            max_values,
+            seen: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    // The label value for the comment, if it gets one
+    pub fn label(&self, comment: &str) -> Option<String> {
+        if comment.is_empty()
+            || comment.len() > self.max_len
+            || comment.starts_with(TRACE_ID_PREFIX)
+            || comment.chars().any(char::is_control)
+        {
+            return None;
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.contains(comment) {
+            if seen.len() >= self.max_values {
+                return None;
+            }
+            seen.insert(comment.to_string());
+        }
+        Some(comment.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comment_label() {
+        let labels = CommentLabels::new(16, 10);
+        assert_eq!(Some("checkout".to_string()), labels.label("checkout"));
+        assert_eq!(None, labels.label(""));
+        assert_eq!(None, labels.label("a comment that is too long"));
+        assert_eq!(None, labels.label("two\nlines"));
+        assert_eq!(None, labels.label("uber-trace-id:6d697c0f076183c:6d697c0f076183c:0:1"));
+    }
+
+    #[test]
+    fn test_comment_cardinality() {
+        let labels = CommentLabels::new(16, 2);
+        assert!(labels.label("first").is_some());
+        assert!(labels.label("second").is_some());
+        assert!(labels.label("third").is_none());
+
+        // The ones seen before are still labeled
+        assert_eq!(Some("first".to_string()), labels.label("first"));
+    }
+}
@@ -0,0 +1,1034 @@
+use std::collections::HashSet;
+use std::env;
+use std::fmt::{self, Display};
+use std::io;
+use std::net::{IpAddr,Ipv6Addr,SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{ArgMatches};
+
+use crate::appconfig::{TrackerOverflow, TrackingMode};
+use crate::blackhole::{ALLOW_BLACKHOLE_ENV};
+use crate::breaker::{BreakerConfig};
+use crate::chaos::{InjectLatency};
+use crate::connlimit::{LimitMode};
+use crate::mongodb::{HEADER_LENGTH, MAX_MESSAGE_SIZE};
+use crate::policy;
+use crate::slowquery::json_string;
+use crate::sni::{SniRoutes};
+use crate::sockopt::{NoDelay};
+use crate::upstreamproxy::{UpstreamProxy};
+
+pub const JAEGER_ADDR: &str = "127.0.0.1:6831";
+pub const ADMIN_PORT: &str = "9898";
+pub const ADMIN_BIND: &str = "0.0.0.0";
+pub const LISTEN_BIND: &str = "0.0.0.0";
+pub const HEALTH_CHECK_INTERVAL: &str = "5";
+pub const CONNECT_TIMEOUT: &str = "5";
+pub const PARSE_TIMEOUT: &str = "30";
+pub const ACCEPT_LOOPS: &str = "1";
+pub const TRACKER_OVERFLOW: &str = "block";
+pub const TRACKING_MODE: &str = "best-effort";
+pub const LIMIT_MODE: &str = "reject";
+pub const STATSD_PREFIX: &str = "mongoproxy";
+pub const OTLP_METRICS_INTERVAL: &str = "30";
+pub const LOG_SAMPLE_RATE: &str = "1";
+pub const LOG_LEVEL: &str = "info";
+pub const BREAKER_WINDOW: &str = "10";
+pub const BREAKER_COOLDOWN: &str = "30";
+pub const SERVICE_NAME: &str = "mongoproxy";
+pub const DOCUMENTS_PER_RESPONSE_BUCKETS: &str = "1,10,100,1000,10000,100000";
+pub const BUFFER_SIZE: &str = "1024";
+pub const NODELAY: &str = "client,server";
+
+// Bytes that the tracker channel holds when --tracker-channel-size is not given,
+// in chunks of --buffer-size
+pub const TRACKER_QUEUE_BYTES: usize = 32 * 1024;
+
+// Smallest default tracker channel, for large read buffers
+const MIN_TRACKER_CHANNEL_SIZE: usize = 4;
+
+// Environment variables that the options fall back to when they are not given on the
+// command line
+pub const SERVICE_NAME_ENV: &str = "MONGOPROXY_SERVICE_NAME";
+pub const JAEGER_ADDR_ENV: &str = "MONGOPROXY_JAEGER_ADDR";
+pub const LOG_MONGO_MESSAGES_ENV: &str = "MONGOPROXY_LOG_MONGO_MESSAGES";
+pub const ENABLE_JAEGER_ENV: &str = "MONGOPROXY_ENABLE_JAEGER";
+
+// Shown instead of the passwords and salts
+const REDACTED: &str = "***";
+
+// An invalid option value or combination of options
+#[derive(Debug,PartialEq)]
+pub struct ConfigError(String);
+
+impl ConfigError {
+    fn new(message: impl Into<String>) -> Self {
+        ConfigError(message.into())
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError(e.to_string())
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// One proxy listener, given with --proxy or --listen and --upstream
+#[derive(Clone,Debug,PartialEq)]
+pub struct ProxyConfig {
+    // The proxy as it was specified, for the metric labels
+    pub spec: String,
+    pub listen: String,
+    // Comma separated upstreams, empty for the original destination
+    pub upstream: String,
+}
+
+// The settings of a proxy invocation, resolved from the flags, environment variables
+// and defaults.
+#[derive(Clone,Debug)]
+pub struct Config {
+    pub proxies: Vec<ProxyConfig>,
+    // Proxy to the built-in stand-in server instead of the upstreams
+    pub blackhole: bool,
+    pub srv_host: Option<String>,
+    pub accept_loops: usize,
+    pub worker_threads: Option<usize>,
+
+    pub admin_addr: SocketAddr,
+    // As user:password
+    pub admin_auth: Option<String>,
+    pub admin_auth_exempt_livez: bool,
+    pub health_check_interval: Duration,
+
+    pub log_level: String,
+    pub log_mongo_messages: bool,
+    pub log_sample_rate: u64,
+    pub service_name: String,
+    pub enable_jaeger: bool,
+    pub namespace_span_names: bool,
+    pub jaeger_addr: String,
+
+    pub statsd_addr: Option<String>,
+    pub statsd_prefix: String,
+    pub otlp_metrics_endpoint: Option<String>,
+    pub otlp_metrics_interval: Duration,
+    pub k8s_pod_labels: bool,
+    pub anonymize_clients: bool,
+    pub client_hash_salt: Option<String>,
+    pub anonymize_client_logs: bool,
+    pub no_client_labels: bool,
+    pub exclude_commands: HashSet<String>,
+    pub use_comment_label: bool,
+    pub connect_time_by_ip: bool,
+    pub command_stats: bool,
+    pub track_document_shape: bool,
+    pub track_collection_scans: bool,
+    pub track_query_selectivity: bool,
+    pub observe_server_status: bool,
+    pub profile_locking: bool,
+    // Buckets of the documents per response histogram, when it's enabled
+    pub documents_per_response: Option<Vec<f64>>,
+    pub slow_query: Option<Duration>,
+    pub enable_profiling: bool,
+    pub enable_metrics_reset: bool,
+
+    pub connect_timeout: Duration,
+    // Wait this long at startup for the static upstreams to accept connections
+    pub wait_for_upstream: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+    pub nodelay: NoDelay,
+    pub parse_timeout: Duration,
+    pub parse_budget: Option<Duration>,
+    pub max_message_size: usize,
+    pub buffer_size: usize,
+    pub tracker_channel_size: usize,
+    pub tracker_overflow: TrackerOverflow,
+    pub tracking_mode: TrackingMode,
+    pub strict_handshake: bool,
+    pub reject_legacy_opcodes: bool,
+    pub deny_commands: HashSet<String>,
+    pub allow_commands: Option<HashSet<String>>,
+    pub rewrite_hello: Option<String>,
+    pub annotate_client: bool,
+
+    pub circuit_breaker: Option<BreakerConfig>,
+    pub pool_upstream: bool,
+    pub upstream_proxy: Option<UpstreamProxy>,
+    pub sni_routes: Option<SniRoutes>,
+    pub max_connections: Option<usize>,
+    pub shed_idle: bool,
+    pub limit_mode: LimitMode,
+    pub max_connections_per_client: Option<usize>,
+    pub max_connection_lifetime: Option<Duration>,
+
+    pub inject_latency: Option<InjectLatency>,
+    pub fault_drop_prob: f64,
+}
+
+impl Config {
+
+    // Resolve the settings from the flags, the environment and the defaults. All the
+    // validation happens here, the first invalid setting is returned as the error.
+    pub fn from_args(matches: &ArgMatches) -> Result<Config, ConfigError> {
+        Config::from_args_and_env(matches, |name| env::var(name).ok())
+    }
+
+    // Same as `from_args`, with the environment variables looked up with `env_var`
+    pub fn from_args_and_env<F>(matches: &ArgMatches, env_var: F) -> Result<Config, ConfigError>
+        where F: Fn(&str) -> Option<String>
+    {
+        let admin_port = matches.value_of("admin_port").unwrap_or(ADMIN_PORT);
+        let admin_bind = matches.value_of("admin_bind").unwrap_or(ADMIN_BIND);
+        let admin_addr = parse_admin_address(admin_bind, admin_port)
+            .map_err(|e| ConfigError::new(format!("Invalid admin address {}:{}: {}", admin_bind, admin_port, e)))?;
+
+        let addresses: Vec<(String, String)> = match matches.value_of("listen") {
+            Some(listen) => vec![parse_listen_upstream(listen, matches.value_of("upstream"))?],
+            None => matches.values_of("proxy")
+                .ok_or_else(|| ConfigError::new("--proxy or --listen is required"))?
+                .map(parse_proxy_addresses)
+                .collect::<Result<_, _>>()?,
+        };
+
+        // The proxy label of the metrics, --listen and --upstream in the same form as --proxy
+        let specs: Vec<String> = match matches.values_of("proxy") {
+            Some(specs) => specs.map(String::from).collect(),
+            None => addresses.iter()
+                .map(|(local, remote)| if remote.is_empty() { local.clone() } else { format!("{}:{}", local, remote) })
+                .collect(),
+        };
+        let proxies: Vec<ProxyConfig> = specs.into_iter()
+            .zip(addresses)
+            .map(|(spec, (listen, upstream))| ProxyConfig { spec, listen, upstream })
+            .collect();
+
+        let srv_host = matches.value_of("srv_host").map(String::from);
+        if srv_host.is_some() {
+            if proxies.len() > 1 {
+                return Err(ConfigError::new("--srv-host can't be used with more than one --proxy"));
+            }
+            if !proxies[0].upstream.is_empty() {
+                return Err(ConfigError::new("--srv-host can't be used together with a remote address in --proxy or --upstream"));
+            }
+        }
+
+        let blackhole = matches.occurrences_of("blackhole") > 0;
+        if blackhole {
+            if !env_var(ALLOW_BLACKHOLE_ENV).map_or(false, |value| parse_env_flag(&value)) {
+                return Err(ConfigError::new(format!("--blackhole is a testing mode, set {}=true to confirm", ALLOW_BLACKHOLE_ENV)));
+            }
+            if proxies.iter().any(|p| !p.upstream.is_empty()) || srv_host.is_some() {
+                return Err(ConfigError::new("--blackhole can't be used together with a remote address or --srv-host"));
+            }
+        }
+
+        let upstream_proxy: Option<UpstreamProxy> = optional(matches, "upstream_proxy", parse_arg)?;
+        // The stand-in server listens on loopback, which the forward proxy can't reach
+        if blackhole && upstream_proxy.is_some() {
+            return Err(ConfigError::new("--blackhole can't be used together with --upstream-proxy"));
+        }
+
+        let sni_routes = match matches.values_of("sni_route") {
+            Some(specs) => Some(SniRoutes::new(specs.map(parse_sni_route).collect::<Result<_, _>>()?)),
+            None => None,
+        };
+        if sni_routes.is_some() {
+            // These need to see the MongoDb messages, which are encrypted in the passthrough
+            for option in &["strict_handshake", "reject_legacy_opcodes", "deny_commands", "allow_commands",
+                    "rewrite_hello", "annotate_client", "pool_upstream", "blackhole"] {
+                if matches.occurrences_of(option) > 0 {
+                    return Err(ConfigError::new(format!("--sni-route can't be used together with --{}", option.replace("_", "-"))));
+                }
+            }
+        }
+
+        let enable_metrics_reset = matches.occurrences_of("enable_metrics_reset") > 0;
+        if enable_metrics_reset && matches.value_of("admin_auth").is_none() {
+            return Err(ConfigError::new("--enable-metrics-reset needs --admin-auth"));
+        }
+
+        let wait_for_upstream = optional(matches, "wait_for_upstream", parse_positive)?.map(Duration::from_secs);
+        if wait_for_upstream.is_some() && srv_host.is_none() && proxies.iter().all(|p| p.upstream.is_empty()) {
+            return Err(ConfigError::new("--wait-for-upstream needs a static upstream"));
+        }
+
+        let max_message_size = parse_max_message_size(matches)?;
+
+        let tracker_overflow: TrackerOverflow = parse_arg(matches, "tracker_overflow", TRACKER_OVERFLOW)?;
+        let tracking_mode: TrackingMode = parse_arg(matches, "tracking_mode", TRACKING_MODE)?;
+        if tracking_mode == TrackingMode::Reliable && tracker_overflow != TrackerOverflow::Block {
+            return Err(ConfigError::new("--tracking-mode reliable needs --tracker-overflow block"));
+        }
+        let pool_upstream = matches.occurrences_of("pool_upstream") > 0;
+        // Reuse relies on the tracker seeing every message
+        if pool_upstream && tracker_overflow != TrackerOverflow::Block {
+            return Err(ConfigError::new("--pool-upstream needs --tracker-overflow block"));
+        }
+
+        let limit_mode: LimitMode = parse_arg(matches, "limit_mode", LIMIT_MODE)?;
+        let shed_idle = matches.occurrences_of("shed_idle") > 0;
+        if limit_mode == LimitMode::Pause && shed_idle {
+            return Err(ConfigError::new("--limit-mode pause can't be used together with --shed-idle"));
+        }
+
+        let buffer_size: usize = parse_positive(matches, "buffer_size", BUFFER_SIZE)?;
+        let tracker_channel_size = optional(matches, "tracker_channel_size", parse_positive)?
+            .unwrap_or_else(|| default_tracker_channel_size(buffer_size));
+
+        let documents_per_response = if matches.occurrences_of("documents_per_response") > 0 {
+            Some(parse_buckets(matches, "documents_per_response_buckets", DOCUMENTS_PER_RESPONSE_BUCKETS)?)
+        } else {
+            None
+        };
+
+        let circuit_breaker = match matches.value_of("circuit_breaker_failures") {
+            Some(_) => Some(BreakerConfig {
+                failure_threshold: parse_positive(matches, "circuit_breaker_failures", "")?,
+                window: Duration::from_secs(parse_arg(matches, "circuit_breaker_window", BREAKER_WINDOW)?),
+                cooldown: Duration::from_secs(parse_arg(matches, "circuit_breaker_cooldown", BREAKER_COOLDOWN)?),
+            }),
+            None => None,
+        };
+
+        let fault_drop_prob: f64 = parse_arg(matches, "fault_drop_prob", "0")?;
+        if !(0.0..=1.0).contains(&fault_drop_prob) {
+            return Err(ConfigError::new("Invalid --fault-drop-prob: must be between 0.0 and 1.0"));
+        }
+
+        let inject_latency = match matches.value_of("inject_latency_ms") {
+            Some(_) => Some(InjectLatency {
+                latency: Duration::from_millis(parse_arg(matches, "inject_latency_ms", "")?),
+                jitter: Duration::from_millis(parse_arg(matches, "inject_jitter_ms", "0")?),
+            }),
+            None => None,
+        };
+
+        Ok(Config {
+            proxies,
+            blackhole,
+            srv_host,
+            accept_loops: parse_positive(matches, "accept_loops", ACCEPT_LOOPS)?,
+            worker_threads: optional(matches, "worker_threads", parse_positive)?,
+
+            admin_addr,
+            admin_auth: matches.value_of("admin_auth").map(String::from),
+            admin_auth_exempt_livez: matches.occurrences_of("admin_auth_exempt_livez") > 0,
+            health_check_interval: Duration::from_secs(
+                parse_positive(matches, "health_check_interval", HEALTH_CHECK_INTERVAL)?),
+
+            log_level: matches.value_of("log_level").unwrap_or(LOG_LEVEL).to_string(),
+            log_mongo_messages: flag_or_env(matches, "log_mongo_messages", env_var(LOG_MONGO_MESSAGES_ENV)),
+            log_sample_rate: parse_positive(matches, "log_sample_rate", LOG_SAMPLE_RATE)?,
+            service_name: value_or_env(matches, "service_name", env_var(SERVICE_NAME_ENV))
+                .unwrap_or_else(|| SERVICE_NAME.to_string()),
+            enable_jaeger: flag_or_env(matches, "enable_jaeger", env_var(ENABLE_JAEGER_ENV)),
+            namespace_span_names: matches.occurrences_of("namespace_span_names") > 0,
+            jaeger_addr: value_or_env(matches, "jaeger_addr", env_var(JAEGER_ADDR_ENV))
+                .unwrap_or_else(|| JAEGER_ADDR.to_string()),
+
+            statsd_addr: matches.value_of("statsd_addr").map(String::from),
+            statsd_prefix: matches.value_of("statsd_prefix").unwrap_or(STATSD_PREFIX).to_string(),
+            otlp_metrics_endpoint: matches.value_of("otlp_metrics_endpoint").map(String::from),
+            otlp_metrics_interval: Duration::from_secs(
+                parse_positive(matches, "otlp_metrics_interval", OTLP_METRICS_INTERVAL)?),
+            k8s_pod_labels: matches.occurrences_of("k8s_pod_labels") > 0,
+            anonymize_clients: matches.occurrences_of("anonymize_clients") > 0,
+            client_hash_salt: matches.value_of("client_hash_salt").map(String::from),
+            anonymize_client_logs: matches.occurrences_of("anonymize_client_logs") > 0,
+            no_client_labels: matches.occurrences_of("no_client_labels") > 0,
+            exclude_commands: matches.value_of("exclude_commands")
+                .map(policy::parse_command_list)
+                .unwrap_or_default(),
+            use_comment_label: matches.occurrences_of("use_comment_label") > 0,
+            connect_time_by_ip: matches.occurrences_of("connect_time_by_ip") > 0,
+            command_stats: matches.occurrences_of("command_stats") > 0,
+            track_document_shape: matches.occurrences_of("track_document_shape") > 0,
+            track_collection_scans: matches.occurrences_of("track_collection_scans") > 0,
+            track_query_selectivity: matches.occurrences_of("track_query_selectivity") > 0,
+            observe_server_status: matches.occurrences_of("observe_server_status") > 0,
+            profile_locking: matches.occurrences_of("profile_locking") > 0,
+            documents_per_response,
+            slow_query: optional(matches, "slow_query_ms", parse_arg)?.map(Duration::from_millis),
+            enable_profiling: matches.occurrences_of("enable_profiling") > 0,
+            enable_metrics_reset,
+
+            connect_timeout: Duration::from_secs(parse_positive(matches, "connect_timeout", CONNECT_TIMEOUT)?),
+            wait_for_upstream,
+            tcp_keepalive: optional(matches, "tcp_keepalive", parse_positive)?.map(Duration::from_secs),
+            nodelay: parse_arg(matches, "nodelay", NODELAY)?,
+            parse_timeout: Duration::from_secs(parse_positive(matches, "parse_timeout", PARSE_TIMEOUT)?),
+            parse_budget: optional(matches, "parse_budget_ms", parse_arg)?.map(Duration::from_millis),
+            max_message_size,
+            buffer_size,
+            tracker_channel_size,
+            tracker_overflow,
+            tracking_mode,
+            strict_handshake: matches.occurrences_of("strict_handshake") > 0,
+            reject_legacy_opcodes: matches.occurrences_of("reject_legacy_opcodes") > 0,
+            deny_commands: matches.value_of("deny_commands")
+                .map(policy::parse_command_list)
+                .unwrap_or_default(),
+            allow_commands: matches.value_of("allow_commands")
+                .map(policy::parse_command_list),
+            rewrite_hello: matches.value_of("rewrite_hello").map(String::from),
+            annotate_client: matches.occurrences_of("annotate_client") > 0,
+
+            circuit_breaker,
+            pool_upstream,
+            upstream_proxy,
+            sni_routes,
+            max_connections: optional(matches, "max_connections", parse_positive)?,
+            shed_idle,
+            limit_mode,
+            max_connections_per_client: optional(matches, "max_connections_per_client", parse_positive)?,
+            max_connection_lifetime: optional(matches, "max_connection_lifetime", parse_positive)?.map(Duration::from_secs),
+
+            inject_latency,
+            fault_drop_prob,
+        })
+    }
+
+    // The configuration as a JSON object, one setting per line. Durations are in the
+    // unit of the corresponding flag and the secrets are redacted.
+    pub fn to_json(&self) -> String {
+        let proxies: Vec<String> = self.proxies.iter()
+            .map(|p| format!("{{\"spec\": {}, \"listen\": {}, \"upstream\": {}}}",
+                json_string(&p.spec), json_string(&p.listen), json_string(&p.upstream)))
+            .collect();
+
+        let mut json = JsonObject::default();
+        json.raw("proxies", format!("[{}]", proxies.join(", ")));
+        json.bool("blackhole", self.blackhole);
+        json.opt_string("srv_host", self.srv_host.as_deref());
+        json.number("accept_loops", self.accept_loops);
+        json.opt_number("worker_threads", self.worker_threads);
+
+        json.string("admin_addr", &self.admin_addr.to_string());
+        json.opt_string("admin_auth", self.admin_auth.as_deref().map(redact_password).as_deref());
+        json.bool("admin_auth_exempt_livez", self.admin_auth_exempt_livez);
+        json.number("health_check_interval", self.health_check_interval.as_secs());
+
+        json.string("log_level", &self.log_level);
+        json.bool("log_mongo_messages", self.log_mongo_messages);
+        json.number("log_sample_rate", self.log_sample_rate);
+        json.string("service_name", &self.service_name);
+        json.bool("enable_jaeger", self.enable_jaeger);
+        json.bool("namespace_span_names", self.namespace_span_names);
+        json.string("jaeger_addr", &self.jaeger_addr);
+
+        json.opt_string("statsd_addr", self.statsd_addr.as_deref());
+        json.string("statsd_prefix", &self.statsd_prefix);
+        json.opt_string("otlp_metrics_endpoint", self.otlp_metrics_endpoint.as_deref());
+        json.number("otlp_metrics_interval", self.otlp_metrics_interval.as_secs());
+        json.bool("k8s_pod_labels", self.k8s_pod_labels);
+        json.bool("anonymize_clients", self.anonymize_clients);
+        json.opt_string("client_hash_salt", self.client_hash_salt.as_ref().map(|_| REDACTED));
+        json.bool("anonymize_client_logs", self.anonymize_client_logs);
+        json.bool("no_client_labels", self.no_client_labels);
+        json.list("exclude_commands", &self.exclude_commands);
+        json.bool("use_comment_label", self.use_comment_label);
+        json.bool("connect_time_by_ip", self.connect_time_by_ip);
+        json.bool("command_stats", self.command_stats);
+        json.bool("track_document_shape", self.track_document_shape);
+        json.bool("track_collection_scans", self.track_collection_scans);
+        json.bool("track_query_selectivity", self.track_query_selectivity);
+        json.bool("observe_server_status", self.observe_server_status);
+        json.bool("profile_locking", self.profile_locking);
+        json.raw("documents_per_response_buckets", match &self.documents_per_response {
+            Some(buckets) => format!("[{}]", buckets.iter().map(f64::to_string).collect::<Vec<_>>().join(", ")),
+            None => "null".to_string(),
+        });
+        json.opt_number("slow_query_ms", self.slow_query.map(|d| d.as_millis()));
+        json.bool("enable_profiling", self.enable_profiling);
+        json.bool("enable_metrics_reset", self.enable_metrics_reset);
+
+        json.number("connect_timeout", self.connect_timeout.as_secs());
+        json.opt_number("wait_for_upstream", self.wait_for_upstream.map(|d| d.as_secs()));
+        json.opt_number("tcp_keepalive", self.tcp_keepalive.map(|d| d.as_secs()));
+        json.string("nodelay", &self.nodelay.to_string());
+        json.number("parse_timeout", self.parse_timeout.as_secs());
+        json.opt_number("parse_budget_ms", self.parse_budget.map(|d| d.as_millis()));
+        json.number("max_message_size", self.max_message_size);
+        json.number("buffer_size", self.buffer_size);
+        json.number("tracker_channel_size", self.tracker_channel_size);
+        json.string("tracker_overflow", &self.tracker_overflow.to_string());
+        json.string("tracking_mode", &self.tracking_mode.to_string());
+        json.bool("strict_handshake", self.strict_handshake);
+        json.bool("reject_legacy_opcodes", self.reject_legacy_opcodes);
+        json.list("deny_commands", &self.deny_commands);
+        match &self.allow_commands {
+            Some(commands) => json.list("allow_commands", commands),
+            None => json.raw("allow_commands", "null".to_string()),
+        }
+        json.opt_string("rewrite_hello", self.rewrite_hello.as_deref());
+        json.bool("annotate_client", self.annotate_client);
+
+        json.opt_number("circuit_breaker_failures", self.circuit_breaker.map(|c| c.failure_threshold));
+        json.opt_number("circuit_breaker_window", self.circuit_breaker.map(|c| c.window.as_secs()));
+        json.opt_number("circuit_breaker_cooldown", self.circuit_breaker.map(|c| c.cooldown.as_secs()));
+        json.bool("pool_upstream", self.pool_upstream);
+        match &self.sni_routes {
+            Some(routes) => json.raw("sni_routes", format!("[{}]",
+                routes.specs().iter().map(|spec| json_string(spec)).collect::<Vec<_>>().join(", "))),
+            None => json.raw("sni_routes", "null".to_string()),
+        }
+        json.opt_string("upstream_proxy", self.upstream_proxy.as_ref().map(|p| p.to_string()).as_deref());
+        json.opt_number("max_connections", self.max_connections);
+        json.bool("shed_idle", self.shed_idle);
+        json.string("limit_mode", &enum_name(self.limit_mode));
+        json.opt_number("max_connections_per_client", self.max_connections_per_client);
+        json.opt_number("max_connection_lifetime", self.max_connection_lifetime.map(|d| d.as_secs()));
+
+        json.opt_number("inject_latency_ms", self.inject_latency.as_ref().map(|i| i.latency.as_millis()));
+        json.opt_number("inject_jitter_ms", self.inject_latency.as_ref().map(|i| i.jitter.as_millis()));
+        json.number("fault_drop_prob", self.fault_drop_prob);
+
+        json.finish()
+    }
+}
+
+// Parse an option or its default value
+pub fn parse_arg<T>(matches: &ArgMatches, name: &str, default: &str) -> Result<T, ConfigError>
+    where T: FromStr, T::Err: Display
+{
+    let value = matches.value_of(name).unwrap_or(default);
+    value.parse::<T>().map_err(|e|
+        ConfigError::new(format!("Invalid --{} value {}: {}", name.replace("_", "-"), value, e)))
+}
+
+// Every message has at least the header, a smaller limit would reject all of them
+pub fn parse_max_message_size(matches: &ArgMatches) -> Result<usize, ConfigError> {
+    let max_message_size: usize = parse_arg(matches, "max_message_size", &MAX_MESSAGE_SIZE.to_string())?;
+    if max_message_size < HEADER_LENGTH {
+        return Err(ConfigError::new(format!("Invalid --max-message-size: must be at least {}", HEADER_LENGTH)));
+    }
+    Ok(max_message_size)
+}
+
+// Parse a count that has to be at least 1
+fn parse_positive<T>(matches: &ArgMatches, name: &str, default: &str) -> Result<T, ConfigError>
+    where T: FromStr + Default + PartialEq, T::Err: Display
+{
+    let value: T = parse_arg(matches, name, default)?;
+    if value == T::default() {
+        return Err(ConfigError::new(format!("Invalid --{}: must be at least 1", name.replace("_", "-"))));
+    }
+    Ok(value)
+}
+
+// The tracker channel capacity for the read buffer size, so that the tracker can fall
+// about the same number of bytes behind whatever the buffer size
+pub(crate) fn default_tracker_channel_size(buffer_size: usize) -> usize {
+    (TRACKER_QUEUE_BYTES / buffer_size).max(MIN_TRACKER_CHANNEL_SIZE)
+}
+
+// Parse a comma separated list of histogram bucket bounds, in increasing order
+fn parse_buckets(matches: &ArgMatches, name: &str, default: &str) -> Result<Vec<f64>, ConfigError> {
+    let value = matches.value_of(name).unwrap_or(default);
+    let invalid = |reason: &str| ConfigError::new(format!("Invalid --{} value {}: {}", name.replace("_", "-"), value, reason));
+
+    let buckets = value.split(',')
+        .map(|bound| bound.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| invalid("expecting comma separated numbers"))?;
+    if buckets.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(invalid("the bounds must be increasing"));
+    }
+    Ok(buckets)
+}
+
+// Parse an option that has no default, None if it's not given
+fn optional<T, F>(matches: &ArgMatches, name: &str, parse: F) -> Result<Option<T>, ConfigError>
+    where F: Fn(&ArgMatches, &str, &str) -> Result<T, ConfigError>
+{
+    match matches.value_of(name) {
+        Some(_) => parse(matches, name, "").map(Some),
+        None => Ok(None),
+    }
+}
+
+// Parse the local and remote address pair from provided proxy definition
+//
+// The accepted forms are:
+//   local-port
+//   local-port:remote-host:remote-port
+//   bind-addr:local-port
+//   bind-addr:local-port:remote-host:remote-port
+//   [ipv6-bind-addr]:local-port:remote-host:remote-port
+//
+// If the spec starts with a numeric port it's the legacy form without a bind address,
+// otherwise the first field is taken as the bind address. The remote can be a comma
+// separated list of failover addresses, with IPv6 hosts in brackets.
+fn parse_proxy_addresses(proxy_def: &str) -> Result<(String,String), io::Error> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput,
+        format!("invalid proxy spec {}: {}", proxy_def, msg));
+
+    let (bind_addr, rest) = if let Some(bracketed) = proxy_def.strip_prefix('[') {
+        // IPv6 bind address, keep the brackets for binding
+        let end = bracketed.find("]:").ok_or_else(|| invalid("missing local port"))?;
+        if bracketed[..end].parse::<Ipv6Addr>().is_err() {
+            return Err(invalid("bind address is not a valid IPv6 address"));
+        }
+        (&proxy_def[..end+2], &bracketed[end+2..])
+    } else {
+        match proxy_def.find(':') {
+            Some(pos) if proxy_def[..pos].parse::<u16>().is_err() => {
+                (&proxy_def[..pos], &proxy_def[pos+1..])
+            },
+            _ => (LISTEN_BIND, proxy_def),
+        }
+    };
+
+    if bind_addr.is_empty() {
+        return Err(invalid("missing bind address"));
+    }
+
+    let (local_port, remote_hostport) = match rest.find(':') {
+        Some(pos) => (&rest[..pos], Some(&rest[pos+1..])),
+        None => (rest, None),
+    };
+
+    parse_port(local_port).map_err(|e| invalid(&format!("local port {}", e)))?;
+
+    match remote_hostport {
+        Some(remote_hostport) => {
+            for hostport in remote_hostport.split(',') {
+                validate_hostport(hostport).map_err(|e| invalid(&format!("remote address {}", e)))?;
+            }
+            Ok((format!("{}:{}", bind_addr, local_port), remote_hostport.to_string()))
+        },
+        None => Ok((format!("{}:{}", bind_addr, local_port), String::from(""))),
+    }
+}
+
+// Parse a --sni-route server.name=host:port[,...] into the server name and the upstreams
+fn parse_sni_route(spec: &str) -> Result<(String, String), ConfigError> {
+    let invalid = |msg: &str| ConfigError::new(format!("Invalid --sni-route {}: {}", spec, msg));
+
+    let pos = spec.find('=').ok_or_else(|| invalid("expecting server.name=host:port"))?;
+    let (name, upstream) = (&spec[..pos], &spec[pos+1..]);
+    if name.is_empty() {
+        return Err(invalid("missing the server name"));
+    }
+    for hostport in upstream.split(',') {
+        validate_hostport(hostport).map_err(|e| invalid(&e))?;
+    }
+    Ok((name.to_string(), upstream.to_string()))
+}
+
+// Parse the --listen and --upstream addresses into the same pair as parse_proxy_addresses.
+// The listen address can be just a port to listen on all interfaces.
+fn parse_listen_upstream(listen: &str, upstream: Option<&str>) -> Result<(String,String), io::Error> {
+    let invalid = |option: &str, msg: &str| io::Error::new(io::ErrorKind::InvalidInput,
+        format!("invalid {}: {}", option, msg));
+
+    let local_hostport = if listen.bytes().all(|b| b.is_ascii_digit()) {
+        parse_port(listen).map_err(|e| invalid("--listen", &e))?;
+        format!("{}:{}", LISTEN_BIND, listen)
+    } else {
+        validate_hostport(listen).map_err(|e| invalid("--listen", &e))?;
+        listen.to_string()
+    };
+
+    let remote_hostport = match upstream {
+        Some(upstream) => {
+            for hostport in upstream.split(',') {
+                validate_hostport(hostport).map_err(|e| invalid("--upstream", &e))?;
+            }
+            upstream.to_string()
+        },
+        None => String::new(),
+    };
+
+    Ok((local_hostport, remote_hostport))
+}
+
+// Check that the host:port has both a host and a valid port. IPv6 hosts need to be in
+// brackets, eg. [::1]:27017
+fn validate_hostport(hostport: &str) -> Result<(), String> {
+    let (host, port) = if let Some(bracketed) = hostport.strip_prefix('[') {
+        let end = bracketed.find("]:").ok_or_else(|| format!("{} is missing the port", hostport))?;
+        let host = &bracketed[..end];
+        if host.parse::<Ipv6Addr>().is_err() {
+            return Err(format!("{} is not a valid IPv6 address", host));
+        }
+        (host, &bracketed[end+2..])
+    } else {
+        let pos = hostport.rfind(':').ok_or_else(|| format!("{} is missing the port", hostport))?;
+        let host = &hostport[..pos];
+        if host.contains(':') {
+            return Err(format!("{} has to be in brackets if it's an IPv6 address", host));
+        }
+        (host, &hostport[pos+1..])
+    };
+
+    if host.is_empty() {
+        return Err(format!("{} is missing the host", hostport));
+    }
+    parse_port(port)
+}
+
+fn parse_port(port: &str) -> Result<(), String> {
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(()),
+        _ => Err(format!("{:?} is not a valid port", port)),
+    }
+}
+
+// Value of the option, or of the environment variable if the option is not given
+fn value_or_env(matches: &ArgMatches, name: &str, env_value: Option<String>) -> Option<String> {
+    match matches.value_of(name) {
+        Some(value) => Some(value.to_string()),
+        None => env_value.filter(|value| !value.is_empty()),
+    }
+}
+
+// A flag is set if it's given on the command line or the environment variable is true
+fn flag_or_env(matches: &ArgMatches, name: &str, env_value: Option<String>) -> bool {
+    matches.occurrences_of(name) > 0 || env_value.map_or(false, |value| parse_env_flag(&value))
+}
+
+fn parse_env_flag(value: &str) -> bool {
+    ["1", "true", "yes"].iter().any(|v| value.eq_ignore_ascii_case(v))
+}
+
+// Parse the admin bind address and port into a socket address
+fn parse_admin_address(bind_addr: &str, port: &str) -> Result<SocketAddr, io::Error> {
+    let ip = bind_addr.parse::<IpAddr>().map_err(|e|
+        io::Error::new(io::ErrorKind::InvalidInput, format!("bad bind address: {}", e)))?;
+    let port = port.parse::<u16>().map_err(|e|
+        io::Error::new(io::ErrorKind::InvalidInput, format!("bad port: {}", e)))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+// Keep the user name of a user:password
+fn redact_password(auth: &str) -> String {
+    match auth.find(':') {
+        Some(pos) => format!("{}:{}", &auth[..pos], REDACTED),
+        None => REDACTED.to_string(),
+    }
+}
+
+// The enum values as they are given on the command line
+fn enum_name<T: std::fmt::Debug>(value: T) -> String {
+    format!("{:?}", value).to_lowercase()
+}
+
+#[derive(Default)]
+struct JsonObject {
+    fields: Vec<String>,
+}
+
+impl JsonObject {
+    fn raw(&mut self, key: &str, value: String) {
+        self.fields.push(format!("  {}: {}", json_string(key), value));
+    }
+
+    fn string(&mut self, key: &str, value: &str) {
+        self.raw(key, json_string(value));
+    }
+
+    fn opt_string(&mut self, key: &str, value: Option<&str>) {
+        self.raw(key, value.map(json_string).unwrap_or_else(|| "null".to_string()));
+    }
+
+    fn number<T: Display>(&mut self, key: &str, value: T) {
+        self.raw(key, value.to_string());
+    }
+
+    fn opt_number<T: Display>(&mut self, key: &str, value: Option<T>) {
+        self.raw(key, value.map(|v| v.to_string()).unwrap_or_else(|| "null".to_string()));
+    }
+
+    fn bool(&mut self, key: &str, value: bool) {
+        self.raw(key, value.to_string());
+    }
+
+    // Sorted, so that the output is the same on every run
+    fn list(&mut self, key: &str, values: &HashSet<String>) {
+        let mut values: Vec<String> = values.iter().map(|v| json_string(v)).collect();
+        values.sort();
+        self.raw(key, format!("[{}]", values.join(", ")));
+    }
+
+    fn finish(self) -> String {
+        format!("{{\n{}\n}}", self.fields.join(",\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli;
+
+    fn from_args(args: &[&str]) -> Result<Config, ConfigError> {
+        from_args_and_env(args, &[])
+    }
+
+    // The environment is passed in rather than set, as the tests run in parallel
+    fn from_args_and_env(args: &[&str], env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let matches = cli::get_matches_from_safe(std::iter::once("mongoproxy").chain(args.iter().cloned()))
+            .map_err(|e| ConfigError::new(e.message))?;
+        Config::from_args_and_env(&matches, |name| {
+            env.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn test_from_args() {
+        let config = from_args(&["--proxy", "27113:localhost:27017", "--proxy", "27114"]).unwrap();
+        assert_eq!(vec![
+            ProxyConfig { spec: "27113:localhost:27017".to_string(), listen: "0.0.0.0:27113".to_string(), upstream: "localhost:27017".to_string() },
+            ProxyConfig { spec: "27114".to_string(), listen: "0.0.0.0:27114".to_string(), upstream: "".to_string() },
+        ], config.proxies);
+        assert_eq!("0.0.0.0:9898".parse::<SocketAddr>().unwrap(), config.admin_addr);
+        assert_eq!(Duration::from_secs(5), config.connect_timeout);
+        assert_eq!(1, config.accept_loops);
+        assert_eq!(None, config.max_connections);
+        assert_eq!(LimitMode::Reject, config.limit_mode);
+        assert_eq!(TrackerOverflow::Block, config.tracker_overflow);
+        assert_eq!(1024, config.buffer_size);
+        assert_eq!(32, config.tracker_channel_size);
+        assert_eq!(NoDelay { client: true, server: true }, config.nodelay);
+        assert_eq!(None, config.documents_per_response);
+        assert_eq!(None, config.upstream_proxy);
+        assert!(!config.enable_metrics_reset);
+    }
+
+    #[test]
+    fn test_listen_upstream() {
+        let config = from_args(&["--listen", "127.0.0.1:27113", "--upstream", "a:27017,b:27017"]).unwrap();
+        assert_eq!("127.0.0.1:27113:a:27017,b:27017", config.proxies[0].spec);
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let config = from_args(&["--proxy", "27113", "--max-connections", "10", "--limit-mode", "pause"]).unwrap();
+        assert_eq!(Some(10), config.max_connections);
+        assert_eq!(LimitMode::Pause, config.limit_mode);
+    }
+
+    #[test]
+    fn test_documents_per_response() {
+        let config = from_args(&["--proxy", "27113", "--documents-per-response"]).unwrap();
+        assert_eq!(Some(vec![1.0, 10.0, 100.0, 1000.0, 10000.0, 100_000.0]), config.documents_per_response);
+        let config = from_args(&["--proxy", "27113", "--documents-per-response", "--documents-per-response-buckets", "1, 50,500"]).unwrap();
+        assert_eq!(Some(vec![1.0, 50.0, 500.0]), config.documents_per_response);
+    }
+
+    #[test]
+    fn test_tracker_channel_size() {
+        // The channel follows the buffer size unless it's given
+        let config = from_args(&["--proxy", "27113", "--buffer-size", "256"]).unwrap();
+        assert_eq!(128, config.tracker_channel_size);
+        let config = from_args(&["--proxy", "27113", "--buffer-size", "65536"]).unwrap();
+        assert_eq!(4, config.tracker_channel_size);
+        let config = from_args(&["--proxy", "27113", "--buffer-size", "65536", "--tracker-channel-size", "16"]).unwrap();
+        assert_eq!(16, config.tracker_channel_size);
+    }
+
+    #[test]
+    fn test_upstream_proxy() {
+        let config = from_args(&["--proxy", "27113:mongo:27017", "--upstream-proxy", "socks5://proxy:1080"]).unwrap();
+        assert_eq!(Some(UpstreamProxy::Socks5("proxy:1080".to_string())), config.upstream_proxy);
+    }
+
+    #[test]
+    fn test_wait_for_upstream() {
+        let config = from_args(&["--proxy", "27113:mongo:27017", "--proxy", "27114", "--wait-for-upstream", "60"]).unwrap();
+        assert_eq!(Some(Duration::from_secs(60)), config.wait_for_upstream);
+    }
+
+    #[test]
+    fn test_sni_routes() {
+        let config = from_args(&["--proxy", "27113", "--sni-route", "a.example.com=mongo-a:27017",
+            "--sni-route", "b.example.com=mongo-b:27017,[::1]:27017"]).unwrap();
+        let routes = config.sni_routes.unwrap();
+        assert_eq!(Some("mongo-a:27017"), routes.upstream("a.example.com"));
+        assert_eq!(Some("mongo-b:27017,[::1]:27017"), routes.upstream("b.example.com"));
+    }
+
+    #[test]
+    fn test_nodelay() {
+        let config = from_args(&["--proxy", "27113", "--nodelay", "client"]).unwrap();
+        assert_eq!(NoDelay { client: true, server: false }, config.nodelay);
+        let config = from_args(&["--proxy", "27113", "--nodelay", "none"]).unwrap();
+        assert_eq!(NoDelay { client: false, server: false }, config.nodelay);
+        assert!(config.to_json().contains("\n  \"nodelay\": \"none\",\n"));
+    }
+
+    #[test]
+    fn test_enable_metrics_reset() {
+        let config = from_args(&["--proxy", "27113", "--admin-auth", "admin:secret", "--enable-metrics-reset"]).unwrap();
+        assert!(config.enable_metrics_reset);
+    }
+
+    #[test]
+    fn test_tracking_mode() {
+        let config = from_args(&["--proxy", "27113"]).unwrap();
+        assert_eq!(TrackingMode::BestEffort, config.tracking_mode);
+
+        let config = from_args(&["--proxy", "27113", "--tracking-mode", "reliable"]).unwrap();
+        assert_eq!(TrackingMode::Reliable, config.tracking_mode);
+        assert!(config.to_json().contains("\n  \"tracking_mode\": \"reliable\",\n"));
+
+        assert!(from_args(&["--proxy", "27113", "--tracking-mode", "complete"]).is_err());
+        assert!(from_args(&["--proxy", "27113", "--tracking-mode", "reliable", "--tracker-overflow", "drop-oldest"]).is_err());
+    }
+
+    #[test]
+    fn test_srv_host() {
+        // The lookup is left for startup
+        let config = from_args(&["--proxy", "27113", "--srv-host", "_mongodb._tcp.cluster0.example.com"]).unwrap();
+        assert_eq!(Some("_mongodb._tcp.cluster0.example.com".to_string()), config.srv_host);
+        assert_eq!("", config.proxies[0].upstream);
+
+        let config = from_args(&["--proxy", "27113", "--srv-host", "_mongodb._tcp.cluster0.example.com",
+            "--wait-for-upstream", "60"]).unwrap();
+        assert_eq!(Some(Duration::from_secs(60)), config.wait_for_upstream);
+    }
+
+    #[test]
+    fn test_from_args_errors() {
+        let errors: &[&[&str]] = &[
+            &["--proxy", "27113:localhost"],
+            &["--listen", "27113", "--upstream", "localhost"],
+            &["--proxy", "27113", "--admin-port", "99999"],
+            &["--proxy", "27113", "--accept-loops", "0"],
+            &["--proxy", "27113", "--connect-timeout", "soon"],
+            &["--proxy", "27113", "--connect-timeout", "0"],
+            &["--proxy", "27113", "--parse-timeout", "0"],
+            &["--proxy", "27113", "--max-message-size", "0"],
+            &["--proxy", "27113", "--max-message-size", "15"],
+            &["--proxy", "27113", "--health-check-interval", "0"],
+            &["--proxy", "27113", "--max-connections", "0"],
+            &["--proxy", "27113", "--max-connections", "10", "--limit-mode", "pause", "--shed-idle"],
+            &["--proxy", "27113", "--limit-mode", "wait"],
+            &["--proxy", "27113", "--pool-upstream", "--tracker-overflow", "drop-oldest"],
+            &["--proxy", "27113", "--tracker-overflow", "drop"],
+            &["--proxy", "27113", "--fault-drop-prob", "1.5"],
+            &["--proxy", "27113", "--proxy", "27114", "--srv-host", "mongo.example.com"],
+            &["--proxy", "27113:localhost:27017", "--srv-host", "mongo.example.com"],
+            &["--proxy", "27113", "--documents-per-response", "--documents-per-response-buckets", "1,many"],
+            &["--proxy", "27113", "--documents-per-response", "--documents-per-response-buckets", "10,1"],
+            &["--proxy", "27113", "--buffer-size", "0"],
+            &["--proxy", "27113", "--tracker-channel-size", "0"],
+            &["--proxy", "27113", "--upstream-proxy", "proxy:1080"],
+            &["--proxy", "27113", "--wait-for-upstream", "60"],
+            &["--proxy", "27113", "--sni-route", "a.example.com"],
+            &["--proxy", "27113", "--sni-route", "=mongo-a:27017"],
+            &["--proxy", "27113", "--sni-route", "a.example.com=mongo-a"],
+            &["--proxy", "27113", "--sni-route", "a.example.com=mongo-a:27017", "--pool-upstream"],
+            &["--proxy", "27113", "--tcp-keepalive", "0"],
+            &["--proxy", "27113", "--nodelay", "both"],
+            &["--proxy", "27113", "--nodelay", "client,none"],
+            &["--proxy", "27113", "--enable-metrics-reset"],
+            &["--proxy", "27113:mongo:27017", "--wait-for-upstream", "0"],
+        ];
+        for args in errors {
+            assert!(from_args(args).is_err(), "{:?}", args);
+        }
+
+        assert_eq!(ConfigError::new("Invalid --accept-loops: must be at least 1"),
+            from_args(&["--proxy", "27113", "--accept-loops", "0"]).unwrap_err());
+        assert_eq!(ConfigError::new("Invalid --connect-timeout: must be at least 1"),
+            from_args(&["--proxy", "27113", "--connect-timeout", "0"]).unwrap_err());
+        assert_eq!(ConfigError::new("Invalid --parse-timeout: must be at least 1"),
+            from_args(&["--proxy", "27113", "--parse-timeout", "0"]).unwrap_err());
+        assert_eq!(ConfigError::new("Invalid --max-message-size: must be at least 16"),
+            from_args(&["--proxy", "27113", "--max-message-size", "15"]).unwrap_err());
+        assert_eq!(ConfigError::new("Invalid --health-check-interval: must be at least 1"),
+            from_args(&["--proxy", "27113", "--health-check-interval", "0"]).unwrap_err());
+    }
+
+    #[test]
+    fn test_blackhole_guard() {
+        assert!(from_args(&["--proxy", "27113", "--blackhole"]).is_err());
+        assert!(from_args_and_env(&["--proxy", "27113", "--blackhole"], &[(ALLOW_BLACKHOLE_ENV, "false")]).is_err());
+
+        let env = [(ALLOW_BLACKHOLE_ENV, "true")];
+        assert!(from_args_and_env(&["--proxy", "27113", "--blackhole"], &env).unwrap().blackhole);
+        assert!(from_args_and_env(&["--proxy", "27113:localhost:27017", "--blackhole"], &env).is_err());
+        assert!(from_args_and_env(&["--proxy", "27113", "--blackhole", "--upstream-proxy", "socks5://proxy:1080"], &env).is_err());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let config = from_args(&["--proxy", "27113:localhost:27017", "--admin-auth", "admin:secret"]).unwrap();
+        let json = config.to_json();
+        assert!(json.starts_with("{\n  \"proxies\": [{\"spec\": \"27113:localhost:27017\", \"listen\": \"0.0.0.0:27113\", \"upstream\": \"localhost:27017\"}],\n"), "{}", json);
+        assert!(json.contains("\n  \"admin_auth\": \"admin:***\",\n"), "{}", json);
+        assert!(!json.contains("secret"));
+        assert!(json.contains("\n  \"connect_timeout\": 5,\n"), "{}", json);
+        assert!(json.contains("\n  \"limit_mode\": \"reject\",\n"), "{}", json);
+        assert!(json.ends_with("\n  \"fault_drop_prob\": 0\n}"), "{}", json);
+    }
+
+    #[test]
+    fn test_parse_proxy_addresses() {
+        assert_eq!(("0.0.0.0:27111".to_string(), "".to_string()),
+            parse_proxy_addresses("27111").unwrap());
+        assert_eq!(("0.0.0.0:27113".to_string(), "localhost:27017".to_string()),
+            parse_proxy_addresses("27113:localhost:27017").unwrap());
+        assert_eq!(("127.0.0.1:5000".to_string(), "".to_string()),
+            parse_proxy_addresses("127.0.0.1:5000").unwrap());
+        assert_eq!(("127.0.0.1:5000".to_string(), "remote:27017".to_string()),
+            parse_proxy_addresses("127.0.0.1:5000:remote:27017").unwrap());
+        assert_eq!(("0.0.0.0:27113".to_string(), "primary:27017,standby:27017".to_string()),
+            parse_proxy_addresses("27113:primary:27017,standby:27017").unwrap());
+        assert_eq!(("0.0.0.0:27113".to_string(), "[::1]:27017".to_string()),
+            parse_proxy_addresses("27113:[::1]:27017").unwrap());
+        assert_eq!(("0.0.0.0:27113".to_string(), "[2001:db8::1]:27017,[2001:db8::2]:27017".to_string()),
+            parse_proxy_addresses("27113:[2001:db8::1]:27017,[2001:db8::2]:27017").unwrap());
+        assert_eq!(("[::1]:27113".to_string(), "".to_string()),
+            parse_proxy_addresses("[::1]:27113").unwrap());
+        assert_eq!(("[::]:27113".to_string(), "[::1]:27017".to_string()),
+            parse_proxy_addresses("[::]:27113:[::1]:27017").unwrap());
+    }
+
+    #[test]
+    fn test_parse_invalid_proxy_addresses() {
+        for spec in &["", ":27017", "27113:", "foo::bar", "localhost:", "27113:localhost",
+                "27113:localhost:", "27113:localhost:port", "27113::27017", "27113:localhost:99999",
+                "0", "27113:localhost:0", "27113:::1:27017", "27113:[::1]", "27113:[::1]:",
+                "27113:[nothost]:27017", "27113:primary:27017,", "27113:primary:27017,standby",
+                "[::1]", "[::1]:", "[::1]27113", "[nothost]:27113", "[::1]:27113:localhost"] {
+            let e = parse_proxy_addresses(spec).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, e.kind(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_parse_listen_upstream() {
+        assert_eq!(("0.0.0.0:27113".to_string(), "".to_string()),
+            parse_listen_upstream("27113", None).unwrap());
+        assert_eq!(("127.0.0.1:27113".to_string(), "localhost:27017".to_string()),
+            parse_listen_upstream("127.0.0.1:27113", Some("localhost:27017")).unwrap());
+        assert_eq!(("[::1]:27113".to_string(), "[2001:db8::1]:27017,standby:27017".to_string()),
+            parse_listen_upstream("[::1]:27113", Some("[2001:db8::1]:27017,standby:27017")).unwrap());
+
+        for (listen, upstream) in &[("", None), ("0", None), ("::1:27113", None), ("localhost", None),
+                ("27113", Some("localhost")), ("27113", Some("primary:27017,")), ("27113", Some("::1:27017"))] {
+            let e = parse_listen_upstream(listen, *upstream).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, e.kind(), "{} {:?}", listen, upstream);
+        }
+    }
+
+    #[test]
+    fn test_parse_env_flag() {
+        assert!(parse_env_flag("1"));
+        assert!(parse_env_flag("TRUE"));
+        assert!(parse_env_flag("yes"));
+        assert!(!parse_env_flag("0"));
+        assert!(!parse_env_flag("false"));
+        assert!(!parse_env_flag(""));
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::future;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::time::{Duration,Instant};
+
+use prometheus::{Counter};
+use tokio::sync::{oneshot,Notify};
+use tokio::time;
+use tracing::{info};
+
+// How long a connection past its lifetime has to be quiet before it's closed, so that the
+// trackers have caught up with the last messages
+const RECYCLE_QUIET_TIME: Duration = Duration::from_millis(200);
+
+// How often to check whether a connection past its lifetime can be closed
+const RECYCLE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+lazy_static! {
+    static ref CONNECTIONS_SHED_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_connections_shed_total",
+            "Number of idle connections closed to make room for new ones"
+            ).unwrap();
+
+    static ref ACCEPT_PAUSED_SECONDS_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_accept_paused_seconds_total",
+            "Time spent not accepting connections because the connection limit was reached"
+            ).unwrap();
+}
+
+// What to do with new connections at the connection limit
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum LimitMode {
+    // Accept and close them right away
+    Reject,
+    // Stop accepting until a connection closes, the new ones wait in the listen backlog
+    Pause,
+}
+
+impl FromStr for LimitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(LimitMode::Reject),
+            "pause" => Ok(LimitMode::Pause),
+            _ => Err("expecting one of reject or pause".to_string()),
+        }
+    }
+}
+
+// Registry of the active connections per client IP address, for limiting the number
+// of connections that a single client can have open.
+#[derive(Clone,Debug)]
+pub struct ClientConnections {
+    max_per_client: usize,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+// An active connection in the registry, it's removed when this is dropped
+pub struct ClientConnection {
+    client: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ClientConnections {
+
+    pub fn new(max_per_client: usize) -> Self {
+        ClientConnections {
+            max_per_client,
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Register a new connection from the client, unless it already is at the limit
+    pub fn try_acquire(&self, client: IpAddr) -> Option<ClientConnection> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(client).or_insert(0);
+        if *count >= self.max_per_client {
+            return None;
+        }
+        *count += 1;
+
+        Some(ClientConnection {
+            client,
+            counts: self.counts.clone(),
+        })
+    }
+
+    pub fn active(&self, client: IpAddr) -> usize {
+        self.counts.lock().unwrap().get(&client).cloned().unwrap_or(0)
+    }
+}
+
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.client) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.client);
+            }
+        }
+    }
+}
+
+// Registry of all the active connections, for limiting the total number of connections.
+// When the limit is reached the new connection is either rejected or, with `shed_idle`,
+// the connection that has been idle the longest is closed to make room for it. A pausing
+// registry instead has the accept loop wait for a free slot with `wait_for_slot`.
+#[derive(Clone,Debug)]
+pub struct ActiveConnections {
+    max_connections: usize,
+    shed_idle: bool,
+    pause: bool,
+    // Notified when a connection is removed
+    released: Arc<Notify>,
+    // Activity timestamps are milliseconds since this
+    epoch: Instant,
+    table: Arc<Mutex<ConnectionTable>>,
+}
+
+#[derive(Debug,Default)]
+struct ConnectionTable {
+    next_id: u64,
+    connections: HashMap<u64, TrackedConnection>,
+}
+
+#[derive(Debug)]
+struct TrackedConnection {
+    last_activity: Arc<AtomicU64>,
+    shed: oneshot::Sender<()>,
+}
+
+// A connection in the registry, it's removed when this is dropped
+pub struct ActiveConnection {
+    id: u64,
+    table: Arc<Mutex<ConnectionTable>>,
+    released: Arc<Notify>,
+    activity: ConnectionActivity,
+    shed: Option<oneshot::Receiver<()>>,
+}
+
+// Handle for marking the connection active, for passing to the proxy tasks
+#[derive(Clone,Debug)]
+pub struct ConnectionActivity {
+    epoch: Instant,
+    last_activity: Arc<AtomicU64>,
+}
+
+impl ActiveConnections {
+
+    pub fn new(max_connections: usize, shed_idle: bool) -> Self {
+        ActiveConnections {
+            max_connections,
+            shed_idle,
+            pause: false,
+            released: Arc::new(Notify::new()),
+            epoch: Instant::now(),
+            table: Arc::new(Mutex::new(ConnectionTable::default())),
+        }
+    }
+
+    // Registry for `LimitMode::Pause`, shedding doesn't go with it
+    pub fn pausing(max_connections: usize) -> Self {
+        ActiveConnections {
+            pause: true,
+            ..ActiveConnections::new(max_connections, false)
+        }
+    }
+
+    // Whether the accept loop should wait for a slot before accepting
+    pub fn pauses_accept(&self) -> bool {
+        self.pause
+    }
+
+    // Wait until there is room for a new connection. The time spent waiting is counted
+    // as paused. With several accept loops another one can still take the slot first.
+    pub async fn wait_for_slot(&self) {
+        let mut paused_at: Option<Instant> = None;
+        loop {
+            if self.active() < self.max_connections {
+                if let Some(paused_at) = paused_at {
+                    ACCEPT_PAUSED_SECONDS_TOTAL.inc_by(paused_at.elapsed().as_secs_f64());
+                }
+                return;
+            }
+            if paused_at.is_none() {
+                info!("Connection limit reached, pausing accept");
+                paused_at = Some(Instant::now());
+            }
+            self.released.notified().await;
+        }
+    }
+
+    // Register a new connection. Returns None if the limit is reached and no connection
+    // was shed to make room.
+    pub fn try_acquire(&self) -> Option<ActiveConnection> {
+        let mut table = self.table.lock().unwrap();
+
+        if table.connections.len() >= self.max_connections {
+            if !self.shed_idle {
+                return None;
+            }
+            let idlest = table.connections.iter()
+                .min_by_key(|(_, c)| c.last_activity.load(Ordering::Relaxed))
+                .map(|(id, _)| *id)?;
+            let shed = table.connections.remove(&idlest).unwrap();
+            info!("Connection limit reached, closing the connection idle the longest");
+            let _ = shed.shed.send(());
+            CONNECTIONS_SHED_TOTAL.inc();
+        }
+
+        let id = table.next_id;
+        table.next_id += 1;
+
+        let activity = ConnectionActivity {
+            epoch: self.epoch,
+            last_activity: Arc::new(AtomicU64::new(0)),
+        };
+        activity.touch();
+
+        let (shed_tx, shed_rx) = oneshot::channel();
+        table.connections.insert(id, TrackedConnection {
+            last_activity: activity.last_activity.clone(),
+            shed: shed_tx,
+        });
+
+        Some(ActiveConnection {
+            id,
+            table: self.table.clone(),
+            released: self.released.clone(),
+            activity,
+            shed: Some(shed_rx),
+        })
+    }
+
+    pub fn active(&self) -> usize {
+        self.table.lock().unwrap().connections.len()
+    }
+}
+
+impl ActiveConnection {
+
+    pub fn activity(&self) -> ConnectionActivity {
+        self.activity.clone()
+    }
+
+    // The signal for closing this connection when it gets shed, can be taken once
+    pub fn take_shed_signal(&mut self) -> Option<oneshot::Receiver<()>> {
+        self.shed.take()
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.table.lock().unwrap().connections.remove(&self.id);
+        self.released.notify();
+    }
+}
+
+impl ConnectionActivity {
+
+    // Activity of a connection that isn't in the registry
+    pub fn unregistered() -> Self {
+        let activity = ConnectionActivity {
+            epoch: Instant::now(),
+            last_activity: Arc::new(AtomicU64::new(0)),
+        };
+        activity.touch();
+        activity
+    }
+
+    pub fn touch(&self) {
+        let now = self.epoch.elapsed().as_millis() as u64;
+        self.last_activity.store(now, Ordering::Relaxed);
+    }
+
+    // Time since the last bytes in either direction
+    pub fn idle_for(&self) -> Duration {
+        let now = self.epoch.elapsed().as_millis() as u64;
+        Duration::from_millis(now.saturating_sub(self.last_activity.load(Ordering::Relaxed)))
+    }
+}
+
+// Completes once the connection has been open for the lifetime and is between messages:
+// no bytes for a moment and `between_messages` says that every request has had its
+// response. Without a lifetime never completes.
+pub async fn lifetime_expired<F>(lifetime: Option<Duration>, activity: Option<ConnectionActivity>, between_messages: F)
+    where F: Fn() -> bool
+{
+    let (lifetime, activity) = match (lifetime, activity) {
+        (Some(lifetime), Some(activity)) => (lifetime, activity),
+        _ => return future::pending().await,
+    };
+
+    time::delay_for(lifetime).await;
+    while activity.idle_for() < RECYCLE_QUIET_TIME || !between_messages() {
+        time::delay_for(RECYCLE_CHECK_INTERVAL).await;
+    }
+}
+
+// Completes when the connection is shed. Without the signal never completes.
+pub async fn shed_signal(signal: Option<oneshot::Receiver<()>>) {
+    if let Some(signal) = signal {
+        if signal.await.is_ok() {
+            return;
+        }
+    }
+    future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_limit() {
+        let connections = ClientConnections::new(2);
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = connections.try_acquire(client).unwrap();
+        let _second = connections.try_acquire(client).unwrap();
+        assert!(connections.try_acquire(client).is_none());
+        assert!(connections.try_acquire(other).is_some());
+
+        drop(first);
+        assert_eq!(1, connections.active(client));
+        assert!(connections.try_acquire(client).is_some());
+    }
+
+    #[test]
+    fn test_released_clients_are_removed() {
+        let connections = ClientConnections::new(1);
+        let client: IpAddr = "::1".parse().unwrap();
+
+        drop(connections.try_acquire(client).unwrap());
+        assert_eq!(0, connections.active(client));
+        assert!(connections.counts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_total_connection_limit() {
+        let connections = ActiveConnections::new(2, false);
+
+        let first = connections.try_acquire().unwrap();
+        let _second = connections.try_acquire().unwrap();
+        assert!(connections.try_acquire().is_none());
+
+        drop(first);
+        assert_eq!(1, connections.active());
+        assert!(connections.try_acquire().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_shed_idle_connection() {
+        let connections = ActiveConnections::new(2, true);
+
+        let mut idle = connections.try_acquire().unwrap();
+        let mut busy = connections.try_acquire().unwrap();
+        idle.activity.last_activity.store(0, Ordering::Relaxed);
+        busy.activity.last_activity.store(1000, Ordering::Relaxed);
+
+        let _third = connections.try_acquire().unwrap();
+        assert_eq!(2, connections.active());
+
+        shed_signal(idle.take_shed_signal()).await;
+        assert!(busy.take_shed_signal().unwrap().try_recv().is_err());
+
+        // Dropping the shed connection does not remove the others
+        drop(idle);
+        assert_eq!(2, connections.active());
+    }
+
+    #[tokio::test]
+    async fn test_pause_at_limit() {
+        let connections = ActiveConnections::pausing(1);
+        assert!(connections.pauses_accept());
+
+        connections.wait_for_slot().await;
+        let first = connections.try_acquire().unwrap();
+
+        let waiting = connections.clone();
+        let second = tokio::spawn(async move { waiting.wait_for_slot().await });
+        tokio::task::yield_now().await;
+        drop(first);
+
+        second.await.unwrap();
+        assert!(connections.try_acquire().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_lifetime_expired() {
+        let in_flight = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let waiting = in_flight.clone();
+        let mut expired = tokio::spawn(lifetime_expired(
+            Some(Duration::from_millis(10)),
+            Some(ConnectionActivity::unregistered()),
+            move || !waiting.load(Ordering::Relaxed)));
+
+        // Past the lifetime and quiet, but a request is waiting for its response
+        assert!(time::timeout(Duration::from_millis(500), &mut expired).await.is_err());
+
+        in_flight.store(false, Ordering::Relaxed);
+        assert!(time::timeout(Duration::from_millis(500), &mut expired).await.is_ok());
+
+        let no_lifetime = lifetime_expired(None, Some(ConnectionActivity::unregistered()), || true);
+        assert!(time::timeout(Duration::from_millis(50), no_lifetime).await.is_err());
+    }
+}
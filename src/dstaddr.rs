@@ -27,29 +27,64 @@ mod linux {
     use std::{io, mem};
     use tracing::warn;
 
+    // From linux/netfilter_ipv6/ip6_tables.h, the same number as SO_ORIGINAL_DST
+    const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+    // IPv6 sockets need the ip6tables option. A dual stack socket can also have an IPv4
+    // client (a v4-mapped address) that was NATed by iptables, so those fall back to the
+    // IPv4 option.
     pub unsafe fn so_original_dst(fd: RawFd) -> io::Result<SocketAddr> {
+        let result = if socket_family(fd)? == libc::AF_INET6 {
+            original_dst(fd, libc::SOL_IPV6, IP6T_SO_ORIGINAL_DST)
+                .or_else(|_| original_dst(fd, libc::SOL_IP, libc::SO_ORIGINAL_DST))
+        } else {
+            original_dst(fd, libc::SOL_IP, libc::SO_ORIGINAL_DST)
+        };
+
+        if let Err(e) = &result {
+            warn!("failed to read SO_ORIGINAL_DST: {:?}", e);
+        }
+        result
+    }
+
+    unsafe fn original_dst(fd: RawFd, level: libc::c_int, option: libc::c_int) -> io::Result<SocketAddr> {
         let mut sockaddr: libc::sockaddr_storage = mem::zeroed();
         let mut socklen: libc::socklen_t = mem::size_of::<libc::sockaddr_storage>() as u32;
 
         let ret = libc::getsockopt(
             fd,
-            libc::SOL_IP,
-            libc::SO_ORIGINAL_DST,
+            level,
+            option,
             &mut sockaddr as *mut _ as *mut _,
             &mut socklen as *mut _ as *mut _,
         );
         if ret != 0 {
-            let e = io::Error::last_os_error();
-            warn!("failed to read SO_ORIGINAL_DST: {:?}", e);
-            return Err(e);
+            return Err(io::Error::last_os_error());
         }
 
         mk_addr(&sockaddr, socklen)
     }
 
+    // Address family of the socket, from its local address
+    unsafe fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+        let mut sockaddr: libc::sockaddr_storage = mem::zeroed();
+        let mut socklen: libc::socklen_t = mem::size_of::<libc::sockaddr_storage>() as u32;
+
+        let ret = libc::getsockname(
+            fd,
+            &mut sockaddr as *mut _ as *mut _,
+            &mut socklen as *mut _ as *mut _,
+        );
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(sockaddr.ss_family as libc::c_int)
+    }
+
     // Borrowed with love from net2-rs
     // https://github.com/rust-lang-nursery/net2-rs/blob/1b4cb4fb05fbad750b271f38221eab583b666e5e/src/socket.rs#L103
-    fn mk_addr(storage: &libc::sockaddr_storage, len: libc::socklen_t) -> io::Result<SocketAddr> {
+    pub fn mk_addr(storage: &libc::sockaddr_storage, len: libc::socklen_t) -> io::Result<SocketAddr> {
         match storage.ss_family as libc::c_int {
             libc::AF_INET => {
                 assert!(len as usize >= mem::size_of::<libc::sockaddr_in>());
@@ -114,3 +149,34 @@ mod linux {
         <u32>::from_be(i)
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::linux::mk_addr;
+    use std::mem;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_mk_addr_v4() {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let sa = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+        sa.sin_family = libc::AF_INET as libc::sa_family_t;
+        sa.sin_port = 27017u16.to_be();
+        sa.sin_addr.s_addr = u32::from_be_bytes([10, 0, 0, 1]).to_be();
+
+        let len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        assert_eq!("10.0.0.1:27017".parse::<SocketAddr>().unwrap(), mk_addr(&storage, len).unwrap());
+    }
+
+    #[test]
+    fn test_mk_addr_v6() {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let sa = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+        sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        sa.sin6_port = 27017u16.to_be();
+        sa.sin6_addr.s6_addr = "2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets();
+
+        let len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+        assert_eq!("[2001:db8::1]:27017".parse::<SocketAddr>().unwrap(), mk_addr(&storage, len).unwrap());
+    }
+}
@@ -0,0 +1,174 @@
+use std::net::{TcpStream,ToSocketAddrs};
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::time::{Duration,Instant};
+
+use tracing::{debug, warn};
+
+// How long to wait for the upstream to accept a probe connection
+const PROBE_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Connects to the comma separated upstreams, returns true if one of them accepts
+pub type Probe = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
+// Readiness check that probes the upstream with a TCP connect. The result is cached
+// for `cache_interval` so that frequent probing doesn't turn into a connect storm.
+//
+// With failover upstreams it's enough that one of them accepts connections. Without
+// a static upstream (original destination mode) there is nothing to probe and the
+// check always succeeds.
+pub struct UpstreamHealth {
+    upstream: Option<String>,
+    cache_interval: Duration,
+    last_check: Mutex<Option<(Instant, bool)>>,
+    startup_check: StartupCheck,
+    probe: Probe,
+}
+
+// Set once the startup self-test of --wait-for-upstream has passed, the readiness
+// check fails until then
+#[derive(Clone,Debug)]
+pub struct StartupCheck(Arc<AtomicBool>);
+
+impl StartupCheck {
+    pub fn passed(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn has_passed(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl UpstreamHealth {
+
+    pub fn new(upstream: Option<String>, cache_interval: Duration) -> Self {
+        UpstreamHealth {
+            upstream,
+            cache_interval,
+            last_check: Mutex::new(None),
+            startup_check: StartupCheck(Arc::new(AtomicBool::new(true))),
+            probe: Box::new(|upstream| upstream.split(',').any(probe_upstream)),
+        }
+    }
+
+    // Probe the upstream with something else than a direct TCP connect, eg. to go
+    // through the same path as the proxied connections
+    pub fn with_probe(mut self, probe: Probe) -> Self {
+        self.probe = probe;
+        self
+    }
+
+    // Not ready until `passed` is called on the returned check
+    pub fn with_startup_check(mut self) -> (Self, StartupCheck) {
+        let check = StartupCheck(Arc::new(AtomicBool::new(false)));
+        self.startup_check = check.clone();
+        (self, check)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        if !self.startup_check.has_passed() {
+            return false;
+        }
+
+        let upstream = match &self.upstream {
+            Some(upstream) => upstream,
+            None => return true,
+        };
+
+        // Hold the lock for the duration of the probe so that concurrent requests
+        // wait for the result instead of probing on their own.
+        let mut last_check = self.last_check.lock().unwrap();
+
+        if let Some((checked_at, result)) = *last_check {
+            if checked_at.elapsed() < self.cache_interval {
+                return result;
+            }
+        }
+
+        let result = (self.probe)(upstream);
+        *last_check = Some((Instant::now(), result));
+        result
+    }
+}
+
+// Return true if any of the addresses the upstream resolves to accepts a connection
+fn probe_upstream(upstream: &str) -> bool {
+    let addrs = match upstream.to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            warn!("Health check failed to resolve {}: {}", upstream, e);
+            return false;
+        },
+    };
+
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, PROBE_CONNECT_TIMEOUT) {
+            Ok(_) => {
+                debug!("Health check connected to {} ({})", upstream, addr);
+                return true;
+            },
+            Err(e) => {
+                warn!("Health check failed to connect to {} ({}): {}", upstream, addr, e);
+            },
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_no_upstream_is_ready() {
+        let health = UpstreamHealth::new(None, Duration::from_secs(5));
+        assert!(health.is_ready());
+    }
+
+    #[test]
+    fn test_upstream_reachability() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let health = UpstreamHealth::new(Some(addr), Duration::from_secs(0));
+        assert!(health.is_ready());
+
+        drop(listener);
+        assert!(!health.is_ready());
+    }
+
+    #[test]
+    fn test_startup_check() {
+        let (health, check) = UpstreamHealth::new(None, Duration::from_secs(5)).with_startup_check();
+        assert!(!health.is_ready());
+
+        check.passed();
+        assert!(health.is_ready());
+    }
+
+    #[test]
+    fn test_with_probe() {
+        let health = UpstreamHealth::new(Some("mongo-a:27017,mongo-b:27017".to_string()), Duration::from_secs(0))
+            .with_probe(Box::new(|upstream| upstream == "mongo-a:27017,mongo-b:27017"));
+        assert!(health.is_ready());
+
+        let health = UpstreamHealth::new(Some("mongo-a:27017".to_string()), Duration::from_secs(0))
+            .with_probe(Box::new(|_| false));
+        assert!(!health.is_ready());
+    }
+
+    #[test]
+    fn test_result_is_cached() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let health = UpstreamHealth::new(Some(addr), Duration::from_secs(3600));
+        assert!(health.is_ready());
+
+        drop(listener);
+        assert!(health.is_ready());
+    }
+}
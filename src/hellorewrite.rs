@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+
+use bson::{Bson, Document};
+use byteorder::{ByteOrder, LittleEndian};
+use prometheus::{Counter};
+use tracing::{debug};
+
+use crate::mongodb::{HEADER_LENGTH, MSG_FLAG_CHECKSUM_PRESENT};
+
+// Length of the OP_REPLY fields between the header and the documents
+const OP_REPLY_PREFIX_LENGTH: usize = 20;
+
+// Array fields of the hello response that list replica set members
+const MEMBER_LIST_FIELDS: [&str; 3] = ["hosts", "passives", "arbiters"];
+
+// String fields of the hello response with a single member address
+const MEMBER_FIELDS: [&str; 2] = ["primary", "me"];
+
+lazy_static! {
+    static ref HELLO_REWRITES_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_hello_rewrites_total",
+            "Number of hello/isMaster responses rewritten to advertise the proxy"
+            ).unwrap();
+}
+
+// Rewrites the replica set member addresses in the hello/isMaster responses to the
+// addresses that the proxy is reachable at, so that the clients keep connecting
+// through the proxy instead of going straight to the members.
+//
+// This and --annotate-client are the only places where the proxy modifies the bytes
+// that pass through it.
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct HelloRewriter {
+    // Address to advertise for the members that have no mapping of their own
+    default: Option<String>,
+    // Member address to the proxy address that forwards to it
+    members: HashMap<String, String>,
+}
+
+impl HelloRewriter {
+
+    // Parse the rewrite spec: comma separated "member=advertised" mappings and
+    // optionally a bare address to advertise for all the other members.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut rewriter = HelloRewriter::default();
+
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.split('=').collect::<Vec<_>>()[..] {
+                [member, advertised] if !member.is_empty() && !advertised.is_empty() => {
+                    rewriter.members.insert(member.to_string(), advertised.to_string());
+                },
+                [advertised] => {
+                    if rewriter.default.is_some() {
+                        return Err("only one default address can be given".to_string());
+                    }
+                    rewriter.default = Some(advertised.to_string());
+                },
+                _ => return Err(format!("invalid mapping: {}", entry)),
+            }
+        }
+
+        if rewriter.default.is_none() && rewriter.members.is_empty() {
+            return Err("no addresses given".to_string());
+        }
+
+        Ok(rewriter)
+    }
+
+    fn advertised<'a>(&'a self, member: &'a str) -> &'a str {
+        self.members.get(member)
+            .or_else(|| self.default.as_ref())
+            .map(|s| s.as_str())
+            .unwrap_or(member)
+    }
+
+    // Return the message with the member addresses rewritten if it is a hello response.
+    // Messages that are not hello responses, or can't be rewritten, give None and should
+    // be forwarded as they are.
+    pub fn rewrite(&self, message: &[u8]) -> Option<Vec<u8>> {
+        if message.len() < HEADER_LENGTH {
+            return None;
+        }
+
+        let op_code = LittleEndian::read_u32(&message[12..16]);
+        let body = &message[HEADER_LENGTH..];
+
+        let new_body = match op_code {
+            2013 => self.rewrite_op_msg(body)?,
+            1 => self.rewrite_op_reply(body)?,
+            _ => return None,
+        };
+
+        let mut buf = Vec::with_capacity(HEADER_LENGTH + new_body.len());
+        buf.extend_from_slice(&message[..HEADER_LENGTH]);
+        buf.extend(new_body);
+        let message_length = buf.len() as u32;
+        LittleEndian::write_u32(&mut buf[..4], message_length);
+
+        HELLO_REWRITES_TOTAL.inc();
+        Some(buf)
+    }
+
+    // Only a single kind 0 section is rewritten, that's what hello responses look
+    // like. The checksum would no longer match, so it is dropped.
+    fn rewrite_op_msg(&self, body: &[u8]) -> Option<Vec<u8>> {
+        if body.len() < 5 {
+            return None;
+        }
+
+        let mut flag_bits = LittleEndian::read_u32(&body[..4]);
+        let mut sections = &body[4..];
+        if flag_bits & MSG_FLAG_CHECKSUM_PRESENT != 0 {
+            sections = &sections[..sections.len().checked_sub(4)?];
+            flag_bits &= !MSG_FLAG_CHECKSUM_PRESENT;
+        }
+
+        if sections.first() != Some(&0) {
+            return None;
+        }
+        let doc = self.rewrite_document(&sections[1..])?;
+
+        let mut buf = Vec::with_capacity(5 + doc.len());
+        buf.extend_from_slice(&flag_bits.to_le_bytes());
+        buf.push(0);
+        buf.extend(doc);
+        Some(buf)
+    }
+
+    // The legacy isMaster response is an OP_REPLY with a single document
+    fn rewrite_op_reply(&self, body: &[u8]) -> Option<Vec<u8>> {
+        if body.len() < OP_REPLY_PREFIX_LENGTH || LittleEndian::read_i32(&body[16..20]) != 1 {
+            return None;
+        }
+        let doc = self.rewrite_document(&body[OP_REPLY_PREFIX_LENGTH..])?;
+
+        let mut buf = Vec::with_capacity(OP_REPLY_PREFIX_LENGTH + doc.len());
+        buf.extend_from_slice(&body[..OP_REPLY_PREFIX_LENGTH]);
+        buf.extend(doc);
+        Some(buf)
+    }
+
+    // Rewrite the serialized document, which must take up all of `buf`
+    fn rewrite_document(&self, buf: &[u8]) -> Option<Vec<u8>> {
+        if buf.len() < 4 || LittleEndian::read_i32(&buf[..4]) as usize != buf.len() {
+            return None;
+        }
+
+        let mut doc = Document::from_reader(&mut &buf[..]).ok()?;
+        if !self.rewrite_hello(&mut doc) {
+            return None;
+        }
+
+        let mut new_buf = Vec::new();
+        match doc.to_writer(&mut new_buf) {
+            Ok(_) => Some(new_buf),
+            Err(e) => {
+                debug!("Failed to serialize the rewritten hello response: {}", e);
+                None
+            },
+        }
+    }
+
+    // Rewrite the member addresses in the hello response. Returns false if the document
+    // is not a hello response from a replica set member or a mongos.
+    fn rewrite_hello(&self, doc: &mut Document) -> bool {
+        if !doc.contains_key("maxWireVersion") {
+            return false;
+        }
+
+        let mut is_hello = false;
+
+        for field in MEMBER_LIST_FIELDS.iter() {
+            if let Some(Bson::Array(members)) = doc.get_mut(field) {
+                let mut rewritten: Vec<Bson> = Vec::with_capacity(members.len());
+                for member in members.iter() {
+                    let member = match member {
+                        Bson::String(addr) => Bson::String(self.advertised(addr).to_string()),
+                        other => other.clone(),
+                    };
+                    // Several members can be behind the same proxy address
+                    if !rewritten.contains(&member) {
+                        rewritten.push(member);
+                    }
+                }
+                *members = rewritten;
+                is_hello = true;
+            }
+        }
+
+        for field in MEMBER_FIELDS.iter() {
+            if let Some(Bson::String(addr)) = doc.get_mut(field) {
+                *addr = self.advertised(addr).to_string();
+                is_hello = true;
+            }
+        }
+
+        is_hello
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+
+    fn message(op_code: u32, body: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; HEADER_LENGTH];
+        LittleEndian::write_u32(&mut buf[..4], (HEADER_LENGTH + body.len()) as u32);
+        LittleEndian::write_u32(&mut buf[4..8], 7);
+        LittleEndian::write_u32(&mut buf[8..12], 42);
+        LittleEndian::write_u32(&mut buf[12..16], op_code);
+        buf.extend_from_slice(body);
+        buf
+    }
+
+    fn op_msg(flag_bits: u32, doc: &Document) -> Vec<u8> {
+        let mut body = flag_bits.to_le_bytes().to_vec();
+        body.push(0);
+        doc.to_writer(&mut body).unwrap();
+        if flag_bits & MSG_FLAG_CHECKSUM_PRESENT != 0 {
+            body.extend_from_slice(&[1, 2, 3, 4]);
+        }
+        message(2013, &body)
+    }
+
+    fn hello() -> Document {
+        doc! {
+            "isWritablePrimary": true,
+            "setName": "rs0",
+            "hosts": ["mongo1:27017", "mongo2:27017", "mongo3:27017"],
+            "arbiters": ["arbiter:27017"],
+            "primary": "mongo1:27017",
+            "me": "mongo1:27017",
+            "maxWireVersion": 9,
+            "ok": 1.0,
+        }
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        let rewriter = HelloRewriter::parse("proxy:27017, mongo1:27017=proxy1:27017").unwrap();
+        assert_eq!("proxy1:27017", rewriter.advertised("mongo1:27017"));
+        assert_eq!("proxy:27017", rewriter.advertised("mongo2:27017"));
+
+        let rewriter = HelloRewriter::parse("mongo1:27017=proxy1:27017").unwrap();
+        assert_eq!("mongo2:27017", rewriter.advertised("mongo2:27017"));
+
+        assert!(HelloRewriter::parse("").is_err());
+        assert!(HelloRewriter::parse("proxy1:27017,proxy2:27017").is_err());
+        assert!(HelloRewriter::parse("mongo1:27017=").is_err());
+        assert!(HelloRewriter::parse("a=b=c").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_op_msg() {
+        let rewriter = HelloRewriter::parse("my-proxy.example.com:27017").unwrap();
+        let buf = rewriter.rewrite(&op_msg(MSG_FLAG_CHECKSUM_PRESENT, &hello())).unwrap();
+
+        // Header is kept, apart from the length. The checksum is gone.
+        assert_eq!(buf.len() as u32, LittleEndian::read_u32(&buf[..4]));
+        assert_eq!(7, LittleEndian::read_u32(&buf[4..8]));
+        assert_eq!(42, LittleEndian::read_u32(&buf[8..12]));
+        assert_eq!(0, LittleEndian::read_u32(&buf[HEADER_LENGTH..HEADER_LENGTH+4]));
+
+        let doc = Document::from_reader(&mut &buf[HEADER_LENGTH+5..]).unwrap();
+        assert_eq!(doc! {
+            "isWritablePrimary": true,
+            "setName": "rs0",
+            "hosts": ["my-proxy.example.com:27017"],
+            "arbiters": ["my-proxy.example.com:27017"],
+            "primary": "my-proxy.example.com:27017",
+            "me": "my-proxy.example.com:27017",
+            "maxWireVersion": 9,
+            "ok": 1.0,
+        }, doc);
+    }
+
+    #[test]
+    fn test_rewrite_op_reply() {
+        let rewriter = HelloRewriter::parse("mongo1:27017=proxy1:27017,mongo2:27017=proxy2:27017").unwrap();
+
+        let mut body = vec![0; OP_REPLY_PREFIX_LENGTH];
+        LittleEndian::write_i32(&mut body[16..20], 1);
+        hello().to_writer(&mut body).unwrap();
+
+        let buf = rewriter.rewrite(&message(1, &body)).unwrap();
+        assert_eq!(buf.len() as u32, LittleEndian::read_u32(&buf[..4]));
+
+        let doc = Document::from_reader(&mut &buf[HEADER_LENGTH+OP_REPLY_PREFIX_LENGTH..]).unwrap();
+        let hosts: Vec<&str> = doc.get_array("hosts").unwrap().iter().filter_map(|h| h.as_str()).collect();
+        assert_eq!(vec!["proxy1:27017", "proxy2:27017", "mongo3:27017"], hosts);
+        assert_eq!("proxy1:27017", doc.get_str("me").unwrap());
+    }
+
+    #[test]
+    fn test_other_messages_are_not_rewritten() {
+        let rewriter = HelloRewriter::parse("proxy:27017").unwrap();
+
+        let find_reply = doc! { "cursor": { "firstBatch": [], "id": 0i64 }, "ok": 1.0 };
+        assert!(rewriter.rewrite(&op_msg(0, &find_reply)).is_none());
+
+        // Standalone server, no member addresses
+        let standalone = doc! { "isWritablePrimary": true, "maxWireVersion": 9, "ok": 1.0 };
+        assert!(rewriter.rewrite(&op_msg(0, &standalone)).is_none());
+
+        // A document sequence after the body document
+        let mut buf = op_msg(0, &hello());
+        buf.extend_from_slice(&[1, 5, 0, 0, 0]);
+        LittleEndian::write_u32(&mut buf[..4], buf.len() as u32);
+        assert!(rewriter.rewrite(&buf).is_none());
+
+        assert!(rewriter.rewrite(&message(2004, &[])).is_none());
+    }
+}
@@ -1,8 +1,10 @@
 use std::thread;
 use std::collections::HashMap;
-use std::net::{SocketAddr};
+use std::net::{SocketAddr,ToSocketAddrs};
+use std::time::{Duration,Instant};
 
 use tracing::{warn,info,debug};
+use prometheus::{Counter,Gauge};
 
 use rustracing::{self,sampler::AllSampler,span::SpanContext,carrier::ExtractFromTextMap};
 use rustracing_jaeger::{reporter::JaegerCompactReporter};
@@ -11,9 +13,36 @@ pub use rustracing_jaeger::{Tracer};
 
 pub const TRACE_ID_PREFIX: &str = "uber-trace-id";
 
+// How often to look up the agent address again, in case it has moved
+const AGENT_RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// After a failed send the agent is looked up again after this long, doubling while the
+// sends keep failing, up to AGENT_RESOLVE_INTERVAL
+const AGENT_RESOLVE_BACKOFF: Duration = Duration::from_secs(1);
+
+// How often the queue depth gauge is updated
+const QUEUE_DEPTH_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref JAEGER_SEND_ERRORS_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_jaeger_send_errors_total",
+            "Number of spans that failed to be sent to the Jaeger agent"
+            ).unwrap();
+
+    static ref TRACING_QUEUE_DEPTH: Gauge =
+        register_gauge!(
+            "mongoproxy_tracing_queue_depth",
+            "Number of finished spans waiting to be sent to the Jaeger agent"
+            ).unwrap();
+}
+
 // Initialize the tracer and start the thread that writes the spans to Jaeger.
 // The tracer then needs to be cloned and passed to each thread.
-pub fn init_tracer(enable_tracer: bool, service_name: &str, jaeger_addr: SocketAddr) -> Option<Tracer> {
+//
+// The agent host name is resolved periodically and after send failures, so that
+// we follow the agent when its address changes.
+pub fn init_tracer(enable_tracer: bool, service_name: &str, jaeger_addr: &str) -> Option<Tracer> {
     if !enable_tracer {
         info!("Tracing not enabled.");
         return None;
@@ -24,19 +53,40 @@ pub fn init_tracer(enable_tracer: bool, service_name: &str, jaeger_addr: SocketA
 
     let (span_tx, span_rx) = crossbeam_channel::unbounded();
     let service_name = service_name.to_owned();
+    let jaeger_addr = jaeger_addr.to_owned();
 
     let mut reporter = JaegerCompactReporter::new(&service_name).unwrap();
-    reporter.set_agent_addr(jaeger_addr).unwrap();
+    let mut agent_addr = None;
+    let mut schedule = ResolveSchedule::new(Instant::now());
+
+    // The spans queue up in the channel if we can't send them as fast as they finish.
+    // Sample the depth on a timer, so that the gauge keeps moving also when the
+    // reporter is stuck.
+    let depth_rx = span_rx.clone();
+    thread::spawn(move || loop {
+        TRACING_QUEUE_DEPTH.set(depth_rx.len() as f64);
+        thread::sleep(QUEUE_DEPTH_INTERVAL);
+    });
 
     thread::spawn(move || {
-        for span in span_rx {
+        for span in span_rx.iter() {
+            if schedule.is_due(Instant::now()) {
+                update_agent_addr(&mut reporter, &jaeger_addr, &mut agent_addr);
+                schedule.resolved(Instant::now());
+            }
+
             debug!("# SPAN: {:?}", span);
             match reporter.report(&[span]) {
                 Ok(_) => {
                     debug!("Sent to collector");
+                    schedule.send_succeeded();
                 },
                 Err(e) => {
+                    JAEGER_SEND_ERRORS_TOTAL.inc();
                     warn!("Failed to report span: {}", e);
+                    // The agent might have moved, look it up again soon, but not on every
+                    // failed span as the lookup blocks the sending
+                    schedule.send_failed(Instant::now());
                 },
             }
         }
@@ -45,6 +95,73 @@ pub fn init_tracer(enable_tracer: bool, service_name: &str, jaeger_addr: SocketA
     Some(Tracer::with_sender(AllSampler, span_tx))
 }
 
+// When to look up the agent address next: every AGENT_RESOLVE_INTERVAL, and sooner
+// after failed sends, backing off while they keep failing
+struct ResolveSchedule {
+    next_resolve: Instant,
+    backoff: Duration,
+}
+
+impl ResolveSchedule {
+
+    fn new(now: Instant) -> Self {
+        ResolveSchedule {
+            next_resolve: now,
+            backoff: AGENT_RESOLVE_BACKOFF,
+        }
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_resolve
+    }
+
+    fn resolved(&mut self, now: Instant) {
+        self.next_resolve = now + AGENT_RESOLVE_INTERVAL;
+    }
+
+    fn send_succeeded(&mut self) {
+        self.backoff = AGENT_RESOLVE_BACKOFF;
+    }
+
+    // Bring the next lookup forward, unless one is already due sooner
+    fn send_failed(&mut self, now: Instant) {
+        let retry_at = now + self.backoff;
+        if retry_at < self.next_resolve {
+            self.next_resolve = retry_at;
+            self.backoff = (self.backoff * 2).min(AGENT_RESOLVE_INTERVAL);
+        }
+    }
+}
+
+// Resolve the agent address and point the reporter to it if it has changed
+fn update_agent_addr(
+    reporter: &mut JaegerCompactReporter,
+    jaeger_addr: &str,
+    agent_addr: &mut Option<SocketAddr>,
+) {
+    let resolved = match jaeger_addr.to_socket_addrs() {
+        Ok(mut addrs) => addrs.next(),
+        Err(e) => {
+            warn!("Failed to resolve Jaeger agent address {}: {}", jaeger_addr, e);
+            return;
+        },
+    };
+
+    if let Some(addr) = resolved {
+        if *agent_addr != Some(addr) {
+            match reporter.set_agent_addr(addr) {
+                Ok(_) => {
+                    info!("Sending spans to Jaeger agent at {} ({})", jaeger_addr, addr);
+                    *agent_addr = Some(addr);
+                },
+                Err(e) => {
+                    warn!("Failed to set Jaeger agent address {}: {}", addr, e);
+                },
+            }
+        }
+    }
+}
+
 // Extract the span from a text map
 //
 // This only returns Some if the span is sampled (flag bits 1 & 2 set). Otherwise
@@ -80,3 +197,36 @@ pub fn extract_from_text<T>(span_text: &str) -> rustracing::Result<Option<SpanCo
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_schedule() {
+        let start = Instant::now();
+        let mut schedule = ResolveSchedule::new(start);
+        assert!(schedule.is_due(start));
+        schedule.resolved(start);
+        assert!(!schedule.is_due(start + Duration::from_secs(29)));
+
+        // A failed send brings the lookup forward, further failures don't
+        schedule.send_failed(start);
+        assert!(schedule.is_due(start + Duration::from_secs(1)));
+        schedule.send_failed(start + Duration::from_millis(500));
+        assert!(schedule.is_due(start + Duration::from_secs(1)));
+
+        // Failures after the lookup back off
+        let resolved = start + Duration::from_secs(1);
+        schedule.resolved(resolved);
+        schedule.send_failed(resolved);
+        assert!(!schedule.is_due(resolved + Duration::from_millis(1999)));
+        assert!(schedule.is_due(resolved + Duration::from_secs(2)));
+
+        // Until the sends work again
+        schedule.send_succeeded();
+        schedule.resolved(resolved);
+        schedule.send_failed(resolved);
+        assert!(schedule.is_due(resolved + Duration::from_secs(1)));
+    }
+}
@@ -8,3 +8,32 @@ pub mod dstaddr;
 pub mod appconfig;
 pub mod mongodb;
 pub mod tracker;
+pub mod health;
+pub mod policy;
+pub mod clientlabel;
+pub mod sockopt;
+pub mod stats;
+pub mod srv;
+pub mod chaos;
+pub mod statsd;
+pub mod metriclabels;
+pub mod connlimit;
+pub mod hellorewrite;
+pub mod breaker;
+pub mod slowquery;
+pub mod parsebudget;
+pub mod upstreampool;
+pub mod upstreamproxy;
+pub mod commentlabel;
+pub mod otlp;
+pub mod config;
+#[cfg(feature = "profiling")]
+pub mod profiling;
+pub mod catchpanic;
+pub mod blackhole;
+pub mod annotate;
+pub mod sni;
+pub mod namespaces;
+pub mod metricreset;
+pub mod chunkqueue;
+pub mod cli;
@@ -1,34 +1,75 @@
-use std::sync::{Arc,Mutex};
-use std::net::{SocketAddr,ToSocketAddrs};
+use std::sync::{Arc,Mutex,PoisonError};
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::net::{IpAddr,Ipv4Addr,SocketAddr,ToSocketAddrs};
 use std::io;
-use std::{thread, str};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::{env, thread, process};
+use std::time::{Duration,Instant};
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt, stream_reader};
 use tokio::net::{TcpListener,TcpStream};
 use tokio::net::tcp::{OwnedReadHalf,OwnedWriteHalf};
+use tokio::stream::Stream;
 use tokio::sync::mpsc;
+use tokio::time;
+use tokio::task;
+use tokio::runtime;
 
-use prometheus::{CounterVec,HistogramVec,Encoder,TextEncoder};
-use clap::{Arg, App, crate_version};
-use tracing::{info, warn, error, debug, info_span, Instrument, Level};
+use prometheus::{Counter,CounterVec,Gauge,GaugeVec,Histogram,HistogramVec,Encoder,TextEncoder};
+#[cfg(target_os = "linux")]
+use prometheus::process_collector::ProcessCollector;
+use clap::{crate_version};
+use tracing::{info, warn, error, debug, info_span, field, Instrument};
 use tracing_subscriber::{FmtSubscriber, EnvFilter};
 use lazy_static::lazy_static;
+use byteorder::{ByteOrder, LittleEndian};
 
 #[macro_use] extern crate prometheus;
 #[macro_use] extern crate rouille;
 
 use mongoproxy::jaeger_tracing;
 use mongoproxy::dstaddr;
-use mongoproxy::appconfig::{AppConfig};
-use mongoproxy::tracker::{MongoStatsTracker};
-use mongoproxy::mongodb::{MsgHeader, MongoMessage};
+use mongoproxy::sockopt;
+use mongoproxy::stats;
+use mongoproxy::metriclabels;
+use mongoproxy::metricreset;
+use mongoproxy::chaos::{self, LatencyInjector};
+use mongoproxy::statsd::{StatsdClient};
+use mongoproxy::otlp::{OtlpExporter};
+#[cfg(feature = "profiling")]
+use mongoproxy::profiling::{self, CpuProfiler, ProfileError};
+use mongoproxy::catchpanic::{self, CatchPanic};
+use mongoproxy::blackhole;
+use mongoproxy::health::{self, UpstreamHealth};
+use mongoproxy::appconfig::{AppConfig, TrackerOverflow, TrackingMode};
+use mongoproxy::cli;
+use mongoproxy::config::{self, Config, LOG_LEVEL};
+use mongoproxy::tracker::{DocumentsPerResponse, MongoStatsTracker};
+use mongoproxy::mongodb::{self, AsyncReadExtPlus, MsgHeader, MongoMessage, HEADER_LENGTH, MSG_FLAG_MORE_TO_COME};
+use mongoproxy::policy::{self, RequestPolicy};
+use mongoproxy::clientlabel::{ClientLabel};
+use mongoproxy::connlimit::{self, ActiveConnection, ActiveConnections, ClientConnections, ConnectionActivity, LimitMode};
+use mongoproxy::hellorewrite::{HelloRewriter};
+use mongoproxy::annotate;
+use mongoproxy::breaker::{BreakerState, CircuitBreakers};
+use mongoproxy::slowquery::{SlowQueryFeed};
+use mongoproxy::parsebudget::{ParseBudget};
+use mongoproxy::upstreampool::{self, UpstreamPool};
+use mongoproxy::upstreamproxy;
+use mongoproxy::sni::{self, SniRoutes};
+use mongoproxy::srv;
+use mongoproxy::commentlabel::{self, CommentLabels};
+use mongoproxy::chunkqueue::{chunk_queue, ChunkReceiver, ChunkSender};
 
 
 type BufBytes = Result<bytes::Bytes, io::Error>;
 
-const JAEGER_ADDR: &str = "127.0.0.1:6831";
-const ADMIN_PORT: &str = "9898";
-const SERVICE_NAME: &str = "mongoproxy";
+// How long to wait for the trackers to finish before returning a connection to the pool
+const POOL_TRACKER_WAIT: Duration = Duration::from_secs(1);
+
+// How often --wait-for-upstream tries to connect to the upstream at startup
+const WAIT_FOR_UPSTREAM_RETRY: Duration = Duration::from_secs(1);
 
 lazy_static! {
     static ref MONGOPROXY_RUNTIME_INFO: CounterVec =
@@ -41,111 +82,562 @@ lazy_static! {
         register_counter_vec!(
             "mongoproxy_client_connections_established_total",
             "Total number of client connections established",
-            &["client"]).unwrap();
+            &["client", "proxy"]).unwrap();
 
     static ref DISCONNECTION_COUNT_TOTAL: CounterVec =
         register_counter_vec!(
             "mongoproxy_client_disconnections_total",
             "Total number of client disconnections",
-            &["client"]).unwrap();
+            &["client", "proxy"]).unwrap();
+
+    static ref SERVER_DISCONNECTION_COUNT_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_server_disconnections_total",
+            "Total number of connections closed by the server",
+            &["client", "proxy"]).unwrap();
 
     static ref CONNECTION_ERRORS_TOTAL: CounterVec =
         register_counter_vec!(
             "mongoproxy_client_connection_errors_total",
             "Total number of errors from handle_connections",
-            &["client"]).unwrap();
+            &["client", "error_kind", "proxy"]).unwrap();
+
+    static ref PARSE_TIMEOUTS_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_parse_timeouts_total",
+            "Number of times the tracker gave up waiting for the rest of a message",
+            &["proxy"]).unwrap();
+
+    static ref PARSE_ABANDONED_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_parse_abandoned_total",
+            "Number of messages the tracker skipped because parsing took longer than --parse-budget-ms",
+            &["proxy"]).unwrap();
+
+    static ref PARSE_RESYNCS_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_parse_resyncs_total",
+            "Number of times the tracker skipped over an invalid message header",
+            &["proxy"]).unwrap();
+
+    static ref TRACKER_CHUNKS_DROPPED_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_tracker_chunks_dropped_total",
+            "Number of proxied chunks not passed to the tracker because it couldn't keep up",
+            &["proxy"]).unwrap();
+
+    static ref LIFETIME_RECYCLED_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_lifetime_recycled_total",
+            "Number of connections closed between messages after --max-connection-lifetime"
+            ).unwrap();
+
+    static ref TRACKER_BUFFERED_BYTES: GaugeVec =
+        register_gauge_vec!(
+            "mongoproxy_tracker_buffered_bytes",
+            "Proxied bytes queued for the trackers and not parsed yet",
+            &["proxy"]).unwrap();
+
+    static ref TRACKER_COVERAGE_RATIO: HistogramVec =
+        register_histogram_vec!(
+            "mongoproxy_tracker_coverage_ratio",
+            "Share of the proxied bytes of a connection that the trackers parsed",
+            &["proxy"],
+            vec![0.5, 0.8, 0.9, 0.95, 0.99, 1.0]).unwrap();
+
+    static ref TRACKER_PANICS_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_tracker_panics_total",
+            "Number of messages the tracker panicked on",
+            &["proxy"]).unwrap();
+
+    static ref TRACKER_BLOCKED_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_tracker_blocked_total",
+            "Number of times proxying waited for the tracker to catch up",
+            &["proxy"]).unwrap();
+
+    static ref TRACKER_BLOCKED_SECONDS_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_tracker_blocked_seconds_total",
+            "Time spent waiting for the tracker to catch up instead of proxying",
+            &["proxy"]).unwrap();
 
     static ref SERVER_CONNECT_TIME_SECONDS: HistogramVec =
         register_histogram_vec!(
             "mongoproxy_server_connect_time_seconds",
             "Time it takes to look up and connect to a server",
-            &["server_addr"]).unwrap();
-}
-
-#[tokio::main]
-async fn main() {
-    let matches = App::new("mongoproxy")
-        .version(crate_version!())
-        .about("Proxies MongoDb requests to obtain metrics")
-        .arg(Arg::with_name("proxy")
-            .long("proxy")
-            .value_name("local-port[:remote-host:remote-port]")
-            .help("Port the proxy listens on (sidecar) and optionally\na target hostport (for static proxy)")
-            .takes_value(true)
-            .required(true))
-        .arg(Arg::with_name("log_mongo_messages")
-            .long("log-mongo-messages")
-            .help("Log the contents of MongoDb messages (adds full BSON parsing)")
-            .takes_value(false)
-            .required(false))
-        .arg(Arg::with_name("enable_jaeger")
-            .long("enable-jaeger")
-            .help("Enable distributed tracing with Jaeger")
-            .takes_value(false)
-            .required(false))
-        .arg(Arg::with_name("jaeger_addr")
-            .long("jaeger-addr")
-            .value_name("Jaeger agent host:port")
-            .help("Jaeger agent hostport to send traces to (compact thrift protocol)")
-            .takes_value(true)
-            .required(false))
-        .arg(Arg::with_name("service_name")
-            .long("service-name")
-            .value_name("SERVICE_NAME")
-            .help("Service name that will be used in Jaeger traces and metric labels")
-            .takes_value(true))
-        .arg(Arg::with_name("admin_port")
-            .long("admin-port")
-            .value_name("ADMIN_PORT")
-            .help(&format!("Port the admin endpoints listens on (metrics and health). Default {}", ADMIN_PORT))
-            .takes_value(true))
-        .get_matches();
-
-    let admin_port = matches.value_of("admin_port").unwrap_or(ADMIN_PORT);
-    let admin_addr = format!("0.0.0.0:{}", admin_port);
-    let service_name = matches.value_of("service_name").unwrap_or(SERVICE_NAME);
-    let log_mongo_messages = matches.occurrences_of("log_mongo_messages") > 0;
-    let enable_jaeger = matches.occurrences_of("enable_jaeger") > 0;
-    let jaeger_addr = lookup_address(matches.value_of("jaeger_addr").unwrap_or(JAEGER_ADDR)).unwrap();
+            &["server_addr", "server_ip", "proxy"]).unwrap();
+
+    static ref TRACKER_LOCK_HOLD_SECONDS: HistogramVec =
+        register_histogram_vec!(
+            "mongoproxy_tracker_lock_hold_seconds",
+            "Time the client and server trackers hold the tracker lock per message",
+            &["tracker", "proxy"],
+            vec![0.000_001, 0.000_005, 0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01]).unwrap();
+
+    static ref PER_CLIENT_LIMIT_HITS_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_per_client_limit_hits_total",
+            "Number of connections closed because the client had too many connections",
+            &["client"]).unwrap();
+
+    static ref CONNECTION_DURATION_SECONDS: Histogram =
+        register_histogram!(
+            "mongoproxy_connection_duration_seconds",
+            "How long the client connections lived, from accept to close",
+            vec![0.1, 1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 14400.0, 86400.0]).unwrap();
+
+    static ref HANDSHAKE_VIOLATIONS_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_handshake_violations_total",
+            "Number of connections closed for not starting with the hello handshake",
+            &["reason"]).unwrap();
+
+    static ref CONNECTION_LIMIT_HITS_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_connection_limit_hits_total",
+            "Number of connections closed because --max-connections was reached"
+            ).unwrap();
+
+    static ref ORIG_DST_SUCCESS_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_orig_dst_success_total",
+            "Number of connections forwarded to their original destination"
+            ).unwrap();
+
+    static ref ORIG_DST_MISSING_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_orig_dst_missing_total",
+            "Number of connections closed because the original destination was not found"
+            ).unwrap();
+
+    static ref SERVER_CONNECT_ATTEMPTS_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_server_connect_attempts_total",
+            "Number of connection attempts to the upstream servers",
+            &["server_addr", "result", "proxy"]).unwrap();
+
+    static ref UPSTREAM_POOL_HITS_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_upstream_pool_hits_total",
+            "Number of client connections served with a pooled server connection"
+            ).unwrap();
+
+    static ref UPSTREAM_POOL_MISSES_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_upstream_pool_misses_total",
+            "Number of client connections that needed a new server connection"
+            ).unwrap();
+
+    static ref SNI_ROUTES_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_sni_routes_total",
+            "Number of TLS passthrough connections by how the upstream was chosen",
+            &["outcome"]).unwrap();
+
+    static ref UPSTREAM_POOL_IDLE: Gauge =
+        register_gauge!(
+            "mongoproxy_upstream_pool_idle",
+            "Number of idle server connections in the upstream pool"
+            ).unwrap();
+}
+
+fn main() {
+    let matches = cli::get_matches_from_safe(env::args_os()).unwrap_or_else(|e| e.exit());
+
+    // RUST_LOG takes precedence over --log-level, as it allows for finer grained
+    // filtering.
+    let log_level = matches.value_of("log_level").unwrap_or(LOG_LEVEL);
+    let env_filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(log_level));
 
     let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::TRACE)
-        .with_env_filter(EnvFilter::from_default_env())
+        .with_env_filter(env_filter)
         .finish();
 
     tracing::subscriber::set_global_default(subscriber)
         .expect("setting default trace subscriber failed");
 
+    if let Some(parse_matches) = matches.subcommand_matches("parse") {
+        let max_message_size = match config::parse_max_message_size(&matches) {
+            Ok(max_message_size) => max_message_size,
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            },
+        };
+        process::exit(run_parse(parse_matches.value_of("file").unwrap(), max_message_size));
+    }
+
+    let mut config = match Config::from_args(&matches) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        },
+    };
+    if matches.occurrences_of("print_config") > 0 {
+        println!("{}", config.to_json());
+        return;
+    }
+
     info!("MongoProxy v{}", crate_version!());
 
-    start_admin_listener(&admin_addr);
-    info!("Admin endpoint at http://{}", admin_addr);
+    // Resolved here rather than with the rest of the config, so that --print-config
+    // and the config validation don't need the network
+    if let Some(srv_name) = &config.srv_host {
+        match srv::lookup_srv(srv_name) {
+            Ok(targets) => config.proxies[0].upstream = srv::failover_list(&targets),
+            Err(e) => {
+                error!("SRV lookup of {} failed: {}", srv_name, e);
+                process::exit(1);
+            },
+        }
+    }
+
+    let admin_auth = match &config.admin_auth {
+        Some(spec) => match AdminAuth::parse(spec, config.admin_auth_exempt_livez) {
+            Ok(auth) => Some(auth),
+            Err(e) => {
+                error!("Invalid --admin-auth: {}", e);
+                process::exit(1);
+            },
+        },
+        None => None,
+    };
+
+    let static_labels = if config.k8s_pod_labels {
+        let labels = metriclabels::pod_labels_from_env();
+        info!("Adding pod labels to metrics: {:?}", labels);
+        labels
+    } else {
+        Vec::new()
+    };
+
+    let slow_queries = config.slow_query.map(SlowQueryFeed::new);
+
+    register_process_metrics();
+
+    if let Some(endpoint) = &config.otlp_metrics_endpoint {
+        let resource = vec![("service.name".to_string(), config.service_name.clone())];
+        match OtlpExporter::new(endpoint, config.otlp_metrics_interval, resource) {
+            Ok(exporter) => exporter.start(static_labels.clone()),
+            Err(e) => {
+                error!("Invalid --otlp-metrics-endpoint {}: {}", endpoint, e);
+                process::exit(1);
+            },
+        }
+    }
 
-    let proxy_spec = matches.value_of("proxy").unwrap();
-    let (local_hostport, remote_hostport) = parse_proxy_addresses(proxy_spec).unwrap();
+    if config.enable_metrics_reset {
+        warn!("Metrics can be reset from the admin port, don't use --enable-metrics-reset in production");
+    }
+
+    let policy = RequestPolicy {
+        reject_legacy_opcodes: config.reject_legacy_opcodes,
+        deny_commands: config.deny_commands.clone(),
+        allow_commands: config.allow_commands.clone(),
+    };
 
-    let app = AppConfig::new(
-        jaeger_tracing::init_tracer(enable_jaeger, &service_name, jaeger_addr),
-        log_mongo_messages,
+    let mut app = AppConfig::new(
+        jaeger_tracing::init_tracer(config.enable_jaeger, &config.service_name, &config.jaeger_addr),
+        config.log_mongo_messages,
     );
+    app.connect_timeout = config.connect_timeout;
+    app.namespace_span_names = config.namespace_span_names;
+    app.parse_timeout = config.parse_timeout;
+    app.parse_budget = config.parse_budget;
+    app.max_message_size = config.max_message_size;
+    app.buffer_size = config.buffer_size;
+    app.tracker_channel_size = config.tracker_channel_size;
+    app.tcp_keepalive = config.tcp_keepalive;
+    app.nodelay = config.nodelay;
+    app.policy = Arc::new(policy);
+    app.slow_queries = slow_queries.clone();
+    app.strict_handshake = config.strict_handshake;
+    app.annotate_client = config.annotate_client;
+    app.log_sample_rate = config.log_sample_rate;
+    app.tracker_overflow = config.tracker_overflow;
+    app.tracking_mode = config.tracking_mode;
+    app.circuit_breakers = config.circuit_breaker.map(CircuitBreakers::new);
+
+    if let Some(routes) = &config.sni_routes {
+        info!("Passing TLS through to the upstreams by server name: {:?}", routes.specs());
+        app.sni_routes = Some(routes.clone());
+    }
+
+    if let Some(proxy) = &config.upstream_proxy {
+        info!("Connecting to the servers through {}", proxy);
+        app.upstream_proxy = Some(proxy.clone());
+    }
+
+    if config.pool_upstream {
+        app.upstream_pool = Some(UpstreamPool::new(upstreampool::MAX_IDLE_PER_UPSTREAM, upstreampool::IDLE_TIMEOUT));
+    }
+
+    if let Some(max_connections) = config.max_connections {
+        app.active_connections = Some(match config.limit_mode {
+            LimitMode::Reject => ActiveConnections::new(max_connections, config.shed_idle),
+            LimitMode::Pause => ActiveConnections::pausing(max_connections),
+        });
+    }
+    app.client_connections = config.max_connections_per_client.map(ClientConnections::new);
+    app.max_connection_lifetime = config.max_connection_lifetime;
+
+    if let Some(spec) = &config.rewrite_hello {
+        match HelloRewriter::parse(spec) {
+            Ok(rewriter) => {
+                info!("Rewriting hello responses: {:?}", rewriter);
+                app.rewrite_hello = Some(rewriter);
+            },
+            Err(e) => {
+                error!("Invalid --rewrite-hello: {}", e);
+                process::exit(1);
+            },
+        }
+    }
+
+    if let Some(inject_latency) = config.inject_latency {
+        warn!("Injecting {:?} (+{:?} jitter) latency to server responses", inject_latency.latency, inject_latency.jitter);
+        app.inject_latency = Some(inject_latency);
+    }
+
+    if config.fault_drop_prob > 0.0 {
+        warn!("Injecting faults: closing {}% of connections", config.fault_drop_prob * 100.0);
+    }
+    app.fault_drop_prob = config.fault_drop_prob;
+
+    if config.track_document_shape && !config.log_mongo_messages && !config.enable_jaeger {
+        warn!("--track-document-shape has no effect without message logging or tracing");
+    }
+    app.track_document_shape = config.track_document_shape;
+    app.track_collection_scans = config.track_collection_scans;
+    app.track_query_selectivity = config.track_query_selectivity;
+    app.observe_server_status = config.observe_server_status;
+    app.profile_locking = config.profile_locking;
+    if let Some(buckets) = &config.documents_per_response {
+        match DocumentsPerResponse::register(buckets.clone()) {
+            Ok(histogram) => app.documents_per_response = Some(histogram),
+            Err(e) => {
+                error!("Invalid --documents-per-response-buckets: {}", e);
+                process::exit(1);
+            },
+        }
+    }
+    app.exclude_commands = config.exclude_commands.clone();
+    if config.use_comment_label {
+        app.comment_labels = Some(CommentLabels::new(commentlabel::MAX_COMMENT_LEN, commentlabel::MAX_COMMENT_VALUES));
+    }
+    app.connect_time_by_ip = config.connect_time_by_ip;
+    app.command_stats = config.command_stats;
+
+    if config.anonymize_clients {
+        app.client_label = ClientLabel::hashed(config.client_hash_salt.as_deref());
+        app.anonymize_client_logs = config.anonymize_client_logs;
+    }
+
+    if config.no_client_labels {
+        app.client_label = ClientLabel::Aggregate;
+    }
+
+    if let Some(statsd_addr) = &config.statsd_addr {
+        match StatsdClient::new(statsd_addr, &config.statsd_prefix) {
+            Ok(statsd) => {
+                info!("Sending metrics to StatsD at {}", statsd_addr);
+                app.statsd = Some(statsd);
+            },
+            Err(e) => {
+                error!("Invalid --statsd-addr {}: {}", statsd_addr, e);
+                process::exit(1);
+            },
+        }
+    }
+
+    for proxy in &config.proxies {
+        MONGOPROXY_RUNTIME_INFO.with_label_values(&[
+            crate_version!(),
+            &proxy.spec,
+            &config.service_name,
+            if config.log_mongo_messages { "true" } else { "false" },
+            if config.enable_jaeger { "true" } else { "false" } ],
+        ).inc();
+    }
+
+    // The admin endpoint and Jaeger reporter run on their own threads, this only
+    // concerns the proxy itself.
+    let mut runtime_builder = runtime::Builder::new();
+    runtime_builder.threaded_scheduler().enable_all();
+    if let Some(worker_threads) = config.worker_threads {
+        runtime_builder.core_threads(worker_threads);
+    }
+
+    let mut runtime = match runtime_builder.build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("Failed to start the runtime: {}", e);
+            process::exit(1);
+        },
+    };
+
+    let mut proxies: Vec<(String, String, String)> = config.proxies.iter()
+        .map(|p| (p.spec.clone(), p.listen.clone(), p.upstream.clone()))
+        .collect();
+
+    if config.blackhole {
+        let addr = match runtime.block_on(blackhole::start()) {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!("Failed to start the blackhole server: {}", e);
+                process::exit(1);
+            },
+        };
+        warn!("Blackhole mode: the clients are answered by a stand-in server, not MongoDb");
+        for (_, _, upstream) in proxies.iter_mut() {
+            *upstream = addr.to_string();
+        }
+    }
+
+    // Built from the final upstreams, after the blackhole address is swapped in. With
+    // several proxies only the upstream of the first one is checked.
+    let remote_hostport = &proxies[0].2;
+    let upstream = if remote_hostport.is_empty() { None } else { Some(remote_hostport.clone()) };
+    let health = UpstreamHealth::new(upstream, config.health_check_interval)
+        .with_probe(upstream_probe(runtime.handle().clone(), app.clone()));
+    let (health, startup_check) = if config.wait_for_upstream.is_some() {
+        let (health, check) = health.with_startup_check();
+        (health, Some(check))
+    } else {
+        (health, None)
+    };
+
+    let admin_addr = config.admin_addr;
+    if let Err(e) = start_admin_listener(admin_addr, admin_auth, health, static_labels, slow_queries,
+        config.enable_profiling, config.command_stats, config.enable_metrics_reset)
+    {
+        error!("Failed to start admin listener: {}", bind_error_message(&admin_addr.to_string(), &e));
+        process::exit(1);
+    }
+    info!("Admin endpoint at http://{}", admin_addr);
 
-    MONGOPROXY_RUNTIME_INFO.with_label_values(&[
-        crate_version!(),
-        &proxy_spec,
-        &service_name,
-        if log_mongo_messages { "true" } else { "false" },
-        if enable_jaeger { "true" } else { "false" } ],
-    ).inc();
+    if let (Some(timeout), Some(startup_check)) = (config.wait_for_upstream, startup_check) {
+        let upstreams: Vec<&str> = proxies.iter()
+            .map(|(_, _, upstream)| upstream.as_str())
+            .filter(|upstream| !upstream.is_empty())
+            .collect();
+        if let Err(e) = runtime.block_on(wait_for_upstreams(&upstreams, &app, timeout)) {
+            error!("{}", e);
+            process::exit(1);
+        }
+        startup_check.passed();
+    }
 
-    run_accept_loop(local_hostport, remote_hostport, &app).await;
+    if let Err(e) = runtime.block_on(run_proxies(proxies, app, config.accept_loops)) {
+        error!("{}", e);
+        process::exit(1);
+    }
 }
 
-// Accept connections in a loop and spawn a task to proxy them. If remote address is not explicitly
-// specified attempt to proxy to the original destination obtained with SO_ORIGINAL_DST socket
-// option.
+// Parse a capture file for the `parse` subcommand, returns the exit status
+fn run_parse(file: &str, max_message_size: usize) -> i32 {
+    let capture = match std::fs::read(file) {
+        Ok(capture) => capture,
+        Err(e) => {
+            error!("Failed to read {}: {}", file, e);
+            return 1;
+        },
+    };
+
+    let mut runtime = match runtime::Builder::new().basic_scheduler().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("Failed to start the runtime: {}", e);
+            return 1;
+        },
+    };
+
+    let stdout = io::stdout();
+    match runtime.block_on(parse_capture(&capture[..], &mut stdout.lock(), max_message_size)) {
+        Ok(count) => {
+            info!("Parsed {} messages from {}", count, file);
+            0
+        },
+        Err(e) => {
+            error!("Failed to parse {}: {}", file, e);
+            1
+        },
+    }
+}
+
+// Run a capture through the same parsing steps as the tracker, printing the header and
+// contents of every message. Returns the number of messages parsed.
+async fn parse_capture(
+    mut rdr: impl AsyncReadExtPlus,
+    out: &mut impl io::Write,
+    max_message_size: usize,
+) -> Result<usize, io::Error> {
+    let mut count = 0;
+    loop {
+        let mut hdr = match MsgHeader::from_reader(&mut rdr).await {
+            Ok(hdr) => hdr,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(count),
+            Err(e) => return Err(e),
+        };
+
+        if !hdr.is_plausible(max_message_size) {
+            match MsgHeader::resync(&hdr, &mut rdr, max_message_size).await {
+                Ok((next_hdr, skipped)) => {
+                    writeln!(out, "invalid header: {}, skipped {} bytes to resync", hdr, skipped)?;
+                    hdr = next_hdr;
+                },
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    writeln!(out, "invalid header: {}, no valid header after it", hdr)?;
+                    return Ok(count);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        let msg = MongoMessage::from_header(&hdr, &mut rdr, false, false, max_message_size).await
+            .map_err(|e| io::Error::new(e.kind(), format!("message {}: {}: {}", count + 1, hdr, e)))?;
+        count += 1;
+
+        writeln!(out, "message {}: {}", count, hdr)?;
+        writeln!(out, "{}", msg.to_string().trim_end())?;
+    }
+}
+
+// Run the proxies side by side, given as (label, local address, remote address). Only
+// returns if one of them can't be started.
+async fn run_proxies(proxies: Vec<(String, String, String)>, app: AppConfig, accept_loops: usize)
+    -> Result<(), String>
+{
+    let (tx, mut rx) = mpsc::channel(proxies.len());
+    for (proxy_label, local_addr, remote_addr) in proxies {
+        let mut tx = tx.clone();
+        let mut app = app.clone();
+        app.proxy_label = proxy_label;
+        tokio::spawn(async move {
+            let result = run_proxy(local_addr.clone(), remote_addr, app, accept_loops).await
+                .map_err(|e| bind_error_message(&local_addr, &e));
+            let _ = tx.send(result).await;
+        });
+    }
+    drop(tx);
+
+    while let Some(result) = rx.recv().await {
+        result?;
+    }
+    Ok(())
+}
+
+// Start listening on the local address and run the accept loops. With more than one accept loop
+// each of them gets its own SO_REUSEPORT listener and the kernel distributes the incoming
+// connections between them.
 //
-// Never returns.
-async fn run_accept_loop(local_addr: String, remote_addr: String, app: &AppConfig)
+// Only returns if the listeners can't be set up.
+async fn run_proxy(local_addr: String, remote_addr: String, app: AppConfig, accept_loops: usize)
+    -> Result<(), io::Error>
 {
     if remote_addr.is_empty() {
         info!("Proxying {} -> <original dst>", local_addr);
@@ -153,13 +645,89 @@ async fn run_accept_loop(local_addr: String, remote_addr: String, app: &AppConfi
         info!("Proxying {} -> {}", local_addr, remote_addr);
     }
 
-    let mut listener = TcpListener::bind(&local_addr).await.unwrap();
+    // Have the pool metrics show up from the start, so the hits compare to the misses
+    lazy_static::initialize(&UPSTREAM_POOL_HITS_TOTAL);
+    lazy_static::initialize(&UPSTREAM_POOL_IDLE);
+
+    let listeners = if accept_loops == 1 {
+        vec![TcpListener::bind(&local_addr).await?]
+    } else {
+        let addr = lookup_address(&local_addr)?;
+        (0..accept_loops)
+            .map(|_| TcpListener::from_std(sockopt::reuseport_listener(&addr)?))
+            .collect::<Result<Vec<_>, io::Error>>()?
+    };
+
+    let accept_tasks: Vec<_> = listeners.into_iter()
+        .map(|listener| tokio::spawn(run_accept_loop(listener, remote_addr.clone(), app.clone())))
+        .collect();
+
+    for task in accept_tasks {
+        let _ = task.await;
+    }
+
+    Ok(())
+}
+
+// Describe a failed listener bind, with a hint for the usual cause
+fn bind_error_message(addr: &str, e: &io::Error) -> String {
+    if e.kind() == io::ErrorKind::AddrInUse {
+        format!("failed to bind {}: already in use (is another mongoproxy running?)", addr)
+    } else {
+        format!("failed to bind {}: {}", addr, e)
+    }
+}
 
+// Accept connections in a loop and spawn a task to proxy them. If remote address is not explicitly
+// specified attempt to proxy to the original destination obtained with SO_ORIGINAL_DST socket
+// option.
+//
+// Never returns.
+async fn run_accept_loop(mut listener: TcpListener, remote_addr: String, app: AppConfig)
+{
     loop {
+        // In pause mode don't accept at the limit, the new connections are left in the
+        // listen backlog until one closes
+        if let Some(connections) = &app.active_connections {
+            if connections.pauses_accept() {
+                connections.wait_for_slot().await;
+            }
+        }
+
         match listener.accept().await {
             Ok((stream, peer_addr)) => {
-                let client_ip_port = peer_addr.to_string();
-                let client_addr = format_client_address(&peer_addr);
+                let accepted_at = Instant::now();
+                let client_label = app.client_label.format(&format_client_address(&peer_addr));
+                let (client_ip_port, client_addr) = if app.anonymize_client_logs {
+                    (client_label.clone(), client_label.clone())
+                } else {
+                    (peer_addr.to_string(), format_client_address(&peer_addr))
+                };
+
+                // Close the connection right away if the client already has too many
+                let client_connection = match &app.client_connections {
+                    Some(connections) => match connections.try_acquire(peer_addr.ip()) {
+                        Some(connection) => Some(connection),
+                        None => {
+                            warn!("Too many connections from {}, closing the connection", client_addr);
+                            PER_CLIENT_LIMIT_HITS_TOTAL.with_label_values(&[&client_label]).inc();
+                            continue;
+                        },
+                    },
+                    None => None,
+                };
+
+                let active_connection = match &app.active_connections {
+                    Some(connections) => match connections.try_acquire() {
+                        Some(connection) => Some(connection),
+                        None => {
+                            warn!("Connection limit reached, closing the connection from {}", client_addr);
+                            CONNECTION_LIMIT_HITS_TOTAL.inc();
+                            continue;
+                        },
+                    },
+                    None => None,
+                };
 
                 let server_addr = if remote_addr.is_empty() {
                     if let Some(sockaddr) = dstaddr::orig_dst_addr(&stream) {
@@ -168,10 +736,14 @@ async fn run_accept_loop(local_addr: String, remote_addr: String, app: &AppConfi
                         // iptables rules to be in place to block direct access
                         // to the proxy port.
                         debug!("Original destination address: {:?}", sockaddr);
+                        ORIG_DST_SUCCESS_TOTAL.inc();
                         sockaddr.to_string()
+                    } else if app.sni_routes.is_some() {
+                        // The server name picks the upstream, there is just no fallback
+                        String::new()
                     } else {
                         error!("Host not set and destination address not found: {}", client_addr);
-                        // TODO: Increase a counter
+                        ORIG_DST_MISSING_TOTAL.inc();
                         continue;
                     }
                 } else {
@@ -180,25 +752,55 @@ async fn run_accept_loop(local_addr: String, remote_addr: String, app: &AppConfi
 
                 let app = app.clone();
                 let server_ip_port = server_addr.clone();
+                let statsd = app.statsd.clone();
+                let proxy_label = app.proxy_label.clone();
 
-                CONNECTION_COUNT_TOTAL.with_label_values(&[&client_addr.to_string()]).inc();
+                CONNECTION_COUNT_TOTAL.with_label_values(&[&client_label, &proxy_label]).inc();
+                if let Some(statsd) = &statsd {
+                    statsd.count("client_connections_established", 1, &[("client", &client_label)]);
+                }
 
                 let conn_handler = async move {
                     info!("new connection from {}", client_addr);
-                    match handle_connection(&server_addr, stream, app).await {
-                        Ok(_) => {
-                            info!("{} closing connection.", client_addr);
+                    match handle_connection(&server_addr, stream, app, active_connection).await {
+                        Ok(ConnectionClose::Client) => {
+                            info!("{} closed the connection.", client_addr);
                             DISCONNECTION_COUNT_TOTAL
-                                .with_label_values(&[&client_addr.to_string()])
+                                .with_label_values(&[&client_label, &proxy_label])
                                 .inc();
+                            if let Some(statsd) = &statsd {
+                                statsd.count("client_disconnections", 1, &[("client", &client_label)]);
+                            }
+                        },
+                        Ok(ConnectionClose::Server) => {
+                            info!("{} connection closed by the server.", client_addr);
+                            SERVER_DISCONNECTION_COUNT_TOTAL
+                                .with_label_values(&[&client_label, &proxy_label])
+                                .inc();
+                            if let Some(statsd) = &statsd {
+                                statsd.count("server_disconnections", 1, &[("client", &client_label)]);
+                            }
+                        },
+                        Ok(ConnectionClose::Recycled) => {
+                            info!("{} connection closed after its max lifetime.", client_addr);
+                            LIFETIME_RECYCLED_TOTAL.inc();
                         },
                         Err(e) => {
                             warn!("{} connection error: {}", client_addr, e);
                             CONNECTION_ERRORS_TOTAL
-                                .with_label_values(&[&client_addr.to_string()])
+                                .with_label_values(&[&client_label, classify_io_error(&e), &proxy_label])
                                 .inc();
+                            if let Some(statsd) = &statsd {
+                                statsd.count("client_connection_errors", 1,
+                                    &[("client", &client_label), ("error_kind", classify_io_error(&e))]);
+                            }
                         },
                     };
+                    CONNECTION_DURATION_SECONDS.observe(accepted_at.elapsed().as_secs_f64());
+                    if let Some(statsd) = &statsd {
+                        statsd.timing("connection_duration", accepted_at.elapsed(), &[("client", &client_label)]);
+                    }
+                    drop(client_connection);
                 };
 
                 tokio::spawn(
@@ -215,63 +817,323 @@ async fn run_accept_loop(local_addr: String, remote_addr: String, app: &AppConfi
     }
 }
 
+// Connect to the first of the comma separated upstreams that accepts the connection.
+// The upstreams are tried strictly in order, moving on to the next one only if the
+// connect fails or times out. Upstreams with an open circuit breaker are skipped.
+async fn connect_upstream(upstreams: &str, app: &AppConfig) -> Result<TcpStream, io::Error> {
+    let mut last_error = None;
+
+    for upstream in upstreams.split(',') {
+        if let Some(breakers) = &app.circuit_breakers {
+            if !breakers.allow(upstream) {
+                debug!("Circuit breaker for {} is open, not connecting", upstream);
+                last_error = Some(io::Error::new(io::ErrorKind::ConnectionRefused,
+                    format!("circuit breaker for {} is open", upstream)));
+                continue;
+            }
+        }
+
+        let span = info_span!("connect_upstream",
+            server_addr = upstream,
+            resolved_addr = field::Empty,
+            outcome = field::Empty,
+            error = field::Empty);
+
+        match connect_server(upstream, app).instrument(span.clone()).await {
+            Ok(stream) => {
+                span.record("outcome", &"success");
+                SERVER_CONNECT_ATTEMPTS_TOTAL.with_label_values(&[upstream, "success", &app.proxy_label]).inc();
+                if let Some(statsd) = &app.statsd {
+                    statsd.count("server_connect_attempts", 1, &[("server_addr", upstream), ("result", "success")]);
+                }
+                if let Some(breakers) = &app.circuit_breakers {
+                    breakers.record_success(upstream);
+                }
+                return Ok(stream);
+            },
+            Err(e) => {
+                span.record("outcome", &"failure");
+                span.record("error", &field::display(&e));
+                SERVER_CONNECT_ATTEMPTS_TOTAL.with_label_values(&[upstream, "failure", &app.proxy_label]).inc();
+                if let Some(statsd) = &app.statsd {
+                    statsd.count("server_connect_attempts", 1, &[("server_addr", upstream), ("result", "failure")]);
+                }
+                if let Some(breakers) = &app.circuit_breakers {
+                    breakers.record_failure(upstream);
+                }
+                warn!("Failed to connect to {}: {}", upstream, e);
+                last_error = Some(e);
+            },
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no upstream to connect to")))
+}
+
+// Look up and connect to the server. The time it takes is observed also for failed
+// connects, labeled with the resolved IP address if that is enabled. With an upstream
+// proxy the server address is passed to the proxy as it is, without looking it up.
+async fn connect_server(server_addr: &str, app: &AppConfig) -> Result<TcpStream, io::Error> {
+    info!("connecting to server: {}", server_addr);
+    let started = Instant::now();
+
+    let (result, server_ip) = dial_server(server_addr, app).await;
+
+    SERVER_CONNECT_TIME_SECONDS
+        .with_label_values(&[server_addr, &server_ip, &app.proxy_label])
+        .observe(started.elapsed().as_secs_f64());
+    if let Some(statsd) = &app.statsd {
+        statsd.timing("server_connect_time", started.elapsed(), &[("server_addr", server_addr)]);
+    }
+
+    result
+}
+
+// Connect to the server, through the upstream proxy if there is one, without recording
+// anything. Returns the resolved IP address for the metric labels along with the result,
+// empty unless --connect-time-by-ip is set.
+async fn dial_server(server_addr: &str, app: &AppConfig) -> (Result<TcpStream, io::Error>, String) {
+    if let Some(proxy) = &app.upstream_proxy {
+        return (proxy.connect(server_addr, app.connect_timeout).await, String::new());
+    }
+
+    let resolved = lookup_address(server_addr);
+    if let Ok(addr) = &resolved {
+        tracing::Span::current().record("resolved_addr", &field::display(addr));
+    }
+    let server_ip = match &resolved {
+        Ok(addr) if app.connect_time_by_ip => addr.ip().to_string(),
+        _ => String::new(),
+    };
+
+    let result = match resolved {
+        Ok(addr) => match time::timeout(app.connect_timeout, TcpStream::connect(&addr)).await {
+            Ok(stream) => stream,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut,
+                format!("timed out connecting to {}", addr))),
+        },
+        Err(e) => Err(e),
+    };
+
+    (result, server_ip)
+}
+
+// Startup self-test of --wait-for-upstream: keep trying to connect to each of the upstreams
+// until one of its failover servers accepts a connection. Gives up after `timeout` in total.
+async fn wait_for_upstreams(upstreams: &[&str], app: &AppConfig, timeout: Duration) -> Result<(), String> {
+    let deadline = Instant::now() + timeout;
+    for upstream in upstreams {
+        info!("Waiting for {} to accept connections", upstream);
+        let wait = async {
+            while !upstream_accepts(upstream, app).await {
+                time::delay_for(WAIT_FOR_UPSTREAM_RETRY).await;
+            }
+        };
+        if time::timeout(deadline.saturating_duration_since(Instant::now()), wait).await.is_err() {
+            return Err(format!("{} did not accept connections within {:?}", upstream, timeout));
+        }
+        info!("{} accepts connections", upstream);
+    }
+    Ok(())
+}
+
+// Does any of the comma separated upstreams accept a connection
+async fn upstream_accepts(upstreams: &str, app: &AppConfig) -> bool {
+    for server_addr in upstreams.split(',') {
+        match connect_server(server_addr, app).await {
+            Ok(_) => return true,
+            Err(e) => warn!("Startup check failed to connect to {}: {}", server_addr, e),
+        }
+    }
+    false
+}
+
+// Readiness probe that connects the same way as the proxied connections, through
+// --upstream-proxy and skipping the upstreams with an open circuit breaker. The admin
+// server is not async, so the connect runs on the proxy runtime.
+fn upstream_probe(runtime: runtime::Handle, app: AppConfig) -> health::Probe {
+    let app = Mutex::new(app);
+    Box::new(move |upstreams| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let app = app.lock().unwrap().clone();
+        let upstreams = upstreams.to_string();
+        runtime.spawn(async move {
+            let _ = tx.send(probe_upstreams(&upstreams, &app).await);
+        });
+        rx.recv().unwrap_or(false)
+    })
+}
+
+// Does any of the comma separated upstreams accept a connection. Unlike connect_upstream
+// this only looks at the breakers, the probes must not open or close them for the
+// proxied connections or show up in the server connect metrics.
+async fn probe_upstreams(upstreams: &str, app: &AppConfig) -> bool {
+    for upstream in upstreams.split(',') {
+        if let Some(breakers) = &app.circuit_breakers {
+            if breakers.state(upstream) == BreakerState::Open {
+                continue;
+            }
+        }
+        match dial_server(upstream, app).await.0 {
+            Ok(_) => return true,
+            Err(e) => debug!("Readiness probe failed to connect to {}: {}", upstream, e),
+        }
+    }
+    false
+}
+
+// The side that closed a connection that ended without errors
+enum ConnectionClose {
+    Client,
+    Server,
+    // Closed by the proxy after --max-connection-lifetime
+    Recycled,
+}
+
 // Open a connection to the server and start passing bytes between the client and the server. Also
 // split the traffic to MongoDb protocol parser, so that we can get some stats out of this.
 //
 // The philosophy here is that we will not change any of the bytes that are passed between the
-// client and the server. Instead we fork off a stream and send it to a separate tracker task,
-// which then parses the messages and collects metrics from it. Should the tracker fail, the
-// proxy still remains operational.
+// client and the server (the exceptions being --rewrite-hello and --annotate-client). Instead we
+// fork off a stream and send it to a separate tracker task, which then parses the messages and
+// collects metrics from it. Should the tracker fail, the proxy still remains operational.
 //
-
-async fn handle_connection(server_addr: &str, client_stream: TcpStream, app: AppConfig)
-    -> Result<(), io::Error>
+// Returns which side closed the connection, or the error that terminated it.
+async fn handle_connection(
+    server_addr: &str,
+    client_stream: TcpStream,
+    app: AppConfig,
+    mut active_connection: Option<ActiveConnection>,
+) -> Result<ConnectionClose, io::Error>
 {
-    info!("connecting to server: {}", server_addr);
-    let timer = SERVER_CONNECT_TIME_SECONDS.with_label_values(&[server_addr]).start_timer();
-    let server_addr = lookup_address(server_addr)?;
-    let server_stream = TcpStream::connect(&server_addr).await?;
-    timer.observe_duration();
+    if let Some(routes) = &app.sni_routes {
+        return passthrough_tls(server_addr, client_stream, routes, &app).await;
+    }
+
+    let upstream_pool = app.upstream_pool.clone();
+    let pooled = match &upstream_pool {
+        Some(pool) => pool.take(server_addr).await,
+        None => None,
+    };
+    let server_stream = match pooled {
+        Some(stream) => {
+            UPSTREAM_POOL_HITS_TOTAL.inc();
+            stream
+        },
+        None => {
+            UPSTREAM_POOL_MISSES_TOTAL.inc();
+            connect_upstream(server_addr, &app).await?
+        },
+    };
+    if let Some(pool) = &upstream_pool {
+        UPSTREAM_POOL_IDLE.set(pool.idle() as f64);
+    }
+    let upstream = server_addr.to_string();
+    let server_addr = server_stream.peer_addr()?;
+    // Through a forward proxy the peer is the proxy, label the metrics with the upstream instead
+    let server_label = if app.upstream_proxy.is_some() { upstream.clone() } else { server_addr.to_string() };
 
-    let client_addr = format_client_address(&client_stream.peer_addr()?);
+    let client_ip = format_client_address(&client_stream.peer_addr()?);
+    let client_addr = app.client_label.format(&client_ip);
+    // The comments end up in the server logs, so they get the hashed address with --anonymize-client-logs
+    let annotate_client = match (app.annotate_client, app.anonymize_client_logs) {
+        (true, true) => Some(client_addr.clone()),
+        (true, false) => Some(client_ip),
+        (false, _) => None,
+    };
 
-    let log_mongo_messages = app.log_mongo_messages;
+    let log_sample_rate = if app.log_mongo_messages { Some(app.log_sample_rate) } else { None };
     let tracing_enabled = app.tracer.is_some();
+    let strict_handshake = app.strict_handshake;
+    let policy = app.policy.clone();
+    let max_message_size = app.max_message_size;
+    let buffer_size = app.buffer_size;
+    let tracker_channel_size = app.tracker_channel_size;
+    let parse_timeout = app.parse_timeout;
+    let parse_budget = app.parse_budget;
+    let tcp_keepalive = app.tcp_keepalive;
+    let nodelay = app.nodelay;
+    let tracker_overflow = app.tracker_overflow;
+    let tracking_mode = app.tracking_mode;
+    let profile_locking = app.profile_locking;
+    let proxy_label = app.proxy_label.clone();
+    let latency_injector = app.inject_latency.map(LatencyInjector::new);
+    let hello_rewriter = app.rewrite_hello.clone();
+
+    let max_connection_lifetime = app.max_connection_lifetime;
+    let activity = match &active_connection {
+        Some(connection) => Some(connection.activity()),
+        None if max_connection_lifetime.is_some() => Some(ConnectionActivity::unregistered()),
+        None => None,
+    };
+    let lifetime_activity = activity.clone();
+    let shed_signal = active_connection.as_mut().and_then(|c| c.take_shed_signal());
+
+    let fault_delay = chaos::pick_fault_delay(app.fault_drop_prob);
+    if let Some(delay) = fault_delay {
+        warn!("Injecting a fault: closing the connection from {} in {:?}", client_addr, delay);
+    }
 
     let tracker = Arc::new(Mutex::new(
             MongoStatsTracker::new(
                 &client_addr,
-                &server_addr.to_string(),
+                &server_label,
                 server_addr,
                 app)));
     let client_tracker = tracker.clone();
     let server_tracker = tracker.clone();
+    let lifetime_tracker = tracker.clone();
 
-    client_stream.set_nodelay(true)?;
-    server_stream.set_nodelay(true)?;
+    client_stream.set_nodelay(nodelay.client)?;
+    server_stream.set_nodelay(nodelay.server)?;
+
+    if let Some(keepalive) = tcp_keepalive {
+        sockopt::set_keepalive(&client_stream, keepalive)?;
+        sockopt::set_keepalive(&server_stream, keepalive)?;
+    }
 
     // Start the trackers to parse and track MongoDb messages from the input stream. This works by
     // having the proxy tasks send a copy of the bytes over a channel and process that channel
     // as a stream of bytes, extracting MongoDb messages and tracking the metrics from there.
 
-    let (client_tx, client_rx): (mpsc::Sender<BufBytes>, mpsc::Receiver<BufBytes>) = mpsc::channel(32);
-    let (server_tx, server_rx): (mpsc::Sender<BufBytes>, mpsc::Receiver<BufBytes>) = mpsc::channel(32);
+    let (client_tx, client_rx): (ChunkSender<BufBytes>, ChunkReceiver<BufBytes>) = chunk_queue(tracker_channel_size);
+    let (server_tx, server_rx): (ChunkSender<BufBytes>, ChunkReceiver<BufBytes>) = chunk_queue(tracker_channel_size);
 
     let signal_client = client_tx.clone();
     let signal_server = server_tx.clone();
 
-    tokio::spawn(async move {
-        track_messages(client_rx, log_mongo_messages, tracing_enabled, move |hdr, msg| {
-            let mut tracker = client_tracker.lock().unwrap();
+    let metrics = TrackerMetrics::new(&proxy_label);
+    let client_lock_hold = metrics.client_lock_hold.clone();
+    let server_lock_hold = metrics.server_lock_hold.clone();
+
+    let coverage = Arc::new(TrackerCoverage::new(&metrics));
+    let client_queue = TrackerQueue::new(client_rx, coverage.clone(), &metrics);
+    let server_queue = TrackerQueue::new(server_rx, coverage.clone(), &metrics);
+
+    let mut client_fork = TrackerFork::new(client_tx, signal_server, tracker_overflow, tracking_mode, activity.clone(), coverage.clone(), metrics.clone());
+    let mut server_fork = TrackerFork::new(server_tx, signal_client, tracker_overflow, tracking_mode, activity, coverage, metrics);
+
+    let client_tracking = tokio::spawn(async move {
+        track_messages(client_queue, log_sample_rate, tracing_enabled, max_message_size, parse_timeout, parse_budget, move |hdr, msg| {
+            // A panic while tracking poisons the lock, keep tracking with the state as it is
+            let mut tracker = client_tracker.lock().unwrap_or_else(PoisonError::into_inner);
+            let locked = if profile_locking { Some(Instant::now()) } else { None };
             tracker.track_client_request(&hdr, &msg);
+            if let Some(locked) = locked {
+                client_lock_hold.observe(locked.elapsed().as_secs_f64());
+            }
         }).await?;
         Ok::<(), io::Error>(())
     }.instrument(info_span!("client tracker")));
 
-    tokio::spawn(async move {
-        track_messages(server_rx, log_mongo_messages, false, move |hdr, msg| {
-            let mut tracker = server_tracker.lock().unwrap();
+    let server_tracking = tokio::spawn(async move {
+        track_messages(server_queue, log_sample_rate, false, max_message_size, parse_timeout, parse_budget, move |hdr, msg| {
+            let mut tracker = server_tracker.lock().unwrap_or_else(PoisonError::into_inner);
+            let locked = if profile_locking { Some(Instant::now()) } else { None };
             tracker.track_server_response(hdr, msg);
+            if let Some(locked) = locked {
+                server_lock_hold.observe(locked.elapsed().as_secs_f64());
+            }
         }).await?;
         Ok::<(), io::Error>(())
     }.instrument(info_span!("server tracker")));
@@ -281,78 +1143,594 @@ async fn handle_connection(server_addr: &str, client_stream: TcpStream, app: App
     let (mut read_client, mut write_client) = client_stream.into_split();
     let (mut read_server, mut write_server) = server_stream.into_split();
 
-    let client_task = async {
-        proxy_bytes(&mut read_client, &mut write_server, client_tx, signal_server).await?;
-        Ok::<(), io::Error>(())
-    }.instrument(info_span!("client proxy"));
+    if strict_handshake {
+        let handshake = check_handshake(
+            &mut read_client, &mut write_server, &mut read_server, &mut client_fork, max_message_size);
+        let response = match time::timeout(parse_timeout, handshake).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(ConnectionClose::Client),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(handshake_violation("timeout", "no handshake within the parse timeout")),
+        };
 
-    let server_task = async {
-        proxy_bytes(&mut read_server, &mut write_client, server_tx, signal_client).await?;
-        Ok::<(), io::Error>(())
+        if let Some(response) = response {
+            match hello_rewriter.as_ref().and_then(|rewriter| rewriter.rewrite(&response)) {
+                Some(rewritten) => write_client.write_all(&rewritten).await?,
+                None => write_client.write_all(&response).await?,
+            }
+            server_fork.send(&response).await;
+        }
+    }
+
+    // Error reply to send to the client if the proxy rejects a request
+    let mut rejection = None;
+
+    let client_task = async {
+        if policy.is_enforcing() || annotate_client.is_some() {
+            proxy_client_messages(
+                &mut read_client, &mut write_server, client_fork,
+                &policy, annotate_client.as_deref(), max_message_size, &mut rejection).await
+        } else {
+            proxy_bytes(&mut read_client, &mut write_server, client_fork, None, buffer_size).await
+        }
+    }.instrument(info_span!("client proxy"));
+
+    let server_task = async {
+        if let Some(rewriter) = &hello_rewriter {
+            proxy_server_messages(
+                &mut read_server, &mut write_client, server_fork,
+                latency_injector, rewriter, max_message_size).await
+        } else {
+            proxy_bytes(&mut read_server, &mut write_client, server_fork, latency_injector, buffer_size).await
+        }
     }.instrument(info_span!("server proxy"));
 
-    match tokio::try_join!(client_task, server_task) {
-        Ok(_) => Ok(()),
-        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
-        Err(e) => Err(e),
+    // Whichever side finishes first ends the connection, the other side is dropped
+    // together with its halves of the sockets.
+    let result = tokio::select! {
+        res = client_task => res.map(|_| ConnectionClose::Client),
+        res = server_task => res.map(|_| ConnectionClose::Server),
+        _ = chaos::injected_fault(fault_delay) => {
+            warn!("Injected fault: closing the connection from {}", client_addr);
+            Err(io::Error::new(io::ErrorKind::ConnectionAborted, "injected fault"))
+        },
+        _ = connlimit::shed_signal(shed_signal) => {
+            info!("Closing the idle connection from {} to make room for new connections", client_addr);
+            Err(io::Error::new(io::ErrorKind::ConnectionAborted, "idle connection shed"))
+        },
+        _ = connlimit::lifetime_expired(max_connection_lifetime, lifetime_activity,
+                || lifetime_tracker.lock().map(|t| t.is_between_messages()).unwrap_or(false)) => {
+            Ok(ConnectionClose::Recycled)
+        },
+    };
+
+    if let Some(reply) = rejection {
+        if let Err(e) = write_client.write_all(&reply).await {
+            debug!("Failed to send the error reply: {}", e);
+        }
+        return result;
+    }
+
+    // A client that disconnected cleanly can leave the server connection to the next one
+    if let (Some(pool), Ok(ConnectionClose::Client)) = (&upstream_pool, &result) {
+        drop(read_client);
+        drop(write_client);
+        if is_server_connection_reusable(client_tracking, server_tracking, &tracker).await {
+            match read_server.reunite(write_server) {
+                Ok(stream) => {
+                    if pool.put(&upstream, stream) {
+                        debug!("Returned the connection to {} to the pool", upstream);
+                    }
+                    UPSTREAM_POOL_IDLE.set(pool.idle() as f64);
+                },
+                Err(e) => warn!("Failed to reunite the server connection: {}", e),
+            }
+        }
+    }
+
+    result
+}
+
+// Read the TLS ClientHello to choose the upstream by the server name, then pass the bytes
+// through unchanged, the ClientHello included. The upstream of the proxy (or the original
+// destination) is the fallback for the server names without a route. As the messages are
+// encrypted there are only the connection level metrics for these connections.
+async fn passthrough_tls(
+    fallback: &str,
+    mut client_stream: TcpStream,
+    routes: &SniRoutes,
+    app: &AppConfig,
+) -> Result<ConnectionClose, io::Error>
+{
+    let hello = match time::timeout(app.parse_timeout, sni::read_client_hello(&mut client_stream)).await {
+        Ok(Ok(Some(hello))) => hello,
+        Ok(Ok(None)) => return Ok(ConnectionClose::Client),
+        Ok(Err(e)) => {
+            SNI_ROUTES_TOTAL.with_label_values(&["not_tls"]).inc();
+            return Err(e);
+        },
+        Err(_) => {
+            SNI_ROUTES_TOTAL.with_label_values(&["not_tls"]).inc();
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "no TLS ClientHello within the parse timeout"));
+        },
+    };
+
+    let upstream = match hello.server_name.as_deref().and_then(|name| routes.upstream(name)) {
+        Some(upstream) => {
+            SNI_ROUTES_TOTAL.with_label_values(&["matched"]).inc();
+            upstream
+        },
+        None if !fallback.is_empty() => {
+            SNI_ROUTES_TOTAL.with_label_values(&["fallback"]).inc();
+            fallback
+        },
+        None => {
+            SNI_ROUTES_TOTAL.with_label_values(&["unrouted"]).inc();
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("no --sni-route for server name {:?}", hello.server_name)));
+        },
+    };
+    debug!("TLS server name {:?} goes to {}", hello.server_name, upstream);
+
+    let server_stream = connect_upstream(upstream, app).await?;
+    client_stream.set_nodelay(app.nodelay.client)?;
+    server_stream.set_nodelay(app.nodelay.server)?;
+    if let Some(keepalive) = app.tcp_keepalive {
+        sockopt::set_keepalive(&client_stream, keepalive)?;
+        sockopt::set_keepalive(&server_stream, keepalive)?;
+    }
+
+    let (mut read_client, mut write_client) = client_stream.into_split();
+    let (mut read_server, mut write_server) = server_stream.into_split();
+    write_server.write_all(&hello.raw).await?;
+
+    tokio::select! {
+        res = tokio::io::copy(&mut read_client, &mut write_server) => res.map(|_| ConnectionClose::Client),
+        res = tokio::io::copy(&mut read_server, &mut write_client) => res.map(|_| ConnectionClose::Server),
     }
 }
 
-// Move bytes between sockets, forking the byte stream into a mpsc channel
-// for processing. Another channel is used to notify the other tracker of
-// failures.
+// Once the trackers have processed all of the bytes, does the tracker state allow handing
+// the server connection over to another client
+async fn is_server_connection_reusable(
+    client_tracking: task::JoinHandle<Result<(), io::Error>>,
+    server_tracking: task::JoinHandle<Result<(), io::Error>>,
+    tracker: &Mutex<MongoStatsTracker>,
+) -> bool {
+    let tracking = async {
+        matches!(client_tracking.await, Ok(Ok(()))) && matches!(server_tracking.await, Ok(Ok(())))
+    };
+    match time::timeout(POOL_TRACKER_WAIT, tracking).await {
+        // After a tracker panic the state can't be trusted, and the lock is poisoned
+        Ok(true) => tracker.lock().map(|t| t.is_reusable()).unwrap_or(false),
+        _ => false,
+    }
+}
+
+// Pass the handshake request to the server, checking that the connection starts with a
+// hello/isMaster request and that the client waits for the response before sending
+// anything else. Returns the server response for the caller to forward, or None if the
+// server closed the connection. Any other start is a violation and an error is returned.
+async fn check_handshake(
+    read_client: &mut OwnedReadHalf,
+    write_server: &mut OwnedWriteHalf,
+    read_server: &mut OwnedReadHalf,
+    client_fork: &mut TrackerFork,
+    max_message_size: usize,
+) -> Result<Option<Vec<u8>>, io::Error>
+{
+    let request = match read_message(read_client, max_message_size).await {
+        Ok(Some(request)) => request,
+        Ok(None) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed before the handshake")),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            return Err(handshake_violation("invalid_message", &e.to_string()));
+        },
+        Err(e) => return Err(e),
+    };
+
+    let request_id = match MongoMessage::from_buffer(&request).await {
+        Ok((hdr, msg)) if policy::is_handshake(&msg) => hdr.request_id,
+        Ok(_) => return Err(handshake_violation("not_hello", "first message is not a hello")),
+        Err(e) => return Err(handshake_violation("invalid_message", &e.to_string())),
+    };
+
+    write_server.write_all(&request).await?;
+    client_fork.send(&request).await;
+
+    // Anything from the client before the response is a protocol violation, so one
+    // byte is enough to tell.
+    let mut early_data = [0; 1];
+    let response = tokio::select! {
+        response = read_message(read_server, max_message_size) => match response? {
+            Some(response) => response,
+            None => return Ok(None),
+        },
+        len = read_client.read(&mut early_data) => {
+            if len? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed during the handshake"));
+            }
+            return Err(handshake_violation("early_data", "client sent data before the hello response"));
+        },
+    };
+
+    if MsgHeader::from_bytes(&response).response_to != request_id {
+        return Err(handshake_violation("bad_response", "server response does not match the hello request"));
+    }
+
+    Ok(Some(response))
+}
+
+fn handshake_violation(reason: &str, message: &str) -> io::Error {
+    warn!("Handshake violation: {}", message);
+    HANDSHAKE_VIOLATIONS_TOTAL.with_label_values(&[reason]).inc();
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("handshake violation: {}", message))
+}
+
+// Move bytes between sockets, forking the byte stream to the tracker for
+// processing. Returns Ok when the read side reaches EOF. If there is a latency
+// injector the bytes are delayed by it before forwarding. The bytes are read up
+// to `buffer_size` at a time.
 async fn proxy_bytes(
     read_from: &mut OwnedReadHalf,
     write_to: &mut OwnedWriteHalf,
-    mut tracker_channel: mpsc::Sender<BufBytes>,
-    mut notify_channel: mpsc::Sender<BufBytes>,
+    mut tracker: TrackerFork,
+    mut latency_injector: Option<LatencyInjector>,
+    buffer_size: usize,
 ) -> Result<(), io::Error>
 {
-    let mut tracker_ok = true;
-
+    let mut buf = vec![0; buffer_size];
     loop {
-        let mut buf = [0; 1024];
         let len = read_from.read(&mut buf).await?;
 
         if len > 0 {
+            if let Some(injector) = latency_injector.as_mut() {
+                injector.delay(&buf[..len]).await;
+            }
             write_to.write_all(&buf[0..len]).await?;
+            tracker.send(&buf[..len]).await;
+        } else {
+            // EOF on read, the peer closed the connection
+            return Ok(());
+        }
+    }
+}
+
+// Move whole MongoDb messages from the client to the server, checking each of them against
+// the request policy before it is forwarded. This is used instead of proxy_bytes when the
+// policy needs to be enforced or the requests annotated with the client address. Returns Ok
+// when the client closes the connection and an error if a message violates the policy. In
+// the latter case `rejection` gets the error reply to send to the client, if it expects one.
+// The tracker gets the messages as the client sent them.
+async fn proxy_client_messages(
+    read_from: &mut OwnedReadHalf,
+    write_to: &mut OwnedWriteHalf,
+    mut tracker: TrackerFork,
+    policy: &RequestPolicy,
+    annotate_client: Option<&str>,
+    max_message_size: usize,
+    rejection: &mut Option<Vec<u8>>,
+) -> Result<(), io::Error>
+{
+    loop {
+        let message = match read_message(read_from, max_message_size).await? {
+            Some(message) => message,
+            None => return Ok(()),
+        };
 
-            if tracker_ok {
-                let bytes = bytes::Bytes::copy_from_slice(&buf[..len]);
+        match MongoMessage::from_buffer(&message).await {
+            Ok((hdr, msg)) => {
+                if let Err(violation) = policy.check(&msg) {
+                    warn!("Rejecting client request: {}", violation.reason);
 
-                if let Err(e) = tracker_channel.send(Ok(bytes)).await {
-                    error!("error sending to tracker, stop: {}", e);
-                    tracker_ok = false;
+                    // Only OP_MSG requests get an error reply. Legacy drivers would expect
+                    // an OP_REPLY, or no reply at all.
+                    if let MongoMessage::Msg(m) = &msg {
+                        if m.flag_bits & MSG_FLAG_MORE_TO_COME == 0 {
+                            *rejection = mongodb::error_reply(
+                                hdr.request_id,
+                                violation.code,
+                                violation.code_name,
+                                &violation.reason).ok();
+                        }
+                    }
 
-                    // Let the other side know that we're closed.
-                    let notification = io::Error::new(
-                        io::ErrorKind::UnexpectedEof, "notify channel close");
-                    let _ = notify_channel.send(Err(notification)).await;
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, violation.reason));
                 }
+            },
+            Err(e) => {
+                // Pass the message on, the server will deal with it
+                debug!("Policy check failed to parse the message: {}", e);
+            },
+        }
+
+        match annotate_client.and_then(|client| annotate::annotate_client(&message, client)) {
+            Some(annotated) => write_to.write_all(&annotated).await?,
+            None => write_to.write_all(&message).await?,
+        }
+        tracker.send(&message).await;
+    }
+}
+
+// Move whole MongoDb messages from the server to the client, rewriting the member addresses
+// in the hello responses. This is used instead of proxy_bytes when --rewrite-hello is enabled.
+// The tracker gets the messages as the server sent them.
+async fn proxy_server_messages(
+    read_from: &mut OwnedReadHalf,
+    write_to: &mut OwnedWriteHalf,
+    mut tracker: TrackerFork,
+    mut latency_injector: Option<LatencyInjector>,
+    rewriter: &HelloRewriter,
+    max_message_size: usize,
+) -> Result<(), io::Error>
+{
+    loop {
+        let message = match read_message(read_from, max_message_size).await? {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+
+        if let Some(injector) = latency_injector.as_mut() {
+            injector.delay(&message).await;
+        }
+
+        match rewriter.rewrite(&message) {
+            Some(rewritten) => write_to.write_all(&rewritten).await?,
+            None => write_to.write_all(&message).await?,
+        }
+        tracker.send(&message).await;
+    }
+}
+
+// Read a complete MongoDb message, header included. Returns None on EOF before the
+// start of a message.
+async fn read_message(read_from: &mut OwnedReadHalf, max_message_size: usize)
+    -> Result<Option<Vec<u8>>, io::Error>
+{
+    let mut header = [0; HEADER_LENGTH];
+    let len = read_from.read(&mut header).await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    read_from.read_exact(&mut header[len..]).await?;
+
+    let message_length = LittleEndian::read_u32(&header[..4]) as usize;
+    if message_length < HEADER_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid MongoDb header"));
+    }
+    if message_length > max_message_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("message length {} exceeds the maximum of {}", message_length, max_message_size)));
+    }
+
+    let mut message = vec![0; message_length];
+    message[..HEADER_LENGTH].copy_from_slice(&header);
+    read_from.read_exact(&mut message[HEADER_LENGTH..]).await?;
+
+    Ok(Some(message))
+}
+
+// The bytes proxied on a connection and the bytes that the trackers got to parse, both
+// directions together. The difference is what was dropped at --tracker-overflow or left
+// unparsed when a tracker gave up. The share that was tracked is recorded once both the
+// proxying and the trackers are done with the connection.
+struct TrackerCoverage {
+    proxied: AtomicU64,
+    tracked: AtomicU64,
+    ratio: Histogram,
+}
+
+impl TrackerCoverage {
+    fn new(metrics: &TrackerMetrics) -> Self {
+        TrackerCoverage {
+            proxied: AtomicU64::new(0),
+            tracked: AtomicU64::new(0),
+            ratio: metrics.coverage_ratio.clone(),
+        }
+    }
+}
+
+impl Drop for TrackerCoverage {
+    fn drop(&mut self) {
+        let proxied = *self.proxied.get_mut();
+        if proxied > 0 {
+            self.ratio.observe(*self.tracked.get_mut() as f64 / proxied as f64);
+        }
+    }
+}
+
+// The tracker metrics of a connection, labeled with the proxy it came in through. They
+// are looked up once per connection rather than for every proxied chunk.
+#[derive(Clone)]
+struct TrackerMetrics {
+    buffered_bytes: Gauge,
+    chunks_dropped: Counter,
+    blocked: Counter,
+    blocked_seconds: Counter,
+    coverage_ratio: Histogram,
+    panics: Counter,
+    parse_timeouts: Counter,
+    parse_abandoned: Counter,
+    parse_resyncs: Counter,
+    client_lock_hold: Histogram,
+    server_lock_hold: Histogram,
+}
+
+impl TrackerMetrics {
+    fn new(proxy_label: &str) -> Self {
+        TrackerMetrics {
+            buffered_bytes: TRACKER_BUFFERED_BYTES.with_label_values(&[proxy_label]),
+            chunks_dropped: TRACKER_CHUNKS_DROPPED_TOTAL.with_label_values(&[proxy_label]),
+            blocked: TRACKER_BLOCKED_TOTAL.with_label_values(&[proxy_label]),
+            blocked_seconds: TRACKER_BLOCKED_SECONDS_TOTAL.with_label_values(&[proxy_label]),
+            coverage_ratio: TRACKER_COVERAGE_RATIO.with_label_values(&[proxy_label]),
+            panics: TRACKER_PANICS_TOTAL.with_label_values(&[proxy_label]),
+            parse_timeouts: PARSE_TIMEOUTS_TOTAL.with_label_values(&[proxy_label]),
+            parse_abandoned: PARSE_ABANDONED_TOTAL.with_label_values(&[proxy_label]),
+            parse_resyncs: PARSE_RESYNCS_TOTAL.with_label_values(&[proxy_label]),
+            client_lock_hold: TRACKER_LOCK_HOLD_SECONDS.with_label_values(&["client", proxy_label]),
+            server_lock_hold: TRACKER_LOCK_HOLD_SECONDS.with_label_values(&["server", proxy_label]),
+        }
+    }
+}
+
+// Sends a copy of the proxied bytes to the tracker over a chunk queue. Another
+// channel is used to notify the other side's tracker of failures. As all the proxied
+// bytes pass through here, this also marks the connection active for --shed-idle.
+struct TrackerFork {
+    tracker_channel: ChunkSender<BufBytes>,
+    notify_channel: ChunkSender<BufBytes>,
+    overflow: TrackerOverflow,
+    mode: TrackingMode,
+    tracker_ok: bool,
+    activity: Option<ConnectionActivity>,
+    coverage: Arc<TrackerCoverage>,
+    metrics: TrackerMetrics,
+}
+
+impl TrackerFork {
+
+    fn new(
+        tracker_channel: ChunkSender<BufBytes>,
+        notify_channel: ChunkSender<BufBytes>,
+        overflow: TrackerOverflow,
+        mode: TrackingMode,
+        activity: Option<ConnectionActivity>,
+        coverage: Arc<TrackerCoverage>,
+        metrics: TrackerMetrics,
+    ) -> Self {
+        TrackerFork {
+            tracker_channel,
+            notify_channel,
+            overflow,
+            mode,
+            tracker_ok: true,
+            activity,
+            coverage,
+            metrics,
+        }
+    }
+
+    // Send a copy of the bytes to the tracker. If the tracker has gone away, or can't
+    // keep up and the overflow policy says so, we stop sending to it and let the other
+    // side's tracker know. Reliable tracking always waits for the tracker.
+    async fn send(&mut self, buf: &[u8]) {
+        if let Some(activity) = &self.activity {
+            activity.touch();
+        }
+        self.coverage.proxied.fetch_add(buf.len() as u64, Ordering::Relaxed);
+
+        if !self.tracker_ok {
+            return;
+        }
+
+        let bytes = bytes::Bytes::copy_from_slice(buf);
+        let buffered = buf.len() as f64;
+        self.metrics.buffered_bytes.add(buffered);
+
+        let result = match (self.mode, self.overflow) {
+            (TrackingMode::Reliable, _) | (_, TrackerOverflow::Block) => match self.tracker_channel.try_send(Ok(bytes)) {
+                // The tracker is behind, wait for it and count how long the proxying stalled
+                Err(TrySendError::Full(bytes)) => {
+                    self.metrics.blocked.inc();
+                    let blocked = Instant::now();
+                    let result = self.tracker_channel.send(bytes).await;
+                    self.metrics.blocked_seconds.inc_by(blocked.elapsed().as_secs_f64());
+                    result.map_err(|e| e.to_string())
+                },
+                other => other.map_err(|e| e.to_string()),
+            },
+            // Make room by evicting the oldest queued chunk, the tracker resyncs over the gap.
+            // A notification from the other side can be evicted too, this tracker then just
+            // keeps going until the connection closes.
+            (TrackingMode::BestEffort, TrackerOverflow::DropOldest) => match self.tracker_channel.send_evicting(Ok(bytes)) {
+                Ok(Some(Ok(evicted))) => {
+                    self.metrics.chunks_dropped.inc();
+                    self.metrics.buffered_bytes.sub(evicted.len() as f64);
+                    Ok(())
+                },
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.to_string()),
+            },
+            (TrackingMode::BestEffort, TrackerOverflow::Disable) => self.tracker_channel.try_send(Ok(bytes))
+                .map_err(|e| e.to_string()),
+        };
+
+        if let Err(e) = result {
+            error!("error sending to tracker, stop: {}", e);
+            self.tracker_ok = false;
+            self.metrics.buffered_bytes.sub(buffered);
+
+            // Let the other side know that we're closed.
+            let notification = io::Error::new(
+                io::ErrorKind::UnexpectedEof, "notify channel close");
+            let _ = self.notify_channel.send(Err(notification)).await;
+        }
+    }
+}
+
+// The receiving end of a tracker channel, taking the chunks off the buffered bytes
+// gauge as the tracker reads them. Whatever the tracker leaves unread when it stops
+// is taken off when the queue is dropped.
+struct TrackerQueue {
+    rx: ChunkReceiver<BufBytes>,
+    coverage: Arc<TrackerCoverage>,
+    metrics: TrackerMetrics,
+}
+
+impl TrackerQueue {
+    fn new(rx: ChunkReceiver<BufBytes>, coverage: Arc<TrackerCoverage>, metrics: &TrackerMetrics) -> Self {
+        TrackerQueue {
+            rx,
+            coverage,
+            metrics: metrics.clone(),
+        }
+    }
+}
+
+impl Stream for TrackerQueue {
+    type Item = BufBytes;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<BufBytes>> {
+        let item = self.rx.poll_recv(cx);
+        if let Poll::Ready(Some(Ok(bytes))) = &item {
+            self.metrics.buffered_bytes.sub(bytes.len() as f64);
+            self.coverage.tracked.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        }
+        item
+    }
+}
+
+impl Drop for TrackerQueue {
+    fn drop(&mut self) {
+        self.rx.close();
+        while let Some(item) = self.rx.try_recv() {
+            if let Ok(bytes) = item {
+                self.metrics.buffered_bytes.sub(bytes.len() as f64);
             }
-        } else {
-            // EOF on read, return Err to signal try_join! to return
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "EOF"));
         }
     }
 }
 
-// Process the mpsc channel as a byte stream, parsing MongoDb messages
+// Process the chunk queue as a byte stream, parsing MongoDb messages
 // and sending them off to a tracker.
 async fn track_messages<F>(
-    rx: mpsc::Receiver<BufBytes>,
-    log_mongo_messages: bool,
+    queue: TrackerQueue,
+    log_sample_rate: Option<u64>,
     collect_tracing_data: bool,
+    max_message_size: usize,
+    parse_timeout: Duration,
+    parse_budget: Option<Duration>,
     mut tracker_fn: F
 ) -> Result<(), io::Error>
     where F: FnMut(MsgHeader, MongoMessage)
 {
-    let mut s = stream_reader(rx);
+    let metrics = queue.metrics.clone();
+    let mut s = stream_reader(queue);
     loop {
-        match MongoMessage::from_reader(&mut s, log_mongo_messages, collect_tracing_data).await {
-            Ok((hdr, msg)) => {
-                tracker_fn(hdr, msg);
-            },
+        // Idle connections can wait for the next message as long as they like, but once
+        // we have the header the rest of the message is expected to follow promptly.
+        let mut hdr = match MsgHeader::from_reader(&mut s).await {
+            Ok(hdr) => hdr,
             Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
                 return Ok(());
             },
@@ -360,7 +1738,117 @@ async fn track_messages<F>(
                 error!("Tracker failed: {}", e);
                 return Err(e);
             }
+        };
+
+        // Garbage in the header usually means that we've lost track of the message
+        // boundaries. Rather than giving up on the connection, skip ahead to the next
+        // thing that looks like a header.
+        if !hdr.is_plausible(max_message_size) {
+            match MsgHeader::resync(&hdr, &mut s, max_message_size).await {
+                Ok((next_hdr, skipped)) => {
+                    metrics.parse_resyncs.inc();
+                    warn!("Invalid message header: {}, skipped {} bytes to resync", hdr, skipped);
+                    hdr = next_hdr;
+                },
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(());
+                },
+                Err(e) => {
+                    error!("Tracker failed: {}", e);
+                    return Err(e);
+                }
+            }
         }
+
+        let log_message = match log_sample_rate {
+            Some(rate) => sample_message_log(rate),
+            None => false,
+        };
+
+        let mut budgeted = ParseBudget::new(&mut s, parse_budget);
+        let parse_message = CatchPanic::new(MongoMessage::from_header(
+            &hdr, &mut budgeted, log_message, collect_tracing_data, max_message_size));
+
+        // A panic in the parser leaves us somewhere in the middle of the message. Carry
+        // on with the next header, which most likely resyncs to the next message.
+        let parsed = match time::timeout(parse_timeout, parse_message).await {
+            Ok(Ok(parsed)) => Ok(parsed),
+            Ok(Err(panic)) => {
+                metrics.panics.inc();
+                error!("Tracker panicked parsing {}: {}", hdr, panic);
+                continue;
+            },
+            Err(e) => Err(e),
+        };
+
+        match parsed {
+            Ok(Ok(msg)) => {
+                let request_id = hdr.request_id;
+                if let Err(panic) = catchpanic::catch_panic(|| tracker_fn(hdr, msg)) {
+                    metrics.panics.inc();
+                    error!("Tracker panicked tracking request_id {}: {}", request_id, panic);
+                }
+            },
+            Ok(Err(_)) if budgeted.is_exhausted() => {
+                // Skip the rest of the message, so that the next one starts at the boundary
+                let remaining = ((hdr.message_length - HEADER_LENGTH) as u64).saturating_sub(budgeted.consumed());
+                metrics.parse_abandoned.inc();
+                warn!("Parsing took too long, skipping {} bytes: {}", remaining, hdr);
+
+                let mut rest = (&mut s).take(remaining);
+                match time::timeout(parse_timeout, tokio::io::copy(&mut rest, &mut tokio::io::sink())).await {
+                    Ok(Ok(len)) if len == remaining => {},
+                    Ok(Ok(_)) => return Ok(()),
+                    Ok(Err(e)) => {
+                        error!("Tracker failed: {}", e);
+                        return Err(e);
+                    },
+                    Err(_) => {
+                        metrics.parse_timeouts.inc();
+                        warn!("Timed out waiting for the rest of the message: {}, stop tracking", hdr);
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "message parse timed out"));
+                    },
+                }
+            },
+            Ok(Err(e)) => {
+                error!("Tracker failed: {}", e);
+                return Err(e);
+            },
+            Err(_) => {
+                metrics.parse_timeouts.inc();
+                warn!("Timed out waiting for the rest of the message: {}, stop tracking", hdr);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "message parse timed out"));
+            },
+        }
+    }
+}
+
+// Memory, file descriptors and CPU time of the proxy process itself, read from /proc
+// whenever the metrics are gathered
+#[cfg(target_os = "linux")]
+fn register_process_metrics() {
+    let collector = ProcessCollector::new(process::id() as libc::pid_t, "mongoproxy");
+    if let Err(e) = prometheus::register(Box::new(collector)) {
+        warn!("Failed to register the process metrics: {}", e);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn register_process_metrics() {}
+
+// Map an io::Error to a metric label value. Keep this to a small fixed set.
+fn classify_io_error(e: &io::Error) -> &'static str {
+    if upstreamproxy::is_upstream_proxy_error(e) {
+        return "upstream_proxy";
+    }
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => "connection_refused",
+        io::ErrorKind::ConnectionReset => "connection_reset",
+        io::ErrorKind::TimedOut => "timed_out",
+        io::ErrorKind::AddrNotAvailable => "addr_not_available",
+        io::ErrorKind::UnexpectedEof => "unexpected_eof",
+        io::ErrorKind::PermissionDenied => "rejected",
+        _ => "other",
     }
 }
 
@@ -372,49 +1860,372 @@ fn lookup_address(addr: &str) -> std::io::Result<SocketAddr> {
     Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no usable address found"))
 }
 
-// Return the peer address of the stream without the :port
+// Return the peer address of the stream without the :port. IPv4 clients of a dual
+// stack listener are formatted as plain IPv4 addresses instead of ::ffff:a.b.c.d, so
+// that they get the same label regardless of how the proxy listens.
 fn format_client_address(sockaddr: &SocketAddr) -> String {
-    let mut addr_str = sockaddr.to_string();
-    if let Some(pos) = addr_str.find(':') {
-        let _ = addr_str.split_off(pos);
+    match sockaddr.ip() {
+        IpAddr::V6(ip) => match ip.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Ipv4Addr::new(a, b, c, d).to_string(),
+            _ => ip.to_string(),
+        },
+        ip => ip.to_string(),
     }
-    addr_str
 }
 
-// Parse the local and remote address pair from provided proxy definition
-fn parse_proxy_addresses(proxy_def: &str) -> Result<(String,String), io::Error> {
-    if let Some(pos) = proxy_def.find(':') {
-        let (local_port, remote_hostport) = proxy_def.split_at(pos);
-        let local_addr = format!("0.0.0.0:{}", local_port);
+// Decide whether to log the next message, 1 in every `rate` messages across all
+// the connections.
+fn sample_message_log(rate: u64) -> bool {
+    static LOG_SAMPLE_COUNTER: AtomicU64 = AtomicU64::new(0);
+    LOG_SAMPLE_COUNTER.fetch_add(1, Ordering::Relaxed) % rate == 0
+}
 
-        Ok((local_addr, remote_hostport[1..].to_string()))
-    } else {
-        Ok((format!("0.0.0.0:{}", proxy_def), String::from("")))
+// Credentials for HTTP Basic authentication on the admin endpoints
+#[derive(Clone)]
+pub struct AdminAuth {
+    user: String,
+    password: String,
+    exempt_livez: bool,
+}
+
+impl AdminAuth {
+
+    // Parse the credentials from a "user:password" string
+    fn parse(spec: &str, exempt_livez: bool) -> Result<Self, io::Error> {
+        match spec.find(':') {
+            Some(pos) if pos > 0 => Ok(AdminAuth {
+                user: spec[..pos].to_string(),
+                password: spec[pos+1..].to_string(),
+                exempt_livez,
+            }),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "expecting user:password")),
+        }
+    }
+
+    fn is_authorized(&self, request: &rouille::Request) -> bool {
+        if self.exempt_livez && request.url() == "/livez" {
+            return true;
+        }
+
+        match rouille::input::basic_http_auth(request) {
+            Some(creds) => {
+                // Evaluate both so that a wrong user name takes as long as a wrong password
+                let user_ok = constant_time_eq(creds.login.as_bytes(), self.user.as_bytes());
+                let password_ok = constant_time_eq(creds.password.as_bytes(), self.password.as_bytes());
+                user_ok & password_ok
+            },
+            None => false,
+        }
+    }
+}
+
+// Serves /debug/profile. Only one profile runs at a time, whichever request gets it.
+#[cfg(feature = "profiling")]
+fn profile_handler(enable_profiling: bool) -> impl Fn(&rouille::Request) -> rouille::Response + Send + Sync {
+    let profiler = if enable_profiling { Some(CpuProfiler::new()) } else { None };
+
+    move |request| {
+        let profiler = match &profiler {
+            Some(profiler) => profiler,
+            None => return rouille::Response::text("profiling not enabled, see --enable-profiling")
+                .with_status_code(404),
+        };
+        let duration = match profiling::parse_seconds(request.get_param("seconds").as_deref()) {
+            Ok(duration) => duration,
+            Err(e) => return rouille::Response::text(e).with_status_code(400),
+        };
+        match profiler.flamegraph(duration) {
+            Ok(svg) => rouille::Response::from_data("image/svg+xml", svg),
+            Err(ProfileError::Busy) => rouille::Response::text("a profile is already running")
+                .with_status_code(409),
+            Err(ProfileError::Failed(e)) => {
+                warn!("CPU profile failed: {}", e);
+                rouille::Response::text(e).with_status_code(500)
+            },
+        }
     }
 }
 
-pub fn start_admin_listener(endpoint: &str) {
-    let endpoint = endpoint.to_owned();
-    thread::spawn(||
-        rouille::start_server(endpoint, move |request| {
+#[cfg(not(feature = "profiling"))]
+fn profile_handler(_enable_profiling: bool) -> impl Fn(&rouille::Request) -> rouille::Response + Send + Sync {
+    |_| rouille::Response::text("profiling not built in, see the profiling cargo feature").with_status_code(404)
+}
+
+// Compare two byte strings in time that only depends on their length
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Start the admin HTTP server in a separate thread. The listener is bound inside the
+// thread, but we wait for the outcome so that bind errors are reported at startup.
+pub fn start_admin_listener(
+    endpoint: SocketAddr,
+    auth: Option<AdminAuth>,
+    health: UpstreamHealth,
+    static_labels: Vec<(String, String)>,
+    slow_queries: Option<SlowQueryFeed>,
+    enable_profiling: bool,
+    command_stats: bool,
+    enable_metrics_reset: bool,
+) -> Result<(), io::Error>
+{
+    let (bind_tx, bind_rx) = std::sync::mpsc::channel();
+    let debug_profile = profile_handler(enable_profiling);
+
+    thread::spawn(move || {
+        let server = rouille::Server::new(endpoint, move |request| {
+            if let Some(auth) = &auth {
+                if !auth.is_authorized(request) {
+                    return rouille::Response::basic_http_auth_login_required("mongoproxy");
+                }
+            }
+
             router!(request,
                 (GET) (/) => {
                     rouille::Response::html(
                         "<a href='/metrics'>metrics</a>\n<br>\n\
-                         <a href='/health'>health</a>\n")
+                         <a href='/health'>health</a>\n<br>\n\
+                         <a href='/livez'>livez</a>\n<br>\n\
+                         <a href='/readyz'>readyz</a>\n<br>\n\
+                         <a href='/stats'>stats</a>\n<br>\n\
+                         <a href='/top-commands'>top-commands</a>\n")
                 },
                 (GET) (/health) => {
                     rouille::Response::text("OK")
                 },
+                (GET) (/livez) => {
+                    rouille::Response::text("OK")
+                },
+                (GET) (/readyz) => {
+                    if health.is_ready() {
+                        rouille::Response::text("OK")
+                    } else {
+                        rouille::Response::text("upstream unreachable").with_status_code(503)
+                    }
+                },
+                (GET) (/stats) => {
+                    if !command_stats {
+                        return rouille::Response::text("command stats not enabled, see --command-stats")
+                            .with_status_code(404);
+                    }
+                    rouille::Response::html(stats::render_html(&stats::response_latency_percentiles()))
+                },
+                (GET) (/top-commands) => {
+                    if !command_stats {
+                        return rouille::Response::text("command stats not enabled, see --command-stats")
+                            .with_status_code(404);
+                    }
+                    match stats::parse_top_params(request.get_param("n").as_deref(), request.get_param("window").as_deref()) {
+                        Ok((n, window)) => rouille::Response::from_data("application/json",
+                            stats::render_top_json(window, &stats::top_commands(n, window))),
+                        Err(e) => rouille::Response::text(e).with_status_code(400),
+                    }
+                },
+                (GET) (/slow-queries/stream) => {
+                    match slow_queries.as_ref().map(|feed| feed.subscribe()) {
+                        Some(Some(stream)) => rouille::Response {
+                            status_code: 200,
+                            headers: vec![
+                                ("Content-Type".into(), "text/event-stream".into()),
+                                ("Cache-Control".into(), "no-cache".into()),
+                            ],
+                            data: rouille::ResponseBody::from_reader(stream),
+                            upgrade: None,
+                        },
+                        Some(None) => rouille::Response::text("too many subscribers").with_status_code(503),
+                        None => rouille::Response::text("slow query stream not enabled, see --slow-query-ms")
+                            .with_status_code(404),
+                    }
+                },
+                (GET) (/debug/profile) => {
+                    debug_profile(request)
+                },
+                (POST) (/admin/reset-metrics) => {
+                    if !enable_metrics_reset {
+                        return rouille::Response::text("metrics reset not enabled, see --enable-metrics-reset")
+                            .with_status_code(404);
+                    }
+                    warn!("Resetting the metrics on request from {}", request.remote_addr());
+                    metricreset::reset();
+                    rouille::Response::text("OK")
+                },
                 (GET) (/metrics) => {
                     let encoder = TextEncoder::new();
-                    let metric_families = prometheus::gather();
+                    let mut metric_families = metricreset::gather();
+                    metriclabels::add_static_labels(&mut metric_families, &static_labels);
                     let mut buffer = vec![];
                     encoder.encode(&metric_families, &mut buffer).unwrap();
                     rouille::Response::from_data("text/plain", buffer)
                 },
                 _ => rouille::Response::empty_404()
             )
-        })
-    );
+        });
+
+        match server {
+            Ok(server) => {
+                let _ = bind_tx.send(Ok(()));
+                server.run();
+            },
+            Err(e) => {
+                // Keep the io::Error when there is one, so that the error kind is preserved
+                let e = match e.downcast::<io::Error>() {
+                    Ok(e) => *e,
+                    Err(e) => io::Error::new(io::ErrorKind::Other, e.to_string()),
+                };
+                let _ = bind_tx.send(Err(e));
+            },
+        }
+    });
+
+    match bind_rx.recv() {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "admin listener thread exited")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongoproxy::mongodb::MAX_MESSAGE_SIZE;
+
+    #[test]
+    fn test_lookup_address() {
+        assert_eq!("127.0.0.1:27017".parse::<SocketAddr>().unwrap(), lookup_address("127.0.0.1:27017").unwrap());
+        assert_eq!("[::1]:27017".parse::<SocketAddr>().unwrap(), lookup_address("[::1]:27017").unwrap());
+    }
+
+    #[test]
+    fn test_format_client_address() {
+        assert_eq!("10.0.0.1", format_client_address(&"10.0.0.1:51234".parse().unwrap()));
+        assert_eq!("2001:db8::1", format_client_address(&"[2001:db8::1]:51234".parse().unwrap()));
+        assert_eq!("::1", format_client_address(&"[::1]:51234".parse().unwrap()));
+        assert_eq!("10.0.0.1", format_client_address(&"[::ffff:10.0.0.1]:51234".parse().unwrap()));
+        assert_eq!("::a00:1", format_client_address(&"[::10.0.0.1]:51234".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_parse_capture() {
+        let reply = mongodb::error_reply(7, 13, "Unauthorized", "not allowed").unwrap();
+        let mut capture = vec![0xff; 5];
+        capture.extend_from_slice(&reply);
+        capture.extend_from_slice(&reply);
+
+        let mut out = Vec::new();
+        assert_eq!(2, parse_capture(&capture[..], &mut out, MAX_MESSAGE_SIZE).await.unwrap());
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("invalid header: "));
+        assert!(out.contains("skipped 5 bytes to resync"));
+        assert!(out.contains("message 2: op: 2013, "));
+
+        // A message cut short is an error
+        let truncated = &reply[..reply.len() - 1];
+        assert!(parse_capture(truncated, &mut Vec::new(), MAX_MESSAGE_SIZE).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_track_messages_across_chunks() {
+        // Small back to back messages like pipelined heartbeats, growing until they
+        // span several of the proxy_bytes reads
+        let mut stream = Vec::new();
+        let mut request_ids = Vec::new();
+        for i in 0..20 {
+            let padding = "x".repeat(i * 100);
+            let message = mongodb::command_reply(0, &bson::doc! { "hello": 1, "padding": padding, "$db": "admin" }).unwrap();
+            request_ids.push(MsgHeader::from_bytes(&message).request_id);
+            stream.extend_from_slice(&message);
+        }
+
+        // The messages come out whole wherever the chunks split them
+        for &chunk_size in &[1024, 1, 7, HEADER_LENGTH, stream.len()] {
+            let chunks: Vec<&[u8]> = stream.chunks(chunk_size).collect();
+            let (tx, rx) = chunk_queue(chunks.len());
+            for chunk in chunks {
+                tx.send(Ok(bytes::Bytes::copy_from_slice(chunk))).await.unwrap();
+            }
+            drop(tx);
+
+            let mut tracked = Vec::new();
+            let metrics = TrackerMetrics::new("test");
+            let coverage = Arc::new(TrackerCoverage::new(&metrics));
+            track_messages(TrackerQueue::new(rx, coverage.clone(), &metrics), None, false, MAX_MESSAGE_SIZE, Duration::from_secs(1), None, |hdr, msg| {
+                if let MongoMessage::Msg(_) = msg {
+                    tracked.push(hdr.request_id);
+                }
+            }).await.unwrap();
+            assert_eq!(request_ids, tracked, "chunk size {}", chunk_size);
+            assert_eq!(stream.len() as u64, coverage.tracked.load(Ordering::Relaxed));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_upstreams() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let app = AppConfig::new(None, false);
+
+        let upstreams = format!("127.0.0.1:1,{}", addr);
+        assert!(wait_for_upstreams(&[&upstreams], &app, Duration::from_secs(5)).await.is_ok());
+
+        drop(listener);
+        let e = wait_for_upstreams(&[&addr], &app, Duration::from_millis(100)).await.unwrap_err();
+        assert_eq!(format!("{} did not accept connections within 100ms", addr), e);
+    }
+
+    #[test]
+    fn test_classify_io_error() {
+        let err = |kind| io::Error::new(kind, "test");
+        assert_eq!("connection_refused", classify_io_error(&err(io::ErrorKind::ConnectionRefused)));
+        assert_eq!("connection_reset", classify_io_error(&err(io::ErrorKind::ConnectionReset)));
+        assert_eq!("timed_out", classify_io_error(&err(io::ErrorKind::TimedOut)));
+        assert_eq!("addr_not_available", classify_io_error(&err(io::ErrorKind::AddrNotAvailable)));
+        assert_eq!("unexpected_eof", classify_io_error(&err(io::ErrorKind::UnexpectedEof)));
+        assert_eq!("rejected", classify_io_error(&err(io::ErrorKind::PermissionDenied)));
+        assert_eq!("other", classify_io_error(&err(io::ErrorKind::Other)));
+        assert_eq!("other", classify_io_error(&err(io::ErrorKind::InvalidData)));
+
+        let proxy_err = io::Error::new(io::ErrorKind::ConnectionRefused,
+            upstreamproxy::UpstreamProxyError("refused".to_string()));
+        assert_eq!("upstream_proxy", classify_io_error(&proxy_err));
+    }
+
+    #[test]
+    fn test_admin_auth() {
+        let auth = AdminAuth::parse("user:pass:word", false).unwrap();
+        assert_eq!("user", auth.user);
+        assert_eq!("pass:word", auth.password);
+
+        assert!(AdminAuth::parse("nopassword", false).is_err());
+        assert!(AdminAuth::parse(":password", false).is_err());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_sample_message_log() {
+        let logged = (0..100).filter(|_| sample_message_log(10)).count();
+        assert_eq!(10, logged);
+        assert!((0..5).all(|_| sample_message_log(1)));
+    }
+
+    #[test]
+    fn test_bind_error_message() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let e = std::net::TcpListener::bind(&addr).unwrap_err();
+
+        assert_eq!(format!("failed to bind {}: already in use (is another mongoproxy running?)", addr),
+            bind_error_message(&addr, &e));
+
+        let e = io::Error::new(io::ErrorKind::PermissionDenied, "permission denied");
+        assert_eq!("failed to bind 0.0.0.0:80: permission denied", bind_error_message("0.0.0.0:80", &e));
+    }
 }
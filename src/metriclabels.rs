@@ -0,0 +1,92 @@
+use std::env;
+
+use prometheus::proto::{LabelPair, MetricFamily};
+
+// Kubernetes downward API environment variables and the metric labels they become
+const POD_ENV_LABELS: &[(&str, &str)] = &[
+    ("POD_NAME", "pod"),
+    ("POD_NAMESPACE", "namespace"),
+    ("NODE_NAME", "node"),
+];
+
+// Labels from the pod metadata in the environment. Variables that are not set (or
+// are empty) are left out.
+pub fn pod_labels_from_env() -> Vec<(String, String)> {
+    pod_labels(|name| env::var(name).ok())
+}
+
+fn pod_labels<F>(lookup: F) -> Vec<(String, String)>
+    where F: Fn(&str) -> Option<String>
+{
+    POD_ENV_LABELS.iter()
+        .filter_map(|(var, label)| {
+            lookup(var)
+                .filter(|value| !value.is_empty())
+                .map(|value| (label.to_string(), value))
+        })
+        .collect()
+}
+
+// Add the labels to all the gathered metrics. A metric that already has a label by
+// the same name keeps its own value.
+pub fn add_static_labels(families: &mut [MetricFamily], labels: &[(String, String)]) {
+    for family in families.iter_mut() {
+        for metric in family.mut_metric().iter_mut() {
+            for (name, value) in labels {
+                if metric.get_label().iter().any(|l| l.get_name() == name) {
+                    continue;
+                }
+                let mut label = LabelPair::new();
+                label.set_name(name.clone());
+                label.set_value(value.clone());
+                metric.mut_label().push(label);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Counter, CounterVec, Opts, Registry};
+
+    #[test]
+    fn test_pod_labels() {
+        let labels = pod_labels(|name| match name {
+            "POD_NAME" => Some("app-1234".to_string()),
+            "POD_NAMESPACE" => Some(String::new()),
+            _ => None,
+        });
+        assert_eq!(vec![("pod".to_string(), "app-1234".to_string())], labels);
+    }
+
+    #[test]
+    fn test_add_static_labels() {
+        let registry = Registry::new();
+        let counter = Counter::new("test_total", "test").unwrap();
+        let counter_vec = CounterVec::new(Opts::new("test_vec_total", "test"), &["pod"]).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(counter_vec.clone())).unwrap();
+        counter.inc();
+        counter_vec.with_label_values(&["own"]).inc();
+
+        let mut families = registry.gather();
+        let labels = vec![
+            ("pod".to_string(), "app-1234".to_string()),
+            ("node".to_string(), "node-1".to_string()),
+        ];
+        add_static_labels(&mut families, &labels);
+
+        let label_values = |family: &MetricFamily| -> Vec<(String, String)> {
+            family.get_metric()[0].get_label().iter()
+                .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                .collect()
+        };
+
+        assert_eq!(labels, label_values(&families[0]));
+        assert_eq!(vec![
+            ("pod".to_string(), "own".to_string()),
+            ("node".to_string(), "node-1".to_string()),
+        ], label_values(&families[1]));
+    }
+}
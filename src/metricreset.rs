@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+
+// The prometheus crate has no way to reset the registered metrics, and they are held
+// in statics all over the place. Instead a reset takes a snapshot of the counters and
+// histograms, and the snapshot is subtracted whenever the metrics are gathered, so
+// that they count from zero again. Gauges are left alone as they describe the current
+// state rather than what happened since start.
+lazy_static! {
+    static ref BASELINE: MetricsBaseline = MetricsBaseline::new();
+}
+
+// Gather the metrics of the default registry, relative to the last reset
+pub fn gather() -> Vec<MetricFamily> {
+    let mut families = prometheus::gather();
+    BASELINE.subtract(&mut families);
+    families
+}
+
+// Zero the counters and histograms of the default registry
+pub fn reset() {
+    BASELINE.reset(&prometheus::gather());
+}
+
+#[derive(Clone,Debug,PartialEq)]
+enum Sample {
+    Counter(f64),
+    Histogram { count: u64, sum: f64, buckets: Vec<u64> },
+}
+
+#[derive(Debug,Default)]
+pub struct MetricsBaseline {
+    samples: Mutex<HashMap<String, Sample>>,
+}
+
+impl MetricsBaseline {
+
+    pub fn new() -> Self {
+        MetricsBaseline {
+            samples: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Take the current values as the new zero
+    pub fn reset(&self, families: &[MetricFamily]) {
+        let mut samples = HashMap::new();
+        for family in families {
+            for metric in family.get_metric() {
+                if let Some(sample) = sample(family.get_field_type(), metric) {
+                    samples.insert(metric_key(family, metric), sample);
+                }
+            }
+        }
+        *self.samples.lock().unwrap() = samples;
+    }
+
+    // Subtract the values at the last reset from the gathered metrics. Metrics that
+    // did not exist at the time of the reset are left as they are.
+    pub fn subtract(&self, families: &mut [MetricFamily]) {
+        let samples = self.samples.lock().unwrap();
+        if samples.is_empty() {
+            return;
+        }
+
+        for family in families.iter_mut() {
+            let field_type = family.get_field_type();
+            for i in 0..family.get_metric().len() {
+                let key = metric_key(family, &family.get_metric()[i]);
+                let metric = &mut family.mut_metric()[i];
+                match samples.get(&key) {
+                    Some(Sample::Counter(value)) if field_type == MetricType::COUNTER => {
+                        let counter = metric.mut_counter();
+                        let current = counter.get_value();
+                        counter.set_value((current - value).max(0.0));
+                    },
+                    Some(Sample::Histogram { count, sum, buckets }) if field_type == MetricType::HISTOGRAM => {
+                        let histogram = metric.mut_histogram();
+                        let current = histogram.get_sample_count();
+                        histogram.set_sample_count(current.saturating_sub(*count));
+                        let current = histogram.get_sample_sum();
+                        histogram.set_sample_sum(current - sum);
+                        for (bucket, base) in histogram.mut_bucket().iter_mut().zip(buckets) {
+                            let current = bucket.get_cumulative_count();
+                            bucket.set_cumulative_count(current.saturating_sub(*base));
+                        }
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
+}
+
+fn sample(field_type: MetricType, metric: &Metric) -> Option<Sample> {
+    match field_type {
+        MetricType::COUNTER => Some(Sample::Counter(metric.get_counter().get_value())),
+        MetricType::HISTOGRAM => {
+            let histogram = metric.get_histogram();
+            Some(Sample::Histogram {
+                count: histogram.get_sample_count(),
+                sum: histogram.get_sample_sum(),
+                buckets: histogram.get_bucket().iter().map(|b| b.get_cumulative_count()).collect(),
+            })
+        },
+        _ => None,
+    }
+}
+
+// The family name and the label values, identifying one time series
+fn metric_key(family: &MetricFamily, metric: &Metric) -> String {
+    let mut key = family.get_name().to_string();
+    for label in metric.get_label() {
+        key.push('\0');
+        key.push_str(label.get_name());
+        key.push('=');
+        key.push_str(label.get_value());
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{CounterVec, Gauge, Histogram, HistogramOpts, Opts, Registry};
+
+    #[test]
+    fn test_reset() {
+        let registry = Registry::new();
+        let counter = CounterVec::new(Opts::new("test_total", "test"), &["op"]).unwrap();
+        let histogram = Histogram::with_opts(HistogramOpts::new("test_seconds", "test").buckets(vec![1.0, 2.0])).unwrap();
+        let gauge = Gauge::new("test_gauge", "test").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+
+        counter.with_label_values(&["find"]).inc_by(3.0);
+        histogram.observe(0.5);
+        histogram.observe(1.5);
+        gauge.set(7.0);
+
+        let baseline = MetricsBaseline::new();
+        baseline.reset(&registry.gather());
+
+        counter.with_label_values(&["find"]).inc();
+        counter.with_label_values(&["insert"]).inc_by(2.0);
+        histogram.observe(1.5);
+
+        let mut families = registry.gather();
+        baseline.subtract(&mut families);
+
+        let family = |name: &str| families.iter().find(|f| f.get_name() == name).unwrap().clone();
+
+        let counters: Vec<f64> = family("test_total").get_metric().iter()
+            .map(|m| m.get_counter().get_value())
+            .collect();
+        assert_eq!(vec![1.0, 2.0], counters);
+
+        let histogram = family("test_seconds").get_metric()[0].get_histogram().clone();
+        assert_eq!(1, histogram.get_sample_count());
+        assert_eq!(1.5, histogram.get_sample_sum());
+        let buckets: Vec<u64> = histogram.get_bucket().iter().map(|b| b.get_cumulative_count()).collect();
+        assert_eq!(vec![0, 1], buckets);
+
+        assert_eq!(7.0, family("test_gauge").get_metric()[0].get_gauge().get_value());
+    }
+}
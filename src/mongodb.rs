@@ -1,15 +1,69 @@
 use std::fmt;
 use tracing::{error, warn, info, debug};
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use async_bson::{DocumentParser, Document, read_cstring};
-use prometheus::{CounterVec};
+use prometheus::{Counter,CounterVec};
 
 use std::io::{Write, Error, ErrorKind};
+use std::sync::atomic::{AtomicU32, Ordering};
 use tokio::io::{self, AsyncReadExt, Result};
+use bson::doc;
 
 
 pub const HEADER_LENGTH: usize = 16;
 
+// MongoDb's own limit for the message size (maxMessageSizeBytes)
+pub const MAX_MESSAGE_SIZE: usize = 48_000_000;
+
+// OP_MSG flag bit that says there is a CRC-32C checksum at the end of the message
+pub const MSG_FLAG_CHECKSUM_PRESENT: u32 = 1;
+
+// OP_MSG flag bit that says the sender will not wait for a reply
+pub const MSG_FLAG_MORE_TO_COME: u32 = 1 << 1;
+
+// Opcodes that we expect to see in a valid message header, used to find the next
+// message when resyncing
+const KNOWN_OP_CODES: [u32; 11] = [1, 2001, 2002, 2004, 2005, 2006, 2007, 2010, 2011, 2012, 2013];
+
+// Parsed document keys for the stage names of an aggregation pipeline. Stages
+// beyond these are not looked at.
+pub const PIPELINE_STAGE_KEYS: [&str; 10] = [
+    "stage_0", "stage_1", "stage_2", "stage_3", "stage_4",
+    "stage_5", "stage_6", "stage_7", "stage_8", "stage_9",
+];
+
+// Parsed document keys for the first top level field names of a query filter.
+// Only the names are kept, never the values.
+pub const FILTER_FIELD_KEYS: [&str; 4] = [
+    "filter_field_0", "filter_field_1", "filter_field_2", "filter_field_3",
+];
+
+// Parsed document keys for the stages of the winning plan in an explain response,
+// from the top of the plan down through the input stages.
+pub const PLAN_STAGE_KEYS: [&str; 4] = [
+    "plan_stage_0", "plan_stage_1", "plan_stage_2", "plan_stage_3",
+];
+
+// Parsed document keys for the operation counters of a serverStatus response, with the
+// `op` label value of each
+pub const OPCOUNTER_KEYS: [(&str, &str); 6] = [
+    ("opcounters_insert", "insert"), ("opcounters_query", "query"), ("opcounters_update", "update"),
+    ("opcounters_delete", "delete"), ("opcounters_getmore", "getmore"), ("opcounters_command", "command"),
+];
+
+// Opcodes that the parser reads to the end of the message
+const FULLY_PARSED_OP_CODES: [u32; 2] = [1, 2013];
+
+// Reasons for mongoproxy_parse_errors_total, keep this a small fixed set
+const PARSE_ERROR_UNKNOWN_OPCODE: &str = "unknown_opcode";
+const PARSE_ERROR_INVALID_HEADER: &str = "invalid_header";
+const PARSE_ERROR_OVERSIZED: &str = "oversized";
+const PARSE_ERROR_TRUNCATED: &str = "truncated";
+const PARSE_ERROR_BAD_BSON: &str = "bad_bson";
+
+// Request ids for the messages that the proxy itself generates
+static NEXT_REQUEST_ID: AtomicU32 = AtomicU32::new(1);
+
 pub trait AsyncReadExtPlus: AsyncReadExt+Unpin+Send {}
 impl <T>AsyncReadExtPlus for T where T: AsyncReadExt+Unpin+Send {}
 
@@ -29,6 +83,36 @@ lazy_static! {
             .match_exact("/filter/$comment", "comment")
             // TODO: support comments also in other pipeline steps
             .match_exact("/pipeline/0/$match/$comment", "comment")
+            .match_name_at("/pipeline/0", 1, "stage_0")
+            .match_name_at("/pipeline/1", 1, "stage_1")
+            .match_name_at("/pipeline/2", 1, "stage_2")
+            .match_name_at("/pipeline/3", 1, "stage_3")
+            .match_name_at("/pipeline/4", 1, "stage_4")
+            .match_name_at("/pipeline/5", 1, "stage_5")
+            .match_name_at("/pipeline/6", 1, "stage_6")
+            .match_name_at("/pipeline/7", 1, "stage_7")
+            .match_name_at("/pipeline/8", 1, "stage_8")
+            .match_name_at("/pipeline/9", 1, "stage_9")
+            .match_name_at("/filter", 1, "filter_field_0")
+            .match_name_at("/filter", 2, "filter_field_1")
+            .match_name_at("/filter", 3, "filter_field_2")
+            .match_name_at("/filter", 4, "filter_field_3")
+            // count, distinct and findAndModify have the filter in "query"
+            .match_name_at("/query", 1, "filter_field_0")
+            .match_name_at("/query", 2, "filter_field_1")
+            .match_name_at("/query", 3, "filter_field_2")
+            .match_name_at("/query", 4, "filter_field_3")
+            .match_exact("/explain/find", "collection")
+            .match_exact("/explain/aggregate", "collection")
+            .match_exact("/queryPlanner/winningPlan/stage", "plan_stage_0")
+            .match_exact("/queryPlanner/winningPlan/inputStage/stage", "plan_stage_1")
+            .match_exact("/queryPlanner/winningPlan/inputStage/inputStage/stage", "plan_stage_2")
+            .match_exact("/queryPlanner/winningPlan/inputStage/inputStage/inputStage/stage", "plan_stage_3")
+            // Explains with executionStats verbosity
+            .match_exact("/executionStats/nReturned", "explain_returned")
+            .match_exact("/executionStats/totalDocsExamined", "explain_docs_examined")
+            .match_exact("/mechanism", "mechanism")
+            .match_exact("/speculativeAuthenticate/mechanism", "speculative_auth")
             .match_exact("/client/application/name", "app_name")
             // Workaround for Elixir Mongo driver that has an extra nested "client"
             .match_exact("/client/client/application/name", "app_name")
@@ -38,7 +122,22 @@ lazy_static! {
             .match_exact("/n", "n")
             // findAndModify returns number of collection in lastErrorObject/n
             .match_exact("/lastErrorObject/n", "n")
-            .match_exact("/nModified", "n_modified");
+            .match_exact("/nModified", "n_modified")
+            .match_exact("/maxWireVersion", "max_wire_version")
+            .match_exact("/minWireVersion", "min_wire_version")
+            .match_exact("/isWritablePrimary", "is_writable_primary")
+            .match_exact("/ismaster", "ismaster")
+            .match_exact("/version", "server_version")
+            .match_exact("/connections/current", "connections_current")
+            .match_exact("/connections/available", "connections_available")
+            .match_exact("/opcounters/insert", "opcounters_insert")
+            .match_exact("/opcounters/query", "opcounters_query")
+            .match_exact("/opcounters/update", "opcounters_update")
+            .match_exact("/opcounters/delete", "opcounters_delete")
+            .match_exact("/opcounters/getmore", "opcounters_getmore")
+            .match_exact("/opcounters/command", "opcounters_command")
+            .match_exact("/maxTimeMS", "max_time_ms")
+            .match_exact("/code", "error_code");
 
     static ref OPCODE_COUNTER: CounterVec =
         register_counter_vec!(
@@ -58,6 +157,18 @@ lazy_static! {
             "Message body parse errors",
             &["error"]).unwrap();
 
+    static ref PARSE_LENGTH_MISMATCH_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_parse_length_mismatch_total",
+            "Messages where the parser did not consume the length given in the header"
+            ).unwrap();
+
+    static ref PARSE_ERRORS_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_parse_errors_total",
+            "Messages that could not be parsed, by reason",
+            &["reason"]).unwrap();
+
 }
 
 #[derive(Debug)]
@@ -105,17 +216,45 @@ impl fmt::Display for MongoMessage {
 
 impl MongoMessage {
 
+    // Read a message from the stream. Messages longer than `max_message_size` are
+    // not parsed, instead an error is returned.
     pub async fn from_reader(
         mut rdr: impl AsyncReadExtPlus,
         log_mongo_messages: bool,
         collect_tracing_data: bool,
+        max_message_size: usize,
     ) -> Result<(MsgHeader, MongoMessage)> {
         let hdr = MsgHeader::from_reader(&mut rdr).await?;
+        let msg = MongoMessage::from_header(
+            &hdr,
+            &mut rdr,
+            log_mongo_messages,
+            collect_tracing_data,
+            max_message_size).await?;
+
+        Ok((hdr, msg))
+    }
 
+    // Read the rest of the message, following the already parsed header
+    pub async fn from_header(
+        hdr: &MsgHeader,
+        rdr: impl AsyncReadExtPlus,
+        log_mongo_messages: bool,
+        collect_tracing_data: bool,
+        max_message_size: usize,
+    ) -> Result<MongoMessage> {
         if hdr.message_length < HEADER_LENGTH {
+            PARSE_ERRORS_TOTAL.with_label_values(&[PARSE_ERROR_INVALID_HEADER]).inc();
             return Err(Error::new(ErrorKind::Other, "Invalid MongoDb header"));
         }
 
+        if hdr.message_length > max_message_size {
+            MESSAGE_PARSE_ERRORS_COUNTER.with_label_values(&["message too large"]).inc();
+            PARSE_ERRORS_TOTAL.with_label_values(&[PARSE_ERROR_OVERSIZED]).inc();
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("message length {} exceeds the maximum of {}", hdr.message_length, max_message_size)));
+        }
+
         debug!("have header: {}", hdr);
 
         // Take only as much as promised in the header.
@@ -131,18 +270,55 @@ impl MongoMessage {
                 collect_tracing_data,
                 message_length).await {
             Ok(msg) => msg,
+            // The caller gave up on the message, it's not a bad message as such
+            Err(e) if e.kind() == ErrorKind::TimedOut => return Err(e),
             Err(e) => {
+                // Running out of the message bytes means the body is shorter than the
+                // header says, anything else is a document that doesn't parse.
+                let reason = if e.kind() == ErrorKind::UnexpectedEof {
+                    PARSE_ERROR_TRUNCATED
+                } else {
+                    PARSE_ERROR_BAD_BSON
+                };
+                let e = truncated(e);
                 error!("Failed to parse MongoDb {} message: {}", hdr.op_code, e);
                 MESSAGE_PARSE_ERRORS_COUNTER.with_label_values(&[&e.to_string()]).inc();
+                PARSE_ERRORS_TOTAL.with_label_values(&[reason]).inc();
                 return Err(e);
             }
         };
 
+        // Skip whatever the parser left over, to stay at the message boundary. The
+        // legacy opcodes are only partially parsed on purpose, but OP_MSG and OP_REPLY
+        // are expected to be consumed whole.
         let len = io::copy(&mut rdr, &mut tokio::io::sink()).await?;
         if len > 0 {
-            warn!("partial message, sinking {} bytes.", len);
+            if FULLY_PARSED_OP_CODES.contains(&hdr.op_code) {
+                PARSE_LENGTH_MISMATCH_TOTAL.inc();
+                warn!("partial message, sinking {} bytes: {}", len, hdr);
+            } else {
+                debug!("partial message, sinking {} bytes.", len);
+            }
         }
 
+        Ok(msg)
+    }
+
+    // Parse a message from a buffer that holds exactly one complete message. Unlike
+    // from_reader this does not update the opcode metrics, it's meant for looking at
+    // the message before it's forwarded and handed to the tracker.
+    pub async fn from_buffer(buf: &[u8]) -> Result<(MsgHeader, MongoMessage)> {
+        let mut rdr = buf;
+        let hdr = MsgHeader::from_reader(&mut rdr).await?;
+        let message_length = (buf.len() - HEADER_LENGTH) as u64;
+
+        let msg = MongoMessage::extract_message(
+            hdr.op_code,
+            &mut rdr,
+            false,
+            false,
+            message_length).await.map_err(truncated)?;
+
         Ok((hdr, msg))
     }
 
@@ -175,6 +351,9 @@ impl MongoMessage {
             },
             _ => {
                 UNSUPPORTED_OPCODE_COUNTER.with_label_values(&[&op.to_string()]).inc();
+                if !KNOWN_OP_CODES.contains(&op) {
+                    PARSE_ERRORS_TOTAL.with_label_values(&[PARSE_ERROR_UNKNOWN_OPCODE]).inc();
+                }
                 warn!("Unhandled OP: {}", op);
                 MongoMessage::None
             },
@@ -202,14 +381,71 @@ impl MsgHeader {
         }
     }
 
+    // Read the header from the stream. EOF before the first field is passed on as is
+    // so that the caller can treat it as a clean end of stream. Running out of bytes
+    // after that means a truncated message.
     pub async fn from_reader(mut rdr: impl AsyncReadExtPlus) -> Result<Self> {
         let message_length  = rdr.read_u32_le().await? as usize;
-        let request_id      = rdr.read_u32_le().await?;
-        let response_to     = rdr.read_u32_le().await?;
-        let op_code         = rdr.read_u32_le().await?;
+        let request_id      = rdr.read_u32_le().await.map_err(truncated)?;
+        let response_to     = rdr.read_u32_le().await.map_err(truncated)?;
+        let op_code         = rdr.read_u32_le().await.map_err(truncated)?;
         Ok(MsgHeader{message_length, request_id, response_to, op_code})
     }
 
+    // Parse the header from a buffer of HEADER_LENGTH bytes
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        MsgHeader{
+            message_length: LittleEndian::read_u32(&buf[0..4]) as usize,
+            request_id:     LittleEndian::read_u32(&buf[4..8]),
+            response_to:    LittleEndian::read_u32(&buf[8..12]),
+            op_code:        LittleEndian::read_u32(&buf[12..16]),
+        }
+    }
+
+    // Does the header frame a message that can be skipped by its length. Unknown
+    // opcodes are fine here, they are skipped as unsupported messages.
+    pub fn is_plausible(&self, max_message_size: usize) -> bool {
+        self.message_length >= HEADER_LENGTH
+            && self.message_length <= max_message_size
+    }
+
+    // Stricter check for the headers found while resyncing, where a random length
+    // alone would match far too easily
+    fn is_known(&self, max_message_size: usize) -> bool {
+        self.is_plausible(max_message_size) && KNOWN_OP_CODES.contains(&self.op_code)
+    }
+
+    // Find the next header with a known opcode in the stream, after `bad` failed the
+    // plausibility check. Slides over the stream a byte at a time, starting from the
+    // bytes of the bad header. Returns the header found and the number of bytes skipped.
+    pub async fn resync(
+        bad: &MsgHeader,
+        mut rdr: impl AsyncReadExtPlus,
+        max_message_size: usize,
+    ) -> Result<(Self, usize)> {
+        let reason = if bad.message_length > max_message_size {
+            PARSE_ERROR_OVERSIZED
+        } else {
+            PARSE_ERROR_INVALID_HEADER
+        };
+        PARSE_ERRORS_TOTAL.with_label_values(&[reason]).inc();
+
+        let mut window = [0u8; HEADER_LENGTH];
+        bad.write(&mut window[..])?;
+
+        let mut skipped = 0;
+        loop {
+            window.copy_within(1.., 0);
+            window[HEADER_LENGTH-1] = rdr.read_u8().await?;
+            skipped += 1;
+
+            let hdr = MsgHeader::from_bytes(&window);
+            if hdr.is_known(max_message_size) {
+                return Ok((hdr, skipped));
+            }
+        }
+    }
+
     pub fn write(&self, mut writer: impl Write) -> Result<()> {
         writer.write_u32::<LittleEndian>(self.message_length as u32)?;
         writer.write_u32::<LittleEndian>(self.request_id)?;
@@ -657,6 +893,77 @@ impl MsgOpCompressed {
     }
 }
 
+// Build an OP_MSG reply with a command error, the same as the server would send for a
+// failed command. This lets us reject a client request so that the driver sees a normal
+// command error instead of a dropped connection.
+pub fn error_reply(response_to: u32, code: i32, code_name: &str, errmsg: &str) -> Result<Vec<u8>> {
+    let doc = doc! {
+        "ok": 0.0,
+        "errmsg": errmsg,
+        "code": code,
+        "codeName": code_name,
+    };
+    command_reply(response_to, &doc)
+}
+
+// Build an OP_MSG reply with the document as the command response
+pub fn command_reply(response_to: u32, doc: &bson::Document) -> Result<Vec<u8>> {
+    let doc_buf = document_bytes(doc)?;
+
+    let hdr = MsgHeader {
+        message_length: HEADER_LENGTH + 4 + 1 + doc_buf.len(),
+        request_id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+        response_to,
+        op_code: 2013,
+    };
+
+    let mut buf = Vec::with_capacity(hdr.message_length);
+    hdr.write(&mut buf)?;
+    buf.write_u32::<LittleEndian>(0)?;     // flag bits
+    buf.write_u8(0)?;                       // section kind 0, a single document
+    buf.write_all(&doc_buf)?;
+
+    Ok(buf)
+}
+
+// Build a legacy OP_REPLY with the document, for commands sent as OP_QUERY
+pub fn op_reply(response_to: u32, doc: &bson::Document) -> Result<Vec<u8>> {
+    let doc_buf = document_bytes(doc)?;
+
+    let hdr = MsgHeader {
+        message_length: HEADER_LENGTH + 20 + doc_buf.len(),
+        request_id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+        response_to,
+        op_code: 1,
+    };
+
+    let mut buf = Vec::with_capacity(hdr.message_length);
+    hdr.write(&mut buf)?;
+    buf.write_u32::<LittleEndian>(0)?;     // response flags
+    buf.write_u64::<LittleEndian>(0)?;     // cursor id
+    buf.write_u32::<LittleEndian>(0)?;     // starting from
+    buf.write_u32::<LittleEndian>(1)?;     // number returned
+    buf.write_all(&doc_buf)?;
+
+    Ok(buf)
+}
+
+fn document_bytes(doc: &bson::Document) -> Result<Vec<u8>> {
+    let mut doc_buf = Vec::new();
+    doc.to_writer(&mut doc_buf).map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+    Ok(doc_buf)
+}
+
+// Turn an EOF in the middle of a message into an error that is not mistaken for
+// the end of the stream.
+fn truncated(e: Error) -> Error {
+    if e.kind() == ErrorKind::UnexpectedEof {
+        Error::new(ErrorKind::InvalidData, "truncated message")
+    } else {
+        e
+    }
+}
+
 // Convert a byte slice into xxd compatible hex dump
 pub fn debug_fmt(buf: &[u8]) -> String {
     let mut hex_str = String::new();
@@ -817,6 +1124,205 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_parse_pipeline_stages() {
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(0).unwrap();     // flag bits
+        buf.write_u8(0).unwrap();                      // section kind
+        let doc = doc! {
+            "aggregate": "kittens",
+            "pipeline": [
+                { "$match": { "color": "black" } },
+                { "$lookup": { "from": "owners", "as": "owner" } },
+                { "$count": "n" },
+            ],
+            "$db": "test",
+        };
+        doc.to_writer(&mut buf).unwrap();
+
+        let msg = MsgOpMsg::from_reader(&mut &buf[..], false, false, buf.len() as u64).await.unwrap();
+        let doc = &msg.documents[0];
+
+        assert_eq!("aggregate", doc.get_str("op").unwrap());
+        assert_eq!(Some("$match"), doc.get_str(PIPELINE_STAGE_KEYS[0]));
+        assert_eq!(Some("$lookup"), doc.get_str(PIPELINE_STAGE_KEYS[1]));
+        assert_eq!(Some("$count"), doc.get_str(PIPELINE_STAGE_KEYS[2]));
+        assert_eq!(None, doc.get_str(PIPELINE_STAGE_KEYS[3]));
+    }
+
+    #[tokio::test]
+    async fn test_parse_explain() {
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(0).unwrap();     // flag bits
+        buf.write_u8(0).unwrap();                      // section kind
+        let doc = doc! {
+            "explain": { "find": "kittens", "filter": { "color": "black" } },
+            "$db": "test",
+        };
+        doc.to_writer(&mut buf).unwrap();
+
+        let msg = MsgOpMsg::from_reader(&mut &buf[..], false, false, buf.len() as u64).await.unwrap();
+        assert_eq!("explain", msg.documents[0].get_str("op").unwrap());
+        assert_eq!("kittens", msg.documents[0].get_str("collection").unwrap());
+
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(0).unwrap();     // flag bits
+        buf.write_u8(0).unwrap();                      // section kind
+        let doc = doc! {
+            "queryPlanner": {
+                "namespace": "test.kittens",
+                "winningPlan": {
+                    "stage": "SORT",
+                    "inputStage": { "stage": "COLLSCAN", "direction": "forward" },
+                },
+            },
+            "ok": 1.0,
+        };
+        doc.to_writer(&mut buf).unwrap();
+
+        let msg = MsgOpMsg::from_reader(&mut &buf[..], false, false, buf.len() as u64).await.unwrap();
+        let doc = &msg.documents[0];
+        assert_eq!(Some("SORT"), doc.get_str(PLAN_STAGE_KEYS[0]));
+        assert_eq!(Some("COLLSCAN"), doc.get_str(PLAN_STAGE_KEYS[1]));
+        assert_eq!(None, doc.get_str(PLAN_STAGE_KEYS[2]));
+    }
+
+    #[tokio::test]
+    async fn test_parse_explain_execution_stats() {
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(0).unwrap();     // flag bits
+        buf.write_u8(0).unwrap();                      // section kind
+        let doc = doc! {
+            "queryPlanner": {
+                "namespace": "test.kittens",
+                "winningPlan": { "stage": "COLLSCAN" },
+            },
+            "executionStats": {
+                "executionSuccess": true,
+                "nReturned": 3,
+                "executionTimeMillis": 12,
+                "totalKeysExamined": 0,
+                "totalDocsExamined": 1200,
+            },
+            "ok": 1.0,
+        };
+        doc.to_writer(&mut buf).unwrap();
+
+        let msg = MsgOpMsg::from_reader(&mut &buf[..], false, false, buf.len() as u64).await.unwrap();
+        let doc = &msg.documents[0];
+        assert_eq!(Some(3), doc.get_i32("explain_returned"));
+        assert_eq!(Some(1200), doc.get_i32("explain_docs_examined"));
+    }
+
+    #[tokio::test]
+    async fn test_parse_server_status() {
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(0).unwrap();     // flag bits
+        buf.write_u8(0).unwrap();                      // section kind
+        let doc = doc! {
+            "host": "mongo-0",
+            "version": "6.0.14",
+            "connections": { "current": 12, "available": 838_848, "totalCreated": 160 },
+            "opcounters": {
+                "insert": 10_i64, "query": 200_i64, "update": 30_i64,
+                "delete": 4_i64, "getmore": 50_i64, "command": 6000_i64,
+            },
+            "ok": 1.0,
+        };
+        doc.to_writer(&mut buf).unwrap();
+
+        let msg = MsgOpMsg::from_reader(&mut &buf[..], false, false, buf.len() as u64).await.unwrap();
+        let doc = &msg.documents[0];
+        assert_eq!(Some(12), doc.get_i32("connections_current"));
+        assert_eq!(Some(838_848), doc.get_i32("connections_available"));
+        assert_eq!(Some(200), doc.get_i64(OPCOUNTER_KEYS[1].0));
+        assert_eq!(Some(6000), doc.get_i64(OPCOUNTER_KEYS[5].0));
+    }
+
+    #[tokio::test]
+    async fn test_parse_build_info() {
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(0).unwrap();     // flag bits
+        buf.write_u8(0).unwrap();                      // section kind
+        let doc = doc! {
+            "version": "6.0.14",
+            "gitVersion": "25225db95574916fecab3af75b184409f8713aef",
+            "versionArray": [6, 0, 14, 0],
+            "ok": 1.0,
+        };
+        doc.to_writer(&mut buf).unwrap();
+
+        let msg = MsgOpMsg::from_reader(&mut &buf[..], false, false, buf.len() as u64).await.unwrap();
+        assert_eq!(Some("6.0.14"), msg.documents[0].get_str("server_version"));
+    }
+
+    #[tokio::test]
+    async fn test_parse_max_time_ms() {
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(0).unwrap();     // flag bits
+        buf.write_u8(0).unwrap();                      // section kind
+        let doc = doc! {
+            "find": "kittens",
+            "filter": { "color": "black" },
+            "maxTimeMS": 5000,
+            "$db": "test",
+        };
+        doc.to_writer(&mut buf).unwrap();
+
+        let msg = MsgOpMsg::from_reader(&mut &buf[..], false, false, buf.len() as u64).await.unwrap();
+        assert_eq!(Some(5000), msg.documents[0].get_i32("max_time_ms"));
+
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(0).unwrap();     // flag bits
+        buf.write_u8(0).unwrap();                      // section kind
+        let doc = doc! {
+            "ok": 0.0,
+            "errmsg": "operation exceeded time limit",
+            "code": 50,
+            "codeName": "MaxTimeMSExpired",
+        };
+        doc.to_writer(&mut buf).unwrap();
+
+        let msg = MsgOpMsg::from_reader(&mut &buf[..], false, false, buf.len() as u64).await.unwrap();
+        assert_eq!(Some(50), msg.documents[0].get_i32("error_code"));
+    }
+
+    #[tokio::test]
+    async fn test_parse_speculative_auth() {
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(0).unwrap();     // flag bits
+        buf.write_u8(0).unwrap();                      // section kind
+        let doc = doc! {
+            "hello": 1,
+            "speculativeAuthenticate": { "saslStart": 1, "mechanism": "SCRAM-SHA-256", "db": "admin" },
+            "$db": "admin",
+        };
+        doc.to_writer(&mut buf).unwrap();
+
+        let msg = MsgOpMsg::from_reader(&mut &buf[..], false, false, buf.len() as u64).await.unwrap();
+        assert_eq!(Some("SCRAM-SHA-256"), msg.documents[0].get_str("speculative_auth"));
+        assert_eq!(None, msg.documents[0].get_str("mechanism"));
+    }
+
+    #[tokio::test]
+    async fn test_parse_filter_fields() {
+        let mut buf = Vec::new();
+        buf.write_u32::<LittleEndian>(0).unwrap();     // flag bits
+        buf.write_u8(0).unwrap();                      // section kind
+        let doc = doc! {
+            "find": "kittens",
+            "filter": { "color": "black", "age": { "$gt": 2 } },
+            "$db": "test",
+        };
+        doc.to_writer(&mut buf).unwrap();
+
+        let msg = MsgOpMsg::from_reader(&mut &buf[..], false, false, buf.len() as u64).await.unwrap();
+        let doc = &msg.documents[0];
+        assert_eq!(Some("color"), doc.get_str(FILTER_FIELD_KEYS[0]));
+        assert_eq!(Some("age"), doc.get_str(FILTER_FIELD_KEYS[1]));
+        assert_eq!(None, doc.get_str(FILTER_FIELD_KEYS[2]));
+    }
+
     #[tokio::test]
     async fn test_parse_op_query() {
         let mut buf = Vec::new();
@@ -937,7 +1443,7 @@ mod tests {
 
         let mut cur = std::io::Cursor::new(buf);
         let mut messages = Vec::new();
-        while let Ok((_, msg)) = MongoMessage::from_reader(&mut cur, false, false).await {
+        while let Ok((_, msg)) = MongoMessage::from_reader(&mut cur, false, false, MAX_MESSAGE_SIZE).await {
             messages.push(msg);
         }
 
@@ -954,6 +1460,214 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_truncated_message() {
+        let mut msg_buf = Vec::new();
+        msgop_to_buf(0, &mut msg_buf);
+
+        let hdr = MsgHeader {
+            message_length: HEADER_LENGTH + msg_buf.len(),
+            request_id: 1,
+            response_to: 0,
+            op_code: 2013,
+        };
+
+        let mut buf = Vec::new();
+        hdr.write(&mut buf).unwrap();
+        buf.extend(&msg_buf[..2]);  // cut in the middle of the flag bits
+
+        let truncated_errors = PARSE_ERRORS_TOTAL.with_label_values(&[PARSE_ERROR_TRUNCATED]).get();
+        let err = MongoMessage::from_reader(&buf[..], false, false, MAX_MESSAGE_SIZE).await.unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert!(PARSE_ERRORS_TOTAL.with_label_values(&[PARSE_ERROR_TRUNCATED]).get() > truncated_errors);
+
+        let err = MongoMessage::from_reader(&buf[..6], false, false, MAX_MESSAGE_SIZE).await.unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+
+        let err = MongoMessage::from_reader(&b""[..], false, false, MAX_MESSAGE_SIZE).await.unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[tokio::test]
+    async fn test_length_mismatch() {
+        let mut msg_buf = Vec::new();
+        msg_buf.write_u32::<LittleEndian>(0).unwrap();  // flag bits
+        msg_buf.write_u8(0).unwrap();                   // section kind 0
+        doc! { "ping": 1 }.to_writer(&mut msg_buf).unwrap();
+        msg_buf.extend(&[9, 1, 2, 3]);                  // unknown section kind
+
+        let hdr = MsgHeader {
+            message_length: HEADER_LENGTH + msg_buf.len(),
+            request_id: 1,
+            response_to: 0,
+            op_code: 2013,
+        };
+
+        let mut buf = Vec::new();
+        hdr.write(&mut buf).unwrap();
+        buf.extend(&msg_buf);
+        let mut next = Vec::new();
+        msgop_to_buf(1, &mut next);
+        MsgHeader { message_length: HEADER_LENGTH + next.len(), request_id: 2, response_to: 0, op_code: 2013 }
+            .write(&mut buf).unwrap();
+        buf.extend(&next);
+
+        let mismatches = PARSE_LENGTH_MISMATCH_TOTAL.get();
+        let mut rdr = &buf[..];
+        MongoMessage::from_reader(&mut rdr, false, false, MAX_MESSAGE_SIZE).await.unwrap();
+        assert!(PARSE_LENGTH_MISMATCH_TOTAL.get() > mismatches);
+
+        // The next message is found at the declared boundary
+        let (hdr, _) = MongoMessage::from_reader(&mut rdr, false, false, MAX_MESSAGE_SIZE).await.unwrap();
+        assert_eq!(2, hdr.request_id);
+        assert!(rdr.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_message_too_large() {
+        let hdr = MsgHeader {
+            message_length: 1024,
+            request_id: 1,
+            response_to: 0,
+            op_code: 2013,
+        };
+
+        let mut buf = Vec::new();
+        hdr.write(&mut buf).unwrap();
+        buf.extend(&[0; 1024 - HEADER_LENGTH][..]);
+
+        let oversized_errors = PARSE_ERRORS_TOTAL.with_label_values(&[PARSE_ERROR_OVERSIZED]).get();
+        let err = MongoMessage::from_reader(&buf[..], false, false, 1023).await.unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert!(PARSE_ERRORS_TOTAL.with_label_values(&[PARSE_ERROR_OVERSIZED]).get() > oversized_errors);
+    }
+
+    #[tokio::test]
+    async fn test_resync() {
+        let mut msg_buf = Vec::new();
+        msgop_to_buf(0, &mut msg_buf);
+
+        let hdr = MsgHeader {
+            message_length: HEADER_LENGTH + msg_buf.len(),
+            request_id: 1,
+            response_to: 0,
+            op_code: 2013,
+        };
+
+        let mut buf = vec![0xff; 21];
+        hdr.write(&mut buf).unwrap();
+        buf.extend(&msg_buf);
+
+        let mut rdr = &buf[..];
+        let bad = MsgHeader::from_reader(&mut rdr).await.unwrap();
+        assert!(!bad.is_plausible(MAX_MESSAGE_SIZE));
+
+        let (found, skipped) = MsgHeader::resync(&bad, &mut rdr, MAX_MESSAGE_SIZE).await.unwrap();
+        assert_eq!(21, skipped);
+        assert_eq!(hdr.message_length, found.message_length);
+        assert_eq!(hdr.op_code, found.op_code);
+
+        let msg = MongoMessage::from_header(&found, &mut rdr, false, false, MAX_MESSAGE_SIZE).await.unwrap();
+        match msg {
+            MongoMessage::Msg(m) => assert_eq!("x0", m.documents[0].get_str("op").unwrap()),
+            other => panic!("expecting OP_MSG, got {}", other),
+        }
+
+        // Nothing plausible before the end of stream
+        let mut rdr = &[0xffu8; 40][..];
+        let bad = MsgHeader::from_reader(&mut rdr).await.unwrap();
+        let err = MsgHeader::resync(&bad, &mut rdr, MAX_MESSAGE_SIZE).await.unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_opcode_is_skipped() {
+        // A legacy OP_KILL_CURSORS with two cursor ids, followed by an OP_MSG
+        let mut kill_cursors = Vec::new();
+        MsgHeader { message_length: HEADER_LENGTH + 24, request_id: 1, response_to: 0, op_code: 2007 }
+            .write(&mut kill_cursors).unwrap();
+        kill_cursors.write_i32::<LittleEndian>(0).unwrap();
+        kill_cursors.write_i32::<LittleEndian>(2).unwrap();
+        kill_cursors.write_i64::<LittleEndian>(1234).unwrap();
+        kill_cursors.write_i64::<LittleEndian>(5678).unwrap();
+
+        let mut msg_buf = Vec::new();
+        msgop_to_buf(0, &mut msg_buf);
+        let mut buf = kill_cursors.clone();
+        MsgHeader { message_length: HEADER_LENGTH + msg_buf.len(), request_id: 2, response_to: 0, op_code: 2013 }
+            .write(&mut buf).unwrap();
+        buf.extend(&msg_buf);
+
+        let unsupported = UNSUPPORTED_OPCODE_COUNTER.with_label_values(&["2007"]).get();
+        let mut rdr = &buf[..];
+        let hdr = MsgHeader::from_reader(&mut rdr).await.unwrap();
+        assert!(hdr.is_plausible(MAX_MESSAGE_SIZE));
+        match MongoMessage::from_header(&hdr, &mut rdr, false, false, MAX_MESSAGE_SIZE).await.unwrap() {
+            MongoMessage::None => {},
+            other => panic!("expecting no message, got {}", other),
+        }
+        assert!(UNSUPPORTED_OPCODE_COUNTER.with_label_values(&["2007"]).get() > unsupported);
+
+        // The whole body was skipped by length, the next message follows
+        let (hdr, msg) = MongoMessage::from_reader(&mut rdr, false, false, MAX_MESSAGE_SIZE).await.unwrap();
+        assert_eq!(2, hdr.request_id);
+        match msg {
+            MongoMessage::Msg(m) => assert_eq!("x0", m.documents[0].get_str("op").unwrap()),
+            other => panic!("expecting OP_MSG, got {}", other),
+        }
+        assert!(rdr.is_empty());
+
+        // Made up opcodes are skipped the same way
+        let mut buf = Vec::new();
+        MsgHeader { message_length: HEADER_LENGTH + 4, request_id: 3, response_to: 0, op_code: 4242 }
+            .write(&mut buf).unwrap();
+        buf.extend(&[1, 2, 3, 4]);
+        let hdr = MsgHeader::from_reader(&mut &buf[..]).await.unwrap();
+        assert!(hdr.is_plausible(MAX_MESSAGE_SIZE));
+    }
+
+    #[tokio::test]
+    async fn test_error_reply() {
+        let buf = error_reply(42, 13, "Unauthorized", "command not allowed").unwrap();
+
+        let (hdr, msg) = MongoMessage::from_reader(&buf[..], false, false, MAX_MESSAGE_SIZE).await.unwrap();
+        assert_eq!(buf.len(), hdr.message_length);
+        assert_eq!(42, hdr.response_to);
+        assert_eq!(2013, hdr.op_code);
+
+        match msg {
+            MongoMessage::Msg(m) => {
+                assert_eq!(0, m.flag_bits);
+                assert_eq!(1, m.documents.len());
+                assert_eq!(Some(0.0), m.documents[0].get_float("ok"));
+            },
+            other => panic!("expecting OP_MSG, got {}", other),
+        }
+
+        let doc = bson::Document::from_reader(&mut &buf[HEADER_LENGTH+5..]).unwrap();
+        assert_eq!(13, doc.get_i32("code").unwrap());
+        assert_eq!("Unauthorized", doc.get_str("codeName").unwrap());
+        assert_eq!("command not allowed", doc.get_str("errmsg").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_op_reply() {
+        let buf = op_reply(42, &doc! { "ismaster": true, "ok": 1.0 }).unwrap();
+
+        let (hdr, msg) = MongoMessage::from_reader(&buf[..], false, false, MAX_MESSAGE_SIZE).await.unwrap();
+        assert_eq!(buf.len(), hdr.message_length);
+        assert_eq!(42, hdr.response_to);
+        assert_eq!(1, hdr.op_code);
+
+        match msg {
+            MongoMessage::Reply(r) => {
+                assert_eq!(1, r.number_returned);
+                assert_eq!(Some(1.0), r.documents[0].get_float("ok"));
+            },
+            other => panic!("expecting OP_REPLY, got {}", other),
+        }
+    }
+
     #[test]
     fn test_debug_fmt() {
         let buf = b"0123456789abcdefg";
@@ -0,0 +1,102 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use prometheus::Gauge;
+
+// Number of distinct databases and collections that are remembered. Past that the
+// gauges stay put, so that an app creating collections on the fly can't grow the
+// sets without bounds.
+pub const MAX_DISTINCT_NAMESPACES: usize = 10000;
+
+lazy_static! {
+    static ref DISTINCT_DATABASES: Gauge =
+        register_gauge!(
+            "mongoproxy_distinct_databases",
+            "Number of distinct databases seen since start, up to a fixed limit"
+            ).unwrap();
+
+    static ref DISTINCT_COLLECTIONS: Gauge =
+        register_gauge!(
+            "mongoproxy_distinct_collections",
+            "Number of distinct collections seen since start, up to a fixed limit"
+            ).unwrap();
+
+    static ref SEEN_NAMESPACES: Mutex<SeenNamespaces> =
+        Mutex::new(SeenNamespaces::new(MAX_DISTINCT_NAMESPACES));
+}
+
+// Record the namespace of a command and update the gauges when it's a new one
+pub fn observe(db: &str, coll: &str) {
+    let mut seen = SEEN_NAMESPACES.lock().unwrap();
+    if seen.insert(db, coll) {
+        DISTINCT_DATABASES.set(seen.databases.len() as f64);
+        DISTINCT_COLLECTIONS.set(seen.collections.len() as f64);
+    }
+}
+
+// The databases and collections (as "db.collection") seen so far, each capped at
+// `max_values` entries
+#[derive(Debug)]
+pub struct SeenNamespaces {
+    max_values: usize,
+    databases: HashSet<String>,
+    collections: HashSet<String>,
+}
+
+impl SeenNamespaces {
+
+    pub fn new(max_values: usize) -> Self {
+        SeenNamespaces {
+            max_values,
+            databases: HashSet::new(),
+            collections: HashSet::new(),
+        }
+    }
+
+    // Returns true if either the database or the collection was not seen before
+    pub fn insert(&mut self, db: &str, coll: &str) -> bool {
+        let mut added = false;
+        if !db.is_empty() && !self.databases.contains(db) && self.databases.len() < self.max_values {
+            self.databases.insert(db.to_string());
+            added = true;
+        }
+        if !coll.is_empty() && self.collections.len() < self.max_values {
+            added |= self.collections.insert(format!("{}.{}", db, coll));
+        }
+        added
+    }
+
+    pub fn databases(&self) -> usize {
+        self.databases.len()
+    }
+
+    pub fn collections(&self) -> usize {
+        self.collections.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seen_namespaces() {
+        let mut seen = SeenNamespaces::new(3);
+        assert!(seen.insert("shop", "orders"));
+        assert!(!seen.insert("shop", "orders"));
+        assert!(seen.insert("shop", "products"));
+        // Same collection name in another database is another collection
+        assert!(seen.insert("billing", "orders"));
+        // Commands without a collection only count the database
+        assert!(!seen.insert("billing", ""));
+        assert_eq!(2, seen.databases());
+        assert_eq!(3, seen.collections());
+
+        // Full, new namespaces are no longer counted
+        assert!(seen.insert("users", "profiles"));
+        assert_eq!(3, seen.databases());
+        assert_eq!(3, seen.collections());
+        assert!(!seen.insert("audit", "events"));
+        assert_eq!(3, seen.databases());
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use opentelemetry::metrics::{Meter, MeterProvider, ObserverResult};
+use opentelemetry::sdk::metrics::selectors;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use prometheus::{Counter};
+use tokio_stream::wrappers::IntervalStream;
+use tokio_stream::Stream;
+use tracing::{error, info, warn};
+
+use crate::metriclabels;
+use crate::metricreset;
+
+// Timeout for a single export to the collector
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The observers of one export all read the same gathered metrics, this is how old the
+// gathered metrics can be before the next observer gathers them again
+const SNAPSHOT_MAX_AGE: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    static ref OTLP_EXPORT_ERRORS_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_otlp_export_errors_total",
+            "Number of failed metric exports to the OTLP collector"
+            ).unwrap();
+}
+
+// Pushes the metrics to an OpenTelemetry collector over OTLP/gRPC, in parallel with the
+// Prometheus endpoint. The metrics are the ones registered for Prometheus, reported
+// through OpenTelemetry observers: counters become monotonic sums and gauges stay
+// gauges. Histograms are exported the Prometheus way, as the `_bucket`, `_sum` and
+// `_count` sums, as the observers can't report already aggregated buckets.
+pub struct OtlpExporter {
+    endpoint: String,
+    interval: Duration,
+    resource: Vec<(String, String)>,
+}
+
+impl OtlpExporter {
+
+    // The endpoint is `http://host:port`, `resource` becomes the resource attributes
+    // of the exported metrics (eg. `service.name`)
+    pub fn new(endpoint: &str, interval: Duration, resource: Vec<(String, String)>) -> Result<Self, String> {
+        validate_endpoint(endpoint)?;
+        Ok(OtlpExporter {
+            endpoint: endpoint.to_string(),
+            interval,
+            resource,
+        })
+    }
+
+    // Export the metrics every interval in a separate thread. The OpenTelemetry exporter
+    // needs a tokio 1 runtime, so the thread runs one of its own.
+    pub fn start(self, static_labels: Vec<(String, String)>) {
+        info!("Exporting metrics to OTLP collector at {} every {:?}", self.endpoint, self.interval);
+        thread::spawn(move || {
+            let runtime = match tokio1::runtime::Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    error!("Failed to start the OTLP exporter: {}", e);
+                    return;
+                },
+            };
+            runtime.block_on(self.run(static_labels));
+        });
+    }
+
+    async fn run(self, static_labels: Vec<(String, String)>) {
+        // The metrics pipeline reports the failed exports to the global error handler
+        let _ = global::set_error_handler(|e| {
+            warn!("Failed to export metrics to the OTLP collector: {}", e);
+            OTLP_EXPORT_ERRORS_TOTAL.inc();
+        });
+
+        let exporter = opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(self.endpoint.clone())
+            .with_timeout(EXPORT_TIMEOUT);
+        let resource: Vec<KeyValue> = self.resource.iter()
+            .map(|(key, value)| KeyValue::new(key.clone(), value.clone()))
+            .collect();
+        let controller = match opentelemetry_otlp::new_pipeline()
+            .metrics(tokio1::spawn, delayed_interval)
+            .with_exporter(exporter)
+            .with_aggregator_selector(selectors::simple::Selector::Inexpensive)
+            .with_period(self.interval)
+            .with_resource(resource)
+            .build()
+        {
+            Ok(controller) => controller,
+            Err(e) => {
+                error!("Failed to start the OTLP exporter: {}", e);
+                return;
+            },
+        };
+        let meter = controller.provider().meter("mongoproxy", Some(env!("CARGO_PKG_VERSION")));
+
+        // New metric families (eg. the first request of a new op) get their observers
+        // before the next export. The meter keeps the observers registered.
+        let snapshot = Arc::new(Mutex::new(Snapshot::new(static_labels)));
+        let mut registered = HashSet::new();
+        loop {
+            let new_series: Vec<(String, SeriesKind, String)> = {
+                let mut snapshot = snapshot.lock().unwrap();
+                snapshot.refresh();
+                snapshot.series.iter()
+                    .filter(|(name, _)| !registered.contains(*name))
+                    .map(|(name, series)| (name.clone(), series.kind, series.help.clone()))
+                    .collect()
+            };
+            for (name, kind, help) in new_series {
+                register_observer(&meter, &name, kind, help, snapshot.clone());
+                registered.insert(name);
+            }
+            tokio1::time::sleep(self.interval).await;
+        }
+    }
+}
+
+fn register_observer(meter: &Meter, name: &str, kind: SeriesKind, help: String, snapshot: Arc<Mutex<Snapshot>>) {
+    let key = name.to_string();
+    let callback = move |result: ObserverResult<f64>| {
+        let mut snapshot = snapshot.lock().unwrap();
+        snapshot.refresh();
+        if let Some(series) = snapshot.series.get(&key) {
+            for point in &series.points {
+                result.observe(point.value, &point.attributes);
+            }
+        }
+    };
+    match kind {
+        SeriesKind::Sum => {
+            meter.f64_sum_observer(name.to_string(), callback).with_description(help).init();
+        },
+        SeriesKind::Gauge => {
+            meter.f64_value_observer(name.to_string(), callback).with_description(help).init();
+        },
+    }
+}
+
+// The first export is after the first interval, same as with the Prometheus scrapes
+fn delayed_interval(duration: Duration) -> impl Stream<Item = tokio1::time::Instant> {
+    IntervalStream::new(tokio1::time::interval_at(tokio1::time::Instant::now() + duration, duration))
+}
+
+fn validate_endpoint(endpoint: &str) -> Result<(), String> {
+    let host = endpoint.strip_prefix("http://")
+        .ok_or_else(|| "only http:// endpoints are supported".to_string())?;
+    let host = host.trim_end_matches('/');
+    if host.contains('/') {
+        return Err("OTLP/gRPC endpoints have no path".to_string());
+    }
+    if !host.contains(':') {
+        return Err("expecting host:port".to_string());
+    }
+    Ok(())
+}
+
+// The Prometheus metrics as of the last gather, by the exported metric name
+struct Snapshot {
+    static_labels: Vec<(String, String)>,
+    gathered: Option<Instant>,
+    series: HashMap<String, Series>,
+}
+
+impl Snapshot {
+
+    fn new(static_labels: Vec<(String, String)>) -> Self {
+        Snapshot {
+            static_labels,
+            gathered: None,
+            series: HashMap::new(),
+        }
+    }
+
+    fn refresh(&mut self) {
+        if self.gathered.map_or(false, |gathered| gathered.elapsed() < SNAPSHOT_MAX_AGE) {
+            return;
+        }
+        let mut families = metricreset::gather();
+        metriclabels::add_static_labels(&mut families, &self.static_labels);
+        self.series = convert(&families);
+        self.gathered = Some(Instant::now());
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum SeriesKind {
+    // Monotonic sum, reported cumulatively like the Prometheus counters
+    Sum,
+    Gauge,
+}
+
+#[derive(Debug,PartialEq)]
+struct Series {
+    kind: SeriesKind,
+    help: String,
+    points: Vec<Point>,
+}
+
+#[derive(Debug,PartialEq)]
+struct Point {
+    value: f64,
+    attributes: Vec<KeyValue>,
+}
+
+fn convert(families: &[MetricFamily]) -> HashMap<String, Series> {
+    let mut series = HashMap::new();
+    for family in families {
+        let name = family.get_name();
+        let help = family.get_help();
+        let metrics = family.get_metric();
+        match family.get_field_type() {
+            MetricType::COUNTER => {
+                series.insert(name.to_string(), Series {
+                    kind: SeriesKind::Sum,
+                    help: help.to_string(),
+                    points: metrics.iter().map(|m| point(m, m.get_counter().get_value(), None)).collect(),
+                });
+            },
+            MetricType::GAUGE | MetricType::UNTYPED => {
+                series.insert(name.to_string(), Series {
+                    kind: SeriesKind::Gauge,
+                    help: help.to_string(),
+                    points: metrics.iter().map(|m| point(m, m.get_gauge().get_value(), None)).collect(),
+                });
+            },
+            MetricType::HISTOGRAM => {
+                series.insert(format!("{}_bucket", name), Series {
+                    kind: SeriesKind::Sum,
+                    help: help.to_string(),
+                    points: metrics.iter().flat_map(bucket_points).collect(),
+                });
+                series.insert(format!("{}_sum", name), Series {
+                    kind: SeriesKind::Sum,
+                    help: help.to_string(),
+                    points: metrics.iter().map(|m| point(m, m.get_histogram().get_sample_sum(), None)).collect(),
+                });
+                series.insert(format!("{}_count", name), Series {
+                    kind: SeriesKind::Sum,
+                    help: help.to_string(),
+                    points: metrics.iter().map(|m| point(m, m.get_histogram().get_sample_count() as f64, None)).collect(),
+                });
+            },
+            MetricType::SUMMARY => {},
+        }
+    }
+    series
+}
+
+// The cumulative bucket counts with the upper bound in `le`. The `+Inf` bucket is not
+// among the gathered ones, it's the sample count.
+fn bucket_points(metric: &Metric) -> Vec<Point> {
+    let histogram = metric.get_histogram();
+    let mut points: Vec<Point> = histogram.get_bucket().iter()
+        .filter(|bucket| !bucket.get_upper_bound().is_infinite())
+        .map(|bucket| point(metric, bucket.get_cumulative_count() as f64, Some(bucket.get_upper_bound().to_string())))
+        .collect();
+    points.push(point(metric, histogram.get_sample_count() as f64, Some("+Inf".to_string())));
+    points
+}
+
+fn point(metric: &Metric, value: f64, le: Option<String>) -> Point {
+    let mut attributes: Vec<KeyValue> = metric.get_label().iter()
+        .map(|l| KeyValue::new(l.get_name().to_string(), l.get_value().to_string()))
+        .collect();
+    if let Some(le) = le {
+        attributes.push(KeyValue::new("le", le));
+    }
+    Point {
+        value: if value.is_finite() { value } else { 0.0 },
+        attributes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{CounterVec, Histogram, HistogramOpts, Opts, Registry};
+
+    #[test]
+    fn test_validate_endpoint() {
+        assert!(validate_endpoint("http://collector:4317").is_ok());
+        assert!(validate_endpoint("http://collector:4317/").is_ok());
+        assert!(validate_endpoint("http://collector:4317/v1/metrics").is_err());
+        assert!(validate_endpoint("https://collector:4317").is_err());
+        assert!(validate_endpoint("http://collector").is_err());
+    }
+
+    #[test]
+    fn test_convert() {
+        let registry = Registry::new();
+        let counter = CounterVec::new(Opts::new("requests_total", "Requests"), &["op"]).unwrap();
+        let histogram = Histogram::with_opts(HistogramOpts::new("latency", "Latency").buckets(vec![0.1, 1.0])).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+        counter.with_label_values(&["find"]).inc_by(2.0);
+        histogram.observe(0.0625);
+        histogram.observe(0.75);
+        histogram.observe(4.0);
+
+        let series = convert(&registry.gather());
+        assert_eq!(Some(&Series {
+            kind: SeriesKind::Sum,
+            help: "Requests".to_string(),
+            points: vec![Point { value: 2.0, attributes: vec![KeyValue::new("op", "find")] }],
+        }), series.get("requests_total"));
+
+        let buckets: Vec<(f64, Vec<KeyValue>)> = series["latency_bucket"].points.iter()
+            .map(|p| (p.value, p.attributes.clone()))
+            .collect();
+        assert_eq!(vec![
+            (1.0, vec![KeyValue::new("le", "0.1")]),
+            (2.0, vec![KeyValue::new("le", "1")]),
+            (3.0, vec![KeyValue::new("le", "+Inf")]),
+        ], buckets);
+        assert_eq!(4.8125, series["latency_sum"].points[0].value);
+        assert_eq!(3.0, series["latency_count"].points[0].value);
+        assert_eq!(SeriesKind::Sum, series["latency_count"].kind);
+    }
+}
@@ -0,0 +1,97 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::AsyncRead;
+
+// Limits the time spent parsing a message, as opposed to waiting for its bytes. The
+// time between reads is what the parser spends working, reads that have to wait for
+// more data don't count towards the budget. Once the budget is used up, reads fail
+// with `TimedOut`.
+pub struct ParseBudget<R> {
+    inner: R,
+    budget: Option<Duration>,
+    spent: Duration,
+    // When the last read returned, the parser has been busy since then
+    resumed: Option<Instant>,
+    consumed: u64,
+}
+
+impl<R> ParseBudget<R> {
+
+    // Without a budget this only counts the bytes
+    pub fn new(inner: R, budget: Option<Duration>) -> Self {
+        ParseBudget {
+            inner,
+            budget,
+            spent: Duration::from_secs(0),
+            resumed: None,
+            consumed: 0,
+        }
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        match self.budget {
+            Some(budget) => self.spent > budget,
+            None => false,
+        }
+    }
+
+    // Number of bytes read through this
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ParseBudget<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Some(resumed) = this.resumed.take() {
+            this.spent += resumed.elapsed();
+        }
+        if this.is_exhausted() {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "parse time budget exceeded")));
+        }
+
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(len)) = result {
+            this.consumed += len as u64;
+            this.resumed = Some(Instant::now());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_budget_exceeded() {
+        let buf = [0u8; 16];
+        let mut rdr = ParseBudget::new(&buf[..], Some(Duration::from_millis(1)));
+
+        assert_eq!(0, rdr.read_u32_le().await.unwrap());
+        thread::sleep(Duration::from_millis(5));
+
+        let e = rdr.read_u32_le().await.unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, e.kind());
+        assert!(rdr.is_exhausted());
+        assert_eq!(4, rdr.consumed());
+    }
+
+    #[tokio::test]
+    async fn test_no_budget() {
+        let buf = [0u8; 16];
+        let mut rdr = ParseBudget::new(&buf[..], None);
+
+        rdr.read_u32_le().await.unwrap();
+        thread::sleep(Duration::from_millis(5));
+        rdr.read_u64_le().await.unwrap();
+        assert!(!rdr.is_exhausted());
+        assert_eq!(12, rdr.consumed());
+    }
+}
@@ -0,0 +1,318 @@
+use crate::mongodb::{MongoMessage, MsgOpQuery};
+
+use std::collections::HashSet;
+
+use prometheus::{CounterVec};
+
+lazy_static! {
+    static ref LEGACY_REJECTED_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_legacy_rejected_total",
+            "Number of connections closed for using legacy opcodes",
+            &["op"]).unwrap();
+
+    static ref COMMANDS_DENIED_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_commands_denied_total",
+            "Number of client commands denied by the command allow or deny list",
+            &["command"]).unwrap();
+}
+
+// Why a request was rejected, with the error code for the error reply to the client
+#[derive(Debug)]
+pub struct PolicyViolation {
+    pub code: i32,
+    pub code_name: &'static str,
+    pub reason: String,
+}
+
+// Rules that client requests are checked against before they are forwarded to the
+// server. Normally the proxy does not look at the bytes before passing them on, so
+// this is only done when at least one of the rules is enabled.
+//
+// Command names in the allow and deny lists are lowercase, commands are matched
+// case insensitively.
+#[derive(Clone,Debug,Default)]
+pub struct RequestPolicy {
+    pub reject_legacy_opcodes: bool,
+    pub deny_commands: HashSet<String>,
+    pub allow_commands: Option<HashSet<String>>,
+}
+
+impl RequestPolicy {
+
+    // Do we need to inspect client messages before forwarding them
+    pub fn is_enforcing(&self) -> bool {
+        self.reject_legacy_opcodes
+            || !self.deny_commands.is_empty()
+            || self.allow_commands.is_some()
+    }
+
+    // Check the client request against the policy. Returns the violation if the
+    // request must not be forwarded.
+    pub fn check(&self, msg: &MongoMessage) -> Result<(), PolicyViolation> {
+        if self.reject_legacy_opcodes {
+            if let Some(op) = legacy_op_name(msg) {
+                LEGACY_REJECTED_TOTAL.with_label_values(&[op]).inc();
+                return Err(PolicyViolation {
+                    code: 352,
+                    code_name: "UnsupportedOpQueryCommand",
+                    reason: format!("legacy opcode {} is not allowed", op),
+                });
+            }
+        }
+
+        if let Some(command) = command_name(msg) {
+            if !self.is_command_allowed(command) {
+                COMMANDS_DENIED_TOTAL.with_label_values(&[&self.denied_label(command)]).inc();
+                return Err(PolicyViolation {
+                    code: 13,
+                    code_name: "Unauthorized",
+                    reason: format!("command {} is not allowed", command),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_command_allowed(&self, command: &str) -> bool {
+        let command = command.to_lowercase();
+
+        if self.deny_commands.contains(&command) {
+            return false;
+        }
+
+        match &self.allow_commands {
+            // The handshake and authentication are always allowed, otherwise clients couldn't connect
+            Some(allowed) => allowed.contains(&command)
+                || is_handshake_command(&command)
+                || is_auth_command(&command),
+            None => true,
+        }
+    }
+
+    // The command label of a denied command. Only the names on the deny list are used,
+    // anything else the client sends is "other" so that it can't create new series.
+    fn denied_label(&self, command: &str) -> String {
+        let command = command.to_lowercase();
+        if self.deny_commands.contains(&command) {
+            command
+        } else {
+            "other".to_string()
+        }
+    }
+}
+
+// Parse a comma separated list of command names
+pub fn parse_command_list(list: &str) -> HashSet<String> {
+    list.split(',')
+        .map(|c| c.trim().to_lowercase())
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+// Name of the command in the client request, if it is one. OP_MSG has the command
+// name as the first key of the body document. For OP_QUERY only queries against the
+// "$cmd" collection are commands.
+fn command_name(msg: &MongoMessage) -> Option<&str> {
+    match msg {
+        MongoMessage::Msg(m) => m.documents.first().and_then(|doc| doc.get_str("op")),
+        MongoMessage::Query(q) if q.full_collection_name.ends_with(".$cmd") => q.query.get_str("op"),
+        _ => None,
+    }
+}
+
+// Is this the hello/isMaster request that starts a connection
+pub fn is_handshake(msg: &MongoMessage) -> bool {
+    match command_name(msg) {
+        Some(command) => is_handshake_command(&command.to_lowercase()),
+        None => false,
+    }
+}
+
+fn is_handshake_command(command: &str) -> bool {
+    command == "ismaster" || command == "hello"
+}
+
+fn is_auth_command(command: &str) -> bool {
+    command == "saslstart" || command == "saslcontinue" || command == "authenticate" || command == "logout"
+}
+
+// Return the name of the legacy opcode if this message uses one. OP_QUERY is allowed
+// for the initial handshake, as drivers send the first isMaster that way.
+fn legacy_op_name(msg: &MongoMessage) -> Option<&'static str> {
+    match msg {
+        MongoMessage::Query(q) if !is_handshake_query(q) => Some("OP_QUERY"),
+        MongoMessage::Insert(_) => Some("OP_INSERT"),
+        MongoMessage::Update(_) => Some("OP_UPDATE"),
+        MongoMessage::Delete(_) => Some("OP_DELETE"),
+        _ => None,
+    }
+}
+
+fn is_handshake_query(q: &MsgOpQuery) -> bool {
+    if !q.full_collection_name.ends_with(".$cmd") {
+        return false;
+    }
+    match q.query.get_str("op") {
+        Some(op) => is_handshake_command(&op.to_lowercase()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mongodb::{MsgHeader, HEADER_LENGTH};
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use bson::doc;
+    use std::io::Write;
+
+    async fn op_query(collection: &str, query: bson::Document) -> MongoMessage {
+        let mut body = Vec::new();
+        body.write_i32::<LittleEndian>(0).unwrap();     // flags
+        body.write_all(collection.as_bytes()).unwrap();
+        body.write_u8(0).unwrap();
+        body.write_i32::<LittleEndian>(0).unwrap();     // number to skip
+        body.write_i32::<LittleEndian>(1).unwrap();     // number to return
+        query.to_writer(&mut body).unwrap();
+
+        let hdr = MsgHeader {
+            message_length: HEADER_LENGTH + body.len(),
+            request_id: 1,
+            response_to: 0,
+            op_code: 2004,
+        };
+        let mut buf = Vec::new();
+        hdr.write(&mut buf).unwrap();
+        buf.extend(&body);
+
+        MongoMessage::from_buffer(&buf).await.unwrap().1
+    }
+
+    async fn op_msg(command: bson::Document) -> MongoMessage {
+        let mut body = Vec::new();
+        body.write_u32::<LittleEndian>(0).unwrap();     // flag bits
+        body.write_u8(0).unwrap();                      // section kind
+        command.to_writer(&mut body).unwrap();
+
+        let hdr = MsgHeader {
+            message_length: HEADER_LENGTH + body.len(),
+            request_id: 1,
+            response_to: 0,
+            op_code: 2013,
+        };
+        let mut buf = Vec::new();
+        hdr.write(&mut buf).unwrap();
+        buf.extend(&body);
+
+        MongoMessage::from_buffer(&buf).await.unwrap().1
+    }
+
+    #[tokio::test]
+    async fn test_reject_legacy_opcodes() {
+        let policy = RequestPolicy { reject_legacy_opcodes: true, ..Default::default() };
+
+        let handshake = op_query("admin.$cmd", doc! { "isMaster": 1 }).await;
+        assert!(policy.check(&handshake).is_ok());
+
+        let find = op_query("test.$cmd", doc! { "find": "kittens" }).await;
+        assert!(policy.check(&find).is_err());
+
+        let query = op_query("test.kittens", doc! { "isMaster": 1 }).await;
+        assert!(policy.check(&query).is_err());
+
+        let permissive = RequestPolicy::default();
+        assert!(!permissive.is_enforcing());
+        assert!(permissive.check(&find).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_deny_commands() {
+        let policy = RequestPolicy {
+            deny_commands: parse_command_list("dropDatabase, shutdown"),
+            ..Default::default()
+        };
+        assert!(policy.is_enforcing());
+
+        let drop = op_msg(doc! { "dropDatabase": 1, "$db": "test" }).await;
+        assert!(policy.check(&drop).is_err());
+
+        let shutdown = op_query("admin.$cmd", doc! { "shutdown": 1 }).await;
+        assert!(policy.check(&shutdown).is_err());
+
+        // The label is the lowercase name from the list
+        let denied = COMMANDS_DENIED_TOTAL.with_label_values(&["dropdatabase"]).get();
+        let drop_upper = op_msg(doc! { "DROPDATABASE": 1, "$db": "test" }).await;
+        assert!(policy.check(&drop_upper).is_err());
+        assert_eq!(denied + 1.0, COMMANDS_DENIED_TOTAL.with_label_values(&["dropdatabase"]).get());
+
+        let find = op_msg(doc! { "find": "kittens", "$db": "test" }).await;
+        assert!(policy.check(&find).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_allow_commands() {
+        let policy = RequestPolicy {
+            allow_commands: Some(parse_command_list("find,getMore")),
+            ..Default::default()
+        };
+
+        let find = op_msg(doc! { "find": "kittens", "$db": "test" }).await;
+        assert!(policy.check(&find).is_ok());
+
+        let get_more = op_msg(doc! { "getmore": 1i64, "$db": "test" }).await;
+        assert!(policy.check(&get_more).is_ok());
+
+        // Commands that are not on the lists are labeled as other
+        let other = COMMANDS_DENIED_TOTAL.with_label_values(&["other"]).get();
+        let insert = op_msg(doc! { "insert": "kittens", "$db": "test" }).await;
+        assert!(policy.check(&insert).is_err());
+        assert_eq!(other + 1.0, COMMANDS_DENIED_TOTAL.with_label_values(&["other"]).get());
+
+        let hello = op_msg(doc! { "hello": 1, "$db": "admin" }).await;
+        assert!(policy.check(&hello).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_allow_commands_authentication() {
+        let policy = RequestPolicy {
+            allow_commands: Some(parse_command_list("find")),
+            ..Default::default()
+        };
+
+        let sasl_start = op_msg(doc! { "saslStart": 1, "mechanism": "SCRAM-SHA-256", "$db": "admin" }).await;
+        assert!(policy.check(&sasl_start).is_ok());
+
+        let sasl_continue = op_msg(doc! { "saslContinue": 1, "conversationId": 1, "$db": "admin" }).await;
+        assert!(policy.check(&sasl_continue).is_ok());
+
+        let find = op_msg(doc! { "find": "kittens", "$db": "test" }).await;
+        assert!(policy.check(&find).is_ok());
+
+        // The deny list still applies to them
+        let policy = RequestPolicy {
+            deny_commands: parse_command_list("authenticate"),
+            ..Default::default()
+        };
+        let authenticate = op_msg(doc! { "authenticate": 1, "mechanism": "MONGODB-X509", "$db": "$external" }).await;
+        assert!(policy.check(&authenticate).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_is_handshake() {
+        assert!(is_handshake(&op_query("admin.$cmd", doc! { "isMaster": 1 }).await));
+        assert!(is_handshake(&op_msg(doc! { "hello": 1, "$db": "admin" }).await));
+        assert!(!is_handshake(&op_query("test.kittens", doc! { "isMaster": 1 }).await));
+        assert!(!is_handshake(&op_msg(doc! { "ping": 1, "$db": "admin" }).await));
+    }
+
+    #[test]
+    fn test_parse_command_list() {
+        let commands = parse_command_list("dropDatabase, shutdown,,");
+        assert_eq!(2, commands.len());
+        assert!(commands.contains("dropdatabase"));
+        assert!(commands.contains("shutdown"));
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Profile length when the request doesn't ask for one
+pub const DEFAULT_PROFILE_SECONDS: u64 = 30;
+
+// Longest profile that can be requested, it holds an admin listener thread meanwhile
+pub const MAX_PROFILE_SECONDS: u64 = 300;
+
+// Sampling frequency, slightly off 100Hz so that it doesn't run in lockstep with timers
+const SAMPLE_FREQUENCY: i32 = 99;
+
+// Captures CPU profiles on demand for the admin endpoint. The profiler samples the
+// whole process, so only one profile can run at a time.
+#[derive(Clone,Debug,Default)]
+pub struct CpuProfiler {
+    running: Arc<AtomicBool>,
+}
+
+#[derive(Debug,PartialEq)]
+pub enum ProfileError {
+    // Another profile is already running
+    Busy,
+    Failed(String),
+}
+
+// Held while a profile runs, lets the next one start when dropped
+struct Running {
+    running: Arc<AtomicBool>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+impl CpuProfiler {
+
+    pub fn new() -> Self {
+        CpuProfiler::default()
+    }
+
+    // Sample the process for the duration and return the profile as a flame graph SVG
+    pub fn flamegraph(&self, duration: Duration) -> Result<Vec<u8>, ProfileError> {
+        let _running = self.try_start().ok_or(ProfileError::Busy)?;
+
+        let guard = pprof::ProfilerGuard::new(SAMPLE_FREQUENCY)
+            .map_err(|e| ProfileError::Failed(e.to_string()))?;
+        thread::sleep(duration);
+        let report = guard.report().build()
+            .map_err(|e| ProfileError::Failed(e.to_string()))?;
+
+        let mut svg = Vec::new();
+        report.flamegraph(&mut svg)
+            .map_err(|e| ProfileError::Failed(e.to_string()))?;
+        Ok(svg)
+    }
+
+    fn try_start(&self) -> Option<Running> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        Some(Running { running: self.running.clone() })
+    }
+}
+
+// The profile duration from the `seconds` query parameter
+pub fn parse_seconds(param: Option<&str>) -> Result<Duration, String> {
+    let seconds = match param {
+        Some(value) => value.parse::<u64>().map_err(|_| "seconds must be a number".to_string())?,
+        None => DEFAULT_PROFILE_SECONDS,
+    };
+    if seconds == 0 || seconds > MAX_PROFILE_SECONDS {
+        return Err(format!("seconds must be between 1 and {}", MAX_PROFILE_SECONDS));
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_profile_at_a_time() {
+        let profiler = CpuProfiler::new();
+        let running = profiler.try_start();
+        assert!(running.is_some());
+        assert!(profiler.clone().try_start().is_none());
+
+        drop(running);
+        assert!(profiler.try_start().is_some());
+    }
+
+    #[test]
+    fn test_parse_seconds() {
+        assert_eq!(Ok(Duration::from_secs(DEFAULT_PROFILE_SECONDS)), parse_seconds(None));
+        assert_eq!(Ok(Duration::from_secs(5)), parse_seconds(Some("5")));
+        assert!(parse_seconds(Some("0")).is_err());
+        assert!(parse_seconds(Some("301")).is_err());
+        assert!(parse_seconds(Some("five")).is_err());
+    }
+}
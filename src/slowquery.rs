@@ -0,0 +1,252 @@
+use std::io::{self, Read};
+use std::sync::{Arc,Mutex};
+use std::time::Duration;
+
+use crossbeam_channel::{self, Receiver, RecvTimeoutError, Sender, TrySendError};
+use prometheus::{Counter};
+
+// Events buffered for each subscriber, newer events are dropped when this is full
+const SUBSCRIBER_BUFFER: usize = 100;
+
+// Each subscriber holds an admin listener thread, so don't let them take all of them
+pub const MAX_SUBSCRIBERS: usize = 4;
+
+// Send a comment line this often when there are no events, so that the subscribers
+// that have gone away are noticed.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+lazy_static! {
+    static ref SLOW_QUERY_EVENTS_DROPPED_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_slow_query_events_dropped_total",
+            "Number of slow query events dropped because a subscriber was lagging"
+            ).unwrap();
+}
+
+// A command that took longer than the slow query threshold
+#[derive(Debug)]
+pub struct SlowQuery<'a> {
+    pub op: &'a str,
+    pub db: &'a str,
+    pub collection: &'a str,
+    pub app: &'a str,
+    pub client: &'a str,
+    pub duration: Duration,
+    // Top level field names of the query filter, the values are never included
+    pub filter_fields: &'a [String],
+}
+
+impl SlowQuery<'_> {
+
+    // The event as a JSON object. The filter is redacted so that only the field
+    // names remain, eg. `{"status": "?"}`.
+    pub fn to_json(&self) -> String {
+        let filter = if self.filter_fields.is_empty() {
+            "null".to_string()
+        } else {
+            let fields: Vec<String> = self.filter_fields.iter()
+                .map(|f| format!("{}:\"?\"", json_string(f)))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        };
+
+        format!("{{\"command\":{},\"db\":{},\"collection\":{},\"app\":{},\"client\":{},\"duration_ms\":{:.3},\"filter\":{}}}",
+            json_string(self.op),
+            json_string(self.db),
+            json_string(self.collection),
+            json_string(self.app),
+            json_string(self.client),
+            self.duration.as_secs_f64() * 1000.0,
+            filter)
+    }
+}
+
+// Broadcasts the slow queries to the subscribers of the admin endpoint stream. Every
+// subscriber has a bounded buffer, a subscriber that doesn't keep up loses events
+// instead of holding up the proxy.
+#[derive(Clone,Debug)]
+pub struct SlowQueryFeed {
+    threshold: Duration,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    events: Sender<String>,
+    // Shared with the EventStream, to notice when it has been dropped
+    stream: Arc<()>,
+}
+
+impl Subscriber {
+    fn is_gone(&self) -> bool {
+        Arc::strong_count(&self.stream) == 1
+    }
+}
+
+impl SlowQueryFeed {
+
+    pub fn new(threshold: Duration) -> Self {
+        SlowQueryFeed {
+            threshold,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn is_slow(&self, duration: Duration) -> bool {
+        duration >= self.threshold
+    }
+
+    pub fn publish(&self, query: &SlowQuery) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() {
+            return;
+        }
+
+        let event = query.to_json();
+        subscribers.retain(|subscriber| match subscriber.events.try_send(event.clone()) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) => {
+                SLOW_QUERY_EVENTS_DROPPED_TOTAL.inc();
+                true
+            },
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    // Start a new stream of events, unless there are too many subscribers already
+    pub fn subscribe(&self) -> Option<EventStream> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| !subscriber.is_gone());
+        if subscribers.len() >= MAX_SUBSCRIBERS {
+            return None;
+        }
+
+        let (tx, rx) = crossbeam_channel::bounded(SUBSCRIBER_BUFFER);
+        let stream = Arc::new(());
+        subscribers.push(Subscriber { events: tx, stream: stream.clone() });
+        Some(EventStream::new(rx, stream))
+    }
+}
+
+// The events of one subscriber in the Server-Sent Events format, for use as an
+// HTTP response body. Reads block until there is an event to send.
+pub struct EventStream {
+    events: Receiver<String>,
+    pending: Vec<u8>,
+    pos: usize,
+    _subscription: Arc<()>,
+}
+
+impl EventStream {
+    fn new(events: Receiver<String>, subscription: Arc<()>) -> Self {
+        EventStream {
+            events,
+            pending: Vec::new(),
+            pos: 0,
+            _subscription: subscription,
+        }
+    }
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.pending.len() {
+            self.pending = match self.events.recv_timeout(KEEPALIVE_INTERVAL) {
+                Ok(event) => format!("data: {}\n\n", event).into_bytes(),
+                Err(RecvTimeoutError::Timeout) => b": keepalive\n\n".to_vec(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            self.pos = 0;
+        }
+
+        let len = buf.len().min(self.pending.len() - self.pos);
+        buf[..len].copy_from_slice(&self.pending[self.pos..self.pos+len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+// The string as a JSON string literal
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(filter_fields: &[String]) -> SlowQuery<'_> {
+        SlowQuery {
+            op: "find",
+            db: "test",
+            collection: "kit\"tens",
+            app: "",
+            client: "127.0.0.1",
+            duration: Duration::from_millis(1500),
+            filter_fields,
+        }
+    }
+
+    #[test]
+    fn test_to_json() {
+        let fields = vec!["color".to_string(), "age".to_string()];
+        assert_eq!(
+            r#"{"command":"find","db":"test","collection":"kit\"tens","app":"","client":"127.0.0.1","duration_ms":1500.000,"filter":{"color":"?","age":"?"}}"#,
+            query(&fields).to_json());
+        assert!(query(&[]).to_json().ends_with(r#""filter":null}"#));
+    }
+
+    #[test]
+    fn test_feed() {
+        let feed = SlowQueryFeed::new(Duration::from_millis(100));
+        assert!(feed.is_slow(Duration::from_millis(100)));
+        assert!(!feed.is_slow(Duration::from_millis(99)));
+
+        let mut first = feed.subscribe().unwrap();
+        let second = feed.subscribe().unwrap();
+        feed.publish(&query(&[]));
+
+        let mut buf = [0; 4096];
+        let len = first.read(&mut buf).unwrap();
+        let event = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(event.starts_with("data: {\"command\":\"find\""));
+        assert!(event.ends_with("}\n\n"));
+
+        // Subscribers that have gone away are removed on the next event
+        drop(second);
+        feed.publish(&query(&[]));
+        assert_eq!(1, feed.subscribers.lock().unwrap().len());
+        assert!(first.read(&mut buf).unwrap() > 0);
+    }
+
+    #[test]
+    fn test_lagging_subscriber() {
+        let feed = SlowQueryFeed::new(Duration::from_millis(100));
+        let _lagging = feed.subscribe().unwrap();
+        for _ in 0..SUBSCRIBER_BUFFER + 10 {
+            feed.publish(&query(&[]));
+        }
+        assert_eq!(1, feed.subscribers.lock().unwrap().len());
+
+        let more: Vec<_> = (1..MAX_SUBSCRIBERS).map(|_| feed.subscribe().unwrap()).collect();
+        assert!(feed.subscribe().is_none());
+
+        // Closed streams free up their slots
+        drop(more);
+        assert!(feed.subscribe().is_some());
+    }
+}
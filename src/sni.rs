@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::io;
+
+use byteorder::{BigEndian, ByteOrder};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const TLS_RECORD_HEADER_LENGTH: usize = 5;
+const TLS_CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const TLS_HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const TLS_EXTENSION_SERVER_NAME: u16 = 0;
+const TLS_SERVER_NAME_HOST: u8 = 0;
+
+// Largest record allowed by the TLS spec, and the largest ClientHello we buffer. Real
+// ones are a few hundred bytes, a bit more with post-quantum key shares.
+const MAX_RECORD_LENGTH: usize = 16 * 1024;
+const MAX_CLIENT_HELLO_LENGTH: usize = 64 * 1024;
+
+// The upstreams of the TLS connections by the server name (SNI) in the ClientHello, from
+// --sni-route. The names are matched exactly, ignoring case.
+#[derive(Clone,Debug,PartialEq)]
+pub struct SniRoutes {
+    routes: HashMap<String, String>,
+}
+
+impl SniRoutes {
+
+    // Routes as (server name, comma separated upstreams)
+    pub fn new(routes: Vec<(String, String)>) -> Self {
+        SniRoutes {
+            routes: routes.into_iter()
+                .map(|(name, upstream)| (name.to_lowercase(), upstream))
+                .collect(),
+        }
+    }
+
+    pub fn upstream(&self, server_name: &str) -> Option<&str> {
+        self.routes.get(&server_name.to_lowercase()).map(String::as_str)
+    }
+
+    // The routes as "name=upstream", sorted by name
+    pub fn specs(&self) -> Vec<String> {
+        let mut specs: Vec<String> = self.routes.iter()
+            .map(|(name, upstream)| format!("{}={}", name, upstream))
+            .collect();
+        specs.sort();
+        specs
+    }
+}
+
+// The ClientHello as it was received, to be replayed to the upstream, and the server
+// name from it
+#[derive(Debug)]
+pub struct ClientHello {
+    pub raw: Vec<u8>,
+    pub server_name: Option<String>,
+}
+
+// Read the TLS records that make up the ClientHello, without consuming anything after
+// them. Returns None if the client closes the connection before sending anything and
+// an InvalidData error if it's not a TLS ClientHello.
+pub async fn read_client_hello<R>(rdr: &mut R) -> io::Result<Option<ClientHello>>
+    where R: AsyncRead + Unpin
+{
+    let mut raw = Vec::new();
+    let mut handshake = Vec::new();
+
+    loop {
+        let mut header = [0; TLS_RECORD_HEADER_LENGTH];
+        if raw.is_empty() {
+            let len = rdr.read(&mut header).await?;
+            if len == 0 {
+                return Ok(None);
+            }
+            rdr.read_exact(&mut header[len..]).await?;
+        } else {
+            rdr.read_exact(&mut header).await?;
+        }
+
+        if header[0] != TLS_CONTENT_TYPE_HANDSHAKE {
+            return Err(invalid("not a TLS handshake"));
+        }
+        let length = BigEndian::read_u16(&header[3..]) as usize;
+        if length == 0 || length > MAX_RECORD_LENGTH {
+            return Err(invalid("invalid TLS record length"));
+        }
+
+        let mut payload = vec![0; length];
+        rdr.read_exact(&mut payload).await?;
+        raw.extend_from_slice(&header);
+        raw.extend_from_slice(&payload);
+        handshake.extend_from_slice(&payload);
+
+        // The ClientHello can be split over several records
+        if handshake.len() >= 4 {
+            let hello_length = 4 + BigEndian::read_u24(&handshake[1..4]) as usize;
+            if hello_length > MAX_CLIENT_HELLO_LENGTH {
+                return Err(invalid("ClientHello too long"));
+            }
+            if handshake.len() >= hello_length {
+                let server_name = parse_server_name(&handshake[..hello_length])?;
+                return Ok(Some(ClientHello { raw, server_name }));
+            }
+        }
+    }
+}
+
+// The host name from the server_name extension of a ClientHello handshake message
+fn parse_server_name(hello: &[u8]) -> io::Result<Option<String>> {
+    let mut rdr = SliceReader(hello);
+    if rdr.u8()? != TLS_HANDSHAKE_CLIENT_HELLO {
+        return Err(invalid("not a ClientHello"));
+    }
+    rdr.take(3)?;                   // length
+    rdr.take(2 + 32)?;              // version and random
+    let session_id = rdr.u8()? as usize;
+    rdr.take(session_id)?;
+    let cipher_suites = rdr.u16()? as usize;
+    rdr.take(cipher_suites)?;
+    let compression_methods = rdr.u8()? as usize;
+    rdr.take(compression_methods)?;
+
+    // No extensions at all
+    if rdr.0.is_empty() {
+        return Ok(None);
+    }
+
+    let extensions_length = rdr.u16()? as usize;
+    let mut extensions = SliceReader(rdr.take(extensions_length)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let length = extensions.u16()? as usize;
+        let data = extensions.take(length)?;
+        if extension_type != TLS_EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = SliceReader(data);
+        let list_length = names.u16()? as usize;
+        let mut names = SliceReader(names.take(list_length)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let length = names.u16()? as usize;
+            let name = names.take(length)?;
+            if name_type == TLS_SERVER_NAME_HOST {
+                let name = std::str::from_utf8(name).map_err(|_| invalid("server name is not valid UTF-8"))?;
+                return Ok(Some(name.to_string()));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Reads the big endian fields of the handshake message, failing on truncated input
+struct SliceReader<'a>(&'a [u8]);
+
+impl<'a> SliceReader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid("truncated ClientHello"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(BigEndian::read_u16(self.take(2)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A minimal ClientHello handshake message with the given extensions
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);                               // session id
+        body.extend_from_slice(&[0, 2, 0x13, 0x01]);  // cipher suites
+        body.extend_from_slice(&[1, 0]);            // compression methods
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(extensions);
+
+        let mut hello = vec![TLS_HANDSHAKE_CLIENT_HELLO];
+        hello.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        hello.extend_from_slice(&body);
+        hello
+    }
+
+    fn server_name_extension(name: &str) -> Vec<u8> {
+        let mut list = vec![TLS_SERVER_NAME_HOST];
+        list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        list.extend_from_slice(name.as_bytes());
+
+        let mut extension = vec![0, 0];
+        extension.extend_from_slice(&(list.len() as u16 + 2).to_be_bytes());
+        extension.extend_from_slice(&(list.len() as u16).to_be_bytes());
+        extension.extend_from_slice(&list);
+        extension
+    }
+
+    // The handshake message split into TLS records of at most `record_size` bytes
+    fn records(hello: &[u8], record_size: usize) -> Vec<u8> {
+        let mut raw = Vec::new();
+        for chunk in hello.chunks(record_size) {
+            raw.extend_from_slice(&[TLS_CONTENT_TYPE_HANDSHAKE, 3, 1]);
+            raw.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            raw.extend_from_slice(chunk);
+        }
+        raw
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello() {
+        // An unrelated extension before the server name
+        let mut extensions = vec![0, 10, 0, 2, 0, 29];
+        extensions.extend_from_slice(&server_name_extension("Mongo-A.example.com"));
+        let hello = client_hello(&extensions);
+
+        for &record_size in &[hello.len(), 10] {
+            let raw = records(&hello, record_size);
+            let mut stream = raw.clone();
+            stream.extend_from_slice(b"application data");
+
+            let mut rdr = &stream[..];
+            let parsed = read_client_hello(&mut rdr).await.unwrap().unwrap();
+            assert_eq!(raw, parsed.raw);
+            assert_eq!(Some("Mongo-A.example.com".to_string()), parsed.server_name);
+            // The rest of the stream is left for proxying
+            assert_eq!(b"application data", rdr);
+        }
+
+        let parsed = read_client_hello(&mut &records(&client_hello(&[]), 512)[..]).await.unwrap().unwrap();
+        assert_eq!(None, parsed.server_name);
+
+        assert!(read_client_hello(&mut &b""[..]).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_not_a_client_hello() {
+        let e = read_client_hello(&mut &b"GET / HTTP/1.1\r\n\r\n"[..]).await.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+
+        // A MongoDb hello instead of TLS
+        let message = crate::mongodb::error_reply(1, 13, "Unauthorized", "not allowed").unwrap();
+        assert!(read_client_hello(&mut &message[..]).await.is_err());
+
+        let hello = client_hello(&server_name_extension("mongo"));
+        let truncated = records(&hello[..hello.len() - 3], 512);
+        assert!(read_client_hello(&mut &truncated[..]).await.is_err());
+    }
+
+    #[test]
+    fn test_sni_routes() {
+        let routes = SniRoutes::new(vec![
+            ("Mongo-A.example.com".to_string(), "mongo-a:27017".to_string()),
+            ("mongo-b.example.com".to_string(), "mongo-b1:27017,mongo-b2:27017".to_string()),
+        ]);
+        assert_eq!(Some("mongo-a:27017"), routes.upstream("mongo-a.example.com"));
+        assert_eq!(Some("mongo-b1:27017,mongo-b2:27017"), routes.upstream("MONGO-B.example.com"));
+        assert_eq!(None, routes.upstream("example.com"));
+        assert_eq!(vec!["mongo-a.example.com=mongo-a:27017", "mongo-b.example.com=mongo-b1:27017,mongo-b2:27017"],
+            routes.specs());
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt::{self, Display};
+use std::io;
+use std::net::{SocketAddr,TcpListener};
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use socket2::{Domain, Socket, Type};
+use socket2::{SockRef, TcpKeepalive};
+
+// Listen backlog for the listeners that we create ourselves, same as std uses
+const LISTEN_BACKLOG: i32 = 128;
+
+// Which sides of the proxied connections get TCP_NODELAY, ie. Nagle's algorithm
+// disabled. Nagle can help throughput on bulk transfers, at the cost of latency for
+// small messages.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct NoDelay {
+    pub client: bool,
+    pub server: bool,
+}
+
+impl FromStr for NoDelay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut nodelay = NoDelay { client: false, server: false };
+        if s == "none" {
+            return Ok(nodelay);
+        }
+        for side in s.split(',') {
+            match side.trim() {
+                "client" => nodelay.client = true,
+                "server" => nodelay.server = true,
+                _ => return Err("expecting client, server, client,server or none".to_string()),
+            }
+        }
+        Ok(nodelay)
+    }
+}
+
+impl Display for NoDelay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.client, self.server) {
+            (true, true) => f.write_str("client,server"),
+            (true, false) => f.write_str("client"),
+            (false, true) => f.write_str("server"),
+            (false, false) => f.write_str("none"),
+        }
+    }
+}
+
+// Create a listening socket with SO_REUSEPORT set, so that several of them can be
+// bound to the same address. The kernel then distributes the incoming connections
+// between the listeners.
+#[cfg(target_os = "linux")]
+pub fn reuseport_listener(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reuseport_listener(_addr: &SocketAddr) -> io::Result<TcpListener> {
+    Err(io::Error::new(io::ErrorKind::Other, "SO_REUSEPORT listeners are only supported on Linux"))
+}
+
+// Enable TCP keepalive on a connection, with the probes starting after `idle` time
+// without traffic
+pub fn set_keepalive<S: AsRawFd>(stream: &S, idle: Duration) -> io::Result<()> {
+    SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_set_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        set_keepalive(&stream, Duration::from_secs(60)).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(Duration::from_secs(60), socket.keepalive_time().unwrap());
+    }
+
+    #[test]
+    fn test_connections_are_distributed() {
+        let first = reuseport_listener(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = reuseport_listener(&addr).unwrap();
+
+        // Keep the clients around so that the connections stay in the accept queues
+        let clients: Vec<TcpStream> = (0..32)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+
+        let mut accepted = Vec::new();
+        for listener in &[first, second] {
+            listener.set_nonblocking(true).unwrap();
+            let mut count = 0;
+            while listener.accept().is_ok() {
+                count += 1;
+            }
+            accepted.push(count);
+        }
+
+        assert_eq!(clients.len(), accepted.iter().sum::<usize>());
+        assert!(accepted.iter().all(|&count| count > 0), "accepted: {:?}", accepted);
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::{self, Error, ErrorKind};
+
+use tracing::{debug, info};
+use trust_dns_resolver::Resolver;
+
+// A target from a SRV record
+#[derive(Debug,Clone,PartialEq)]
+pub struct SrvTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub host: String,
+}
+
+impl SrvTarget {
+    pub fn hostport(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+// Look up the SRV records of `name`, eg. "_mongodb._tcp.cluster0.example.com", with the
+// system resolver configuration. The targets are ordered by preference: lowest priority
+// first and highest weight first within the same priority. TXT records are not looked at.
+//
+// This blocks and runs a runtime of its own, so call it before starting the proxy runtime.
+pub fn lookup_srv(name: &str) -> io::Result<Vec<SrvTarget>> {
+    debug!("Looking up SRV {}", name);
+
+    let resolver = Resolver::from_system_conf()?;
+    let lookup = resolver.srv_lookup(name)
+        .map_err(|e| Error::new(ErrorKind::NotFound, e.to_string()))?;
+
+    let targets: Vec<SrvTarget> = lookup.iter()
+        .map(|srv| SrvTarget {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            port: srv.port(),
+            host: srv.target().to_utf8().trim_end_matches('.').to_string(),
+        })
+        .collect();
+    if targets.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, "no SRV records found"));
+    }
+
+    let targets = by_preference(targets);
+    info!("SRV {} targets: {:?}", name, targets);
+    Ok(targets)
+}
+
+// The targets as a comma separated failover list, in the same form as the upstream
+// of --proxy
+pub fn failover_list(targets: &[SrvTarget]) -> String {
+    targets.iter()
+        .map(SrvTarget::hostport)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn by_preference(mut targets: Vec<SrvTarget>) -> Vec<SrvTarget> {
+    targets.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)));
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(priority: u16, weight: u16, host: &str) -> SrvTarget {
+        SrvTarget { priority, weight, port: 27017, host: host.to_string() }
+    }
+
+    #[test]
+    fn test_failover_list() {
+        let targets = by_preference(vec![
+            target(1, 10, "mongo3.example.com"),
+            target(0, 5, "mongo2.example.com"),
+            target(0, 10, "mongo1.example.com"),
+        ]);
+        assert_eq!("mongo1.example.com:27017,mongo2.example.com:27017,mongo3.example.com:27017",
+            failover_list(&targets));
+
+        assert_eq!("mongo1.example.com:27017", failover_list(&[target(0, 0, "mongo1.example.com")]));
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::slowquery::json_string;
+
+// Keep this many of the most recent latencies per command
+const SAMPLES_PER_COMMAND: usize = 1000;
+
+// The /top-commands window is counted in buckets of this length, up to the max window
+const RATE_BUCKET_SECONDS: u64 = 10;
+const MAX_TOP_WINDOW_SECONDS: u64 = 3600;
+const MAX_RATE_BUCKETS: u64 = MAX_TOP_WINDOW_SECONDS / RATE_BUCKET_SECONDS;
+
+const DEFAULT_TOP_COMMANDS: usize = 10;
+const MAX_TOP_COMMANDS: usize = 100;
+
+// A connection adds its latencies to the shared stats after this many responses, or
+// with the first response after this much time has passed since the last time
+const FLUSH_RESPONSES: usize = 64;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// The commands are from a fixed set, anything else is counted as this so that the
+// clients can't grow the stats with made up command names
+pub const OTHER_COMMAND: &str = "other";
+
+lazy_static! {
+    static ref RESPONSE_LATENCIES: Mutex<LatencySamples> = Mutex::new(LatencySamples::new());
+    static ref COMMAND_RATES: Mutex<CommandRates> = Mutex::new(CommandRates::new(Instant::now()));
+}
+
+// Latency percentiles of a command, in seconds
+#[derive(Debug)]
+pub struct CommandStats {
+    pub command: String,
+    pub samples: usize,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+// The most recent response latencies per command. This is for a quick look at the
+// current state of things on the /stats endpoint, Prometheus histograms are the
+// real source of truth.
+pub struct LatencySamples {
+    samples: HashMap<&'static str, VecDeque<f64>>,
+}
+
+impl LatencySamples {
+
+    pub fn new() -> Self {
+        LatencySamples {
+            samples: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, command: &'static str, seconds: f64) {
+        let samples = self.samples
+            .entry(command)
+            .or_insert_with(|| VecDeque::with_capacity(SAMPLES_PER_COMMAND));
+
+        if samples.len() == SAMPLES_PER_COMMAND {
+            samples.pop_front();
+        }
+        samples.push_back(seconds);
+    }
+
+    // Percentiles for every command, ordered by command name
+    pub fn percentiles(&self) -> Vec<CommandStats> {
+        let mut result: Vec<CommandStats> = self.samples.iter()
+            .map(|(command, samples)| {
+                let mut sorted: Vec<f64> = samples.iter().cloned().collect();
+                sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                CommandStats {
+                    command: command.to_string(),
+                    samples: sorted.len(),
+                    p50: percentile(&sorted, 50.0),
+                    p95: percentile(&sorted, 95.0),
+                    p99: percentile(&sorted, 99.0),
+                }
+            })
+            .collect();
+
+        result.sort_by(|a, b| a.command.cmp(&b.command));
+        result
+    }
+}
+
+impl Default for LatencySamples {
+    fn default() -> Self {
+        LatencySamples::new()
+    }
+}
+
+// Number of responses and their total latency
+#[derive(Clone,Copy,Debug,Default)]
+struct CommandCount {
+    count: u64,
+    seconds: f64,
+}
+
+impl CommandCount {
+    fn add(&mut self, other: CommandCount) {
+        self.count += other.count;
+        self.seconds += other.seconds;
+    }
+}
+
+// A command on the /top-commands list, with the average latency in seconds
+#[derive(Debug,PartialEq)]
+pub struct TopCommand {
+    pub command: String,
+    pub count: u64,
+    pub avg_latency: f64,
+}
+
+// Response counts and latencies per command since start, and in short buckets for the
+// most recent hour. These are for the /top-commands endpoint, the same responses are
+// in the Prometheus metrics.
+pub struct CommandRates {
+    started: Instant,
+    totals: HashMap<&'static str, CommandCount>,
+    // Bucket number and the counts in it, oldest first
+    buckets: VecDeque<(u64, HashMap<&'static str, CommandCount>)>,
+}
+
+impl CommandRates {
+
+    pub fn new(started: Instant) -> Self {
+        CommandRates {
+            started,
+            totals: HashMap::new(),
+            buckets: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, command: &'static str, seconds: f64, now: Instant) {
+        let response = CommandCount { count: 1, seconds };
+        self.totals.entry(command).or_default().add(response);
+
+        let bucket = self.bucket_of(now);
+        match self.buckets.back_mut() {
+            Some((number, counts)) if *number == bucket => {
+                counts.entry(command).or_default().add(response);
+            },
+            _ => {
+                let mut counts = HashMap::new();
+                counts.insert(command, response);
+                self.buckets.push_back((bucket, counts));
+            },
+        }
+
+        while matches!(self.buckets.front(), Some((number, _)) if number + MAX_RATE_BUCKETS <= bucket) {
+            self.buckets.pop_front();
+        }
+    }
+
+    // The `n` commands with the most responses, within the `window` before `now` or
+    // since start. The window is rounded up to whole buckets.
+    pub fn top(&self, n: usize, window: Option<Duration>, now: Instant) -> Vec<TopCommand> {
+        let counts = match window {
+            Some(window) => {
+                let buckets = ((window.as_secs() + RATE_BUCKET_SECONDS - 1) / RATE_BUCKET_SECONDS).max(1);
+                let first = (self.bucket_of(now) + 1).saturating_sub(buckets);
+                let mut counts: HashMap<&'static str, CommandCount> = HashMap::new();
+                for (_, bucket) in self.buckets.iter().filter(|(number, _)| *number >= first) {
+                    for (command, count) in bucket {
+                        counts.entry(*command).or_default().add(*count);
+                    }
+                }
+                counts
+            },
+            None => self.totals.clone(),
+        };
+
+        let mut top: Vec<TopCommand> = counts.into_iter()
+            .map(|(command, c)| TopCommand {
+                command: command.to_string(),
+                count: c.count,
+                avg_latency: c.seconds / c.count as f64,
+            })
+            .collect();
+        top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.command.cmp(&b.command)));
+        top.truncate(n);
+        top
+    }
+
+    fn bucket_of(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started).as_secs() / RATE_BUCKET_SECONDS
+    }
+}
+
+// The server response latencies of one connection that are not in the shared stats
+// yet. Collecting them per connection keeps the locks off the path of every response.
+// Whatever is left is added when the connection closes.
+#[derive(Debug,Default)]
+pub struct PendingLatencies {
+    latencies: Vec<(&'static str, f64)>,
+    last_flush: Option<Instant>,
+}
+
+impl PendingLatencies {
+
+    pub fn record(&mut self, command: &'static str, seconds: f64) {
+        self.latencies.push((command, seconds));
+
+        let now = Instant::now();
+        let last_flush = *self.last_flush.get_or_insert(now);
+        if self.latencies.len() >= FLUSH_RESPONSES || now.duration_since(last_flush) >= FLUSH_INTERVAL {
+            self.flush(now);
+        }
+    }
+
+    fn flush(&mut self, now: Instant) {
+        self.last_flush = Some(now);
+        if self.latencies.is_empty() {
+            return;
+        }
+
+        let mut samples = RESPONSE_LATENCIES.lock().unwrap();
+        let mut rates = COMMAND_RATES.lock().unwrap();
+        for (command, seconds) in self.latencies.drain(..) {
+            samples.record(command, seconds);
+            rates.record(command, seconds, now);
+        }
+    }
+}
+
+impl Drop for PendingLatencies {
+    fn drop(&mut self) {
+        self.flush(Instant::now());
+    }
+}
+
+pub fn top_commands(n: usize, window: Option<Duration>) -> Vec<TopCommand> {
+    COMMAND_RATES.lock().unwrap().top(n, window, Instant::now())
+}
+
+// The number of commands and the window from the `n` and `window` (seconds) query
+// parameters of /top-commands. Without a window the counts are since start.
+pub fn parse_top_params(n: Option<&str>, window: Option<&str>) -> Result<(usize, Option<Duration>), String> {
+    let n = match n {
+        Some(value) => value.parse::<usize>().map_err(|_| "n must be a number".to_string())?,
+        None => DEFAULT_TOP_COMMANDS,
+    };
+    if n == 0 || n > MAX_TOP_COMMANDS {
+        return Err(format!("n must be between 1 and {}", MAX_TOP_COMMANDS));
+    }
+
+    let window = match window {
+        Some(value) => {
+            let seconds = value.parse::<u64>().map_err(|_| "window must be a number of seconds".to_string())?;
+            if seconds == 0 || seconds > MAX_TOP_WINDOW_SECONDS {
+                return Err(format!("window must be between 1 and {} seconds", MAX_TOP_WINDOW_SECONDS));
+            }
+            Some(Duration::from_secs(seconds))
+        },
+        None => None,
+    };
+    Ok((n, window))
+}
+
+// Render the top commands as JSON, with the average latencies in milliseconds
+pub fn render_top_json(window: Option<Duration>, top: &[TopCommand]) -> String {
+    let commands: Vec<String> = top.iter()
+        .map(|c| format!("{{\"command\": {}, \"count\": {}, \"avg_latency_ms\": {:.3}}}",
+            json_string(&c.command), c.count, c.avg_latency * 1000.0))
+        .collect();
+    let window = match window {
+        Some(window) => window.as_secs().to_string(),
+        None => "null".to_string(),
+    };
+    format!("{{\"window_seconds\": {}, \"commands\": [{}]}}\n", window, commands.join(", "))
+}
+
+pub fn response_latency_percentiles() -> Vec<CommandStats> {
+    RESPONSE_LATENCIES.lock().unwrap().percentiles()
+}
+
+// Nearest rank percentile of sorted values
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1) - 1]
+}
+
+// Render the percentiles as a HTML table, with latencies in milliseconds
+pub fn render_html(stats: &[CommandStats]) -> String {
+    let mut html = String::new();
+    html.push_str("<table>\n<tr><th>command</th><th>samples</th><th>p50 ms</th><th>p95 ms</th><th>p99 ms</th></tr>\n");
+    for s in stats {
+        let _ = writeln!(html, "<tr><td>{}</td><td>{}</td><td>{:.3}</td><td>{:.3}</td><td>{:.3}</td></tr>",
+            escape_html(&s.command), s.samples, s.p50 * 1000.0, s.p95 * 1000.0, s.p99 * 1000.0);
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let mut latencies = LatencySamples::new();
+        for i in 1..=100 {
+            latencies.record("find", i as f64);
+        }
+        latencies.record("insert", 0.5);
+
+        let stats = latencies.percentiles();
+        assert_eq!(2, stats.len());
+
+        assert_eq!("find", stats[0].command);
+        assert_eq!(100, stats[0].samples);
+        assert_eq!(50.0, stats[0].p50);
+        assert_eq!(95.0, stats[0].p95);
+        assert_eq!(99.0, stats[0].p99);
+
+        assert_eq!("insert", stats[1].command);
+        assert_eq!(0.5, stats[1].p50);
+        assert_eq!(0.5, stats[1].p99);
+    }
+
+    #[test]
+    fn test_only_recent_samples_are_kept() {
+        let mut latencies = LatencySamples::new();
+        for _ in 0..SAMPLES_PER_COMMAND {
+            latencies.record("find", 10.0);
+        }
+        for _ in 0..SAMPLES_PER_COMMAND {
+            latencies.record("find", 1.0);
+        }
+
+        let stats = latencies.percentiles();
+        assert_eq!(SAMPLES_PER_COMMAND, stats[0].samples);
+        assert_eq!(1.0, stats[0].p99);
+    }
+
+    #[test]
+    fn test_top_commands() {
+        let started = Instant::now();
+        let mut rates = CommandRates::new(started);
+        let at = |seconds| started + Duration::from_secs(seconds);
+
+        rates.record("insert", 0.25, at(0));
+        rates.record("insert", 0.75, at(0));
+        rates.record("find", 0.5, at(0));
+        for _ in 0..3 {
+            rates.record("find", 0.5, at(100));
+        }
+        rates.record("ping", 0.5, at(100));
+
+        let top = rates.top(2, None, at(100));
+        assert_eq!(vec![
+            TopCommand { command: "find".to_string(), count: 4, avg_latency: 0.5 },
+            TopCommand { command: "insert".to_string(), count: 2, avg_latency: 0.5 },
+        ], top);
+
+        // Only the last minute
+        let top = rates.top(10, Some(Duration::from_secs(60)), at(100));
+        assert_eq!(vec!["find", "ping"], top.iter().map(|c| c.command.as_str()).collect::<Vec<_>>());
+        assert_eq!(3, top[0].count);
+
+        // The buckets older than the max window are dropped, the totals are kept
+        rates.record("ping", 0.5, at(MAX_TOP_WINDOW_SECONDS + 100));
+        assert_eq!(1, rates.top(10, Some(Duration::from_secs(MAX_TOP_WINDOW_SECONDS)), at(MAX_TOP_WINDOW_SECONDS + 100)).len());
+        assert_eq!(3, rates.top(10, None, at(MAX_TOP_WINDOW_SECONDS + 100)).len());
+    }
+
+    #[test]
+    fn test_pending_latencies() {
+        let count = |command: &str| top_commands(MAX_TOP_COMMANDS, None).iter()
+            .find(|c| c.command == command)
+            .map_or(0, |c| c.count);
+
+        let mut pending = PendingLatencies::default();
+        pending.record("test_pending", 0.5);
+        for _ in 1..FLUSH_RESPONSES {
+            pending.record("test_pending", 0.5);
+        }
+        assert_eq!(FLUSH_RESPONSES as u64, count("test_pending"));
+
+        pending.record("test_pending", 0.5);
+        assert_eq!(FLUSH_RESPONSES as u64, count("test_pending"));
+        drop(pending);
+        assert_eq!(FLUSH_RESPONSES as u64 + 1, count("test_pending"));
+    }
+
+    #[test]
+    fn test_parse_top_params() {
+        assert_eq!(Ok((DEFAULT_TOP_COMMANDS, None)), parse_top_params(None, None));
+        assert_eq!(Ok((5, Some(Duration::from_secs(300)))), parse_top_params(Some("5"), Some("300")));
+        assert!(parse_top_params(Some("0"), None).is_err());
+        assert!(parse_top_params(Some("many"), None).is_err());
+        assert!(parse_top_params(None, Some("0")).is_err());
+        assert!(parse_top_params(None, Some("3601")).is_err());
+    }
+
+    #[test]
+    fn test_render_top_json() {
+        let top = vec![TopCommand { command: "find".to_string(), count: 3, avg_latency: 0.0015 }];
+        assert_eq!("{\"window_seconds\": 60, \"commands\": [{\"command\": \"find\", \"count\": 3, \"avg_latency_ms\": 1.500}]}\n",
+            render_top_json(Some(Duration::from_secs(60)), &top));
+        assert_eq!("{\"window_seconds\": null, \"commands\": []}\n", render_top_json(None, &[]));
+    }
+
+    #[test]
+    fn test_render_html() {
+        let stats = vec![CommandStats {
+            command: "<find>".to_string(),
+            samples: 1,
+            p50: 0.001,
+            p95: 0.002,
+            p99: 0.003,
+        }];
+
+        let html = render_html(&stats);
+        assert!(html.contains("<td>&lt;find&gt;</td><td>1</td><td>1.000</td><td>2.000</td><td>3.000</td>"));
+    }
+}
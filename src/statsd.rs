@@ -0,0 +1,104 @@
+use std::fmt;
+use std::io;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::time::Duration;
+
+use cadence::prelude::*;
+use cadence::{MetricBuilder, UdpMetricSink};
+use tracing::debug;
+
+// Pushes metrics to a StatsD agent with cadence, in parallel with the Prometheus
+// metrics. Tags are sent in the DogStatsD format. Sending is fire and forget: the
+// socket is non blocking and failed sends are dropped.
+#[derive(Clone)]
+pub struct StatsdClient {
+    client: Arc<cadence::StatsdClient>,
+    prefix: String,
+}
+
+impl fmt::Debug for StatsdClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StatsdClient").field("prefix", &self.prefix).finish()
+    }
+}
+
+impl StatsdClient {
+
+    pub fn new(addr: &str, prefix: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        let sink = UdpMetricSink::from(addr, socket)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let client = cadence::StatsdClient::builder(prefix, sink)
+            .with_error_handler(|e| debug!("Failed to send StatsD metric: {}", e))
+            .build();
+
+        Ok(StatsdClient {
+            client: Arc::new(client),
+            prefix: prefix.to_string(),
+        })
+    }
+
+    pub fn count(&self, name: &str, value: u64, tags: &[(&str, &str)]) {
+        let tags = sanitize_tags(tags);
+        send_with_tags(self.client.count_with_tags(name, value as i64), &tags);
+    }
+
+    // Timers are in whole milliseconds
+    pub fn timing(&self, name: &str, value: Duration, tags: &[(&str, &str)]) {
+        let tags = sanitize_tags(tags);
+        send_with_tags(self.client.time_with_tags(name, value.as_millis() as u64), &tags);
+    }
+}
+
+fn send_with_tags<'a, T: cadence::Metric + From<String>>(mut metric: MetricBuilder<'a, '_, T>, tags: &'a [(&'a str, String)]) {
+    for (tag, value) in tags {
+        metric = metric.with_tag(tag, value);
+    }
+    metric.send();
+}
+
+fn sanitize_tags<'a>(tags: &[(&'a str, &str)]) -> Vec<(&'a str, String)> {
+    tags.iter().map(|(tag, value)| (*tag, sanitize_tag_value(value))).collect()
+}
+
+// Tag values can't contain the separators of the line format
+fn sanitize_tag_value(value: &str) -> String {
+    value.replace(&['|', ',', '#', '\n'][..], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent() -> UdpSocket {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        agent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        agent
+    }
+
+    fn receive(agent: &UdpSocket) -> String {
+        let mut buf = [0; 1024];
+        let len = agent.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_sanitize_tag_value() {
+        assert_eq!("a_b_c", sanitize_tag_value("a|b,c"));
+        assert_eq!("find", sanitize_tag_value("find"));
+    }
+
+    #[test]
+    fn test_send_metrics() {
+        let agent = agent();
+        let client = StatsdClient::new(&agent.local_addr().unwrap().to_string(), "mongoproxy").unwrap();
+
+        client.timing("response_latency", Duration::from_millis(12), &[("op", "find")]);
+        assert_eq!("mongoproxy.response_latency:12|ms|#op:find", receive(&agent));
+
+        client.count("client_connection_errors", 1, &[("client", "a|b"), ("error_kind", "reset")]);
+        assert_eq!("mongoproxy.client_connection_errors:1|c|#client:a_b,error_kind:reset", receive(&agent));
+    }
+}
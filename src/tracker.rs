@@ -1,12 +1,17 @@
-use crate::mongodb::{MsgHeader,MongoMessage,ResponseDocuments};
+use crate::mongodb::{MsgHeader,MongoMessage,ResponseDocuments,MSG_FLAG_MORE_TO_COME,PIPELINE_STAGE_KEYS,PLAN_STAGE_KEYS,FILTER_FIELD_KEYS,OPCOUNTER_KEYS};
+use crate::slowquery::{SlowQuery};
 use crate::jaeger_tracing;
 use crate::appconfig::{AppConfig};
+use crate::stats;
+use crate::namespaces;
 
+use std::fmt;
 use std::time::{Instant};
 use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex};
 
 use tracing::{debug, warn, info_span};
-use prometheus::{Counter,CounterVec,HistogramVec,Gauge};
+use prometheus::{Counter,CounterVec,HistogramOpts,HistogramVec,Gauge,GaugeVec};
 
 use async_bson::Document;
 
@@ -16,8 +21,14 @@ use rustracing_jaeger::span::{SpanContextState};
 use rustracing::span::SpanContext;
 
 
+// Stop counting the getMores of new cursors when a connection has this many open
+const MAX_TRACKED_CURSORS: usize = 1000;
+
 // Common labels for all op metrics
-const OP_LABELS: &[&str] = &["client", "app", "op", "collection", "db", "replicaset", "server"];
+const OP_LABELS: &[&str] = &["client", "app", "op", "op_type", "collection", "db", "replicaset", "server", "is_system", "comment", "proxy"];
+
+// Databases that hold the server's own data rather than the application's
+const SYSTEM_DATABASES: &[&str] = &["admin", "config", "local"];
 
 // Allow this many server responses to wait for a matching client request
 const MAX_OUTSTANDING_SERVER_RESPONSES: usize = 1024;
@@ -25,6 +36,15 @@ const MAX_OUTSTANDING_SERVER_RESPONSES: usize = 1024;
 // Allow this many client requests to wait for a matching server response
 const MAX_OUTSTANDING_CLIENT_REQUESTS: usize = 32;
 
+// Server error code for a command that ran out of its maxTimeMS
+const MAX_TIME_MS_EXPIRED: i32 = 50;
+
+// Replicaset label value until we've seen the hello response
+const REPLICASET_PENDING: &str = "pending";
+
+// Replicaset label value for standalone servers
+const REPLICASET_NONE: &str = "none";
+
 lazy_static! {
     static ref APP_CONNECTION_COUNT_TOTAL: CounterVec =
         register_counter_vec!(
@@ -38,6 +58,12 @@ lazy_static! {
             "Total number of client disconnections",
             &["app"]).unwrap();
 
+    static ref CONNECTIONS_NO_HANDSHAKE_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_connections_no_handshake_total",
+            "Number of connections closed without a single valid MongoDb message"
+            ).unwrap();
+
     static ref UNSUPPORTED_OPNAME_COUNTER: CounterVec =
         register_counter_vec!(
             "mongoproxy_unsupported_op_name_count_total",
@@ -68,6 +94,119 @@ lazy_static! {
         "Cursor trace parent mapping HashMap size"
         ).unwrap();
 
+    static ref SERVER_MAX_WIRE_VERSION: GaugeVec =
+        register_gauge_vec!(
+            "mongoproxy_server_max_wire_version",
+            "maxWireVersion reported by the server in the hello response",
+            &["server"]).unwrap();
+
+    static ref SERVER_MIN_WIRE_VERSION: GaugeVec =
+        register_gauge_vec!(
+            "mongoproxy_server_min_wire_version",
+            "minWireVersion reported by the server in the hello response",
+            &["server"]).unwrap();
+
+    static ref SERVER_IS_WRITABLE_PRIMARY: GaugeVec =
+        register_gauge_vec!(
+            "mongoproxy_server_is_writable_primary",
+            "1 if the last hello response said the server is a writable primary, 0 if not",
+            &["server"]).unwrap();
+
+    static ref SERVER_VERSION_INFO: GaugeVec =
+        register_gauge_vec!(
+            "mongoproxy_server_version_info",
+            "Server version from the last buildInfo or serverStatus response, always 1",
+            &["server", "version"]).unwrap();
+
+    static ref OBSERVED_SERVER_CONNECTIONS: GaugeVec =
+        register_gauge_vec!(
+            "mongoproxy_observed_server_connections",
+            "Server connections from the last serverStatus response that a client ran",
+            &["server", "state"]).unwrap();
+
+    static ref OBSERVED_SERVER_OPCOUNTERS: GaugeVec =
+        register_gauge_vec!(
+            "mongoproxy_observed_server_opcounters",
+            "Server operation counters from the last serverStatus response that a client ran",
+            &["server", "op"]).unwrap();
+
+    // The version label currently set for each server, to remove it when it changes
+    static ref SERVER_VERSIONS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+
+    static ref HELLO_RESPONSES_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_server_hello_responses_total",
+            "Number of hello/isMaster responses seen from the server",
+            &["server"]).unwrap();
+
+    static ref AGGREGATION_STAGES_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_aggregation_stages_total",
+            "Number of aggregation pipeline stages by stage operator",
+            &["stage"]).unwrap();
+
+    static ref COLLECTION_SCANS_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_collection_scans_total",
+            "Number of explained queries with a COLLSCAN in the winning plan",
+            &["collection"]).unwrap();
+
+    static ref DOCS_EXAMINED_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_docs_examined_total",
+            "Number of documents examined by the explained queries",
+            &["collection"]).unwrap();
+
+    static ref DOCS_RETURNED_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_docs_returned_total",
+            "Number of documents returned by the explained queries",
+            &["collection"]).unwrap();
+
+    static ref QUERY_SELECTIVITY: HistogramVec =
+        register_histogram_vec!(
+            "mongoproxy_query_selectivity",
+            "Documents examined per document returned by the explained queries",
+            &["collection"],
+            vec![1.0, 10.0, 100.0, 1000.0, 10000.0]).unwrap();
+
+    static ref AUTH_ATTEMPTS_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_auth_attempts_total",
+            "Number of authentication attempts by mechanism",
+            &["mechanism"]).unwrap();
+
+    static ref AUTH_FAILURES_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_auth_failures_total",
+            "Number of failed authentication attempts by mechanism",
+            &["mechanism"]).unwrap();
+
+    static ref GETMORE_BATCHES: HistogramVec =
+        register_histogram_vec!(
+            "mongoproxy_getmore_batches",
+            "Number of getMore round trips per cursor",
+            &["collection"],
+            vec![0.0, 1.0, 2.0, 5.0, 10.0, 50.0, 100.0, 1000.0]).unwrap();
+
+    static ref SINGLE_BATCH_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_single_batch_total",
+            "Number of find and aggregate cursors exhausted by the first batch",
+            &["op", "collection"]).unwrap();
+
+    static ref MULTI_BATCH_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_multi_batch_total",
+            "Number of find and aggregate cursors left open after the first batch",
+            &["op", "collection"]).unwrap();
+
+    static ref EXHAUST_RESPONSES_TOTAL: Counter =
+        register_counter!(
+            "mongoproxy_exhaust_responses_total",
+            "Number of server responses with moreToCome set"
+            ).unwrap();
+
     static ref SERVER_RESPONSE_LATENCY_SECONDS: HistogramVec =
         register_histogram_vec!(
             "mongoproxy_response_latency_seconds",
@@ -109,23 +248,86 @@ lazy_static! {
             "Number of non-ok server responses",
             OP_LABELS).unwrap();
 
+    static ref MESSAGE_SIZE_BYTES: HistogramVec =
+        register_histogram_vec!(
+            "mongoproxy_message_size_bytes",
+            "Size of the MongoDb messages, from the message header",
+            &["direction"],
+            vec![256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262_144.0, 1_048_576.0,
+                4_194_304.0, 16_777_216.0, 48_000_000.0]).unwrap();
+
+    static ref COMMAND_DEPTH: HistogramVec =
+        register_histogram_vec!(
+            "mongoproxy_command_depth",
+            "Maximum nesting depth of the command documents",
+            &["op"],
+            vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0]).unwrap();
+
+    static ref COMMAND_FIELD_COUNT: HistogramVec =
+        register_histogram_vec!(
+            "mongoproxy_command_field_count",
+            "Number of top level fields in the command documents",
+            &["op"],
+            vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0]).unwrap();
+
+    static ref MAX_TIME_MS: HistogramVec =
+        register_histogram_vec!(
+            "mongoproxy_max_time_ms",
+            "The maxTimeMS of the commands that have one",
+            &["op"],
+            vec![10.0, 100.0, 1000.0, 5000.0, 10000.0, 30000.0, 60000.0, 300_000.0]).unwrap();
+
+    static ref MAX_TIME_EXPIRED_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_max_time_expired_total",
+            "Number of commands that failed with MaxTimeMSExpired",
+            &["command"]).unwrap();
+
+    static ref EXCLUDED_COMMANDS_TOTAL: CounterVec =
+        register_counter_vec!(
+            "mongoproxy_excluded_commands_total",
+            "Number of requests left out of the per-request metrics by --exclude-commands",
+            &["op"]).unwrap();
+
     static ref CLIENT_BYTES_SENT_TOTAL: CounterVec =
         register_counter_vec!(
             "mongoproxy_client_bytes_sent_total",
             "Total number of bytes sent by the client",
-            &["client"]).unwrap();
+            &["client", "proxy"]).unwrap();
 
     static ref CLIENT_BYTES_RECV_TOTAL: CounterVec =
         register_counter_vec!(
             "mongoproxy_client_bytes_received_total",
             "Total number of bytes sent by the server",
-            &["client"]).unwrap();
+            &["client", "proxy"]).unwrap();
 
     static ref OTHER_MONGODB_OPS: HashSet<&'static str> =
         ["isMaster", "ismaster", "ping", "whatsmyuri", "buildInfo", "buildinfo", "drop",
         "saslStart", "saslContinue", "getLog", "getFreeMonitoringStatus", "killCursors",
         "listDatabases", "listIndexes", "createIndexes", "listCollections", "replSetGetStatus",
-        "endSessions", "dropDatabase", "_id", "q", "getMore"].iter().cloned().collect();
+        "endSessions", "dropDatabase", "_id", "q", "getMore", "hello", "explain",
+        "authenticate"].iter().cloned().collect();
+
+    // Aggregation stages that we have a label for, others are lumped together
+    static ref AGGREGATION_STAGES: HashSet<&'static str> =
+        ["$addFields", "$bucket", "$bucketAuto", "$changeStream", "$collStats", "$count",
+        "$currentOp", "$densify", "$documents", "$facet", "$fill", "$geoNear", "$graphLookup",
+        "$group", "$indexStats", "$limit", "$listLocalSessions", "$listSessions", "$lookup",
+        "$match", "$merge", "$out", "$planCacheStats", "$project", "$redact", "$replaceRoot",
+        "$replaceWith", "$sample", "$search", "$searchMeta", "$set", "$setWindowFields", "$skip",
+        "$sort", "$sortByCount", "$unionWith", "$unset", "$unwind"].iter().cloned().collect();
+
+    // Authentication mechanisms that we have a label for, others are "other"
+    static ref AUTH_MECHANISMS: HashSet<&'static str> =
+        ["SCRAM-SHA-1", "SCRAM-SHA-256", "MONGODB-X509", "MONGODB-CR", "MONGODB-AWS",
+        "MONGODB-OIDC", "PLAIN", "GSSAPI"].iter().cloned().collect();
+
+    // Commands for the `read` and `write` op_type, everything else is `other`
+    static ref READ_OPS: HashSet<&'static str> =
+        ["find", "aggregate", "count", "distinct", "getMore", "query"].iter().cloned().collect();
+
+    static ref WRITE_OPS: HashSet<&'static str> =
+        ["insert", "update", "delete", "findAndModify", "findandmodify"].iter().cloned().collect();
 
     // Operations that have collection name as op value
     static ref MONGODB_COLLECTION_OPS: HashSet<&'static str> =
@@ -139,6 +341,35 @@ lazy_static! {
 // a risk of collision if there are multiple databases on the same server.
 pub type CursorTraceMapper = HashMap<(std::net::SocketAddr,i64), Vec<u8>>;
 
+// Histogram of the number of documents in each response, including every getMore
+// batch and exhaust response. It is registered at startup when enabled, as the
+// buckets are configurable.
+#[derive(Clone)]
+pub struct DocumentsPerResponse {
+    histogram: HistogramVec,
+}
+
+impl DocumentsPerResponse {
+    pub fn register(buckets: Vec<f64>) -> Result<Self, prometheus::Error> {
+        let opts = HistogramOpts::new(
+            "mongoproxy_documents_per_response",
+            "Number of documents in each query response").buckets(buckets);
+        let histogram = HistogramVec::new(opts, &["op", "collection"])?;
+        prometheus::register(Box::new(histogram.clone()))?;
+        Ok(DocumentsPerResponse { histogram })
+    }
+
+    fn observe(&self, op: &str, collection: &str, documents: i32) {
+        self.histogram.with_label_values(&[op, collection]).observe(f64::from(documents));
+    }
+}
+
+impl fmt::Debug for DocumentsPerResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DocumentsPerResponse")
+    }
+}
+
 
 // Stripped down version of the client request. We need this mostly for timing
 // stats and metric labels.
@@ -150,6 +381,19 @@ struct ClientRequest {
     cursor_id: i64,
     span: Option<Span<SpanContextState>>,
     message_length: usize,
+    // With exhaust cursors the server sends several responses to one request, each
+    // with moreToCome set except the last one.
+    responses: u32,
+    more_to_come: bool,
+    docs_returned: i32,
+    // Field names of the query filter, for the slow query events
+    filter_fields: Vec<String>,
+    // Targets a system namespace, eg. driver housekeeping or admin commands
+    is_system: bool,
+    // Left out of the per-request metrics with --exclude-commands
+    excluded: bool,
+    // Label value from the $comment with --use-comment-label, empty if there is none
+    comment: String,
 }
 
 impl ClientRequest {
@@ -160,6 +404,7 @@ impl ClientRequest {
         let mut coll = String::from("");
         let mut cursor_id = 0;
         let mut span = None;
+        let mut filter_fields = Vec::new();
 
         match msg {
             MongoMessage::Msg(m) => {
@@ -192,6 +437,17 @@ impl ClientRequest {
                         if let Some(have_db) = s.get_str("db") {
                             db = have_db.to_string();
                         }
+
+                        if tracker.app.slow_queries.is_some() {
+                            filter_fields = FILTER_FIELD_KEYS.iter()
+                                .filter_map(|key| s.get_str(key))
+                                .map(|field| field.to_string())
+                                .collect();
+                        }
+                    }
+
+                    if op == "getMore" && cursor_id == 0 {
+                        cursor_id = s.get_i64("op_value").unwrap_or(0);
                     }
 
                     if let Some(tracer) = &tracker.app.tracer {
@@ -199,14 +455,13 @@ impl ClientRequest {
                         // trace id. Instead we need follow from the span that was created by the
                         // initial "find" or "aggregate" operation.
                         if op == "getMore" {
-                            if let Some(cursor) = s.get_i64("op_value") {
-                                cursor_id = cursor;
+                            if cursor_id != 0 {
                                 let trace_mapper = tracker.app.trace_mapper.lock().unwrap();
 
                                 if let Some(parent_span_id) = trace_mapper.get(&(tracker.server_addr_sa, cursor_id)) {
                                     if let Ok(Some(parent)) = SpanContext::extract_from_binary(&mut &parent_span_id[..]) {
                                         span = Some(tracer
-                                            .span(op.to_owned())
+                                            .span(span_name(tracker.app.namespace_span_names, &op, &coll))
                                             .child_of(&parent)
                                             .start());
                                         debug!("Created a new span for getMore: cursor_id={} parent={:?}", cursor_id, parent);
@@ -223,7 +478,7 @@ impl ClientRequest {
                                 Ok(Some(parent)) => {
                                     debug!("Extracted trace header: {:?}", parent);
                                     let mut new_span = tracer
-                                        .span(op.to_owned())
+                                        .span(span_name(tracker.app.namespace_span_names, &op, &coll))
                                         .child_of(&parent)
                                         .tag(Tag::new("app", tracker.client_application.clone()))
                                         .tag(Tag::new("client", tracker.client_addr.clone()))
@@ -295,6 +550,20 @@ impl ClientRequest {
             },
         }
 
+        let is_system = is_system_namespace(&db, &coll);
+
+        let comment = match &tracker.app.comment_labels {
+            Some(labels) => message_comment(msg).and_then(|c| labels.label(c)).unwrap_or_default(),
+            None => String::new(),
+        };
+        if !comment.is_empty() {
+            if let Some(span) = &mut span {
+                span.set_tag(|| Tag::new("comment", comment.clone()));
+            }
+        }
+        let excluded = !tracker.app.exclude_commands.is_empty()
+            && tracker.app.exclude_commands.contains(&op.to_lowercase());
+
         ClientRequest {
             coll,
             db,
@@ -303,12 +572,62 @@ impl ClientRequest {
             message_time,
             span,
             message_length,
+            responses: 0,
+            more_to_come: false,
+            docs_returned: 0,
+            filter_fields,
+            is_system,
+            excluded,
+            comment,
         }
     }
 
     fn is_collection_op(&self) -> bool {
         !self.coll.is_empty()
     }
+
+    // Should this request show up in the per-request metrics
+    fn is_recorded(&self) -> bool {
+        !self.excluded
+    }
+}
+
+// Whether the command reads or writes documents, for the op_type label
+fn op_type(op: &str) -> &'static str {
+    if READ_OPS.contains(op) {
+        "read"
+    } else if WRITE_OPS.contains(op) {
+        "write"
+    } else {
+        "other"
+    }
+}
+
+// The command name for /stats and /top-commands, from the commands that we know of
+fn stats_command(op: &str) -> &'static str {
+    MONGODB_COLLECTION_OPS.get(op)
+        .or_else(|| READ_OPS.get(op))
+        .or_else(|| WRITE_OPS.get(op))
+        .or_else(|| OTHER_MONGODB_OPS.get(op))
+        .copied()
+        .unwrap_or(stats::OTHER_COMMAND)
+}
+
+// Namespaces in the admin, config and local databases and the `system.*` collections
+// of any database
+fn is_system_namespace(db: &str, coll: &str) -> bool {
+    SYSTEM_DATABASES.contains(&db) || coll.starts_with("system.")
+}
+
+// The span name of a command, with the collection following the OpenTelemetry database
+// span naming (eg. "find products") when enabled. Commands without a collection keep
+// just the command name.
+fn span_name(with_namespace: bool, op: &str, coll: &str) -> String {
+    if with_namespace && !coll.is_empty() {
+        format!("{} {}", op, coll)
+    } else {
+        op.to_owned()
+    }
 }
 
 pub struct MongoStatsTracker {
@@ -320,16 +639,38 @@ pub struct MongoStatsTracker {
     server_responses:       Vec<(MsgHeader, MongoMessage)>,
     replicaset:             String,
     server_host:            String,
+    // Mechanism of the authentication in progress, saslContinue doesn't repeat it
+    auth_mechanism:         String,
+    // Has the client tried to authenticate, the server connection then has its state
+    authenticated:          bool,
+    // Collection and number of getMores so far of the open cursors
+    cursor_batches:         HashMap<i64, (String, u32)>,
+    // Has a valid MongoDb message been parsed in either direction
+    seen_message:           bool,
+    // Response latencies for /stats and /top-commands, with --command-stats
+    pending_latencies:      stats::PendingLatencies,
     app:                    AppConfig,
 }
 
 impl Drop for MongoStatsTracker {
     fn drop(&mut self) {
+        // Cursors that were still open when the connection closed, or that were killed,
+        // get recorded with the getMores they had so far.
+        for (coll, batches) in self.cursor_batches.values() {
+            GETMORE_BATCHES.with_label_values(&[coll]).observe(f64::from(*batches));
+        }
+
         if !self.client_application.is_empty() {
             APP_DISCONNECTION_COUNT_TOTAL
                 .with_label_values(&[&self.client_application])
                 .inc();
         }
+
+        // Port scanners, health checkers and clients speaking the wrong protocol. The
+        // trackers are done with the connection by now, so nothing is still in flight.
+        if !self.seen_message {
+            CONNECTIONS_NO_HANDSHAKE_TOTAL.inc();
+        }
     }
 }
 
@@ -345,8 +686,13 @@ impl MongoStatsTracker{
             client_request_map: HashMap::new(),
             server_responses: Vec::new(),
             client_application: String::from(""),
-            replicaset: String::from(""),
+            replicaset: String::from(REPLICASET_PENDING),
             server_host: String::from(""),
+            auth_mechanism: String::from(""),
+            authenticated: false,
+            cursor_batches: HashMap::new(),
+            seen_message: false,
+            pending_latencies: stats::PendingLatencies::default(),
             app,
         }
     }
@@ -355,8 +701,20 @@ impl MongoStatsTracker{
         self.app.tracer.is_some()
     }
 
+    // Could the server connection be handed over to another client. That's the case when
+    // every request has had its response and the client never authenticated.
+    pub fn is_reusable(&self) -> bool {
+        !self.authenticated && self.is_between_messages()
+    }
+
+    // Has every request that the tracker has seen had its response
+    pub fn is_between_messages(&self) -> bool {
+        self.client_request_map.is_empty() && self.server_responses.is_empty()
+    }
+
     pub fn track_client_request(&mut self, hdr: &MsgHeader, msg: &MongoMessage) {
-        CLIENT_BYTES_SENT_TOTAL.with_label_values(&[&self.client_addr]).inc_by(hdr.message_length as f64);
+        CLIENT_BYTES_SENT_TOTAL.with_label_values(&[&self.client_addr, &self.app.proxy_label]).inc_by(hdr.message_length as f64);
+        MESSAGE_SIZE_BYTES.with_label_values(&["request"]).observe(hdr.message_length as f64);
 
         let span = info_span!("track_client_request");
         let _ = span.enter();
@@ -365,6 +723,7 @@ impl MongoStatsTracker{
         if let MongoMessage::None = msg {
             return;
         }
+        self.seen_message = true;
 
         if self.client_application.is_empty() {
             if let Some(app_name) = extract_app_name(&msg) {
@@ -376,6 +735,34 @@ impl MongoStatsTracker{
         }
 
         let req = ClientRequest::from(&self, hdr.message_length, &msg);
+        if req.excluded {
+            EXCLUDED_COMMANDS_TOTAL.with_label_values(&[&req.op]).inc();
+        }
+
+        if !req.db.is_empty() {
+            namespaces::observe(&req.db, &req.coll);
+        }
+
+        if req.op == "aggregate" {
+            count_aggregation_stages(&msg);
+        }
+
+        if let Some(max_time_ms) = max_time_ms(&msg) {
+            MAX_TIME_MS.with_label_values(&[&req.op]).observe(max_time_ms);
+        }
+
+        if req.op == "saslStart" || req.op == "authenticate" {
+            self.track_auth_attempt(&msg);
+            self.authenticated = true;
+        }
+
+        if is_hello_op(&req.op) && is_speculative_auth(&msg) {
+            self.authenticated = true;
+        }
+
+        if self.app.track_document_shape {
+            observe_document_shape(&req.op, &msg);
+        }
 
         // If we're tracking cursors for tracing purposes then also handle
         // the cleanup.
@@ -394,6 +781,22 @@ impl MongoStatsTracker{
         RESPONSE_MATCH_HASHMAP_CAPACITY.set(self.client_request_map.capacity() as f64);
     }
 
+    // Count the start of an authentication and remember the mechanism for the rest of
+    // the conversation. Only the mechanism name is looked at, never the payload.
+    fn track_auth_attempt(&mut self, msg: &MongoMessage) {
+        let mechanism = match msg {
+            MongoMessage::Msg(m) => m.documents.iter().find_map(|doc| doc.get_str("mechanism")),
+            MongoMessage::Query(q) => q.query.get_str("mechanism"),
+            _ => None,
+        };
+
+        self.auth_mechanism = match mechanism {
+            Some(mechanism) if AUTH_MECHANISMS.contains(mechanism) => mechanism.to_string(),
+            _ => String::from("other"),
+        };
+        AUTH_ATTEMPTS_TOTAL.with_label_values(&[&self.auth_mechanism]).inc();
+    }
+
     // Handle "killCursors" to clean up the trace parent hash map
     fn maybe_kill_cursors(&mut self, op: &str, msg: &MongoMessage) {
         if let MongoMessage::Msg(msg) = msg {
@@ -416,21 +819,31 @@ impl MongoStatsTracker{
         }
     }
 
+    // Label values as StatsD tags
+    fn statsd_tags<'a>(&'a self, req: &'a ClientRequest) -> Vec<(&'static str, &'a str)> {
+        OP_LABELS.iter().cloned().zip(self.label_values(req).iter().cloned()).collect()
+    }
+
     // Label values for common metrics
-    fn label_values<'a>(&'a self, req: &'a ClientRequest) -> [&'a str; 7] {
+    fn label_values<'a>(&'a self, req: &'a ClientRequest) -> [&'a str; 11] {
         [
             &self.client_addr,
             &self.client_application,
             &req.op,
+            op_type(&req.op),
             &req.coll,
             &req.db,
             &self.replicaset,
             &self.server_host,
+            if req.is_system { "true" } else { "false" },
+            &req.comment,
+            &self.app.proxy_label,
         ]
     }
 
     pub fn track_server_response(&mut self, hdr: MsgHeader, msg: MongoMessage) {
-        CLIENT_BYTES_RECV_TOTAL.with_label_values(&[&self.client_addr]).inc_by(hdr.message_length as f64);
+        CLIENT_BYTES_RECV_TOTAL.with_label_values(&[&self.client_addr, &self.app.proxy_label]).inc_by(hdr.message_length as f64);
+        MESSAGE_SIZE_BYTES.with_label_values(&["response"]).observe(hdr.message_length as f64);
 
         let span = info_span!("track_server_response");
         let _ = span.enter();
@@ -439,6 +852,7 @@ impl MongoStatsTracker{
         if let MongoMessage::None = msg {
             return;
         }
+        self.seen_message = true;
 
         // Match the outstanding server responses with the client requests. Since we're
         // processing the requests and responses concurrently, it can happen that the
@@ -450,6 +864,13 @@ impl MongoStatsTracker{
         while let Some((hdr, msg)) = self.server_responses.pop() {
             if let Some(mut client_request) = self.client_request_map.remove(&hdr.response_to) {
                 self.observe_server_response_to(&hdr, &msg, &mut client_request);
+
+                // The next response of an exhaust cursor is a response to this one, keep
+                // the request around for it.
+                if client_request.more_to_come {
+                    EXHAUST_RESPONSES_TOTAL.inc();
+                    self.client_request_map.insert(hdr.request_id, client_request);
+                }
             } else if outstanding_responses.len() < MAX_OUTSTANDING_SERVER_RESPONSES {
                 outstanding_responses.push((hdr, msg));
             } else {
@@ -462,16 +883,34 @@ impl MongoStatsTracker{
     }
 
     fn observe_server_response_to(&mut self, hdr: &MsgHeader, msg: &MongoMessage, mut client_request: &mut ClientRequest) {
-        if client_request.is_collection_op() {
-            SERVER_RESPONSE_LATENCY_SECONDS
-                .with_label_values(&self.label_values(&client_request))
-                .observe(client_request.message_time.elapsed().as_secs_f64());
+        client_request.more_to_come = match msg {
+            MongoMessage::Msg(m) => m.flag_bits & MSG_FLAG_MORE_TO_COME != 0,
+            _ => false,
+        };
+        client_request.responses += 1;
+
+        if client_request.is_collection_op() && client_request.is_recorded() {
+            // With exhaust cursors only the first response has a latency, the rest of
+            // them just add to the response sizes.
+            if client_request.responses == 1 {
+                let latency = client_request.message_time.elapsed().as_secs_f64();
+                SERVER_RESPONSE_LATENCY_SECONDS
+                    .with_label_values(&self.label_values(&client_request))
+                    .observe(latency);
+                if self.app.command_stats {
+                    self.pending_latencies.record(stats_command(&client_request.op), latency);
+                }
+                if let Some(statsd) = &self.app.statsd {
+                    statsd.timing("response_latency", client_request.message_time.elapsed(), &self.statsd_tags(&client_request));
+                }
+                CLIENT_REQUEST_SIZE_TOTAL
+                    .with_label_values(&self.label_values(&client_request))
+                    .observe(client_request.message_length as f64);
+                self.publish_slow_query(&client_request);
+            }
             SERVER_RESPONSE_SIZE_TOTAL
                 .with_label_values(&self.label_values(&client_request))
                 .observe(hdr.message_length as f64);
-            CLIENT_REQUEST_SIZE_TOTAL
-                .with_label_values(&self.label_values(&client_request))
-                .observe(client_request.message_length as f64);
         }
 
         // Look into the server response and exract some counters from it.
@@ -482,10 +921,7 @@ impl MongoStatsTracker{
                 self.process_response_documents(&mut client_request, m.get_documents());
             },
             MongoMessage::Reply(r) => {
-                for doc in &r.documents {
-                    // The first isMaster response is an OP_REPLY so we need to look at it
-                    self.try_parsing_replicaset(doc);
-                }
+                // The first isMaster response is usually an OP_REPLY
                 self.process_response_documents(&mut client_request, r.get_documents());
             },
             MongoMessage::Compressed(m) => {
@@ -497,9 +933,38 @@ impl MongoStatsTracker{
         }
     }
 
+    fn publish_slow_query(&self, client_request: &ClientRequest) {
+        if let Some(feed) = &self.app.slow_queries {
+            let duration = client_request.message_time.elapsed();
+            if feed.is_slow(duration) {
+                feed.publish(&SlowQuery {
+                    op: &client_request.op,
+                    db: &client_request.db,
+                    collection: &client_request.coll,
+                    app: &self.client_application,
+                    client: &self.client_addr,
+                    duration,
+                    filter_fields: &client_request.filter_fields,
+                });
+            }
+        }
+    }
+
     fn process_response_documents(&mut self, client_request: &mut ClientRequest, documents: &[Document]) {
         for section in documents {
-            self.try_parsing_replicaset(section);
+            if is_hello_op(&client_request.op) {
+                self.process_hello_response(section);
+            }
+
+            if is_version_op(&client_request.op) {
+                if let Some(version) = section.get_str("server_version") {
+                    self.observe_server_version(version);
+                }
+            }
+
+            if self.app.observe_server_status && client_request.op == "serverStatus" {
+                self.observe_server_status(section);
+            }
 
             if let Some(ok) = section.get_float("ok") {
                 if ok == 0.0 {
@@ -508,12 +973,31 @@ impl MongoStatsTracker{
                             Tag::new("error", true)
                         });
                     }
-                    SERVER_RESPONSE_ERRORS_TOTAL
-                        .with_label_values(&self.label_values(&client_request))
-                        .inc();
+                    if is_auth_op(&client_request.op) {
+                        AUTH_FAILURES_TOTAL.with_label_values(&[&self.auth_mechanism]).inc();
+                    }
+                    if section.get_i32("error_code") == Some(MAX_TIME_MS_EXPIRED) {
+                        MAX_TIME_EXPIRED_TOTAL.with_label_values(&[&client_request.op]).inc();
+                    }
+                    if client_request.is_recorded() {
+                        SERVER_RESPONSE_ERRORS_TOTAL
+                            .with_label_values(&self.label_values(&client_request))
+                            .inc();
+                        if let Some(statsd) = &self.app.statsd {
+                            statsd.count("server_response_errors", 1, &self.statsd_tags(&client_request));
+                        }
+                    }
                 }
             }
 
+            if self.app.track_collection_scans && client_request.op == "explain" {
+                count_collection_scans(&client_request.coll, section);
+            }
+
+            if self.app.track_query_selectivity && client_request.op == "explain" {
+                count_docs_examined(&client_request.coll, section);
+            }
+
             let mut n_docs_returned = None;
             let mut n_docs_changed = None;
 
@@ -535,11 +1019,29 @@ impl MongoStatsTracker{
                 n_docs_changed = Some(section.get_i32("n").unwrap_or(0));
             }
 
+            // Exhaust cursors return the documents in several responses, record
+            // the total after the last one.
+            if let Some(n) = n_docs_returned {
+                client_request.docs_returned += n;
+                if let Some(histogram) = &self.app.documents_per_response {
+                    if client_request.is_collection_op() && client_request.is_recorded() {
+                        histogram.observe(&client_request.op, &client_request.coll, n);
+                    }
+                }
+            }
+            let n_docs_returned = if client_request.more_to_come {
+                None
+            } else if client_request.responses > 1 {
+                Some(client_request.docs_returned)
+            } else {
+                n_docs_returned
+            };
+
             if let Some(n) = n_docs_returned {
                 if let Some(span) = &mut client_request.span {
                     span.set_tag(|| Tag::new("documents_returned", n as i64));
                 }
-                if client_request.is_collection_op() {
+                if client_request.is_collection_op() && client_request.is_recorded() {
                     DOCUMENTS_RETURNED_TOTAL
                         .with_label_values(&self.label_values(&client_request))
                         .observe(n as f64);
@@ -550,13 +1052,17 @@ impl MongoStatsTracker{
                 if let Some(span) = &mut client_request.span {
                     span.set_tag(|| Tag::new("documents_changed", n as i64));
                 }
-                if client_request.is_collection_op() {
+                if client_request.is_collection_op() && client_request.is_recorded() {
                     DOCUMENTS_CHANGED_TOTAL
                         .with_label_values(&self.label_values(&client_request))
                         .observe(f64::from(n.abs()));
                 }
             }
 
+            if let Some(cursor_id) = section.get_i64("cursor_id") {
+                self.track_cursor_batches(client_request, cursor_id);
+            }
+
             // Handle the span creation for the cursor operations.
             if let Some(cursor_id) = section.get_i64("cursor_id") {
                 if cursor_id == 0 {
@@ -605,19 +1111,213 @@ impl MongoStatsTracker{
         }
     }
 
-    fn try_parsing_replicaset(&mut self, doc: &Document) {
-        if let Some(op) = doc.get_str("op") {
-            if op == "hosts" {
-                if let Some(replicaset) = doc.get_str("replicaset") {
-                    self.replicaset = replicaset.to_owned();
+    // Count the getMores of each cursor, from the find or aggregate that opens it to
+    // the getMore that exhausts it. Cursors that are exhausted right away have 0.
+    fn track_cursor_batches(&mut self, client_request: &ClientRequest, cursor_id: i64) {
+        if client_request.op == "find" || client_request.op == "aggregate" {
+            let labels = [client_request.op.as_str(), &client_request.coll];
+            if cursor_id == 0 {
+                SINGLE_BATCH_TOTAL.with_label_values(&labels).inc();
+                GETMORE_BATCHES.with_label_values(&[&client_request.coll]).observe(0.0);
+            } else {
+                MULTI_BATCH_TOTAL.with_label_values(&labels).inc();
+                if self.cursor_batches.len() < MAX_TRACKED_CURSORS {
+                    self.cursor_batches.insert(cursor_id, (client_request.coll.clone(), 0));
                 }
-                if let Some(server_host) = doc.get_str("server_host") {
-                    self.server_host = server_host.to_owned();
+            }
+        } else if client_request.op == "getMore" && client_request.cursor_id != 0 {
+            if let Some((_, batches)) = self.cursor_batches.get_mut(&client_request.cursor_id) {
+                *batches += 1;
+            }
+            if cursor_id == 0 {
+                if let Some((coll, batches)) = self.cursor_batches.remove(&client_request.cursor_id) {
+                    GETMORE_BATCHES.with_label_values(&[&coll]).observe(f64::from(batches));
+                }
+            }
+        }
+    }
+
+    // The hello (or legacy isMaster) response tells us the wire protocol versions that
+    // the server supports and the replicaset it belongs to. Standalone servers don't
+    // have a setName.
+    fn process_hello_response(&mut self, doc: &Document) {
+        HELLO_RESPONSES_TOTAL.with_label_values(&[&self.server_addr]).inc();
+
+        self.replicaset = doc.get_str("replicaset").unwrap_or(REPLICASET_NONE).to_owned();
+        if let Some(server_host) = doc.get_str("server_host") {
+            self.server_host = server_host.to_owned();
+        }
+
+        if let Some(max_wire_version) = doc.get_i32("max_wire_version") {
+            SERVER_MAX_WIRE_VERSION
+                .with_label_values(&[&self.server_addr])
+                .set(f64::from(max_wire_version));
+        }
+        if let Some(min_wire_version) = doc.get_i32("min_wire_version") {
+            SERVER_MIN_WIRE_VERSION
+                .with_label_values(&[&self.server_addr])
+                .set(f64::from(min_wire_version));
+        }
+        if let Some(primary) = is_writable_primary(doc) {
+            SERVER_IS_WRITABLE_PRIMARY
+                .with_label_values(&[&self.server_addr])
+                .set(if primary { 1.0 } else { 0.0 });
+        }
+    }
+
+    // Only the fields that the client asked for are in the response, the others are left as they were
+    fn observe_server_status(&self, doc: &Document) {
+        for (key, state) in &[("connections_current", "current"), ("connections_available", "available")] {
+            if let Some(value) = get_number(doc, key) {
+                OBSERVED_SERVER_CONNECTIONS.with_label_values(&[&self.server_addr, state]).set(value);
+            }
+        }
+        for (key, op) in OPCOUNTER_KEYS.iter() {
+            if let Some(value) = get_number(doc, key) {
+                OBSERVED_SERVER_OPCOUNTERS.with_label_values(&[&self.server_addr, op]).set(value);
+            }
+        }
+    }
+
+    fn observe_server_version(&self, version: &str) {
+        let mut versions = SERVER_VERSIONS.lock().unwrap();
+        if let Some(previous) = versions.get(&self.server_addr) {
+            if previous == version {
+                return;
+            }
+            let _ = SERVER_VERSION_INFO.remove_label_values(&[&self.server_addr, previous]);
+        }
+        SERVER_VERSION_INFO.with_label_values(&[&self.server_addr, version]).set(1.0);
+        versions.insert(self.server_addr.clone(), version.to_owned());
+    }
+
+}
+
+// Count the explain responses where the winning plan scans the whole collection
+fn count_collection_scans(collection: &str, doc: &Document) {
+    let is_collscan = PLAN_STAGE_KEYS.iter()
+        .map(|key| doc.get_str(key))
+        .take_while(|stage| stage.is_some())
+        .any(|stage| stage == Some("COLLSCAN"));
+
+    if is_collscan {
+        COLLECTION_SCANS_TOTAL.with_label_values(&[collection]).inc();
+    }
+}
+
+// Count the documents examined and returned by an explain that ran the query, ie. one
+// with executionStats. Plain queryPlanner explains don't have the counts.
+fn count_docs_examined(collection: &str, doc: &Document) {
+    if let (Some(examined), Some(returned)) = (doc.get_i32("explain_docs_examined"), doc.get_i32("explain_returned")) {
+        DOCS_EXAMINED_TOTAL.with_label_values(&[collection]).inc_by(f64::from(examined));
+        DOCS_RETURNED_TOTAL.with_label_values(&[collection]).inc_by(f64::from(returned));
+        QUERY_SELECTIVITY.with_label_values(&[collection]).observe(selectivity(examined, returned));
+    }
+}
+
+// Documents examined per document returned, a query that returns nothing counts as
+// returning one so that scanning for nothing still shows up
+fn selectivity(examined: i32, returned: i32) -> f64 {
+    f64::from(examined) / f64::from(returned.max(1))
+}
+
+// Count the stage operators of an aggregation pipeline. Only the known stage names
+// become label values.
+fn count_aggregation_stages(msg: &MongoMessage) {
+    if let MongoMessage::Msg(m) = msg {
+        if let Some(doc) = m.documents.first() {
+            for key in PIPELINE_STAGE_KEYS.iter() {
+                match doc.get_str(key) {
+                    Some(stage) => {
+                        let stage = if AGGREGATION_STAGES.contains(stage) { stage } else { "other" };
+                        AGGREGATION_STAGES_TOTAL.with_label_values(&[stage]).inc();
+                    },
+                    None => break,
                 }
             }
         }
     }
+}
+
+// Record the shape of the command document. This needs the raw document bytes, which
+// are only kept around when logging or tracing.
+fn observe_document_shape(op: &str, msg: &MongoMessage) {
+    if let MongoMessage::Msg(m) = msg {
+        if let Some(bytes) = m.documents.first().and_then(|doc| doc.get_raw_bytes()) {
+            if let Ok(doc) = bson::Document::from_reader(&mut &bytes[..]) {
+                COMMAND_DEPTH.with_label_values(&[op]).observe(document_depth(&doc) as f64);
+                COMMAND_FIELD_COUNT.with_label_values(&[op]).observe(doc.len() as f64);
+            }
+        }
+    }
+}
+
+// Nesting depth of a document, counting arrays as a level too
+fn document_depth(doc: &bson::Document) -> usize {
+    1 + doc.values().map(value_depth).max().unwrap_or(0)
+}
+
+fn value_depth(value: &bson::Bson) -> usize {
+    match value {
+        bson::Bson::Document(doc) => document_depth(doc),
+        bson::Bson::Array(arr) => 1 + arr.iter().map(value_depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn is_auth_op(op: &str) -> bool {
+    op == "saslStart" || op == "saslContinue" || op == "authenticate"
+}
+
+// Is this the connection handshake op
+fn is_hello_op(op: &str) -> bool {
+    op == "hello" || op == "isMaster" || op == "ismaster"
+}
 
+// Newer servers say isWritablePrimary in the hello response, the older ones only ismaster
+fn is_writable_primary(doc: &Document) -> Option<bool> {
+    doc.get_bool("is_writable_primary").or_else(|| doc.get_bool("ismaster"))
+}
+
+// Does the hello carry the first step of an authentication, as drivers send it
+// when they have credentials
+fn is_speculative_auth(msg: &MongoMessage) -> bool {
+    match msg {
+        MongoMessage::Msg(m) => m.documents.iter().any(|doc| doc.contains_key("speculative_auth")),
+        MongoMessage::Query(q) => q.query.contains_key("speculative_auth"),
+        _ => false,
+    }
+}
+
+// The $comment of the command, if it's a string
+fn message_comment(msg: &MongoMessage) -> Option<&str> {
+    match msg {
+        MongoMessage::Msg(m) => m.documents.iter().find_map(|doc| doc.get_str("comment")),
+        MongoMessage::Query(q) => q.query.get_str("comment"),
+        _ => None,
+    }
+}
+
+// The maxTimeMS of the command, drivers send it as any of the number types
+fn max_time_ms(msg: &MongoMessage) -> Option<f64> {
+    let doc = match msg {
+        MongoMessage::Msg(m) => m.documents.first()?,
+        MongoMessage::Query(q) => &q.query,
+        _ => return None,
+    };
+    get_number(doc, "max_time_ms")
+}
+
+// A numeric field whatever its BSON type
+fn get_number(doc: &Document, key: &str) -> Option<f64> {
+    doc.get_i32(key).map(f64::from)
+        .or_else(|| doc.get_i64(key).map(|v| v as f64))
+        .or_else(|| doc.get_float(key))
+}
+
+// Ops with the server version in the response
+fn is_version_op(op: &str) -> bool {
+    op == "buildInfo" || op == "buildinfo" || op == "serverStatus"
 }
 
 /// Extract `appname` from MongoDb `isMaster` query
@@ -631,3 +1331,181 @@ fn extract_app_name(msg: &MongoMessage) -> Option<&str> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bson::doc;
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use crate::mongodb::{HEADER_LENGTH, MAX_MESSAGE_SIZE};
+
+    // An OP_MSG with a single document section, parsed the way the trackers get it
+    async fn op_msg(request_id: u32, response_to: u32, flag_bits: u32, doc: &bson::Document) -> (MsgHeader, MongoMessage) {
+        let mut doc_buf = Vec::new();
+        doc.to_writer(&mut doc_buf).unwrap();
+
+        let hdr = MsgHeader {
+            message_length: HEADER_LENGTH + 4 + 1 + doc_buf.len(),
+            request_id,
+            response_to,
+            op_code: 2013,
+        };
+        let mut buf = Vec::new();
+        hdr.write(&mut buf).unwrap();
+        buf.write_u32::<LittleEndian>(flag_bits).unwrap();
+        buf.write_u8(0).unwrap();
+        buf.extend_from_slice(&doc_buf);
+
+        MongoMessage::from_reader(&buf[..], false, false, MAX_MESSAGE_SIZE).await.unwrap()
+    }
+
+    async fn reply_document(doc: &bson::Document) -> Document {
+        match op_msg(10, 1, 0, doc).await.1 {
+            MongoMessage::Msg(mut m) => m.documents.remove(0),
+            other => panic!("expecting OP_MSG, got {}", other),
+        }
+    }
+
+    fn new_tracker() -> MongoStatsTracker {
+        MongoStatsTracker::new(
+            "127.0.0.1:45678",
+            "127.0.0.1:27017",
+            "127.0.0.1:27017".parse().unwrap(),
+            AppConfig::new(None, false))
+    }
+
+    async fn request(tracker: &mut MongoStatsTracker, request_id: u32, doc: &bson::Document) {
+        let (hdr, msg) = op_msg(request_id, 0, 0, doc).await;
+        tracker.track_client_request(&hdr, &msg);
+    }
+
+    async fn response(tracker: &mut MongoStatsTracker, request_id: u32, response_to: u32, flag_bits: u32, doc: &bson::Document) {
+        let (hdr, msg) = op_msg(request_id, response_to, flag_bits, doc).await;
+        tracker.track_server_response(hdr, msg);
+    }
+
+    #[tokio::test]
+    async fn test_exhaust_responses() {
+        let mut tracker = new_tracker();
+        let exhaust_responses = EXHAUST_RESPONSES_TOTAL.get();
+
+        request(&mut tracker, 1, &doc! { "getMore": 42_i64, "collection": "kittens", "$db": "test" }).await;
+        response(&mut tracker, 10, 1, MSG_FLAG_MORE_TO_COME, &doc! {
+            "cursor": { "nextBatch": [{ "a": 1 }, { "a": 2 }], "id": 42_i64, "ns": "test.kittens" }, "ok": 1.0,
+        }).await;
+
+        // The next response in the chain is a response to this one
+        let req = &tracker.client_request_map[&10];
+        assert_eq!((1, 2, true), (req.responses, req.docs_returned, req.more_to_come));
+
+        response(&mut tracker, 11, 10, MSG_FLAG_MORE_TO_COME, &doc! {
+            "cursor": { "nextBatch": [{ "a": 3 }, { "a": 4 }], "id": 42_i64, "ns": "test.kittens" }, "ok": 1.0,
+        }).await;
+        let req = &tracker.client_request_map[&11];
+        assert_eq!((2, 4, true), (req.responses, req.docs_returned, req.more_to_come));
+
+        response(&mut tracker, 12, 11, 0, &doc! {
+            "cursor": { "nextBatch": [{ "a": 5 }], "id": 0_i64, "ns": "test.kittens" }, "ok": 1.0,
+        }).await;
+        assert!(tracker.is_between_messages());
+        assert_eq!(exhaust_responses + 2.0, EXHAUST_RESPONSES_TOTAL.get());
+    }
+
+    #[tokio::test]
+    async fn test_auth_mechanism() {
+        let mut tracker = new_tracker();
+        let failures = AUTH_FAILURES_TOTAL.with_label_values(&["SCRAM-SHA-256"]).get();
+
+        request(&mut tracker, 1, &doc! { "saslStart": 1, "mechanism": "SCRAM-SHA-256", "$db": "admin" }).await;
+        assert_eq!("SCRAM-SHA-256", tracker.auth_mechanism);
+        assert!(tracker.authenticated);
+        response(&mut tracker, 10, 1, 0, &doc! { "conversationId": 1, "done": false, "ok": 1.0 }).await;
+        assert!(!tracker.is_reusable());
+
+        // saslContinue doesn't repeat the mechanism, its failure counts for the saslStart's
+        request(&mut tracker, 2, &doc! { "saslContinue": 1, "conversationId": 1, "$db": "admin" }).await;
+        response(&mut tracker, 11, 2, 0, &doc! { "ok": 0.0, "errmsg": "Authentication failed.", "code": 18 }).await;
+        assert_eq!(failures + 1.0, AUTH_FAILURES_TOTAL.with_label_values(&["SCRAM-SHA-256"]).get());
+
+        request(&mut tracker, 3, &doc! { "saslStart": 1, "mechanism": "SCRAM-SHA-3000", "$db": "admin" }).await;
+        assert_eq!("other", tracker.auth_mechanism);
+    }
+
+    #[tokio::test]
+    async fn test_cursor_batches() {
+        let mut tracker = new_tracker();
+
+        request(&mut tracker, 1, &doc! { "find": "kittens", "filter": {}, "$db": "test" }).await;
+        response(&mut tracker, 10, 1, 0, &doc! {
+            "cursor": { "firstBatch": [{ "a": 1 }], "id": 42_i64, "ns": "test.kittens" }, "ok": 1.0,
+        }).await;
+        assert_eq!(Some(&("kittens".to_string(), 0)), tracker.cursor_batches.get(&42));
+
+        request(&mut tracker, 2, &doc! { "getMore": 42_i64, "collection": "kittens", "$db": "test" }).await;
+        response(&mut tracker, 11, 2, 0, &doc! {
+            "cursor": { "nextBatch": [{ "a": 2 }], "id": 42_i64, "ns": "test.kittens" }, "ok": 1.0,
+        }).await;
+        assert_eq!(Some(&("kittens".to_string(), 1)), tracker.cursor_batches.get(&42));
+
+        // The getMore that exhausts the cursor counts as a batch too
+        request(&mut tracker, 3, &doc! { "getMore": 42_i64, "collection": "kittens", "$db": "test" }).await;
+        response(&mut tracker, 12, 3, 0, &doc! {
+            "cursor": { "nextBatch": [{ "a": 3 }], "id": 0_i64, "ns": "test.kittens" }, "ok": 1.0,
+        }).await;
+        assert!(tracker.cursor_batches.is_empty());
+
+        // A cursor that is exhausted right away is never tracked
+        request(&mut tracker, 4, &doc! { "find": "kittens", "filter": {}, "$db": "test" }).await;
+        response(&mut tracker, 13, 4, 0, &doc! {
+            "cursor": { "firstBatch": [], "id": 0_i64, "ns": "test.kittens" }, "ok": 1.0,
+        }).await;
+        assert!(tracker.cursor_batches.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_max_tracked_cursors() {
+        let mut tracker = new_tracker();
+        for cursor_id in 1..=MAX_TRACKED_CURSORS as i64 {
+            tracker.cursor_batches.insert(cursor_id, ("kittens".to_string(), 0));
+        }
+
+        request(&mut tracker, 1, &doc! { "find": "kittens", "filter": {}, "$db": "test" }).await;
+        response(&mut tracker, 10, 1, 0, &doc! {
+            "cursor": { "firstBatch": [{ "a": 1 }], "id": 5000_i64, "ns": "test.kittens" }, "ok": 1.0,
+        }).await;
+        assert_eq!(MAX_TRACKED_CURSORS, tracker.cursor_batches.len());
+        assert!(!tracker.cursor_batches.contains_key(&5000));
+
+        // Exhausting a tracked cursor makes room for the next one
+        request(&mut tracker, 2, &doc! { "getMore": 1_i64, "collection": "kittens", "$db": "test" }).await;
+        response(&mut tracker, 11, 2, 0, &doc! {
+            "cursor": { "nextBatch": [], "id": 0_i64, "ns": "test.kittens" }, "ok": 1.0,
+        }).await;
+        request(&mut tracker, 3, &doc! { "find": "kittens", "filter": {}, "$db": "test" }).await;
+        response(&mut tracker, 12, 3, 0, &doc! {
+            "cursor": { "firstBatch": [{ "a": 1 }], "id": 5001_i64, "ns": "test.kittens" }, "ok": 1.0,
+        }).await;
+        assert_eq!(MAX_TRACKED_CURSORS, tracker.cursor_batches.len());
+        assert!(tracker.cursor_batches.contains_key(&5001));
+    }
+
+    #[test]
+    fn test_selectivity() {
+        assert_eq!(400.0, selectivity(1200, 3));
+        assert_eq!(1.0, selectivity(3, 3));
+        assert_eq!(1200.0, selectivity(1200, 0));
+        assert_eq!(0.0, selectivity(0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_is_writable_primary() {
+        let doc = reply_document(&doc! { "isWritablePrimary": true, "ismaster": false, "ok": 1.0 }).await;
+        assert_eq!(Some(true), is_writable_primary(&doc));
+
+        let doc = reply_document(&doc! { "ismaster": false, "secondary": true, "ok": 1.0 }).await;
+        assert_eq!(Some(false), is_writable_primary(&doc));
+
+        let doc = reply_document(&doc! { "ok": 1.0 }).await;
+        assert_eq!(None, is_writable_primary(&doc));
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::sync::{Arc,Mutex};
+use std::time::{Duration,Instant};
+
+use tokio::net::TcpStream;
+use tokio::time;
+use tracing::{debug};
+
+// Idle server connections kept per upstream
+pub const MAX_IDLE_PER_UPSTREAM: usize = 16;
+
+// Close the pooled connections that have not been reused within this
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Server connections that a client has finished with, waiting for the next client to
+// the same upstream. Only connections that don't carry any state of the previous client
+// (no authentication, no request in flight) may be put here, the caller checks that.
+#[derive(Clone,Debug)]
+pub struct UpstreamPool {
+    max_idle: usize,
+    idle_timeout: Duration,
+    idle: Arc<Mutex<HashMap<String, Vec<IdleConnection>>>>,
+}
+
+#[derive(Debug)]
+struct IdleConnection {
+    stream: TcpStream,
+    since: Instant,
+}
+
+impl UpstreamPool {
+
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Self {
+        UpstreamPool {
+            max_idle,
+            idle_timeout,
+            idle: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Take the most recently used connection to the upstream that is still open.
+    // Connections that the server has closed or that have bytes waiting are dropped.
+    pub async fn take(&self, upstream: &str) -> Option<TcpStream> {
+        loop {
+            let mut conn = self.pop(upstream)?;
+            if is_quiet(&mut conn.stream).await {
+                return Some(conn.stream);
+            }
+            debug!("Dropping a pooled connection to {} that is no longer usable", upstream);
+        }
+    }
+
+    // Keep the connection for the next client, unless there are enough already
+    pub fn put(&self, upstream: &str, stream: TcpStream) -> bool {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.entry(upstream.to_string()).or_insert_with(Vec::new);
+        if connections.len() >= self.max_idle {
+            return false;
+        }
+        connections.push(IdleConnection { stream, since: Instant::now() });
+        true
+    }
+
+    // Number of idle connections over all the upstreams
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().values().map(|c| c.len()).sum()
+    }
+
+    fn pop(&self, upstream: &str) -> Option<IdleConnection> {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.get_mut(upstream)?;
+        connections.retain(|c| c.since.elapsed() < self.idle_timeout);
+        let conn = connections.pop();
+        if connections.is_empty() {
+            idle.remove(upstream);
+        }
+        conn
+    }
+}
+
+// An idle server connection has nothing to read. Being readable means that the server
+// has closed it, or sent something that no client asked for.
+async fn is_quiet(stream: &mut TcpStream) -> bool {
+    let mut buf = [0u8; 1];
+    time::timeout(Duration::from_millis(0), stream.peek(&mut buf)).await.is_err()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    async fn connection_pair(listener: &mut TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_pool() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = UpstreamPool::new(1, IDLE_TIMEOUT);
+
+        let (first, _first_server) = connection_pair(&mut listener).await;
+        let (second, _second_server) = connection_pair(&mut listener).await;
+        assert!(pool.put("mongo:27017", first));
+        assert!(!pool.put("mongo:27017", second));
+        assert_eq!(1, pool.idle());
+
+        assert!(pool.take("other:27017").await.is_none());
+        assert!(pool.take("mongo:27017").await.is_some());
+        assert!(pool.take("mongo:27017").await.is_none());
+        assert_eq!(0, pool.idle());
+    }
+
+    #[tokio::test]
+    async fn test_unusable_connections_are_dropped() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = UpstreamPool::new(2, IDLE_TIMEOUT);
+
+        let (closed, closed_server) = connection_pair(&mut listener).await;
+        drop(closed_server);
+        let (chatty, mut chatty_server) = connection_pair(&mut listener).await;
+        chatty_server.write_all(b"unexpected").await.unwrap();
+        time::delay_for(Duration::from_millis(50)).await;
+
+        pool.put("mongo:27017", closed);
+        pool.put("mongo:27017", chatty);
+        assert!(pool.take("mongo:27017").await.is_none());
+        assert_eq!(0, pool.idle());
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = UpstreamPool::new(2, Duration::from_millis(0));
+
+        let (conn, _server) = connection_pair(&mut listener).await;
+        pool.put("mongo:27017", conn);
+        assert!(pool.take("mongo:27017").await.is_none());
+    }
+}